@@ -0,0 +1,78 @@
+//! Write amplification of the bitmap when growing files through FileMgr. Before bitmap changes were
+//! batched, every allocated block cost one more bitmap block write.
+//! Run with `cargo bench`
+
+extern crate libc;
+extern crate fuse;
+extern crate time;
+
+#[allow(dead_code, unused_imports)]
+#[path="../src/file_mgr.rs"]
+mod file_mgr;
+use file_mgr::*;
+use block_io::*;
+use block_mgr::{BlockMgr, DEFAULT_BLOCK_CNT};
+
+const FILE_SIZE: usize = 4 << 20;
+
+/// Count the writes reaching the storage, and those of them to the bitmap
+struct CountingBlockIO {
+    block_io: FakeMemBlockIO,
+    write_cnt: std::rc::Rc<std::cell::Cell<(usize, usize)>>, // (all, bitmap)
+}
+
+impl CountingBlockIO {
+    fn count(&self, block_id: Id, cnt: usize) {
+        let bitmap_block_cnt = DEFAULT_BLOCK_CNT.div_ceil(BLOCK_SIZE * 8);
+        let (all, bitmap) = self.write_cnt.get();
+        let is_bitmap = block_id >= 1 && block_id as usize <= bitmap_block_cnt;
+        self.write_cnt.set((all + cnt, bitmap + if is_bitmap { cnt } else { 0 }));
+    }
+}
+
+impl BlockIO for CountingBlockIO {
+    fn read(&mut self, block_id: Id) -> Result<[u8; BLOCK_SIZE], std::io::Error> {
+        self.block_io.read(block_id)
+    }
+
+    fn write(&mut self, block_id: Id, data: &[u8]) -> Result<(), std::io::Error> {
+        self.count(block_id, 1);
+        self.block_io.write(block_id, data)
+    }
+
+    fn write_blocks(&mut self, block_id: Id, data: &[u8]) -> Result<(), std::io::Error> {
+        self.count(block_id, data.len() / BLOCK_SIZE);
+        self.block_io.write_blocks(block_id, data)
+    }
+}
+
+fn run(chunk_size: usize) -> Result<(), std::io::Error> {
+    let write_cnt = std::rc::Rc::new(std::cell::Cell::new((0, 0)));
+    let block_io = CountingBlockIO { block_io: FakeMemBlockIO::new(), write_cnt: write_cnt.clone() };
+    let mut file_mgr = FileMgr::new(Box::new(BlockMgr::new(Box::new(block_io))));
+    file_mgr.init(true)?;
+    let inode = file_mgr.read_root_inode()?;
+    let free_block_cnt = file_mgr.free_block_cnt();
+    let chunk: Vec<u8> = (0 .. chunk_size).map(|i| (i % 251) as u8).collect();
+
+    write_cnt.set((0, 0));
+    for i in 0 .. FILE_SIZE / chunk_size {
+        file_mgr.write_file(&inode, i * chunk_size, &chunk)?;
+    }
+    file_mgr.sync()?;
+    let (all, bitmap) = write_cnt.get();
+    let alloc_cnt = free_block_cnt - file_mgr.free_block_cnt();
+    println!(
+        "{:>5} KiB writes: {:5} blocks allocated, {:5} block writes, of which {:5} to the bitmap (was {:5} unbatched)",
+        chunk_size >> 10, alloc_cnt, all, bitmap, alloc_cnt
+    );
+    Ok(())
+}
+
+fn main() -> Result<(), std::io::Error> {
+    println!("Growing a {} MiB file", FILE_SIZE >> 20);
+    for &chunk_size in &[4 << 10, 64 << 10, 1 << 20] {
+        run(chunk_size)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,108 @@
+//! Large sequential I/O through FileMgr, with and without vectored BlockIO operations.
+//! Run with `cargo bench`
+
+extern crate libc;
+extern crate fuse;
+extern crate time;
+
+#[allow(dead_code, unused_imports)]
+#[path="../src/file_mgr.rs"]
+mod file_mgr;
+use file_mgr::*;
+use block_io::*;
+use block_mgr::{BlockMgr, DEFAULT_BLOCK_CNT, device_block_cnt};
+
+const FILE_SIZE: usize = 6 << 20; // Files are capped at ~8 MiB by the single indirect block of 32-bit ids
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// Count the calls reaching the image. If not `vectored`, ranges are split into single blocks,
+/// which is what FileMgr did before
+struct CountingBlockIO {
+    block_io: ImageBlockIO,
+    vectored: bool,
+    call_cnt: std::rc::Rc<std::cell::Cell<usize>>,
+}
+
+impl BlockIO for CountingBlockIO {
+    fn read(&mut self, block_id: Id) -> Result<[u8; BLOCK_SIZE], std::io::Error> {
+        self.call_cnt.set(self.call_cnt.get() + 1);
+        self.block_io.read(block_id)
+    }
+
+    fn write(&mut self, block_id: Id, data: &[u8]) -> Result<(), std::io::Error> {
+        self.call_cnt.set(self.call_cnt.get() + 1);
+        self.block_io.write(block_id, data)
+    }
+
+    fn read_blocks(&mut self, block_id: Id, buf: &mut [u8]) -> Result<(), std::io::Error> {
+        if !self.vectored {
+            for (i, chunk) in buf.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+                chunk.copy_from_slice(&self.read(block_id + i as Id)?);
+            }
+            return Ok(())
+        }
+        self.call_cnt.set(self.call_cnt.get() + 1);
+        self.block_io.read_blocks(block_id, buf)
+    }
+
+    fn write_blocks(&mut self, block_id: Id, data: &[u8]) -> Result<(), std::io::Error> {
+        if !self.vectored {
+            for (i, chunk) in data.chunks_exact(BLOCK_SIZE).enumerate() {
+                self.write(block_id + i as Id, chunk)?;
+            }
+            return Ok(())
+        }
+        self.call_cnt.set(self.call_cnt.get() + 1);
+        self.block_io.write_blocks(block_id, data)
+    }
+}
+
+fn throughput(bytes: usize, elapsed: std::time::Duration) -> f64 {
+    bytes as f64 / (1 << 20) as f64 / elapsed.as_secs_f64()
+}
+
+fn run(vectored: bool) -> Result<(), std::io::Error> {
+    let mut path = std::env::temp_dir();
+    path.push(format!("rfs-bench-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let call_cnt = std::rc::Rc::new(std::cell::Cell::new(0));
+    let block_io = CountingBlockIO {
+        block_io: ImageBlockIO::new(path.clone(), device_block_cnt(DEFAULT_BLOCK_CNT))?, vectored, call_cnt: call_cnt.clone()
+    };
+    let mut file_mgr = FileMgr::new(Box::new(BlockMgr::new(Box::new(block_io))));
+    file_mgr.init(true)?;
+    let inode = file_mgr.read_root_inode()?;
+    let chunk: Vec<u8> = (0 .. CHUNK_SIZE).map(|i| (i % 251) as u8).collect();
+
+    call_cnt.set(0);
+    let begin = std::time::Instant::now();
+    for i in 0 .. FILE_SIZE / CHUNK_SIZE {
+        file_mgr.write_file(&inode, i * CHUNK_SIZE, &chunk)?;
+    }
+    let write_time = begin.elapsed();
+    let write_calls = call_cnt.get();
+
+    call_cnt.set(0);
+    let begin = std::time::Instant::now();
+    for i in 0 .. FILE_SIZE / CHUNK_SIZE {
+        let data = file_mgr.read_file(&inode, i * CHUNK_SIZE, CHUNK_SIZE)?;
+        assert_eq!(data, chunk);
+    }
+    let read_time = begin.elapsed();
+    let read_calls = call_cnt.get();
+
+    println!(
+        "{:>9}: write {:8.1} MiB/s ({:6} backend calls), read {:8.1} MiB/s ({:6} backend calls)",
+        if vectored { "vectored" } else { "per-block" },
+        throughput(FILE_SIZE, write_time), write_calls, throughput(FILE_SIZE, read_time), read_calls
+    );
+    drop(inode);
+    drop(file_mgr);
+    std::fs::remove_file(&path)
+}
+
+fn main() -> Result<(), std::io::Error> {
+    println!("Sequential I/O of a {} MiB file in {} KiB chunks", FILE_SIZE >> 20, CHUNK_SIZE >> 10);
+    run(false)?;
+    run(true)
+}
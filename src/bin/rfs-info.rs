@@ -0,0 +1,74 @@
+extern crate libc;
+extern crate fuse;
+extern crate time;
+
+#[allow(dead_code, unused_imports)]
+#[path="../file_mgr.rs"]
+mod file_mgr;
+use file_mgr::*;
+use block_io::*;
+use block_mgr::BlockMgr;
+
+fn format_time(sec: i64) -> String {
+    if sec == 0 {
+        String::from("never")
+    } else {
+        time::at(time::Timespec::new(sec, 0)).rfc822().to_string()
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 2 {
+        println!("Usage:");
+        println!(" {} storage", args[0]);
+        println!("Show the super block of a storage directory or image file");
+        std::process::exit(-1);
+    }
+    if !std::path::Path::new(&args[1]).exists() {
+        println!("{} does not exist", args[1]);
+        std::process::exit(-1);
+    }
+
+    let block_io = open_path(std::path::PathBuf::from(&args[1]), 0)?;
+    let mut block_mgr = BlockMgr::new(Box::new(ReadOnlyBlockIO::new(block_io)));
+    if !block_mgr.is_formatted()? {
+        println!("{} is not formatted", args[1]);
+        std::process::exit(-1);
+    }
+    block_mgr.init(false)?;
+    let super_block = block_mgr.super_block();
+    println!("Format version:    {}", super_block.version);
+    println!("UUID:              {}", super_block.uuid_string());
+    println!("Label:             {}", super_block.label);
+    println!("Block size:        {}", super_block.block_size);
+    println!("Block id size:     {}", super_block.id_size);
+    println!("Blocks:            {}", super_block.block_cnt);
+    println!("Free blocks:       {}", super_block.free_block_cnt);
+    println!("Journal blocks:    {}", super_block.journal_block_cnt);
+    println!("Layout:            bitmap at {}, data at {}, journal at {}",
+             super_block.bitmap_start, super_block.data_start, super_block.journal_start);
+    match super_block.inode_table_size {
+        0 => println!("Inode table:       none, every inode takes a data block"),
+        size => println!("Inode table:       {} inodes, bitmap at {}, table at {}",
+                         size, super_block.inode_bitmap_start, super_block.inode_table_start),
+    }
+    println!("Inline data:       {}", if super_block.inode_flags { "yes" } else { "no" });
+    println!("Large files:       {}", if super_block.large_files { "yes" } else { "no, at most 4 GiB" });
+    println!("Extents:           {}", if super_block.extents { "yes" } else { "no, block pointers only" });
+    println!("Extended attrs:    {}", if super_block.xattrs { "yes" } else { "no" });
+    match super_block.inode_cnt {
+        Some(cnt) => println!("Inodes:            {}", cnt),
+        None => println!("Inodes:            unknown until mounted writable"),
+    }
+    match super_block.quota_inode {
+        0 => println!("Quotas:            disabled"),
+        id => println!("Quotas:            enabled, kept in inode {}", id),
+    }
+    println!("Created:           {}", format_time(super_block.created));
+    println!("Last mounted:      {}", format_time(super_block.last_mounted));
+    println!("Last written:      {}", format_time(super_block.last_written));
+    println!("Mount count:       {}", super_block.mount_cnt);
+    println!("State:             {}", if super_block.clean { "clean" } else { "not cleanly unmounted" });
+    Ok(())
+}
@@ -0,0 +1,43 @@
+extern crate libc;
+extern crate fuse;
+extern crate time;
+
+#[allow(dead_code, unused_imports)]
+#[path="../file_mgr.rs"]
+mod file_mgr;
+use file_mgr::*;
+use block_io::*;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    let command = args.get(1).map(|arg| arg.as_str());
+    if !matches!((command, args.len()), (Some("commit"), 4) | (Some("discard"), 3)) {
+        println!("Usage:");
+        println!(" {} commit delta_dir base", args[0]);
+        println!(" {} discard delta_dir", args[0]);
+        println!("Commit the delta directory of OVERLAY into the base directory or image file, or throw it away");
+        std::process::exit(-1);
+    }
+
+    let base: Box<dyn BlockIO> = match args.get(3) {
+        Some(path) => {
+            if !std::path::Path::new(path).exists() {
+                println!("{} does not exist", path);
+                std::process::exit(-1);
+            }
+            // The image grows if the delta has blocks beyond it
+            open_path(std::path::PathBuf::from(path), 0)?
+        },
+        None => Box::new(ReadOnlyBlockIO::new(Box::new(FakeMemBlockIO::new())))
+    };
+    let mut overlay = OverlayBlockIO::new(base, Some(std::path::PathBuf::from(&args[2])))?;
+    let delta_cnt = overlay.delta_cnt();
+    if command == Some("commit") {
+        overlay.commit()?;
+        println!("Committed {} blocks", delta_cnt);
+    } else {
+        overlay.drop_delta()?;
+        println!("Discarded {} blocks", delta_cnt);
+    }
+    Ok(())
+}
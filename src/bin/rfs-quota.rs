@@ -0,0 +1,114 @@
+extern crate libc;
+extern crate fuse;
+extern crate time;
+
+#[allow(dead_code, unused_imports)]
+#[path="../file_mgr.rs"]
+mod file_mgr;
+use file_mgr::*;
+use block_io::*;
+use block_mgr::{BlockMgr, parse_size};
+use quota::{Limits, QuotaKind, GRACE_PERIOD};
+
+fn usage(program: &str) -> ! {
+    println!("Usage:");
+    println!(" {} storage", program);
+    println!("Show the usage and the limits of every user and group");
+    println!(" {} storage user|group id block_soft block_hard inode_soft inode_hard", program);
+    println!("Set the limits of a user or a group on an unmounted storage, enabling quotas if they are not yet");
+    println!("Block limits are sizes like 512M or 2G, inode limits are counts. 0 means no limit");
+    println!("A soft limit can be exceeded for {} days before it is enforced", GRACE_PERIOD / (24 * 3600));
+    std::process::exit(-1);
+}
+
+fn format_limit(limit: u64) -> String {
+    if limit == 0 {
+        String::from("-")
+    } else {
+        limit.to_string()
+    }
+}
+
+fn format_grace(over_since: i64) -> String {
+    if over_since == 0 {
+        return String::new()
+    }
+    let left = over_since + GRACE_PERIOD - block_mgr::super_block::now();
+    if left <= 0 {
+        String::from(" (grace expired)")
+    } else {
+        format!(" (grace {}h left)", (left + 3599) / 3600)
+    }
+}
+
+fn show(file_mgr: &FileMgr) {
+    let quota = match file_mgr.quota() {
+        Some(quota) => quota,
+        None => {
+            println!("Quotas are not enabled");
+            return
+        }
+    };
+    println!("{:<16} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+             "Owner", "Blocks", "Soft", "Hard", "Inodes", "Soft", "Hard");
+    for (&(kind, id), record) in quota.records() {
+        let owner = match kind {
+            QuotaKind::User => format!("user {}", id),
+            QuotaKind::Group => format!("group {}", id),
+        };
+        println!("{:<16} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}{}{}", owner,
+                 record.block_usage, format_limit(record.blocks.soft), format_limit(record.blocks.hard),
+                 record.inode_usage, format_limit(record.inodes.soft), format_limit(record.inodes.hard),
+                 format_grace(record.block_over_since), format_grace(record.inode_over_since));
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 2 && args.len() != 8 {
+        usage(&args[0]);
+    }
+    if !std::path::Path::new(&args[1]).exists() {
+        println!("{} does not exist", args[1]);
+        std::process::exit(-1);
+    }
+
+    let block_io = open_path(std::path::PathBuf::from(&args[1]), 0)?;
+    if args.len() == 2 {
+        let mut file_mgr = FileMgr::new(Box::new(BlockMgr::new(Box::new(ReadOnlyBlockIO::new(block_io)))));
+        if !file_mgr.is_formatted()? {
+            println!("{} is not formatted", args[1]);
+            std::process::exit(-1);
+        }
+        file_mgr.init(false)?;
+        show(&file_mgr);
+        return Ok(())
+    }
+
+    let kind = match args[2].as_str() {
+        "user" => QuotaKind::User,
+        "group" => QuotaKind::Group,
+        _ => usage(&args[0])
+    };
+    let id = args[3].parse::<u32>()?;
+    let blocks = Limits {
+        soft: (parse_size(&args[4])?.div_ceil(BLOCK_SIZE)) as u64,
+        hard: (parse_size(&args[5])?.div_ceil(BLOCK_SIZE)) as u64,
+    };
+    let inodes = Limits { soft: args[6].parse::<u64>()?, hard: args[7].parse::<u64>()? };
+
+    let mut file_mgr = FileMgr::new(Box::new(BlockMgr::new(block_io)));
+    if !file_mgr.is_formatted()? {
+        println!("{} is not formatted", args[1]);
+        std::process::exit(-1);
+    }
+    file_mgr.init(false)?;
+    if file_mgr.inode_cnt().is_none() {
+        let inode_cnt = file_mgr.inode_refs()?.len() as u64; // The quota inode is counted when created
+        file_mgr.set_inode_cnt(inode_cnt);
+    }
+    file_mgr.set_quota((kind, id), blocks, inodes)?;
+    file_mgr.sync()?;
+    show(&file_mgr);
+    Ok(())
+}
@@ -0,0 +1,117 @@
+extern crate libc;
+extern crate fuse;
+extern crate time;
+
+#[allow(dead_code, unused_imports)]
+#[path="../file_mgr.rs"]
+mod file_mgr;
+use file_mgr::*;
+use block_io::*;
+use block_io::trace_block_io::{read_header, TraceOp, TraceRecord};
+use block_io::checksum_block_io::crc32c;
+use block_mgr::{BlockMgr, DEFAULT_BLOCK_CNT, device_block_cnt};
+use block_mgr::super_block::SuperBlock;
+
+/// Let the replayed device outlive the BlockMgr created for each check
+struct SharedBlockIO(std::rc::Rc<std::cell::RefCell<Box<dyn BlockIO>>>);
+
+impl BlockIO for SharedBlockIO {
+    fn read(&mut self, block_id: Id) -> Result<[u8; BLOCK_SIZE], std::io::Error> {
+        self.0.borrow_mut().read(block_id)
+    }
+
+    fn write(&mut self, block_id: Id, data: &[u8]) -> Result<(), std::io::Error> {
+        self.0.borrow_mut().write(block_id, data)
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+/// Check the volume as mounting would see it, and that a cleanly unmounted volume has the right
+/// counts in its super block
+fn check(device: &std::rc::Rc<std::cell::RefCell<Box<dyn BlockIO>>>) -> Result<Vec<String>, std::io::Error> {
+    // Recover from the journal as mounting would, but without touching the replayed device
+    let snapshot = OverlayBlockIO::new(Box::new(SharedBlockIO(device.clone())), None)?;
+    let mut file_mgr = FileMgr::new(Box::new(BlockMgr::new(Box::new(snapshot))));
+    if !file_mgr.is_formatted()? {
+        return Ok(vec![]) // Not formatted yet
+    }
+    let stored = SuperBlock::parse(&device.borrow_mut().read(0)?)?;
+    file_mgr.init(false)?;
+    if file_mgr.inode_cnt() == Some(0) {
+        return Ok(vec![]) // Root inode not created yet
+    }
+    let mut problems = file_mgr.check()?;
+    if stored.clean {
+        if stored.free_block_cnt != file_mgr.free_block_cnt() {
+            problems.push(format!("super block counts {} free blocks, but the bitmap {}",
+                                  stored.free_block_cnt, file_mgr.free_block_cnt()));
+        }
+        if let Some(inode_cnt) = stored.inode_cnt.filter(|&cnt| Some(cnt) != file_mgr.inode_cnt()) {
+            problems.push(format!("super block counts {} inodes, but the inode bitmap {}",
+                                  inode_cnt, file_mgr.inode_cnt().unwrap_or(0)));
+        }
+    }
+    Ok(problems)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    let do_check = args.iter().any(|arg| arg == "--check");
+    let paths: Vec<&String> = args[1 ..].iter().filter(|arg| !arg.starts_with("--")).collect();
+    if paths.is_empty() || paths.len() > 2 {
+        println!("Usage:");
+        println!(" {} trace_file [image_file] [--check]", args[0]);
+        println!("Replay a trace recorded with TRACE_FILE and TRACE_DATA onto image_file, or onto memory if not given");
+        println!(" --check : Check filesystem invariants after every write");
+        std::process::exit(-1);
+    }
+
+    let mut reader = std::io::BufReader::new(std::fs::File::open(paths[0])?);
+    read_header(&mut reader)?;
+    let device: Box<dyn BlockIO> = match paths.get(1) {
+        Some(path) => Box::new(ImageBlockIO::new(std::path::PathBuf::from(path), device_block_cnt(DEFAULT_BLOCK_CNT))?),
+        None => Box::new(FakeMemBlockIO::new())
+    };
+    let device = std::rc::Rc::new(std::cell::RefCell::new(device));
+
+    let mut step = 0;
+    let mut problem_cnt = 0;
+    while let Some(record) = TraceRecord::read_from(&mut reader)? {
+        step += 1;
+        match record.op {
+            TraceOp::Read => {
+                let data = device.borrow_mut().read(record.block_id)?;
+                if record.hash.is_some_and(|hash| hash != crc32c(&data)) {
+                    println!("Step {}: read of block {} diverges from the trace", step, record.block_id);
+                    problem_cnt += 1;
+                }
+            },
+            TraceOp::Write => {
+                let data = match record.data {
+                    Some(data) => data,
+                    None => {
+                        println!("Step {}: write data not recorded. Was TRACE_DATA set?", step);
+                        std::process::exit(-1);
+                    }
+                };
+                device.borrow_mut().write(record.block_id, &data[..])?;
+                if do_check {
+                    for problem in check(&device)? {
+                        println!("Step {} (write block {} at {}µs): {}", step, record.block_id, record.timestamp, problem);
+                        problem_cnt += 1;
+                    }
+                }
+            },
+            TraceOp::Flush => device.borrow_mut().flush()?,
+            TraceOp::Discard => device.borrow_mut().discard(record.block_id, 1)?
+        }
+    }
+    println!("Replayed {} steps, {} problems found", step, problem_cnt);
+    if problem_cnt > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,64 @@
+extern crate libc;
+extern crate fuse;
+extern crate time;
+
+#[allow(dead_code, unused_imports)]
+#[path="../file_mgr.rs"]
+mod file_mgr;
+use file_mgr::*;
+use block_io::*;
+use block_mgr::{BlockMgr, parse_size};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    let checksum = args.iter().any(|arg| arg == "--checksum");
+    let params: Vec<&String> = args[1 ..].iter().filter(|arg| !arg.starts_with("--")).collect();
+    if params.len() != 2 {
+        println!("Usage:");
+        println!(" {} storage new_size [--checksum]", args[0]);
+        println!("Grow or shrink the filesystem of an unmounted storage directory or image file to new_size, like 512M or 2G");
+        println!(" --checksum : The storage is mounted with CHECKSUM, whose checksums move along");
+        std::process::exit(-1);
+    }
+    if !std::path::Path::new(params[0]).exists() {
+        println!("{} does not exist", params[0]);
+        std::process::exit(-1);
+    }
+    let block_cnt = parse_size(params[1])? / BLOCK_SIZE;
+
+    let mut block_io = open_path(std::path::PathBuf::from(params[0]), 0)?;
+    let device_block_cnt = match block_mgr::probe_device_block_cnt(&mut *block_io)? {
+        Some(device_block_cnt) => device_block_cnt,
+        None => {
+            println!("{} is not formatted", params[0]);
+            std::process::exit(-1);
+        }
+    };
+    if checksum {
+        block_io = Box::new(ChecksumBlockIO::new(block_io, device_block_cnt));
+    }
+    let mut file_mgr = FileMgr::new(Box::new(BlockMgr::new(block_io)));
+    file_mgr.init(false)?;
+    if file_mgr.inode_cnt().is_none() {
+        let inode_cnt = file_mgr.inode_refs()?.len() as u64; // The new super block keeps it
+        file_mgr.set_inode_cnt(inode_cnt);
+    }
+    let old_block_cnt = file_mgr.block_cnt();
+    file_mgr.begin();
+    let ret = file_mgr.resize(block_cnt, true);
+    let ret = match ret {
+        Ok(()) => file_mgr.commit(),
+        Err(err) => file_mgr.abort().and(Err(err))
+    };
+    if let Err(err) = ret {
+        match err.raw_os_error() {
+            Some(libc::ENOSPC) => println!("The used blocks do not fit in {} blocks", block_cnt),
+            Some(libc::EBUSY) => println!("Some blocks after block {} are used by unreachable inodes", block_cnt),
+            _ => println!("Failed to resize: {}", err)
+        }
+        std::process::exit(1);
+    }
+    file_mgr.sync()?;
+    println!("Resized from {} to {} blocks", old_block_cnt, block_cnt);
+    Ok(())
+}
@@ -0,0 +1,47 @@
+extern crate libc;
+extern crate fuse;
+extern crate time;
+
+#[allow(dead_code, unused_imports)]
+#[path="../file_mgr.rs"]
+mod file_mgr;
+use file_mgr::*;
+use block_io::*;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    let checksum = args.iter().any(|arg| arg == "--checksum");
+    let paths: Vec<&String> = args[1 ..].iter().filter(|arg| !arg.starts_with("--")).collect();
+    if paths.len() != 2 {
+        println!("Usage:");
+        println!(" {} healthy_mirror replaced_mirror [--checksum]", args[0]);
+        println!("Rebuild a replaced mirror of MIRRORS from a healthy one. Both are directories or image files");
+        println!(" --checksum : The mirrors are mounted with CHECKSUM");
+        std::process::exit(-1);
+    }
+
+    // Take the geometry from the healthy mirror
+    let mut healthy = open_path(std::path::PathBuf::from(paths[0]), 0)?;
+    let device_block_cnt = match block_mgr::probe_device_block_cnt(&mut *healthy)? {
+        Some(device_block_cnt) => device_block_cnt,
+        None => {
+            println!("{} is not formatted", paths[0]);
+            std::process::exit(-1);
+        }
+    };
+    let total_block_cnt = if checksum {
+        device_block_cnt + ChecksumBlockIO::checksum_block_cnt(device_block_cnt)
+    } else {
+        device_block_cnt
+    };
+    let replaced = open_path(std::path::PathBuf::from(paths[1]), total_block_cnt)?;
+    let children = vec![healthy, replaced].into_iter().map(|block_io| if checksum {
+        Box::new(ChecksumBlockIO::new(block_io, device_block_cnt))
+    } else {
+        block_io
+    }).collect();
+    let mut mirror = MirrorBlockIO::new(children);
+    mirror.resync(1, device_block_cnt)?;
+    println!("Resynced {} blocks", device_block_cnt);
+    Ok(())
+}
@@ -0,0 +1,46 @@
+extern crate libc;
+extern crate fuse;
+extern crate time;
+
+#[allow(dead_code, unused_imports)]
+#[path="../file_mgr.rs"]
+mod file_mgr;
+use file_mgr::*;
+use block_io::*;
+use block_mgr::BlockMgr;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    let checksum = args.iter().any(|arg| arg == "--checksum");
+    let paths: Vec<&String> = args[1 ..].iter().filter(|arg| !arg.starts_with("--")).collect();
+    if paths.len() != 1 {
+        println!("Usage:");
+        println!(" {} storage [--checksum]", args[0]);
+        println!("Discard the free blocks of an unmounted storage directory or image file, like fstrim");
+        println!(" --checksum : The storage is mounted with CHECKSUM");
+        std::process::exit(-1);
+    }
+    if !std::path::Path::new(paths[0]).exists() {
+        println!("{} does not exist", paths[0]);
+        std::process::exit(-1);
+    }
+
+    let mut block_io = open_path(std::path::PathBuf::from(paths[0]), 0)?;
+    let device_block_cnt = match block_mgr::probe_device_block_cnt(&mut *block_io)? {
+        Some(device_block_cnt) => device_block_cnt,
+        None => {
+            println!("{} is not formatted", paths[0]);
+            std::process::exit(-1);
+        }
+    };
+    if checksum {
+        // So the checksums of the discarded blocks are cleared as well
+        block_io = Box::new(ChecksumBlockIO::new(block_io, device_block_cnt));
+    }
+    let mut block_mgr = BlockMgr::new(block_io);
+    block_mgr.init(false)?;
+    let cnt = block_mgr.trim()?;
+    block_mgr.sync()?;
+    println!("Discarded {} free blocks", cnt);
+    Ok(())
+}
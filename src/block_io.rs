@@ -1,37 +1,157 @@
+#[path="cached_block_io.rs"]
+pub mod cached_block_io;
+pub use cached_block_io::CachedBlockIO;
+
+#[path="checksum_block_io.rs"]
+pub mod checksum_block_io;
+pub use checksum_block_io::ChecksumBlockIO;
+
+#[path="trace_block_io.rs"]
+pub mod trace_block_io;
+pub use trace_block_io::TraceBlockIO;
+
+#[path="nbd_block_io.rs"]
+pub mod nbd_block_io;
+pub use nbd_block_io::NbdBlockIO;
+
+#[path="mirror_block_io.rs"]
+pub mod mirror_block_io;
+pub use mirror_block_io::MirrorBlockIO;
+
+#[path="overlay_block_io.rs"]
+pub mod overlay_block_io;
+pub use overlay_block_io::OverlayBlockIO;
+
+#[cfg(test)]
+#[path="fault_block_io.rs"]
+pub mod fault_block_io;
+
 pub const BLOCK_SIZE: usize = 4096;
-pub type Id = u16;
+pub type Id = u32;
 
 pub trait BlockIO {
     fn read(&mut self, block_id: Id) -> Result<[u8; BLOCK_SIZE], std::io::Error>;
     fn write(&mut self, block_id: Id, data: &[u8]) -> Result<(), std::io::Error>;
+
+    /// Read consecutive blocks starting from `block_id` into `buf`, whose length is a multiple of
+    /// BLOCK_SIZE. Backends able to do it in one go should override this
+    fn read_blocks(&mut self, block_id: Id, buf: &mut [u8]) -> Result<(), std::io::Error> {
+        assert_eq!(buf.len() % BLOCK_SIZE, 0);
+        for (i, chunk) in buf.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+            chunk.copy_from_slice(&self.read(block_id + i as Id)?);
+        }
+        Ok(())
+    }
+
+    /// Write `data`, whose length is a multiple of BLOCK_SIZE, to consecutive blocks starting from
+    /// `block_id`
+    fn write_blocks(&mut self, block_id: Id, data: &[u8]) -> Result<(), std::io::Error> {
+        assert_eq!(data.len() % BLOCK_SIZE, 0);
+        for (i, chunk) in data.chunks_exact(BLOCK_SIZE).enumerate() {
+            self.write(block_id + i as Id, chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Make all previous writes persistent
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    /// Tell the storage that `cnt` blocks starting from `block_id` are no longer used, so it can
+    /// release their space (like TRIM). Their contents are undefined until written again. Backends
+    /// unable to release space just ignore it
+    fn discard(&mut self, _block_id: Id, _cnt: usize) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    /// Number of blocks kept after the first `block_cnt` ones for the BlockIO itself, like checksums,
+    /// which the volume must not use
+    fn tail_block_cnt(&self, _block_cnt: usize) -> usize {
+        0
+    }
+
+    /// Move the blocks counted by `tail_block_cnt` to after the first `block_cnt` ones, for a volume
+    /// being resized. The old ones are left as they are, so they still match the storage if the new
+    /// size never takes effect. Only the new places are written
+    fn resize(&mut self, _block_cnt: usize) -> Result<(), std::io::Error> {
+        Ok(())
+    }
 }
 
-pub struct FakeMemBlockIO {
-    blocks: Vec<Box<[u8; BLOCK_SIZE]>>,
+/// Reject every write with EROFS, for read-only mounts
+pub struct ReadOnlyBlockIO {
+    block_io: Box<dyn BlockIO>,
 }
 
-impl FakeMemBlockIO {
-    fn ensure_length(&mut self, block_id: Id) {
-        while self.blocks.len() <= block_id as usize {
-            self.blocks.push(Box::new([0; BLOCK_SIZE]));
-        }
+impl ReadOnlyBlockIO {
+    pub fn new(block_io: Box<dyn BlockIO>) -> ReadOnlyBlockIO {
+        ReadOnlyBlockIO { block_io }
+    }
+}
+
+impl BlockIO for ReadOnlyBlockIO {
+    fn read(&mut self, block_id: Id) -> Result<[u8; BLOCK_SIZE], std::io::Error> {
+        self.block_io.read(block_id)
+    }
+
+    fn write(&mut self, _block_id: Id, _data: &[u8]) -> Result<(), std::io::Error> {
+        Err(std::io::Error::from_raw_os_error(libc::EROFS))
+    }
+
+    fn discard(&mut self, _block_id: Id, _cnt: usize) -> Result<(), std::io::Error> {
+        Err(std::io::Error::from_raw_os_error(libc::EROFS))
+    }
+
+    fn tail_block_cnt(&self, block_cnt: usize) -> usize {
+        self.block_io.tail_block_cnt(block_cnt)
     }
 
+    fn resize(&mut self, _block_cnt: usize) -> Result<(), std::io::Error> {
+        Err(std::io::Error::from_raw_os_error(libc::EROFS))
+    }
+}
+
+/// Blocks never written or discarded take no memory, and read as zeros
+pub struct FakeMemBlockIO {
+    blocks: Vec<Option<Box<[u8; BLOCK_SIZE]>>>,
+}
+
+impl FakeMemBlockIO {
     pub fn new() -> FakeMemBlockIO {
         FakeMemBlockIO { blocks: Vec::new() }
     }
+
+    /// Number of blocks holding data
+    #[allow(dead_code)] // Only used by tests
+    pub fn used_block_cnt(&self) -> usize {
+        self.blocks.iter().filter(|block| block.is_some()).count()
+    }
 }
 
 impl BlockIO for FakeMemBlockIO {
     fn read(&mut self, block_id: Id) -> Result<[u8; BLOCK_SIZE], std::io::Error> {
-        self.ensure_length(block_id);
-        Ok(*self.blocks[block_id as usize])
+        match self.blocks.get(block_id as usize) {
+            Some(Some(block)) => Ok(**block),
+            _ => Ok([0; BLOCK_SIZE])
+        }
     }
 
     fn write(&mut self, block_id: Id, data: &[u8]) -> Result<(), std::io::Error> {
         assert_eq!(data.len(), BLOCK_SIZE);
-        self.ensure_length(block_id);
-        self.blocks[block_id as usize].copy_from_slice(data);
+        if self.blocks.len() <= block_id as usize {
+            self.blocks.resize(block_id as usize + 1, None);
+        }
+        let block = self.blocks[block_id as usize].get_or_insert_with(|| Box::new([0; BLOCK_SIZE]));
+        block.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn discard(&mut self, block_id: Id, cnt: usize) -> Result<(), std::io::Error> {
+        let end = std::cmp::min(block_id as usize + cnt, self.blocks.len());
+        for block in self.blocks.iter_mut().take(end).skip(block_id as usize) {
+            *block = None;
+        }
         Ok(())
     }
 }
@@ -43,7 +163,7 @@ pub struct FileBlockIO {
 impl FileBlockIO {
     pub fn new(path: std::path::PathBuf) -> Result<FileBlockIO, std::io::Error> {
         std::fs::create_dir_all(&path)?;
-        Ok(FileBlockIO { path: path })
+        Ok(FileBlockIO { path })
     }
 }
 
@@ -63,5 +183,177 @@ impl BlockIO for FileBlockIO {
         path.push(format!("blk-{}", block_id));
         std::fs::write(&path, data)
     }
+
+    /// Delete the block files, which then read as zeros
+    fn discard(&mut self, block_id: Id, cnt: usize) -> Result<(), std::io::Error> {
+        for id in block_id as usize .. block_id as usize + cnt {
+            let mut path = std::path::PathBuf::from(&self.path);
+            path.push(format!("blk-{}", id));
+            match std::fs::remove_file(&path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+                _ => ()
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Open `path` as storage: an existing directory is used like STORAGE_DIR, anything else as an
+/// image file of `block_cnt` blocks
+pub fn open_path(path: std::path::PathBuf, block_cnt: usize) -> Result<Box<dyn BlockIO>, std::io::Error> {
+    if path.is_dir() {
+        Ok(Box::new(FileBlockIO::new(path)?))
+    } else {
+        Ok(Box::new(ImageBlockIO::new(path, block_cnt)?))
+    }
+}
+
+/// Keep all blocks in one preallocated image file. Block `i` lives at offset `i * BLOCK_SIZE`
+pub struct ImageBlockIO {
+    file: std::fs::File,
+}
+
+impl ImageBlockIO {
+    pub fn new(path: std::path::PathBuf, block_cnt: usize) -> Result<ImageBlockIO, std::io::Error> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+        let size = (block_cnt * BLOCK_SIZE) as u64;
+        if file.metadata()?.len() < size {
+            file.set_len(size)?; // Sparse on most filesystems
+        }
+        Ok(ImageBlockIO { file })
+    }
+}
+
+impl BlockIO for ImageBlockIO {
+    fn read(&mut self, block_id: Id) -> Result<[u8; BLOCK_SIZE], std::io::Error> {
+        let mut data = [0; BLOCK_SIZE];
+        self.read_blocks(block_id, &mut data)?;
+        Ok(data)
+    }
+
+    fn write(&mut self, block_id: Id, data: &[u8]) -> Result<(), std::io::Error> {
+        assert_eq!(data.len(), BLOCK_SIZE);
+        self.write_blocks(block_id, data)
+    }
+
+    fn read_blocks(&mut self, block_id: Id, buf: &mut [u8]) -> Result<(), std::io::Error> {
+        use std::os::unix::fs::FileExt;
+        assert_eq!(buf.len() % BLOCK_SIZE, 0);
+        let offset = block_id as u64 * BLOCK_SIZE as u64;
+        let mut read_cnt = 0;
+        while read_cnt < buf.len() {
+            match self.file.read_at(&mut buf[read_cnt ..], offset + read_cnt as u64) {
+                Ok(0) => { // Beyond the end of the image
+                    for byte in buf[read_cnt ..].iter_mut() {
+                        *byte = 0;
+                    }
+                    break
+                },
+                Ok(n) => read_cnt += n,
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, block_id: Id, data: &[u8]) -> Result<(), std::io::Error> {
+        use std::os::unix::fs::FileExt;
+        assert_eq!(data.len() % BLOCK_SIZE, 0);
+        self.file.write_all_at(data, block_id as u64 * BLOCK_SIZE as u64)
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.file.sync_data()
+    }
+
+    /// Punch a hole, which then reads as zeros. Ignored by filesystems unable to do it
+    fn discard(&mut self, block_id: Id, cnt: usize) -> Result<(), std::io::Error> {
+        use std::os::unix::io::AsRawFd;
+        let ret = unsafe {
+            libc::fallocate(self.file.as_raw_fd(), libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                            block_id as i64 * BLOCK_SIZE as i64, (cnt * BLOCK_SIZE) as i64)
+        };
+        if ret != 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+                return Err(err)
+            }
+        }
+        Ok(())
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_only() -> Result<(), std::io::Error> {
+        let mut mem = FakeMemBlockIO::new();
+        mem.write(1, &[1; BLOCK_SIZE])?;
+        let mut block_io = ReadOnlyBlockIO::new(Box::new(mem));
+        assert_eq!(block_io.read(1)?[..], [1; BLOCK_SIZE][..]);
+        assert_eq!(block_io.write(1, &[2; BLOCK_SIZE]).unwrap_err().raw_os_error(), Some(libc::EROFS));
+        assert_eq!(block_io.read(1)?[..], [1; BLOCK_SIZE][..]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_discard() -> Result<(), std::io::Error> {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("rfs-test-discard-{}", std::process::id()));
+        let mut image_path = dir.clone();
+        image_path.set_extension("img");
+        let block_ios: Vec<Box<dyn BlockIO>> = vec![
+            Box::new(FakeMemBlockIO::new()),
+            Box::new(FileBlockIO::new(dir.clone())?),
+            Box::new(ImageBlockIO::new(image_path.clone(), 4)?),
+        ];
+        for mut block_io in block_ios {
+            block_io.write_blocks(1, &[1; 3 * BLOCK_SIZE])?;
+            block_io.discard(2, 2)?;
+            block_io.discard(10, 1)?; // Never written
+            assert_eq!(block_io.read(1)?[..], [1; BLOCK_SIZE][..]);
+            assert_eq!(block_io.read(2)?[..], [0; BLOCK_SIZE][..]);
+            assert_eq!(block_io.read(3)?[..], [0; BLOCK_SIZE][..]);
+        }
+        let mut path = dir.clone();
+        path.push("blk-2");
+        assert!(!path.exists());
+        std::fs::remove_dir_all(&dir)?;
+        std::fs::remove_file(&image_path)
+    }
+
+    #[test]
+    fn test_image_read_write() -> Result<(), std::io::Error> {
+        let mut path = std::env::temp_dir();
+        path.push(format!("rfs-test-image-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let mut block_io = ImageBlockIO::new(path.clone(), 4)?;
+            assert_eq!(block_io.read(2)?[..], [0; BLOCK_SIZE][..]);
+            assert_eq!(block_io.read(10)?[..], [0; BLOCK_SIZE][..]); // Beyond the preallocated size
+            block_io.write(1, &[1; BLOCK_SIZE])?;
+            block_io.write(5, &[5; BLOCK_SIZE])?;
+        }
+        let mut block_io = ImageBlockIO::new(path.clone(), 4)?;
+        assert_eq!(block_io.read(0)?[..], [0; BLOCK_SIZE][..]);
+        assert_eq!(block_io.read(1)?[..], [1; BLOCK_SIZE][..]);
+        assert_eq!(block_io.read(5)?[..], [5; BLOCK_SIZE][..]);
+
+        let mut data = vec![2; 3 * BLOCK_SIZE];
+        data[BLOCK_SIZE .. 2 * BLOCK_SIZE].copy_from_slice(&[3; BLOCK_SIZE]);
+        block_io.write_blocks(2, &data)?;
+        let mut buf = vec![9; 6 * BLOCK_SIZE];
+        block_io.read_blocks(1, &mut buf)?;
+        assert_eq!(buf[.. BLOCK_SIZE], [1; BLOCK_SIZE][..]);
+        assert_eq!(buf[BLOCK_SIZE .. 4 * BLOCK_SIZE], data[..]);
+        assert_eq!(buf[4 * BLOCK_SIZE .. 5 * BLOCK_SIZE], [5; BLOCK_SIZE][..]);
+        assert_eq!(buf[5 * BLOCK_SIZE ..], [0; BLOCK_SIZE][..]); // Beyond the end of the image
+        std::fs::remove_file(&path)
+    }
+}
@@ -6,71 +6,962 @@ pub mod block_io;
 use block_io::*;
 use block_io::{Id, BLOCK_SIZE};
 
+#[path="super_block.rs"]
+pub mod super_block;
+
+use super_block::{SuperBlock, INODE_SIZE};
+
+#[path="journal.rs"]
+pub mod journal;
+
+use journal::Journal;
+
+const BITS_PER_BLOCK: usize = BLOCK_SIZE * 8;
+
+/// Number of data blocks of a newly formatted volume if not specified
+pub const DEFAULT_BLOCK_CNT: usize = BLOCK_SIZE * 8;
+
+/// Number of blocks of the journal of a newly formatted volume
+pub const JOURNAL_BLOCK_CNT: usize = 256;
+
+/// Data blocks per inode of a newly formatted volume, i.e. files of 16 KiB on average
+const BLOCKS_PER_INODE: usize = 4;
+
+/// Fewest inodes of a newly formatted volume, for tiny volumes
+const MIN_INODE_CNT: usize = 64;
+
+/// Number of inodes of a newly formatted volume with `block_cnt` data blocks, which a volume resized
+/// to `block_cnt` grows to as well
+fn format_inode_cnt(block_cnt: usize) -> usize {
+    std::cmp::max(block_cnt / BLOCKS_PER_INODE, MIN_INODE_CNT)
+}
+
+/// Blocks of the inode table copied at a time when it moves
+const RESIZE_COPY_BLOCK_CNT: usize = 256;
+
+/// Number of blocks on the underlying device of a newly formatted volume with `block_cnt` data blocks:
+/// super block + bitmap blocks + inode bitmap blocks + inode table blocks + data blocks + journal blocks
+pub fn device_block_cnt(block_cnt: usize) -> usize {
+    SuperBlock::new(block_cnt, format_inode_cnt(block_cnt), JOURNAL_BLOCK_CNT, "", [0; 16]).device_block_cnt()
+}
+
+/// Number of blocks on the underlying device of an existing volume, or None if not formatted.
+/// This is for setting up BlockIO layers which need the geometry before a BlockMgr can be created
+pub fn probe_device_block_cnt(block_io: &mut dyn BlockIO) -> Result<Option<usize>, std::io::Error> {
+    let super_block = block_io.read(0)?;
+    if !SuperBlock::is_formatted(&super_block) {
+        return Ok(None)
+    }
+    let super_block = SuperBlock::parse(&super_block)?;
+    Ok(Some(super_block.device_block_cnt()))
+}
+
+/// Parse a size like 512M or 2G into bytes
+pub fn parse_size(value: &str) -> Result<usize, std::num::ParseIntError> {
+    let (digits, unit) = match value.char_indices().last() {
+        Some((i, 'K')) => (&value[.. i], 1 << 10),
+        Some((i, 'M')) => (&value[.. i], 1 << 20),
+        Some((i, 'G')) => (&value[.. i], 1 << 30),
+        Some((i, 'T')) => (&value[.. i], 1 << 40),
+        _ => (value, 1)
+    };
+    Ok(digits.parse::<usize>()? * unit)
+}
+
+/// Decode a block id stored with `buf.len()` bytes, which is the `id_size` of the volume
+pub fn decode_id(buf: &[u8]) -> Id {
+    let mut bytes = [0; std::mem::size_of::<Id>()];
+    bytes[.. buf.len()].copy_from_slice(buf);
+    Id::from_le_bytes(bytes)
+}
+
+/// Encode a block id with `buf.len()` bytes, which is the `id_size` of the volume
+pub fn encode_id(buf: &mut [u8], id: Id) {
+    let bytes = id.to_le_bytes();
+    assert!(bytes[buf.len() ..].iter().all(|&byte| byte == 0));
+    buf.copy_from_slice(&bytes[.. buf.len()]);
+}
+
 pub struct BlockMgr {
     block_io: Box<dyn BlockIO>,
-    bitmap_block: [u8; BLOCK_SIZE],
+    format_block_cnt: usize,
+    format_label: String,
+    super_block: SuperBlock,
+    super_block_dirty: bool, // Counters changed since the super block was last written
+    mounted: bool,
+    block_cnt: usize,
+    bitmap: Vec<u8>,
+    free_extents: std::collections::BTreeMap<usize, usize>, // Start -> length of runs of free blocks, counting from 0
+    alloc_limit: usize, // Blocks from it on are not allocated, so the volume can be shrunk
+    reserved_percent: usize, // Of all blocks, only allocated for privileged callers
+    privileged: bool, // Whether the current caller may allocate the reserved blocks
+    alloc_budget: Option<usize>, // Blocks the current caller may still allocate under its quota
+    dirty_bitmap: std::collections::BTreeSet<usize>, // Bitmap blocks changed in memory only
+    inode_bitmap: Vec<u8>, // Empty if inodes take data blocks
+    free_inode_cnt: usize, // Of the inode table
+    next_inode: usize, // Where to look for a free inode first, counting from 0
+    dirty_inode_bitmap: std::collections::BTreeSet<usize>, // Inode bitmap blocks changed in memory only
+    discard_batch: Option<usize>, // Discard freed blocks once this many have piled up, or never if None
+    freed: std::collections::BTreeSet<usize>, // Freed since the last discard, counting from 0
+    pending_free: Vec<usize>, // Freed in the running transaction, so not reused before it commits
+    journal: Option<Journal>, // None for volumes formatted by older versions
+    txn_inode_cnt: Option<u64>, // Of the super block when the outermost transaction began, to undo in `abort`
 }
 
 impl BlockMgr {
     fn format(&mut self) -> Result<(), std::io::Error> {
-        let mut super_block = [0; BLOCK_SIZE];
-        super_block[0 .. 4].copy_from_slice(&[114, 102, 115, 46]);
-        self.block_io.write(0, &super_block)?;
-        self.block_io.write(1, &[0; BLOCK_SIZE])?; // bitmap block
+        let block_cnt = self.format_block_cnt;
+        if device_block_cnt(block_cnt) > Id::MAX as usize {
+            return Err(std::io::Error::from_raw_os_error(libc::EFBIG))
+        }
+        if self.format_label.len() > super_block::LABEL_SIZE {
+            return Err(std::io::Error::from_raw_os_error(libc::ENAMETOOLONG))
+        }
+        let super_block = SuperBlock::new(block_cnt, format_inode_cnt(block_cnt), JOURNAL_BLOCK_CNT, &self.format_label,
+                                          super_block::random_uuid()?);
+        for i in 0 .. super_block.bitmap_block_cnt() {
+            self.block_io.write((super_block.bitmap_start + i) as Id, &[0; BLOCK_SIZE])?;
+        }
+        // The inode table is not cleared. Every inode is cleared when allocated
+        for i in 0 .. super_block.inode_bitmap_block_cnt() {
+            self.block_io.write((super_block.inode_bitmap_start + i) as Id, &[0; BLOCK_SIZE])?;
+        }
+        self.block_io.write(super_block.journal_start as Id, &Journal::empty_header())?;
+        self.block_io.write(0, &super_block.to_block())?; // Last, so a half formatted volume is not mounted
         Ok(())
     }
 
-    fn first_empty_block(&self) -> Result<Id, std::io::Error> {
-        for i in 0 .. BLOCK_SIZE {
-            let occupied = (!self.bitmap_block[i]).trailing_zeros() as usize;
-            if occupied != 8 {
-                return Ok((i * 8 + occupied) as Id)
+    /// Build the free extent index from the bitmap, up to the allocation limit
+    fn scan_free_extents(&mut self) {
+        self.free_extents.clear();
+        let mut run_start = None;
+        for id in 0 ..= self.alloc_limit {
+            let free = id < self.alloc_limit && (self.bitmap[id / 8] & (1 << (id % 8))) == 0;
+            match (free, run_start) {
+                (true, None) => run_start = Some(id),
+                (false, Some(start)) => {
+                    self.free_extents.insert(start, id - start);
+                    run_start = None;
+                },
+                _ => ()
             }
         }
-        Err(std::io::Error::from_raw_os_error(libc::ENOSPC))
     }
 
+    /// Choose up to `cnt` free blocks in a row, preferring to start exactly at `goal`, then the first
+    /// run after `goal` long enough, then any run long enough, and at last any run at all
+    fn find_free_run(&self, goal: usize, cnt: usize) -> Option<(usize, usize)> {
+        if let Some((&start, &len)) = self.free_extents.range(..= goal).next_back() {
+            if start + len > goal {
+                return Some((goal, std::cmp::min(cnt, start + len - goal)))
+            }
+        }
+        let mut ordered = self.free_extents.range(goal ..).chain(self.free_extents.range(.. goal));
+        if let Some((&start, _)) = ordered.clone().find(|(_, &len)| len >= cnt) {
+            return Some((start, cnt))
+        }
+        ordered.next().map(|(&start, &len)| (start, std::cmp::min(cnt, len)))
+    }
+
+    /// Remove [`begin`, `begin + cnt`) from the free extent it lies in
+    fn take_free_run(&mut self, begin: usize, cnt: usize) {
+        let (&start, &len) = self.free_extents.range(..= begin).next_back().unwrap();
+        assert!(start + len >= begin + cnt);
+        self.free_extents.remove(&start);
+        if begin > start {
+            self.free_extents.insert(start, begin - start);
+        }
+        if start + len > begin + cnt {
+            self.free_extents.insert(begin + cnt, start + len - begin - cnt);
+        }
+    }
+
+    /// Return a block to the free extent index, merging it with its neighbours
+    fn put_free_block(&mut self, id: usize) {
+        if id >= self.alloc_limit {
+            return
+        }
+        let (mut start, mut len) = (id, 1);
+        if let Some((&prev_start, &prev_len)) = self.free_extents.range(.. id).next_back() {
+            assert!(prev_start + prev_len <= id);
+            if prev_start + prev_len == id {
+                self.free_extents.remove(&prev_start);
+                start = prev_start;
+                len += prev_len;
+            }
+        }
+        if let Some(next_len) = self.free_extents.remove(&(id + 1)) {
+            len += next_len;
+        }
+        self.free_extents.insert(start, len);
+    }
+
+    /// Remember to persist the bitmap block holding the bit of `id`
+    fn mark_bitmap_dirty(&mut self, id: Id) {
+        self.dirty_bitmap.insert(id as usize / BITS_PER_BLOCK);
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.journal.as_ref().is_some_and(|journal| journal.in_transaction())
+    }
+
+    /// Read a device block, seeing the writes of the running transaction
+    fn read_device(&mut self, device_id: Id) -> Result<[u8; BLOCK_SIZE], std::io::Error> {
+        if let Some(data) = self.journal.as_ref().and_then(|journal| journal.read(device_id)) {
+            return Ok(*data)
+        }
+        self.block_io.read(device_id)
+    }
+
+    /// Read consecutive device blocks starting from `device_id` into `buf`, like `read_device`
+    fn read_devices(&mut self, device_id: Id, buf: &mut [u8]) -> Result<(), std::io::Error> {
+        if self.journal.as_ref().is_none_or(Journal::is_empty) {
+            return self.block_io.read_blocks(device_id, buf)
+        }
+        for (i, chunk) in buf.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+            chunk.copy_from_slice(&self.read_device(device_id + i as Id)?);
+        }
+        Ok(())
+    }
+
+    /// Fails with EROFS once a transaction is aborted, see `Journal::write`
+    fn check_aborted(&self) -> Result<(), std::io::Error> {
+        match &self.journal {
+            Some(journal) if journal.is_aborted() => Err(std::io::Error::from_raw_os_error(libc::EROFS)),
+            _ => Ok(())
+        }
+    }
+
+    /// Write a device block, through the journal if in a transaction
+    fn write_device(&mut self, device_id: Id, data: &[u8]) -> Result<(), std::io::Error> {
+        self.check_aborted()?;
+        match &mut self.journal {
+            Some(journal) if journal.in_transaction() => journal.write(device_id, data),
+            _ => self.block_io.write(device_id, data)
+        }
+    }
+
+    /// Start a transaction. Until the matching `commit`, every write is kept in memory, and then goes
+    /// to the storage atomically through the journal. Transactions can be nested, and only the
+    /// outermost one commits. Without a journal, writes go to the storage directly as usual
+    pub fn begin(&mut self) {
+        if let Some(journal) = &mut self.journal {
+            if !journal.in_transaction() {
+                self.txn_inode_cnt = self.super_block.inode_cnt;
+            }
+            journal.begin();
+        }
+    }
+
+    /// End a transaction started by `begin`, together with the bitmap changes made in it. The blocks
+    /// freed in it can be allocated again only after the outermost one commits, as the transaction
+    /// may still be lost until then, and with it the writes unlinking them
+    pub fn commit(&mut self) -> Result<(), std::io::Error> {
+        let flushed = self.flush_bitmap();
+        let committed = match &mut self.journal {
+            Some(journal) => journal.commit(&mut *self.block_io),
+            None => Ok(())
+        };
+        if !self.in_transaction() {
+            for id in std::mem::take(&mut self.pending_free) {
+                if !self.is_allocated(id as Id + 1) {
+                    self.put_free_block(id);
+                }
+            }
+        }
+        flushed?;
+        committed?;
+        self.discard_freed(false);
+        Ok(())
+    }
+
+    /// End a transaction started by `begin` for an operation which failed half way, without writing
+    /// anything of it. The outermost one drops the whole transaction and reads the bitmaps back, so
+    /// the blocks and inodes allocated or freed in it are as before. Inodes cached by the caller have
+    /// to be read again then, which is when it returns true. Without a journal, the writes have
+    /// reached the storage already, so it commits instead
+    pub fn abort(&mut self) -> Result<bool, std::io::Error> {
+        match &mut self.journal {
+            Some(journal) if !journal.is_aborted() => {
+                if !journal.abort() {
+                    return Ok(false)
+                }
+                self.super_block.inode_cnt = self.txn_inode_cnt; // Counted again with an inode table
+                self.load_bitmaps()?;
+                Ok(true)
+            },
+            _ => self.commit().map(|_| false)
+        }
+    }
+
+    /// Commit the running transaction and start a new one, if it is not nested and has filled half
+    /// of the journal. For operations too large for one transaction, to call whenever what they have
+    /// done so far is consistent on its own
+    pub fn checkpoint(&mut self) -> Result<(), std::io::Error> {
+        let full = match &self.journal {
+            Some(journal) => journal.depth() == 1 &&
+                journal.buffered_cnt() + self.dirty_bitmap.len() + self.dirty_inode_bitmap.len() >= journal.capacity() / 2,
+            None => false
+        };
+        if full {
+            self.commit()?;
+            self.begin();
+        }
+        Ok(())
+    }
+
+    /// Device block of block `_id`, which counts from 1
+    fn device_id(&self, _id: Id) -> Id {
+        _id - 1 + self.super_block.data_start as Id
+    }
+
+    #[allow(dead_code)] // Only used by tests and tools
     pub fn new(block_io: Box<dyn BlockIO>) -> BlockMgr {
-        BlockMgr { block_io: block_io, bitmap_block: [0; BLOCK_SIZE] }
+        BlockMgr::with_block_cnt(block_io, DEFAULT_BLOCK_CNT)
+    }
+
+    /// Format the volume with `block_cnt` data blocks if needed
+    pub fn with_block_cnt(block_io: Box<dyn BlockIO>, block_cnt: usize) -> BlockMgr {
+        BlockMgr {
+            block_io,
+            format_block_cnt: block_cnt,
+            format_label: String::new(),
+            super_block: SuperBlock::new(0, 0, 0, "", [0; 16]), // Read in `init`
+            super_block_dirty: false,
+            mounted: false,
+            block_cnt: 0,
+            bitmap: vec![],
+            free_extents: std::collections::BTreeMap::new(),
+            alloc_limit: 0,
+            reserved_percent: 0,
+            privileged: true,
+            alloc_budget: None,
+            dirty_bitmap: std::collections::BTreeSet::new(),
+            inode_bitmap: vec![],
+            free_inode_cnt: 0,
+            next_inode: 0,
+            dirty_inode_bitmap: std::collections::BTreeSet::new(),
+            discard_batch: None,
+            freed: std::collections::BTreeSet::new(),
+            pending_free: vec![],
+            journal: None,
+            txn_inode_cnt: None,
+        }
+    }
+
+    /// Label of the volume if it is formatted by `init`
+    pub fn set_format_label(&mut self, label: &str) {
+        self.format_label = String::from(label);
     }
 
     pub fn is_formatted(&mut self) -> Result<bool, std::io::Error> {
-        let super_block = self.block_io.read(0)?;
-        Ok(&super_block[0 .. 4] == [114, 102, 115, 46])
+        Ok(SuperBlock::is_formatted(&self.block_io.read(0)?))
     }
 
     pub fn init(&mut self, need_format: bool) -> Result<(), std::io::Error> {
         if need_format {
             self.format()?;
         }
-        self.bitmap_block = self.block_io.read(1)?;
+        self.super_block = SuperBlock::parse(&self.block_io.read(0)?)?;
+        if !self.super_block.clean {
+            eprintln!("Warning: the volume was not cleanly unmounted. Some recent changes may be lost");
+        }
+        let block_cnt = self.super_block.block_cnt;
+        self.block_cnt = block_cnt;
+        self.journal = match self.super_block.journal_block_cnt {
+            0 => None,
+            journal_block_cnt => {
+                let mut journal = Journal::new(self.super_block.journal_start as Id, journal_block_cnt);
+                // Before reading anything else, which may be in the journal
+                let replayed_cnt = journal.replay(&mut *self.block_io)?;
+                if replayed_cnt > 0 {
+                    eprintln!("Recovered {} blocks from the journal", replayed_cnt);
+                }
+                Some(journal)
+            }
+        };
+        self.alloc_limit = block_cnt;
+        self.freed.clear();
+        self.load_bitmaps()?;
+        self.super_block_dirty = false;
+        self.mounted = false;
+        Ok(())
+    }
+
+    /// Read the bitmaps from the storage, dropping the changes not yet flushed, and count the free
+    /// blocks and inodes again
+    fn load_bitmaps(&mut self) -> Result<(), std::io::Error> {
+        let bitmap_block_cnt = self.block_cnt.div_ceil(BITS_PER_BLOCK);
+        let mut bitmap = vec![0; bitmap_block_cnt * BLOCK_SIZE];
+        self.read_devices(self.super_block.bitmap_start as Id, &mut bitmap)?;
+        self.bitmap = bitmap;
+        self.scan_free_extents();
+        self.dirty_bitmap.clear();
+        self.pending_free.clear();
+        // The bitmap is the truth, in case the counter was not written before a crash
+        let allocated_cnt: usize = self.bitmap.iter().map(|byte| byte.count_ones() as usize).sum();
+        self.super_block.free_block_cnt = self.block_cnt - allocated_cnt;
+        let mut inode_bitmap = vec![0; self.super_block.inode_bitmap_block_cnt() * BLOCK_SIZE];
+        self.read_devices(self.super_block.inode_bitmap_start as Id, &mut inode_bitmap)?;
+        self.inode_bitmap = inode_bitmap;
+        self.dirty_inode_bitmap.clear();
+        self.next_inode = 0;
+        if self.has_inode_table() {
+            let inode_cnt: usize = self.inode_bitmap.iter().map(|byte| byte.count_ones() as usize).sum();
+            self.free_inode_cnt = self.super_block.inode_table_size - inode_cnt;
+            self.super_block.inode_cnt = Some(inode_cnt as u64);
+        }
+        Ok(())
+    }
+
+    /// Mark the volume as in use, and upgrade the super block to the latest version. After this, the
+    /// super block is kept up to date by `sync`, until `unmount`
+    pub fn mount(&mut self) -> Result<(), std::io::Error> {
+        self.super_block.version = super_block::VERSION;
+        self.super_block.mount_cnt += 1;
+        self.super_block.last_mounted = super_block::now();
+        self.super_block.clean = false;
+        self.mounted = true;
+        self.write_super_block()
+    }
+
+    /// Mark the volume as cleanly unmounted. Everything else should have been flushed before
+    pub fn unmount(&mut self) -> Result<(), std::io::Error> {
+        self.flush_bitmap()?;
+        self.super_block.clean = true;
+        self.mounted = false;
+        self.write_super_block()
+    }
+
+    fn write_super_block(&mut self) -> Result<(), std::io::Error> {
+        self.check_aborted()?;
+        if self.super_block_dirty {
+            self.super_block.last_written = super_block::now();
+        }
+        self.block_io.write(0, &self.super_block.to_block())?;
+        self.block_io.flush()?;
+        self.super_block_dirty = false;
+        Ok(())
+    }
+
+    pub fn super_block(&self) -> &SuperBlock {
+        &self.super_block
+    }
+
+    /// Number of inodes of the volume, or None if the volume was formatted by an older version
+    pub fn inode_cnt(&self) -> Option<u64> {
+        self.super_block.inode_cnt
+    }
+
+    pub fn set_inode_cnt(&mut self, inode_cnt: u64) {
+        self.super_block.inode_cnt = Some(inode_cnt);
+        self.super_block_dirty = true;
+    }
+
+    /// Count inodes created (positive `delta`) or deleted (negative `delta`), if the count is known
+    pub fn adjust_inode_cnt(&mut self, delta: i64) {
+        if let Some(inode_cnt) = self.super_block.inode_cnt {
+            self.super_block.inode_cnt = Some((inode_cnt as i64 + delta) as u64);
+            self.super_block_dirty = true;
+        }
+    }
+
+    /// Bytes of a block id stored in inodes and directories of this volume
+    pub fn id_size(&self) -> usize {
+        self.super_block.id_size
+    }
+
+    /// Number of data blocks of the volume
+    pub fn block_cnt(&self) -> usize {
+        self.block_cnt
+    }
+
+    /// Number of unallocated data blocks, counted from the bitmap at `init` and kept up to date since
+    pub fn free_block_cnt(&self) -> usize {
+        self.super_block.free_block_cnt
+    }
+
+    /// Keep `percent` of all blocks for privileged callers, so they can still work when others have
+    /// filled the volume
+    pub fn set_reserved_percent(&mut self, percent: usize) {
+        self.reserved_percent = percent;
+    }
+
+    /// Whether the following allocations are made for a privileged caller
+    pub fn set_privileged(&mut self, privileged: bool) {
+        self.privileged = privileged;
+    }
+
+    /// Tell the storage about freed blocks once `batch` of them have piled up, and on `sync`, so it
+    /// can release their space. Never if None
+    pub fn set_discard_batch(&mut self, batch: Option<usize>) {
+        assert!(batch != Some(0));
+        self.discard_batch = batch;
+    }
+
+    /// Fail allocations with EDQUOT after `budget` more blocks, or never if None
+    pub fn set_alloc_budget(&mut self, budget: Option<usize>) {
+        self.alloc_budget = budget;
+    }
+
+    /// Inode holding the quota table, or 0 if quotas are not enabled
+    pub fn quota_inode(&self) -> Id {
+        self.super_block.quota_inode
+    }
+
+    /// Written with the super block on the next `sync`
+    pub fn set_quota_inode(&mut self, id: Id) {
+        self.super_block.quota_inode = id;
+        self.super_block_dirty = true;
+    }
+
+    /// Number of free blocks which unprivileged callers can allocate
+    pub fn avail_block_cnt(&self) -> usize {
+        self.free_block_cnt().saturating_sub(self.block_cnt * self.reserved_percent / 100)
+    }
+
+    /// Number of inodes which can still be created. Without an inode table, every inode takes a block
+    /// of its own, so this is the same as the free block count
+    pub fn free_inode_cnt(&self) -> usize {
+        if self.has_inode_table() {
+            self.free_inode_cnt
+        } else {
+            self.super_block.free_block_cnt
+        }
+    }
+
+    /// Whether inodes are kept in the inode table, rather than in data blocks of their own as on
+    /// volumes formatted by older versions
+    pub fn has_inode_table(&self) -> bool {
+        self.super_block.inode_table_size > 0
+    }
+
+    /// Whether inodes have a flags word, for inline data and extents. Only in newer inode tables
+    pub fn has_inode_flags(&self) -> bool {
+        self.super_block.inode_flags && self.has_inode_table()
+    }
+
+    /// Whether files may exceed 4 GiB, which needs the inode layout of newer inode tables
+    pub fn has_large_files(&self) -> bool {
+        self.super_block.large_files && self.has_inode_flags()
+    }
+
+    /// Whether regular files may map their blocks by extents
+    pub fn has_extents(&self) -> bool {
+        self.super_block.extents && self.has_inode_flags()
+    }
+
+    /// Whether inodes can have extended attributes, which needs the inode layout of newer inode
+    /// tables
+    pub fn has_xattrs(&self) -> bool {
+        self.super_block.xattrs && self.has_large_files()
+    }
+
+    /// Bytes of each inode
+    pub fn inode_size(&self) -> usize {
+        if self.has_inode_table() { INODE_SIZE } else { BLOCK_SIZE }
+    }
+
+    /// Allocate an inode, whose content is left as it was. Like blocks, it is marked in the inode
+    /// bitmap on the next `flush_bitmap`
+    pub fn new_inode(&mut self) -> Result<Id, std::io::Error> {
+        if !self.has_inode_table() {
+            return self.new_block()
+        }
+        // From where the last one was found, instead of scanning the allocated ones again every time
+        let size = self.super_block.inode_table_size;
+        let start = std::cmp::min(self.next_inode, size) / 8;
+        let index = (start .. size.div_ceil(8)).chain(0 .. start)
+            .filter(|&i| self.inode_bitmap[i] != 0xff)
+            .map(|i| i * 8 + self.inode_bitmap[i].trailing_ones() as usize)
+            .find(|&index| index < size);
+        let index = match index {
+            Some(index) => index,
+            None => return Err(std::io::Error::from_raw_os_error(libc::ENOSPC))
+        };
+        self.next_inode = index + 1;
+        self.inode_bitmap[index / 8] |= 1 << (index % 8);
+        self.dirty_inode_bitmap.insert(index / BITS_PER_BLOCK);
+        self.free_inode_cnt -= 1;
+        self.super_block_dirty = true;
+        Ok(index as Id + 1)
+    }
+
+    pub fn del_inode(&mut self, id: Id) -> Result<(), std::io::Error> {
+        if !self.has_inode_table() {
+            return self.del_block(id)
+        }
+        let index = id as usize - 1;
+        self.inode_bitmap[index / 8] &= !(1 << (index % 8));
+        self.dirty_inode_bitmap.insert(index / BITS_PER_BLOCK);
+        self.next_inode = std::cmp::min(self.next_inode, index);
+        self.free_inode_cnt += 1;
+        self.super_block_dirty = true;
         Ok(())
     }
 
+    pub fn is_inode_allocated(&self, id: Id) -> bool {
+        if !self.has_inode_table() {
+            return self.is_allocated(id)
+        }
+        let index = id as usize - 1;
+        index < self.super_block.inode_table_size && (self.inode_bitmap[index / 8] & (1 << (index % 8))) != 0
+    }
+
+    /// Read inode `id` into the beginning of a block
+    pub fn read_inode(&mut self, id: Id) -> Result<[u8; BLOCK_SIZE], std::io::Error> {
+        assert!(self.is_inode_allocated(id));
+        if !self.has_inode_table() {
+            return self.read_block(id)
+        }
+        let (device_id, offset) = self.super_block.locate_inode(id);
+        let block = self.read_device(device_id)?;
+        let mut data = [0; BLOCK_SIZE];
+        data[.. INODE_SIZE].copy_from_slice(&block[offset .. offset + INODE_SIZE]);
+        Ok(data)
+    }
+
+    /// Write inode `id`, whose length is `inode_size`. The other inodes sharing the block are kept
+    pub fn write_inode(&mut self, id: Id, data: &[u8]) -> Result<(), std::io::Error> {
+        assert!(self.is_inode_allocated(id));
+        assert_eq!(data.len(), self.inode_size());
+        if !self.has_inode_table() {
+            return self.write_block(id, data)
+        }
+        let (device_id, offset) = self.super_block.locate_inode(id);
+        let mut block = self.read_device(device_id)?;
+        block[offset .. offset + INODE_SIZE].copy_from_slice(data);
+        self.write_device(device_id, &block)
+    }
+
     pub fn new_block(&mut self) -> Result<Id, std::io::Error> {
-        let id = self.first_empty_block()?;
-        self.bitmap_block[(id / 8) as usize] |= 1 << (id % 8);
-        self.block_io.write(1, &self.bitmap_block)?;
-        Ok(id + 1) // Root inode = 1
+        self.new_block_near(1)
+    }
+
+    /// Allocate a block as close after `goal` as possible, e.g. next to the previous block of a file
+    pub fn new_block_near(&mut self, goal: Id) -> Result<Id, std::io::Error> {
+        Ok(self.new_blocks(goal, 1)?.0)
+    }
+
+    /// Allocate up to `cnt` consecutive blocks, as close after `goal` as possible. Returns the first
+    /// block and how many are allocated, which is less than `cnt` only if no free run is long enough
+    pub fn new_blocks(&mut self, goal: Id, cnt: usize) -> Result<(Id, usize), std::io::Error> {
+        assert!(cnt > 0);
+        let goal = std::cmp::min(std::cmp::max(goal, 1) as usize - 1, self.block_cnt.saturating_sub(1));
+        let cnt = if self.privileged { cnt } else { std::cmp::min(cnt, self.avail_block_cnt()) };
+        if cnt == 0 {
+            return Err(std::io::Error::from_raw_os_error(libc::ENOSPC)) // Only reserved blocks are left
+        }
+        let cnt = match self.alloc_budget {
+            Some(0) => return Err(std::io::Error::from_raw_os_error(libc::EDQUOT)),
+            Some(budget) => std::cmp::min(cnt, budget),
+            None => cnt
+        };
+        let (begin, cnt) = match self.find_free_run(goal, cnt) {
+            Some(run) => run,
+            None => return Err(std::io::Error::from_raw_os_error(libc::ENOSPC))
+        };
+        self.take_free_run(begin, cnt);
+        if let Some(budget) = &mut self.alloc_budget {
+            *budget -= cnt;
+        }
+        for id in begin .. begin + cnt {
+            self.bitmap[id / 8] |= 1 << (id % 8);
+        }
+        self.super_block.free_block_cnt -= cnt;
+        self.super_block_dirty = true;
+        self.dirty_bitmap.extend(begin / BITS_PER_BLOCK ..= (begin + cnt - 1) / BITS_PER_BLOCK);
+        Ok((begin as Id + 1, cnt)) // Root inode = 1
     }
 
     pub fn del_block(&mut self, _id: Id) -> Result<(), std::io::Error> {
         let id = _id - 1;
-        self.bitmap_block[(id / 8) as usize] &= !(1 << (id % 8));
-        self.block_io.write(1, &self.bitmap_block)?;
+        self.bitmap[(id / 8) as usize] &= !(1 << (id % 8));
+        if self.in_transaction() {
+            self.pending_free.push(id as usize);
+        } else {
+            self.put_free_block(id as usize);
+        }
+        if self.discard_batch.is_some() {
+            self.freed.insert(id as usize);
+        }
+        self.super_block.free_block_cnt += 1;
+        self.super_block_dirty = true;
+        self.mark_bitmap_dirty(id);
         Ok(())
     }
 
-    pub fn read_block(&mut self, _id: Id) -> Result<[u8; BLOCK_SIZE], std::io::Error> {
+    /// Persist the bitmap changes made by `new_block`, `del_block`, `new_inode` and `del_inode` since
+    /// the last call. They are only kept in memory until then, so the caller decides the order of
+    /// writes for crash safety: a newly allocated block must be persisted here before anything
+    /// pointing to it is written, while a block can be freed only after nothing on the storage points
+    /// to it any more. A crash in between leaks some blocks at worst
+    pub fn flush_bitmap(&mut self) -> Result<(), std::io::Error> {
+        while let Some(index) = self.dirty_bitmap.pop_first() {
+            let mut block = [0; BLOCK_SIZE];
+            block.copy_from_slice(&self.bitmap[index * BLOCK_SIZE .. (index + 1) * BLOCK_SIZE]);
+            if let Err(err) = self.write_device((self.super_block.bitmap_start + index) as Id, &block) {
+                self.dirty_bitmap.insert(index);
+                return Err(err)
+            }
+        }
+        // Same order for inodes
+        while let Some(index) = self.dirty_inode_bitmap.pop_first() {
+            let mut block = [0; BLOCK_SIZE];
+            block.copy_from_slice(&self.inode_bitmap[index * BLOCK_SIZE .. (index + 1) * BLOCK_SIZE]);
+            if let Err(err) = self.write_device((self.super_block.inode_bitmap_start + index) as Id, &block) {
+                self.dirty_inode_bitmap.insert(index);
+                return Err(err)
+            }
+        }
+        self.discard_freed(false);
+        Ok(())
+    }
+
+    /// Discard the freed blocks which are still free, if enough of them have piled up or `force` is
+    /// set. Only once their frees are persisted, which is when `flush_bitmap` or `commit` is done, as
+    /// nothing on the storage points to them any more then. Everything is flushed first, so the
+    /// writes unlinking them cannot land after the discards
+    fn discard_freed(&mut self, force: bool) {
+        let batch = match self.discard_batch {
+            Some(batch) if !self.in_transaction() && self.dirty_bitmap.is_empty() && self.check_aborted().is_ok() => batch,
+            _ => return
+        };
+        if self.freed.is_empty() || (!force && self.freed.len() < batch) {
+            return
+        }
+        let freed = std::mem::take(&mut self.freed);
+        let mut runs: Vec<(usize, usize)> = vec![];
+        for id in freed.into_iter().filter(|&id| id < self.block_cnt && !self.is_allocated(id as Id + 1)) {
+            match runs.last_mut() {
+                Some((start, len)) if *start + *len == id => *len += 1,
+                _ => runs.push((id, 1))
+            }
+        }
+        // Discarding is only to save space, so failing to do it does not fail the operation
+        let ret = self.block_io.flush().and_then(|_| {
+            runs.iter().try_for_each(|&(start, len)| self.block_io.discard(self.device_id(start as Id + 1), len))
+        });
+        if let Err(err) = ret {
+            eprintln!("Failed to discard freed blocks: {}", err);
+        }
+    }
+
+    /// Discard all free blocks, like fstrim. Returns how many are discarded
+    pub fn trim(&mut self) -> Result<usize, std::io::Error> {
+        assert!(!self.in_transaction());
+        self.check_aborted()?;
+        self.flush_bitmap()?;
+        self.block_io.flush()?;
+        self.freed.clear();
+        let runs: Vec<(usize, usize)> = self.free_extents.iter().map(|(&start, &len)| (start, len)).collect();
+        for &(start, len) in &runs {
+            self.block_io.discard(self.device_id(start as Id + 1), len)?;
+        }
+        Ok(runs.iter().map(|&(_, len)| len).sum())
+    }
+
+    /// Only allocate blocks up to `limit` from now on, so the blocks after it can be emptied before
+    /// shrinking the volume to `limit` blocks. Fails with ENOSPC if the allocated blocks do not fit.
+    /// None to lift the limit
+    pub fn limit_alloc(&mut self, limit: Option<usize>) -> Result<(), std::io::Error> {
+        let limit = limit.unwrap_or(self.block_cnt);
+        if limit < self.block_cnt - self.free_block_cnt() {
+            return Err(std::io::Error::from_raw_os_error(libc::ENOSPC))
+        }
+        self.alloc_limit = std::cmp::min(limit, self.block_cnt);
+        self.scan_free_extents();
+        Ok(())
+    }
+
+    /// Change the number of data blocks to `block_cnt`. When shrinking, the blocks after it must
+    /// have been freed, see `limit_alloc`. The inode table grows with the volume like when formatting,
+    /// but never shrinks. The bitmap, the inode bitmap with the inode table, and the journal are moved
+    /// after the data blocks if they are in the way. They are written at places the current layout
+    /// does not use, before the super block is switched to the new layout, so a crash in between
+    /// leaves the volume as is. The tail of the BlockIO, like checksums, moves along, see
+    /// `BlockIO::resize`. As the journal may move, the running transaction is committed first, and
+    /// goes on in the new journal
+    pub fn resize(&mut self, block_cnt: usize) -> Result<(), std::io::Error> {
+        let in_transaction = self.in_transaction();
+        if in_transaction {
+            assert_eq!(self.journal.as_ref().unwrap().depth(), 1);
+            self.commit()?;
+        }
+        let ret = self.resize_layout(block_cnt);
+        if in_transaction {
+            self.begin();
+        }
+        ret
+    }
+
+    fn resize_layout(&mut self, block_cnt: usize) -> Result<(), std::io::Error> {
+        self.check_aborted()?;
+        if block_cnt == 0 {
+            return Err(std::io::Error::from_raw_os_error(libc::EINVAL))
+        }
+        if block_cnt as u64 >= 1 << (8 * self.id_size()) {
+            return Err(std::io::Error::from_raw_os_error(libc::EFBIG)) // Ids of the volume cannot reach it
+        }
+        if (block_cnt .. self.block_cnt).any(|id| self.is_allocated(id as Id + 1)) {
+            return Err(std::io::Error::from_raw_os_error(libc::EBUSY))
+        }
+        let old = &self.super_block;
+        let mut new = old.clone();
+        new.block_cnt = block_cnt;
+        if self.has_inode_table() {
+            new.inode_table_size = std::cmp::max(old.inode_table_size, format_inode_cnt(block_cnt));
+        }
+        let old_bitmap_moves = old.bitmap_start > old.data_start;
+        let bitmap_moves = old_bitmap_moves || new.bitmap_block_cnt() > old.bitmap_block_cnt();
+        let old_inodes_move = old.inode_table_size > 0 && old.inode_bitmap_start > old.data_start;
+        let inodes_move = old_inodes_move || new.inode_bitmap_block_cnt() > old.inode_bitmap_block_cnt() ||
+            new.inode_table_block_cnt() > old.inode_table_block_cnt();
+        let moved_block_cnt = if bitmap_moves { new.bitmap_block_cnt() } else { 0 } +
+            if inodes_move { new.inode_bitmap_block_cnt() + new.inode_table_block_cnt() } else { 0 } + old.journal_block_cnt;
+        let old_moved_start = if old_bitmap_moves {
+            old.bitmap_start
+        } else if old_inodes_move {
+            old.inode_bitmap_start
+        } else {
+            old.journal_start
+        };
+        // Neither the moved blocks nor the tail of the BlockIO after them may overlap the old places,
+        // nor the old tail
+        let old_end = old.device_block_cnt() + self.block_io.tail_block_cnt(old.device_block_cnt());
+        let mut free_start = old.data_start + block_cnt;
+        let new_end = free_start + moved_block_cnt + self.block_io.tail_block_cnt(free_start + moved_block_cnt);
+        if new_end > old_moved_start && free_start < old_end {
+            free_start = old_end;
+        }
+        if bitmap_moves {
+            new.bitmap_start = free_start;
+            free_start += new.bitmap_block_cnt();
+        }
+        if inodes_move {
+            new.inode_bitmap_start = free_start;
+            new.inode_table_start = free_start + new.inode_bitmap_block_cnt();
+            free_start = new.inode_table_start + new.inode_table_block_cnt();
+        }
+        new.journal_start = free_start;
+        if new.device_block_cnt() > Id::MAX as usize {
+            return Err(std::io::Error::from_raw_os_error(libc::EFBIG))
+        }
+
+        self.flush_bitmap()?;
+        self.block_io.resize(new.device_block_cnt())?;
+        let ret = self.switch_layout(new, block_cnt, inodes_move);
+        if ret.is_err() {
+            if let Err(err) = self.block_io.resize(self.super_block.device_block_cnt()) {
+                eprintln!("Failed to restore the layout of the storage: {}", err);
+            }
+        }
+        ret
+    }
+
+    /// Write the bitmap, the inode bitmap with the inode table if `inodes_move`, and the journal where
+    /// `new` puts them, and at last the super block
+    fn switch_layout(&mut self, mut new: SuperBlock, block_cnt: usize, inodes_move: bool) -> Result<(), std::io::Error> {
+        self.bitmap.resize(new.bitmap_block_cnt() * BLOCK_SIZE, 0);
+        self.block_io.write_blocks(new.bitmap_start as Id, &self.bitmap)?;
+        if inodes_move {
+            self.inode_bitmap.resize(new.inode_bitmap_block_cnt() * BLOCK_SIZE, 0);
+            self.block_io.write_blocks(new.inode_bitmap_start as Id, &self.inode_bitmap)?;
+            let old = &self.super_block;
+            let (old_start, old_cnt) = (old.inode_table_start, old.inode_table_block_cnt());
+            let mut buf = vec![0; RESIZE_COPY_BLOCK_CNT * BLOCK_SIZE];
+            for i in (0 .. old_cnt).step_by(RESIZE_COPY_BLOCK_CNT) {
+                let buf = &mut buf[.. std::cmp::min(old_cnt - i, RESIZE_COPY_BLOCK_CNT) * BLOCK_SIZE];
+                self.block_io.read_blocks((old_start + i) as Id, buf)?;
+                self.block_io.write_blocks((new.inode_table_start + i) as Id, buf)?;
+            }
+        }
+        if new.journal_block_cnt > 0 {
+            self.block_io.write(new.journal_start as Id, &Journal::empty_header())?;
+        }
+        self.block_io.flush()?;
+        new.version = super_block::VERSION;
+        new.free_block_cnt = self.super_block.free_block_cnt + block_cnt - self.block_cnt;
+        let free_inode_cnt = self.free_inode_cnt + new.inode_table_size - self.super_block.inode_table_size;
+        let old = std::mem::replace(&mut self.super_block, new);
+        self.super_block_dirty = true;
+        if let Err(err) = self.write_super_block() {
+            self.super_block = old;
+            return Err(err)
+        }
+        // Only now, so transactions never go to a journal the super block does not point to
+        if self.super_block.journal_block_cnt > 0 {
+            self.journal = Some(Journal::new(self.super_block.journal_start as Id, self.super_block.journal_block_cnt));
+        }
+        self.free_inode_cnt = free_inode_cnt;
+        self.block_cnt = block_cnt;
+        self.limit_alloc(None)
+    }
+
+    pub fn sync(&mut self) -> Result<(), std::io::Error> {
+        self.flush_bitmap()?;
+        if self.mounted && self.super_block_dirty {
+            self.write_super_block()?;
+        } else {
+            self.block_io.flush()?;
+        }
+        self.discard_freed(true);
+        Ok(())
+    }
+
+    pub fn is_allocated(&self, _id: Id) -> bool {
         let id = _id - 1;
-        assert!((self.bitmap_block[(id / 8) as usize] & (1 << (id % 8))) != 0);
-        self.block_io.read(id + 2)
+        (id as usize) < self.block_cnt && (self.bitmap[(id / 8) as usize] & (1 << (id % 8))) != 0
+    }
+
+    pub fn read_block(&mut self, _id: Id) -> Result<[u8; BLOCK_SIZE], std::io::Error> {
+        assert!(self.is_allocated(_id));
+        self.read_device(self.device_id(_id))
     }
 
     pub fn write_block(&mut self, _id: Id, data: &[u8]) -> Result<(), std::io::Error> {
-        let id = _id - 1;
-        assert!((self.bitmap_block[(id / 8) as usize] & (1 << (id % 8))) != 0);
-        self.block_io.write(id + 2, data)
+        assert!(self.is_allocated(_id));
+        self.super_block_dirty = true;
+        self.write_device(self.device_id(_id), data)
+    }
+
+    /// Read consecutive blocks starting from `_id` into `buf`
+    pub fn read_blocks(&mut self, _id: Id, buf: &mut [u8]) -> Result<(), std::io::Error> {
+        for i in 0 .. buf.len() / BLOCK_SIZE {
+            assert!(self.is_allocated(_id + i as Id));
+        }
+        self.read_devices(self.device_id(_id), buf)
+    }
+
+    /// Write `data` to consecutive blocks starting from `_id`
+    pub fn write_blocks(&mut self, _id: Id, data: &[u8]) -> Result<(), std::io::Error> {
+        for i in 0 .. data.len() / BLOCK_SIZE {
+            assert!(self.is_allocated(_id + i as Id));
+        }
+        self.check_aborted()?;
+        self.super_block_dirty = true;
+        if self.in_transaction() {
+            for (i, chunk) in data.chunks_exact(BLOCK_SIZE).enumerate() {
+                self.write_device(self.device_id(_id + i as Id), chunk)?;
+            }
+            return Ok(())
+        }
+        self.block_io.write_blocks(self.device_id(_id), data)
+    }
+
+    /// Write file data to consecutive blocks starting from `_id`, straight to the storage even in a
+    /// transaction, so large writes do not fill the journal. The data lands before the transaction
+    /// linking the blocks commits, as committing flushes the storage first. The blocks are either
+    /// allocated in the transaction or already linked, since freed ones are not reused before it
+    /// commits, so a crash at worst leaves new data in them
+    pub fn write_data_blocks(&mut self, _id: Id, data: &[u8]) -> Result<(), std::io::Error> {
+        for i in 0 .. data.len() / BLOCK_SIZE {
+            assert!(self.is_allocated(_id + i as Id));
+            assert!(self.journal.as_ref().is_none_or(|journal| journal.read(self.device_id(_id + i as Id)).is_none()));
+        }
+        self.check_aborted()?;
+        self.super_block_dirty = true;
+        self.block_io.write_blocks(self.device_id(_id), data)
     }
 }
 
@@ -79,6 +970,23 @@ mod tests {
     use super::*;
     use block_io::FakeMemBlockIO;
 
+    /// Let the device outlive the BlockMgr, to mount it again
+    struct SharedBlockIO(std::rc::Rc<std::cell::RefCell<FakeMemBlockIO>>);
+
+    impl BlockIO for SharedBlockIO {
+        fn read(&mut self, block_id: Id) -> Result<[u8; BLOCK_SIZE], std::io::Error> {
+            self.0.borrow_mut().read(block_id)
+        }
+
+        fn write(&mut self, block_id: Id, data: &[u8]) -> Result<(), std::io::Error> {
+            self.0.borrow_mut().write(block_id, data)
+        }
+
+        fn discard(&mut self, block_id: Id, cnt: usize) -> Result<(), std::io::Error> {
+            self.0.borrow_mut().discard(block_id, cnt)
+        }
+    }
+
     #[test]
     fn test_new_del_blocks() -> Result<(), std::io::Error> {
         let mut block_mgr = BlockMgr::new(Box::new(FakeMemBlockIO::new()));
@@ -88,10 +996,297 @@ mod tests {
             let id = block_mgr.new_block()?;
             assert_eq!(id, i);
         }
+        assert_eq!(block_mgr.free_block_cnt(), DEFAULT_BLOCK_CNT - 32);
         block_mgr.del_block(20)?;
         block_mgr.del_block(10)?;
         let id = block_mgr.new_block()?;
         assert_eq!(id, 10);
+        assert_eq!(block_mgr.free_block_cnt(), DEFAULT_BLOCK_CNT - 31);
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_blocks_near_goal() -> Result<(), std::io::Error> {
+        let mut block_mgr = BlockMgr::with_block_cnt(Box::new(FakeMemBlockIO::new()), 100);
+        block_mgr.init(true)?;
+        assert_eq!(block_mgr.new_blocks(11, 10)?, (11, 10));
+        assert_eq!(block_mgr.new_block_near(15)?, 21); // Right after the taken run
+        assert_eq!(block_mgr.new_blocks(15, 20)?, (22, 20)); // The goal is taken
+        assert_eq!(block_mgr.new_blocks(5, 10)?, (5, 6)); // Stay at the goal even if the run is short
+        assert_eq!(block_mgr.new_blocks(95, 10)?, (95, 6)); // Best effort at the end
+        block_mgr.del_block(21)?;
+        block_mgr.del_block(20)?;
+        assert_eq!(block_mgr.new_blocks(96, 60)?, (1, 4)); // No run is long enough, so wrap around
+        assert_eq!(block_mgr.new_blocks(20, 3)?, (20, 2)); // Merged after freeing, and no more
+        assert_eq!(block_mgr.free_block_cnt(), 100 - 10 - 1 - 20 - 6 - 6 - 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reserved_blocks() -> Result<(), std::io::Error> {
+        let mut block_mgr = BlockMgr::with_block_cnt(Box::new(FakeMemBlockIO::new()), 100);
+        block_mgr.init(true)?;
+        block_mgr.set_reserved_percent(10);
+        block_mgr.set_privileged(false);
+        assert_eq!(block_mgr.avail_block_cnt(), 90);
+        assert_eq!(block_mgr.new_blocks(1, 80)?, (1, 80));
+        assert_eq!(block_mgr.new_blocks(81, 20)?, (81, 10)); // Stop at the reserved blocks
+        assert_eq!(block_mgr.avail_block_cnt(), 0);
+        assert_eq!(block_mgr.new_block().unwrap_err().raw_os_error(), Some(libc::ENOSPC));
+        block_mgr.set_privileged(true);
+        assert_eq!(block_mgr.new_block()?, 91);
+        assert_eq!(block_mgr.free_block_cnt(), 9);
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_overflow() -> Result<(), std::io::Error> {
+        let mut block_mgr = BlockMgr::with_block_cnt(Box::new(FakeMemBlockIO::new()), 1000);
+        block_mgr.init(true)?;
+        assert_eq!(block_mgr.new_blocks(1, 300)?, (1, 300));
+        block_mgr.begin();
+        block_mgr.del_block(1)?;
+        assert_eq!(block_mgr.new_block()?, 301); // Not reused before committed
+        block_mgr.write_data_blocks(2, &[1; 299 * BLOCK_SIZE])?; // Not journaled
+        let ret = block_mgr.write_blocks(2, &[2; 299 * BLOCK_SIZE]);
+        assert_eq!(ret.unwrap_err().raw_os_error(), Some(libc::ENOSPC));
+        assert_eq!(block_mgr.write_block(2, &[3; BLOCK_SIZE]).unwrap_err().raw_os_error(), Some(libc::EROFS));
+        assert_eq!(block_mgr.commit().unwrap_err().raw_os_error(), Some(libc::EROFS));
+        assert_eq!(block_mgr.sync().unwrap_err().raw_os_error(), Some(libc::EROFS));
+        assert_eq!(block_mgr.read_block(2)?[..], [1; BLOCK_SIZE][..]);
+        assert_eq!(block_mgr.new_block()?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_discard_freed() -> Result<(), std::io::Error> {
+        let device = std::rc::Rc::new(std::cell::RefCell::new(FakeMemBlockIO::new()));
+        let mut block_mgr = BlockMgr::with_block_cnt(Box::new(SharedBlockIO(device.clone())), 100);
+        block_mgr.init(true)?;
+        block_mgr.set_discard_batch(Some(2));
+        let data_start = block_mgr.super_block().data_start;
+        let stored = |id: usize| device.borrow_mut().read((id - 1 + data_start) as Id).unwrap()[0];
+        assert_eq!(block_mgr.new_blocks(1, 4)?, (1, 4));
+        block_mgr.write_blocks(1, &[1; 4 * BLOCK_SIZE])?;
+
+        block_mgr.del_block(1)?;
+        block_mgr.flush_bitmap()?;
+        assert_eq!(stored(1), 1); // Not enough to discard yet
+        block_mgr.del_block(2)?;
+        assert_eq!(stored(2), 1); // Not persisted as free yet
+        block_mgr.flush_bitmap()?;
+        assert_eq!((stored(1), stored(2)), (0, 0));
+
+        block_mgr.del_block(3)?;
+        assert_eq!(block_mgr.new_block()?, 1);
+        block_mgr.write_block(1, &[2; BLOCK_SIZE])?;
+        block_mgr.del_block(4)?;
+        block_mgr.begin();
+        block_mgr.flush_bitmap()?;
+        assert_eq!((stored(3), stored(4)), (1, 1)); // Not until committed
+        block_mgr.commit()?;
+        assert_eq!((stored(1), stored(3), stored(4)), (2, 0, 0));
+
+        block_mgr.write_block(1, &[3; BLOCK_SIZE])?;
+        assert_eq!(block_mgr.trim()?, 99);
+        assert_eq!(stored(1), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_block_bitmap() -> Result<(), std::io::Error> {
+        let block_cnt = BITS_PER_BLOCK + 10;
+        let device = std::rc::Rc::new(std::cell::RefCell::new(FakeMemBlockIO::new()));
+        let mut block_mgr = BlockMgr::with_block_cnt(Box::new(SharedBlockIO(device.clone())), block_cnt);
+        block_mgr.init(true)?;
+        assert_eq!(probe_device_block_cnt(&mut *device.borrow_mut())?, Some(device_block_cnt(block_cnt)));
+        let data_start = block_mgr.super_block().data_start;
+        for i in 1 ..= block_cnt {
+            assert_eq!(block_mgr.new_block()?, i as Id);
+        }
+        assert_eq!(block_mgr.new_block().unwrap_err().raw_os_error(), Some(libc::ENOSPC));
+        block_mgr.del_block(block_cnt as Id - 3)?;
+        block_mgr.write_block(block_cnt as Id, &[1; BLOCK_SIZE])?;
+        assert_eq!(device.borrow_mut().read((data_start + block_cnt - 1) as Id)?[..], [1; BLOCK_SIZE][..]);
+        assert_eq!(device.borrow_mut().read(1)?[..], [0; BLOCK_SIZE][..]); // Bitmap not written yet
+        block_mgr.sync()?;
+
+        let mut block_mgr = BlockMgr::new(Box::new(SharedBlockIO(device.clone())));
+        block_mgr.init(false)?;
+        assert_eq!(block_mgr.block_cnt(), block_cnt);
+        assert!(block_mgr.is_allocated(block_cnt as Id - 4));
+        assert!(!block_mgr.is_allocated(block_cnt as Id - 3));
+        assert_eq!(block_mgr.new_block()?, block_cnt as Id - 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resize() -> Result<(), std::io::Error> {
+        let device = std::rc::Rc::new(std::cell::RefCell::new(FakeMemBlockIO::new()));
+        let mut block_mgr = BlockMgr::with_block_cnt(Box::new(SharedBlockIO(device.clone())), 100);
+        block_mgr.init(true)?;
+        let data_start = block_mgr.super_block().data_start; // After the inode bitmap and the inode table
+        assert_eq!(data_start, 7);
+        assert_eq!(block_mgr.new_blocks(1, 50)?, (1, 50));
+        block_mgr.write_block(50, &[5; BLOCK_SIZE])?;
+
+        // The bitmap and the inode table do not fit in front of the data blocks any more, so they move
+        // after them
+        let block_cnt = BITS_PER_BLOCK + 10;
+        block_mgr.resize(block_cnt)?;
+        let super_block = block_mgr.super_block();
+        assert_eq!((super_block.bitmap_start, super_block.data_start, super_block.inode_table_start, super_block.journal_start),
+                   (data_start + block_cnt, data_start, data_start + 3 + block_cnt, data_start + 516 + block_cnt));
+        assert_eq!(block_mgr.free_block_cnt(), block_cnt - 50);
+        assert_eq!(block_mgr.new_block_near(block_cnt as Id)?, block_cnt as Id);
+        block_mgr.sync()?;
+
+        let mut block_mgr = BlockMgr::new(Box::new(SharedBlockIO(device.clone())));
+        block_mgr.init(false)?;
+        assert_eq!(probe_device_block_cnt(&mut *device.borrow_mut())?, Some(data_start + 516 + block_cnt + JOURNAL_BLOCK_CNT));
+        assert_eq!(block_mgr.block_cnt(), block_cnt);
+        assert_eq!(block_mgr.read_block(50)?[..], [5; BLOCK_SIZE][..]);
+        assert!(block_mgr.is_allocated(block_cnt as Id));
+
+        assert_eq!(block_mgr.resize(60).unwrap_err().raw_os_error(), Some(libc::EBUSY));
+        block_mgr.del_block(block_cnt as Id)?;
+        block_mgr.resize(60)?;
+        let super_block = block_mgr.super_block();
+        assert_eq!((super_block.bitmap_start, super_block.journal_start), (data_start + 60, data_start + 575));
+        assert_eq!(block_mgr.free_block_cnt(), 10);
+
+        let mut block_mgr = BlockMgr::new(Box::new(SharedBlockIO(device.clone())));
+        block_mgr.init(false)?;
+        assert_eq!(block_mgr.block_cnt(), 60);
+        assert_eq!(block_mgr.read_block(50)?[..], [5; BLOCK_SIZE][..]);
+        assert_eq!(block_mgr.new_blocks(1, 20)?, (51, 10));
+        Ok(())
+    }
+
+    #[test]
+    fn test_mount_and_unmount() -> Result<(), std::io::Error> {
+        let device = std::rc::Rc::new(std::cell::RefCell::new(FakeMemBlockIO::new()));
+        let mut block_mgr = BlockMgr::with_block_cnt(Box::new(SharedBlockIO(device.clone())), 100);
+        block_mgr.set_format_label("data");
+        block_mgr.init(true)?;
+        block_mgr.mount()?;
+        block_mgr.new_block()?;
+        block_mgr.new_block()?;
+        block_mgr.new_inode()?;
+        block_mgr.adjust_inode_cnt(1);
+        block_mgr.sync()?;
+        // Crashed here
+        let mut block_mgr = BlockMgr::new(Box::new(SharedBlockIO(device.clone())));
+        block_mgr.init(false)?;
+        assert!(!block_mgr.super_block().clean);
+        assert_eq!(block_mgr.super_block().free_block_cnt, 98);
+        assert_eq!(block_mgr.inode_cnt(), Some(1));
+        block_mgr.mount()?;
+        block_mgr.unmount()?;
+
+        let mut block_mgr = BlockMgr::new(Box::new(SharedBlockIO(device.clone())));
+        block_mgr.init(false)?;
+        let super_block = block_mgr.super_block();
+        assert!(super_block.clean);
+        assert_eq!(super_block.label, "data");
+        assert_eq!(super_block.mount_cnt, 2);
+        assert_eq!(super_block.block_cnt, 100);
+        assert!(super_block.last_written >= super_block.created);
+        Ok(())
+    }
+
+    #[test]
+    fn test_inode_table() -> Result<(), std::io::Error> {
+        let device = std::rc::Rc::new(std::cell::RefCell::new(FakeMemBlockIO::new()));
+        let mut block_mgr = BlockMgr::with_block_cnt(Box::new(SharedBlockIO(device.clone())), 100);
+        block_mgr.init(true)?;
+        assert_eq!(block_mgr.inode_size(), INODE_SIZE);
+        assert_eq!(block_mgr.free_inode_cnt(), MIN_INODE_CNT);
+        for i in 1 ..= MIN_INODE_CNT {
+            assert_eq!(block_mgr.new_inode()?, i as Id);
+            block_mgr.write_inode(i as Id, &[i as u8; INODE_SIZE])?;
+        }
+        assert_eq!(block_mgr.new_inode().unwrap_err().raw_os_error(), Some(libc::ENOSPC));
+        assert_eq!(block_mgr.free_block_cnt(), 100); // Inodes take no data blocks
+        block_mgr.del_inode(17)?;
+        block_mgr.sync()?;
+
+        let mut block_mgr = BlockMgr::new(Box::new(SharedBlockIO(device.clone())));
+        block_mgr.init(false)?;
+        assert_eq!(block_mgr.inode_cnt(), Some(MIN_INODE_CNT as u64 - 1));
+        assert_eq!(block_mgr.free_inode_cnt(), 1);
+        assert!(!block_mgr.is_inode_allocated(17));
+        // Inodes sharing a block with the rewritten one are kept
+        assert_eq!(block_mgr.read_inode(16)?[.. INODE_SIZE], [16; INODE_SIZE][..]);
+        assert_eq!(block_mgr.read_inode(18)?[.. INODE_SIZE], [18; INODE_SIZE][..]);
+        assert_eq!(block_mgr.new_inode()?, 17);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resize_inode_table() -> Result<(), std::io::Error> {
+        let device = std::rc::Rc::new(std::cell::RefCell::new(FakeMemBlockIO::new()));
+        let mut block_mgr = BlockMgr::with_block_cnt(Box::new(SharedBlockIO(device.clone())), 100);
+        block_mgr.init(true)?;
+        for i in 1 ..= MIN_INODE_CNT {
+            assert_eq!(block_mgr.new_inode()?, i as Id);
+            block_mgr.write_inode(i as Id, &[i as u8; INODE_SIZE])?;
+        }
+        assert_eq!(block_mgr.new_inode().unwrap_err().raw_os_error(), Some(libc::ENOSPC));
+
+        // Grows like when formatting, so the inode table moves after the data blocks
+        block_mgr.resize(1000)?;
+        let super_block = block_mgr.super_block();
+        assert_eq!(super_block.inode_table_size, format_inode_cnt(1000));
+        assert!(super_block.inode_table_start > super_block.data_start + 1000);
+        assert_eq!(block_mgr.free_inode_cnt(), format_inode_cnt(1000) - MIN_INODE_CNT);
+        assert_eq!(block_mgr.new_inode()?, MIN_INODE_CNT as Id + 1);
+        block_mgr.write_inode(MIN_INODE_CNT as Id + 1, &[100; INODE_SIZE])?;
+        block_mgr.sync()?;
+
+        let mut block_mgr = BlockMgr::new(Box::new(SharedBlockIO(device.clone())));
+        block_mgr.init(false)?;
+        assert_eq!(block_mgr.free_inode_cnt(), format_inode_cnt(1000) - MIN_INODE_CNT - 1);
+        assert_eq!(block_mgr.read_inode(1)?[.. INODE_SIZE], [1; INODE_SIZE][..]);
+        assert_eq!(block_mgr.read_inode(MIN_INODE_CNT as Id)?[.. INODE_SIZE], [MIN_INODE_CNT as u8; INODE_SIZE][..]);
+
+        // Never shrinks, as the inodes are in use
+        block_mgr.resize(500)?;
+        assert_eq!(block_mgr.super_block().inode_table_size, format_inode_cnt(1000));
+        let mut block_mgr = BlockMgr::new(Box::new(SharedBlockIO(device.clone())));
+        block_mgr.init(false)?;
+        assert_eq!(block_mgr.read_inode(MIN_INODE_CNT as Id + 1)?[.. INODE_SIZE], [100; INODE_SIZE][..]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_legacy_volume() -> Result<(), std::io::Error> {
+        let mut device = FakeMemBlockIO::new();
+        device.write(0, &{
+            let mut super_block = [0; BLOCK_SIZE];
+            super_block[0 .. 4].copy_from_slice(&super_block::MAGIC);
+            super_block
+        })?;
+        let mut bitmap = [0; BLOCK_SIZE];
+        bitmap[0] = 0b11;
+        device.write(1, &bitmap)?;
+        device.write(3, &[3; BLOCK_SIZE])?;
+
+        let mut block_mgr = BlockMgr::new(Box::new(device));
+        block_mgr.init(false)?;
+        assert_eq!(block_mgr.id_size(), 2);
+        assert_eq!(block_mgr.block_cnt(), BITS_PER_BLOCK);
+        assert_eq!(block_mgr.read_block(2)?[..], [3; BLOCK_SIZE][..]);
+        assert_eq!(block_mgr.new_block()?, 3);
+        assert_eq!(block_mgr.inode_cnt(), None);
+
+        block_mgr.set_inode_cnt(1);
+        block_mgr.mount()?;
+        block_mgr.unmount()?;
+        block_mgr.init(false)?;
+        assert_eq!(block_mgr.super_block().version, super_block::VERSION);
+        assert_eq!(block_mgr.id_size(), 2);
+        assert_eq!(block_mgr.inode_cnt(), Some(1));
         Ok(())
     }
 }
@@ -0,0 +1,169 @@
+use super::{BlockIO, Id, BLOCK_SIZE};
+
+struct CacheEntry {
+    data: Box<[u8; BLOCK_SIZE]>,
+    dirty: bool,
+    last_use: u64,
+}
+
+/// Write-back LRU cache over any other BlockIO. Dirty blocks reach the underlying BlockIO only
+/// when evicted or when `flush` is called
+pub struct CachedBlockIO {
+    block_io: Box<dyn BlockIO>,
+    capacity: usize,
+    entries: std::collections::HashMap<Id, CacheEntry>,
+    lru: std::collections::BTreeMap<u64 /* last_use */, Id>,
+    clock: u64,
+}
+
+impl CachedBlockIO {
+    pub fn new(block_io: Box<dyn BlockIO>, capacity: usize) -> CachedBlockIO {
+        assert!(capacity > 0);
+        CachedBlockIO {
+            block_io,
+            capacity,
+            entries: std::collections::HashMap::new(),
+            lru: std::collections::BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    fn touch(&mut self, block_id: Id) {
+        self.clock += 1;
+        let entry = self.entries.get_mut(&block_id).unwrap();
+        self.lru.remove(&entry.last_use);
+        entry.last_use = self.clock;
+        self.lru.insert(self.clock, block_id);
+    }
+
+    fn insert(&mut self, block_id: Id, data: Box<[u8; BLOCK_SIZE]>, dirty: bool) -> Result<(), std::io::Error> {
+        while self.entries.len() >= self.capacity {
+            self.evict()?;
+        }
+        self.entries.insert(block_id, CacheEntry { data, dirty, last_use: 0 });
+        self.touch(block_id);
+        Ok(())
+    }
+
+    fn evict(&mut self) -> Result<(), std::io::Error> {
+        let (&last_use, &block_id) = self.lru.iter().next().unwrap();
+        let entry = &self.entries[&block_id];
+        if entry.dirty {
+            self.block_io.write(block_id, &entry.data[..])?; // Keep the entry if failed
+        }
+        self.lru.remove(&last_use);
+        self.entries.remove(&block_id);
+        Ok(())
+    }
+}
+
+impl BlockIO for CachedBlockIO {
+    fn read(&mut self, block_id: Id) -> Result<[u8; BLOCK_SIZE], std::io::Error> {
+        if !self.entries.contains_key(&block_id) {
+            let data = self.block_io.read(block_id)?;
+            self.insert(block_id, Box::new(data), false)?;
+        } else {
+            self.touch(block_id);
+        }
+        Ok(*self.entries[&block_id].data)
+    }
+
+    fn write(&mut self, block_id: Id, data: &[u8]) -> Result<(), std::io::Error> {
+        assert_eq!(data.len(), BLOCK_SIZE);
+        if let Some(entry) = self.entries.get_mut(&block_id) {
+            entry.data.copy_from_slice(data);
+            entry.dirty = true;
+            self.touch(block_id);
+            Ok(())
+        } else {
+            let mut block = Box::new([0; BLOCK_SIZE]);
+            block.copy_from_slice(data);
+            self.insert(block_id, block, true)
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        let mut dirty_ids: Vec<Id> = self.entries.iter().filter(|(_, entry)| entry.dirty).map(|(&id, _)| id).collect();
+        dirty_ids.sort_unstable(); // Sequential order is friendlier to the backend
+        for block_id in dirty_ids {
+            let entry = self.entries.get_mut(&block_id).unwrap();
+            self.block_io.write(block_id, &entry.data[..])?;
+            entry.dirty = false;
+        }
+        self.block_io.flush()
+    }
+
+    /// Cached copies are dropped, even dirty ones, as their contents no longer matter
+    fn discard(&mut self, block_id: Id, cnt: usize) -> Result<(), std::io::Error> {
+        for id in block_id .. block_id + cnt as Id {
+            if let Some(entry) = self.entries.remove(&id) {
+                self.lru.remove(&entry.last_use);
+            }
+        }
+        self.block_io.discard(block_id, cnt)
+    }
+
+    fn tail_block_cnt(&self, block_cnt: usize) -> usize {
+        self.block_io.tail_block_cnt(block_cnt)
+    }
+
+    /// Flushed first, so the blocks written before reach the layout they were written for
+    fn resize(&mut self, block_cnt: usize) -> Result<(), std::io::Error> {
+        self.flush()?;
+        self.block_io.resize(block_cnt)
+    }
+}
+
+impl Drop for CachedBlockIO {
+    fn drop(&mut self) {
+        // Should have been flushed by `destroy`. This is only a last resort
+        if let Err(err) = self.flush() {
+            eprintln!("Failed to flush block cache: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::FakeMemBlockIO;
+
+    /// Count writes reaching the underlying BlockIO
+    struct CountingBlockIO {
+        block_io: FakeMemBlockIO,
+        write_cnt: std::rc::Rc<std::cell::Cell<usize>>,
+    }
+
+    impl BlockIO for CountingBlockIO {
+        fn read(&mut self, block_id: Id) -> Result<[u8; BLOCK_SIZE], std::io::Error> {
+            self.block_io.read(block_id)
+        }
+
+        fn write(&mut self, block_id: Id, data: &[u8]) -> Result<(), std::io::Error> {
+            self.write_cnt.set(self.write_cnt.get() + 1);
+            self.block_io.write(block_id, data)
+        }
+    }
+
+    #[test]
+    fn test_write_back_and_evict() -> Result<(), std::io::Error> {
+        let write_cnt = std::rc::Rc::new(std::cell::Cell::new(0));
+        let counting = CountingBlockIO { block_io: FakeMemBlockIO::new(), write_cnt: write_cnt.clone() };
+        let mut block_io = CachedBlockIO::new(Box::new(counting), 2);
+        for i in 0 .. 10 {
+            block_io.write(0, &[i; BLOCK_SIZE])?;
+        }
+        assert_eq!(write_cnt.get(), 0);
+        block_io.write(1, &[1; BLOCK_SIZE])?;
+        block_io.read(0)?; // Now block 1 is the least recently used
+        block_io.write(2, &[2; BLOCK_SIZE])?;
+        assert_eq!(write_cnt.get(), 1); // Block 1 evicted
+        assert_eq!(block_io.read(1)?[..], [1; BLOCK_SIZE][..]);
+        assert_eq!(block_io.read(0)?[..], [9; BLOCK_SIZE][..]);
+        block_io.flush()?;
+        assert_eq!(write_cnt.get(), 3);
+        block_io.flush()?;
+        assert_eq!(write_cnt.get(), 3); // Nothing dirty
+        Ok(())
+    }
+}
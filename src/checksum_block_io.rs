@@ -0,0 +1,276 @@
+use std::convert::TryInto;
+
+use super::{BlockIO, Id, BLOCK_SIZE};
+
+const CHECKSUM_SIZE: usize = std::mem::size_of::<u32>();
+const ENTRY_SIZE: usize = 2 * CHECKSUM_SIZE; // Of the last write, then of the write before it
+const CHECKSUMS_PER_BLOCK: usize = BLOCK_SIZE / ENTRY_SIZE;
+const WRITTEN_BITS_PER_BLOCK: usize = BLOCK_SIZE * 8;
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82f63b78 } else { crc >> 1 };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+pub fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc = (crc >> 8) ^ CRC32C_TABLE[((crc ^ byte as u32) & 0xff) as usize];
+    }
+    !crc
+}
+
+/// Store a CRC32C for every block and verify it on every read. Blocks `0 .. block_cnt` are
+/// passed through to the underlying BlockIO as is, while the checksums are kept in extra blocks
+/// following them, and then a bitmap of the blocks written through this layer. A block neither
+/// marked written nor with a stored checksum has never been written through this layer, so images
+/// created without checksums can still be read. As the bits are kept apart from the checksums, a
+/// zeroed checksum block does not turn the verification off: the blocks it covers fail to verify.
+///
+/// The checksum of a write reaches the storage before the data, and the checksum of the previous
+/// data is kept next to it. A crash in between leaves the previous data, which still reads fine
+pub struct ChecksumBlockIO {
+    block_io: Box<dyn BlockIO>,
+    block_cnt: usize,
+    checksum_blocks: std::collections::HashMap<Id, [u8; BLOCK_SIZE]>,
+    corruption_cnt: std::sync::Arc<std::sync::atomic::AtomicU64>,
+}
+
+impl ChecksumBlockIO {
+    pub fn new(block_io: Box<dyn BlockIO>, block_cnt: usize) -> ChecksumBlockIO {
+        ChecksumBlockIO {
+            block_io,
+            block_cnt,
+            checksum_blocks: std::collections::HashMap::new(),
+            corruption_cnt: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        }
+    }
+
+    /// Number of extra blocks needed to hold the checksums of `block_cnt` blocks and their written bits
+    pub fn checksum_block_cnt(block_cnt: usize) -> usize {
+        block_cnt.div_ceil(CHECKSUMS_PER_BLOCK) + block_cnt.div_ceil(WRITTEN_BITS_PER_BLOCK)
+    }
+
+    /// Counter of detected corruptions, which can be polled from other threads
+    pub fn corruption_cnt(&self) -> std::sync::Arc<std::sync::atomic::AtomicU64> {
+        self.corruption_cnt.clone()
+    }
+
+    fn locate(&self, block_id: Id) -> (Id /* checksum block */, usize /* offset */) {
+        let index = block_id as usize;
+        assert!(index < self.block_cnt);
+        ((self.block_cnt + index / CHECKSUMS_PER_BLOCK) as Id, index % CHECKSUMS_PER_BLOCK * ENTRY_SIZE)
+    }
+
+    fn locate_written(&self, block_id: Id) -> (Id /* bitmap block */, usize /* byte */, u8 /* mask */) {
+        let index = block_id as usize;
+        let first = self.block_cnt + self.block_cnt.div_ceil(CHECKSUMS_PER_BLOCK);
+        let bit = index % WRITTEN_BITS_PER_BLOCK;
+        ((first + index / WRITTEN_BITS_PER_BLOCK) as Id, bit / 8, 1 << (bit % 8))
+    }
+
+    fn checksums(checksum_block: &[u8; BLOCK_SIZE], offset: usize) -> (u32 /* last */, u32 /* previous */) {
+        (
+            u32::from_le_bytes(checksum_block[offset .. offset + CHECKSUM_SIZE].try_into().unwrap()),
+            u32::from_le_bytes(checksum_block[offset + CHECKSUM_SIZE .. offset + ENTRY_SIZE].try_into().unwrap())
+        )
+    }
+
+    fn set_checksums(checksum_block: &mut [u8; BLOCK_SIZE], offset: usize, last: u32, previous: u32) {
+        checksum_block[offset .. offset + CHECKSUM_SIZE].copy_from_slice(&last.to_le_bytes());
+        checksum_block[offset + CHECKSUM_SIZE .. offset + ENTRY_SIZE].copy_from_slice(&previous.to_le_bytes());
+    }
+
+    fn checksum_block(&mut self, id: Id) -> Result<&mut [u8; BLOCK_SIZE], std::io::Error> {
+        if !self.checksum_blocks.contains_key(&id) {
+            let block = self.block_io.read(id)?;
+            self.checksum_blocks.insert(id, block);
+        }
+        Ok(self.checksum_blocks.get_mut(&id).unwrap())
+    }
+}
+
+impl BlockIO for ChecksumBlockIO {
+    fn read(&mut self, block_id: Id) -> Result<[u8; BLOCK_SIZE], std::io::Error> {
+        let data = self.block_io.read(block_id)?;
+        let (bitmap_id, byte, mask) = self.locate_written(block_id);
+        let written = self.checksum_block(bitmap_id)?[byte] & mask != 0;
+        let (checksum_id, offset) = self.locate(block_id);
+        let checksum_block = self.checksum_block(checksum_id)?;
+        let (last, previous) = ChecksumBlockIO::checksums(checksum_block, offset);
+        let checked = written || last != 0;
+        let crc = crc32c(&data);
+        if checked && crc != last && crc == previous {
+            // The last write was lost in a crash. Its checksum no longer applies, which is written
+            // with the next write to the checksum block
+            ChecksumBlockIO::set_checksums(checksum_block, offset, previous, 0);
+        } else if checked && crc != last {
+            let cnt = self.corruption_cnt.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
+            eprintln!("Checksum mismatch on block {} ({} corruptions detected so far)", block_id, cnt);
+            return Err(std::io::Error::from_raw_os_error(libc::EIO))
+        }
+        Ok(data)
+    }
+
+    /// The checksum is written first, then the written bit, which is only set once, and the data last
+    fn write(&mut self, block_id: Id, data: &[u8]) -> Result<(), std::io::Error> {
+        let (checksum_id, offset) = self.locate(block_id);
+        let checksum_block = self.checksum_block(checksum_id)?;
+        let (last, _) = ChecksumBlockIO::checksums(checksum_block, offset);
+        let crc = crc32c(data);
+        if crc != last {
+            ChecksumBlockIO::set_checksums(checksum_block, offset, crc, last);
+            let checksum_block = *checksum_block;
+            self.block_io.write(checksum_id, &checksum_block)?;
+        }
+        let (bitmap_id, byte, mask) = self.locate_written(block_id);
+        let bitmap_block = self.checksum_block(bitmap_id)?;
+        if bitmap_block[byte] & mask == 0 {
+            bitmap_block[byte] |= mask;
+            let bitmap_block = *bitmap_block;
+            self.block_io.write(bitmap_id, &bitmap_block)?;
+        }
+        self.block_io.write(block_id, data)
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.block_io.flush()
+    }
+
+    /// The checksums and written bits are cleared, as the blocks may read as anything afterwards
+    fn discard(&mut self, block_id: Id, cnt: usize) -> Result<(), std::io::Error> {
+        let mut dirty_checksum_ids = std::collections::BTreeSet::new();
+        for id in block_id .. block_id + cnt as Id {
+            let (checksum_id, offset) = self.locate(id);
+            ChecksumBlockIO::set_checksums(self.checksum_block(checksum_id)?, offset, 0, 0);
+            let (bitmap_id, byte, mask) = self.locate_written(id);
+            self.checksum_block(bitmap_id)?[byte] &= !mask;
+            dirty_checksum_ids.extend([checksum_id, bitmap_id]);
+        }
+        for checksum_id in dirty_checksum_ids {
+            let checksum_block = self.checksum_blocks[&checksum_id];
+            self.block_io.write(checksum_id, &checksum_block)?;
+        }
+        self.block_io.discard(block_id, cnt)
+    }
+
+    fn tail_block_cnt(&self, block_cnt: usize) -> usize {
+        ChecksumBlockIO::checksum_block_cnt(block_cnt)
+    }
+
+    /// The checksums and written bits of the blocks kept by both sizes are copied, and those of the
+    /// blocks only in the new size start cleared, so they are not verified until written
+    fn resize(&mut self, block_cnt: usize) -> Result<(), std::io::Error> {
+        let kept = std::cmp::min(self.block_cnt, block_cnt);
+        let mut checksums = vec![0; block_cnt.div_ceil(CHECKSUMS_PER_BLOCK) * BLOCK_SIZE];
+        for i in 0 .. kept.div_ceil(CHECKSUMS_PER_BLOCK) {
+            let (checksum_id, _) = self.locate((i * CHECKSUMS_PER_BLOCK) as Id);
+            checksums[i * BLOCK_SIZE .. (i + 1) * BLOCK_SIZE].copy_from_slice(self.checksum_block(checksum_id)?);
+        }
+        checksums[kept * ENTRY_SIZE ..].fill(0);
+        let mut written = vec![0; block_cnt.div_ceil(WRITTEN_BITS_PER_BLOCK) * BLOCK_SIZE];
+        for i in 0 .. kept.div_ceil(WRITTEN_BITS_PER_BLOCK) {
+            let (bitmap_id, _, _) = self.locate_written((i * WRITTEN_BITS_PER_BLOCK) as Id);
+            written[i * BLOCK_SIZE .. (i + 1) * BLOCK_SIZE].copy_from_slice(self.checksum_block(bitmap_id)?);
+        }
+        written[kept.div_ceil(8) ..].fill(0);
+        if kept % 8 != 0 {
+            written[kept / 8] &= (1 << (kept % 8)) - 1;
+        }
+        let tail = [checksums, written].concat();
+        self.block_io.write_blocks(block_cnt as Id, &tail)?;
+        self.block_cnt = block_cnt;
+        self.checksum_blocks = tail.chunks_exact(BLOCK_SIZE).enumerate()
+            .map(|(i, block)| ((block_cnt + i) as Id, block.try_into().unwrap())).collect();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::ImageBlockIO;
+    use super::super::fault_block_io::FaultBlockIO;
+
+    #[test]
+    fn test_crc32c() {
+        assert_eq!(crc32c(b"123456789"), 0xe3069283);
+    }
+
+    #[test]
+    fn test_detect_corruption() -> Result<(), std::io::Error> {
+        let mut path = std::env::temp_dir();
+        path.push(format!("rfs-test-checksum-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let block_cnt = 4;
+        let device_cnt = block_cnt + ChecksumBlockIO::checksum_block_cnt(block_cnt);
+        let mut block_io = ChecksumBlockIO::new(Box::new(ImageBlockIO::new(path.clone(), device_cnt)?), block_cnt);
+        let corruption_cnt = block_io.corruption_cnt();
+        block_io.write(1, &[1; BLOCK_SIZE])?;
+        block_io.write(2, &[2; BLOCK_SIZE])?;
+        assert_eq!(block_io.read(0)?[..], [0; BLOCK_SIZE][..]); // Never written
+        assert_eq!(block_io.read(1)?[..], [1; BLOCK_SIZE][..]);
+
+        // Flip a byte behind its back
+        let mut raw = ImageBlockIO::new(path.clone(), device_cnt)?;
+        let mut block = raw.read(2)?;
+        block[100] ^= 1;
+        raw.write(2, &block)?;
+
+        let err = block_io.read(2).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        assert_eq!(corruption_cnt.load(std::sync::atomic::Ordering::Relaxed), 1);
+        assert_eq!(block_io.read(1)?[..], [1; BLOCK_SIZE][..]);
+
+        // Zeroing the checksums does not make the blocks unchecked, as they are still marked written
+        raw.write(block_cnt as Id, &[0; BLOCK_SIZE])?;
+        raw.write(1, &[9; BLOCK_SIZE])?;
+        let mut block_io = ChecksumBlockIO::new(Box::new(ImageBlockIO::new(path.clone(), device_cnt)?), block_cnt);
+        assert_eq!(block_io.read(0)?[..], [0; BLOCK_SIZE][..]);
+        let err = block_io.read(1).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        std::fs::remove_file(&path)
+    }
+
+    #[test]
+    fn test_crash_between_checksum_and_data() -> Result<(), std::io::Error> {
+        let mut path = std::env::temp_dir();
+        path.push(format!("rfs-test-checksum-crash-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let block_cnt = 4;
+        let device_cnt = block_cnt + ChecksumBlockIO::checksum_block_cnt(block_cnt);
+        let fault_block_io = FaultBlockIO::new(Box::new(ImageBlockIO::new(path.clone(), device_cnt)?));
+        let plan = fault_block_io.plan();
+        let mut block_io = ChecksumBlockIO::new(Box::new(fault_block_io), block_cnt);
+        block_io.write(1, &[1; BLOCK_SIZE])?;
+        plan.borrow_mut().power_cut(2); // The checksum reaches the storage, the data does not
+        block_io.write(1, &[2; BLOCK_SIZE])?;
+
+        let mut block_io = ChecksumBlockIO::new(Box::new(ImageBlockIO::new(path.clone(), device_cnt)?), block_cnt);
+        let corruption_cnt = block_io.corruption_cnt();
+        assert_eq!(block_io.read(1)?[..], [1; BLOCK_SIZE][..]);
+        // The lost checksum is dropped, so a crash during the next write leaves a readable block too
+        block_io.write(1, &[3; BLOCK_SIZE])?;
+        let mut raw = ImageBlockIO::new(path.clone(), device_cnt)?;
+        raw.write(1, &[1; BLOCK_SIZE])?;
+        let mut block_io = ChecksumBlockIO::new(Box::new(ImageBlockIO::new(path.clone(), device_cnt)?), block_cnt);
+        assert_eq!(block_io.read(1)?[..], [1; BLOCK_SIZE][..]);
+        assert_eq!(corruption_cnt.load(std::sync::atomic::Ordering::Relaxed), 0);
+        std::fs::remove_file(&path)
+    }
+}
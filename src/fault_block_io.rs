@@ -0,0 +1,180 @@
+use super::{BlockIO, Id, BLOCK_SIZE};
+
+/// Script of faults to inject. Operations are counted from 1 since the BlockIO was created
+#[derive(Default)]
+pub struct FaultPlan {
+    read_cnt: usize,
+    write_cnt: usize,
+    fail_read: Option<(usize, i32 /* errno */)>,
+    fail_write: Option<(usize, i32 /* errno */)>,
+    tear_write: Option<(usize, usize /* bytes reaching the disk */)>,
+    power_cut: Option<usize>,
+}
+
+impl FaultPlan {
+    /// Fail the `nth` read from now on (1 = the next one) with `errno`
+    pub fn fail_read(&mut self, nth: usize, errno: i32) {
+        self.fail_read = Some((self.read_cnt + nth, errno));
+    }
+
+    /// Fail the `nth` write from now on (1 = the next one) with `errno`. Nothing is written
+    pub fn fail_write(&mut self, nth: usize, errno: i32) {
+        self.fail_write = Some((self.write_cnt + nth, errno));
+    }
+
+    /// Only write the first `bytes` bytes of the `nth` write from now on, but report success
+    pub fn tear_write(&mut self, nth: usize, bytes: usize) {
+        assert!(bytes < BLOCK_SIZE);
+        self.tear_write = Some((self.write_cnt + nth, bytes));
+    }
+
+    /// Silently drop the `nth` write from now on and every write after it. Like a page cache, the
+    /// dropped writes are still visible to later reads through this BlockIO, but never reach the
+    /// underlying one
+    pub fn power_cut(&mut self, nth: usize) {
+        self.power_cut = Some(self.write_cnt + nth);
+    }
+
+    pub fn read_cnt(&self) -> usize {
+        self.read_cnt
+    }
+
+    pub fn write_cnt(&self) -> usize {
+        self.write_cnt
+    }
+}
+
+/// Forward everything to another BlockIO, except the faults scripted in a shared FaultPlan
+pub struct FaultBlockIO {
+    block_io: Box<dyn BlockIO>,
+    plan: std::rc::Rc<std::cell::RefCell<FaultPlan>>,
+    lost_writes: std::collections::HashMap<Id, Box<[u8; BLOCK_SIZE]>>,
+}
+
+impl FaultBlockIO {
+    pub fn new(block_io: Box<dyn BlockIO>) -> FaultBlockIO {
+        FaultBlockIO {
+            block_io,
+            plan: std::rc::Rc::new(std::cell::RefCell::new(FaultPlan::default())),
+            lost_writes: std::collections::HashMap::new(),
+        }
+    }
+
+    /// Handle to script faults after the BlockIO has been handed over to a BlockMgr
+    pub fn plan(&self) -> std::rc::Rc<std::cell::RefCell<FaultPlan>> {
+        self.plan.clone()
+    }
+}
+
+impl BlockIO for FaultBlockIO {
+    fn read(&mut self, block_id: Id) -> Result<[u8; BLOCK_SIZE], std::io::Error> {
+        let mut plan = self.plan.borrow_mut();
+        plan.read_cnt += 1;
+        if let Some((nth, errno)) = plan.fail_read {
+            if nth == plan.read_cnt {
+                return Err(std::io::Error::from_raw_os_error(errno))
+            }
+        }
+        if let Some(block) = self.lost_writes.get(&block_id) {
+            return Ok(**block)
+        }
+        self.block_io.read(block_id)
+    }
+
+    fn write(&mut self, block_id: Id, data: &[u8]) -> Result<(), std::io::Error> {
+        let mut plan = self.plan.borrow_mut();
+        plan.write_cnt += 1;
+        if let Some((nth, errno)) = plan.fail_write {
+            if nth == plan.write_cnt {
+                return Err(std::io::Error::from_raw_os_error(errno))
+            }
+        }
+        if let Some((nth, bytes)) = plan.tear_write {
+            if nth == plan.write_cnt {
+                let mut block = self.block_io.read(block_id)?;
+                block[.. bytes].copy_from_slice(&data[.. bytes]);
+                return self.block_io.write(block_id, &block)
+            }
+        }
+        if let Some(nth) = plan.power_cut {
+            if nth <= plan.write_cnt {
+                let mut block = Box::new([0; BLOCK_SIZE]);
+                block.copy_from_slice(data);
+                self.lost_writes.insert(block_id, block);
+                return Ok(())
+            }
+        }
+        self.block_io.write(block_id, data)
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        if let Some(nth) = self.plan.borrow().power_cut {
+            if nth <= self.plan.borrow().write_cnt {
+                return Ok(())
+            }
+        }
+        self.block_io.flush()
+    }
+
+    /// Lost after a power cut, like writes
+    fn discard(&mut self, block_id: Id, cnt: usize) -> Result<(), std::io::Error> {
+        if let Some(nth) = self.plan.borrow().power_cut {
+            if nth <= self.plan.borrow().write_cnt {
+                return Ok(())
+            }
+        }
+        self.block_io.discard(block_id, cnt)
+    }
+
+    fn tail_block_cnt(&self, block_cnt: usize) -> usize {
+        self.block_io.tail_block_cnt(block_cnt)
+    }
+
+    fn resize(&mut self, block_cnt: usize) -> Result<(), std::io::Error> {
+        self.block_io.resize(block_cnt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{FakeMemBlockIO, ImageBlockIO};
+
+    #[test]
+    fn test_fail_nth() -> Result<(), std::io::Error> {
+        let mut block_io = FaultBlockIO::new(Box::new(FakeMemBlockIO::new()));
+        let plan = block_io.plan();
+        plan.borrow_mut().fail_write(2, libc::ENOSPC);
+        plan.borrow_mut().fail_read(1, libc::EIO);
+        block_io.write(0, &[1; BLOCK_SIZE])?;
+        assert_eq!(block_io.write(1, &[1; BLOCK_SIZE]).unwrap_err().raw_os_error(), Some(libc::ENOSPC));
+        assert_eq!(block_io.read(0).unwrap_err().raw_os_error(), Some(libc::EIO));
+        assert_eq!(block_io.read(1)?[..], [0; BLOCK_SIZE][..]);
+        assert_eq!(plan.borrow().read_cnt(), 2);
+        assert_eq!(plan.borrow().write_cnt(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tear_and_power_cut() -> Result<(), std::io::Error> {
+        let mut path = std::env::temp_dir();
+        path.push(format!("rfs-test-fault-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let mut block_io = FaultBlockIO::new(Box::new(ImageBlockIO::new(path.clone(), 2)?));
+            let plan = block_io.plan();
+            block_io.write(0, &[1; BLOCK_SIZE])?;
+            plan.borrow_mut().tear_write(1, 100);
+            plan.borrow_mut().power_cut(2);
+            block_io.write(0, &[2; BLOCK_SIZE])?;
+            block_io.write(1, &[2; BLOCK_SIZE])?;
+            assert_eq!(block_io.read(1)?[..], [2; BLOCK_SIZE][..]); // Still in the "page cache"
+        }
+        let mut block_io = ImageBlockIO::new(path.clone(), 2)?;
+        let block = block_io.read(0)?;
+        assert_eq!(block[.. 100], [2; 100][..]);
+        assert_eq!(block[100 ..], [1; BLOCK_SIZE - 100][..]);
+        assert_eq!(block_io.read(1)?[..], [0; BLOCK_SIZE][..]);
+        std::fs::remove_file(&path)
+    }
+}
@@ -6,22 +6,38 @@ use std::convert::TryInto;
 pub mod inode;
 pub use inode::{block_io, block_mgr};
 
+#[path="quota.rs"]
+pub mod quota;
+
 use inode::*;
+use quota::{Limits, Owner, QuotaTable, owners};
 use block_io::{Id, BLOCK_SIZE};
-use block_mgr::BlockMgr;
+use block_mgr::{BlockMgr, decode_id, encode_id};
+
+/// Each directory item is like [ inode (id size) | name length (1B) | name ]
+pub const DIR_ITEM_SIZE: usize = 64;
 
-const INODE_TALBE_SIZE: usize = Id::max_value() as usize + 1;
+/// Minimum size of the inode table before dropping the entries of closed inodes
+const INODE_TABLE_PRUNE_SIZE: usize = 1024;
+
+/// Most blocks freed by one step of truncating in a transaction, see `truncate_steps`. Each dirties a
+/// bitmap block at worst, which must fit in half of the journal together with the index blocks
+const TRUNCATE_STEP: usize = 64;
 
 pub struct FileMgr {
     block_mgr: Box<BlockMgr>,
-    inode_table: Vec<std::rc::Weak<Inode>>,
+    inode_table: std::collections::HashMap<Id, std::rc::Weak<Inode>>, // Open inodes only
+    prune_size: usize,
+    quota: Option<QuotaTable>, // None if quotas are not enabled
+    last_generation: u64, // Of the last inode created, on volumes without an inode table
 }
 
 impl FileMgr {
     pub fn new(block_mgr: Box<BlockMgr>) -> FileMgr {
-        let mut obj = FileMgr { block_mgr: block_mgr, inode_table: Vec:: new() };
-        obj.inode_table.resize_with(INODE_TALBE_SIZE, || std::rc::Weak::new());
-        obj
+        FileMgr {
+            block_mgr, inode_table: std::collections::HashMap::new(), prune_size: INODE_TABLE_PRUNE_SIZE, quota: None,
+            last_generation: 0
+        }
     }
 
     pub fn is_formatted(&mut self) -> Result<bool, std::io::Error> {
@@ -34,26 +50,219 @@ impl FileMgr {
             let root_inode = self.new_inode()?;
             assert_eq!(root_inode.id(), 1);
         }
-        Ok(())
+        self.load_quota()
     }
 
+    /// A new inode owned by root
     pub fn new_inode(&mut self) -> Result<std::rc::Rc<Inode>, std::io::Error> {
-        let id = self.block_mgr.new_block()?;
-        let mut block = self.block_mgr.read_block(id)?;
-        let mut generation = u64::from_le_bytes(block[0 .. 8].try_into().unwrap());
+        self.new_inode_owned_by(0, 0)
+    }
+
+    /// A new inode owned by `uid` and `gid`, which are charged for it. Fails with EDQUOT if either of
+    /// them has no inode left in its quota
+    pub fn new_inode_owned_by(&mut self, uid: u32, gid: u32) -> Result<std::rc::Rc<Inode>, std::io::Error> {
+        let now = block_mgr::super_block::now();
+        if let Some(quota) = &self.quota {
+            quota.check(&owners(uid, gid), 0, 1, now)?;
+        }
+        let id = self.block_mgr.new_inode()?;
+        let inode_size = self.block_mgr.inode_size();
+        let mut data = self.block_mgr.read_inode(id)?;
+        let mut generation = u64::from_le_bytes(data[0 .. 8].try_into().unwrap());
         generation = generation.overflowing_add(1).0;
-        block[0 .. 8].copy_from_slice(&generation.to_le_bytes());
-        block[8 ..].copy_from_slice(&[0; BLOCK_SIZE - 8]);
-        self.block_mgr.write_block(id, &block)?;
-        self.read_inode(id)
+        if !self.block_mgr.has_inode_table() {
+            // The block may have been discarded since it was last an inode, losing the generation.
+            // Never go back before the current time, or the last inode created in the same second,
+            // so the same id does not get the same generation again
+            generation = std::cmp::max(generation, std::cmp::max((now as u64) << 16, self.last_generation + 1));
+            self.last_generation = generation;
+        }
+        data[0 .. 8].copy_from_slice(&generation.to_le_bytes());
+        data[8 .. inode_size].fill(0);
+        self.block_mgr.write_inode(id, &data[.. inode_size])?;
+        self.block_mgr.adjust_inode_cnt(1);
+        self.block_mgr.flush_bitmap()?; // Before the inode is linked into any directory
+        let inode = self.read_inode(id)?;
+        inode.set_uid(uid);
+        inode.set_gid(gid);
+        if inode.inline_capacity() > 0 {
+            inode.set_inline(true); // Until it outgrows the inode
+        }
+        inode.flush(&mut self.block_mgr)?;
+        if let Some(quota) = &mut self.quota {
+            quota.charge(&owners(uid, gid), 0, 1, now);
+        }
+        Ok(inode)
+    }
+
+    /// Blocks of `inode` charged to its owner: data blocks, index blocks and the xattr block
+    fn charged_block_cnt(&mut self, inode: &Inode) -> Result<u64, std::io::Error> {
+        let end = (inode.length() as usize).div_ceil(BLOCK_SIZE);
+        let data_block_cnt: usize = inode.mapped_runs(&mut self.block_mgr, 0, end)?.iter().map(|run| run.2).sum();
+        let xattr_block_cnt = (inode.xattr_block() != 0) as usize;
+        Ok((data_block_cnt + inode.index_blocks(&mut self.block_mgr)?.len() + xattr_block_cnt) as u64)
+    }
+
+    /// Charge the blocks allocated since the free block count was `free_block_cnt` to the owner of
+    /// `inode`, or refund the blocks freed since
+    fn charge_blocks(&mut self, inode: &Inode, free_block_cnt: usize) {
+        if let Some(quota) = &mut self.quota {
+            let blocks = free_block_cnt as i64 - self.block_mgr.free_block_cnt() as i64;
+            quota.charge(&owners(inode.uid(), inode.gid()), blocks, 0, block_mgr::super_block::now());
+        }
+    }
+
+    /// Change the owner of `inode`, moving its usage to the new owner. Fails with EDQUOT if it does not
+    /// fit in the quota of the new user or group
+    pub fn set_owner(&mut self, inode: &Inode, uid: u32, gid: u32) -> Result<(), std::io::Error> {
+        if self.quota.is_some() {
+            let blocks = self.charged_block_cnt(inode)?;
+            let quota = self.quota.as_mut().unwrap();
+            let now = block_mgr::super_block::now();
+            let old_owners = owners(inode.uid(), inode.gid());
+            let new_owners: Vec<Owner> = owners(uid, gid).iter().cloned()
+                .filter(|owner| !old_owners.contains(owner)).collect();
+            quota.check(&new_owners, blocks, 1, now)?;
+            quota.charge(&old_owners, -(blocks as i64), -1, now);
+            quota.charge(&owners(uid, gid), blocks as i64, 1, now);
+        }
+        inode.set_uid(uid);
+        inode.set_gid(gid);
+        Ok(())
+    }
+
+    /// Read the quota table if quotas are enabled. The usage is counted again if the volume was not
+    /// cleanly unmounted, as it is only saved on `sync`
+    fn load_quota(&mut self) -> Result<(), std::io::Error> {
+        let id = self.block_mgr.quota_inode();
+        if id == 0 {
+            return Ok(())
+        }
+        let inode = self.read_inode(id)?;
+        let data = self.read_file(&inode, 0, inode.length() as usize)?;
+        let mut quota = QuotaTable::parse(&data);
+        if !self.block_mgr.super_block().clean {
+            self.count_usage(&mut quota)?;
+        }
+        self.quota = Some(quota);
+        Ok(())
+    }
+
+    /// Count the usage of every user and group from the inodes reachable from the root
+    fn count_usage(&mut self, quota: &mut QuotaTable) -> Result<(), std::io::Error> {
+        quota.clear_usage();
+        let quota_inode = self.block_mgr.quota_inode();
+        for id in self.inode_refs()?.into_keys().filter(|&id| id != quota_inode) {
+            let inode = self.read_inode(id)?;
+            let blocks = self.charged_block_cnt(&inode)?;
+            quota.add_usage(&owners(inode.uid(), inode.gid()), blocks, 1);
+        }
+        quota.update_grace(block_mgr::super_block::now());
+        Ok(())
+    }
+
+    /// Write the quota table to the quota inode if changed. The quota inode itself is not charged
+    fn save_quota(&mut self) -> Result<(), std::io::Error> {
+        let mut quota = match self.quota.take() {
+            Some(quota) if quota.is_dirty() => quota,
+            quota => {
+                self.quota = quota;
+                return Ok(())
+            }
+        };
+        let inode = self.read_inode(self.block_mgr.quota_inode());
+        let data = quota.to_bytes();
+        let ret = inode.and_then(|inode| {
+            self.truncate_file(&inode, data.len())?;
+            self.write_file(&inode, 0, &data).map(|_| ())
+        });
+        if ret.is_ok() {
+            quota.set_clean();
+        }
+        self.quota = Some(quota);
+        ret
+    }
+
+    /// Set the block and inode limits of a user or a group, enabling quotas first if they are not yet
+    #[allow(dead_code)] // Only used by rfs-quota
+    pub fn set_quota(&mut self, owner: Owner, blocks: Limits, inodes: Limits) -> Result<(), std::io::Error> {
+        if self.quota.is_none() {
+            let inode = self.new_inode()?;
+            inode.set_mode(libc::S_IFREG as u16 | 0o600);
+            inode.flush(&mut self.block_mgr)?;
+            self.block_mgr.set_quota_inode(inode.id());
+            let mut quota = QuotaTable::new();
+            self.count_usage(&mut quota)?;
+            self.quota = Some(quota);
+        }
+        self.quota.as_mut().unwrap().set_limits(owner, blocks, inodes, block_mgr::super_block::now());
+        self.save_quota()
+    }
+
+    /// None if quotas are not enabled
+    #[allow(dead_code)] // Only used by rfs-quota
+    pub fn quota(&self) -> Option<&QuotaTable> {
+        self.quota.as_ref()
+    }
+
+    /// Mark the volume as in use. Only for writable mounts
+    pub fn mount(&mut self) -> Result<(), std::io::Error> {
+        self.block_mgr.mount()
+    }
+
+    /// Mark the volume as cleanly unmounted, after flushing everything
+    pub fn unmount(&mut self) -> Result<(), std::io::Error> {
+        self.sync()?;
+        self.block_mgr.unmount()
+    }
+
+    /// Number of inodes of the volume, or None if the volume was formatted by an older version
+    pub fn inode_cnt(&self) -> Option<u64> {
+        self.block_mgr.inode_cnt()
+    }
+
+    pub fn set_inode_cnt(&mut self, inode_cnt: u64) {
+        self.block_mgr.set_inode_cnt(inode_cnt)
+    }
+
+    /// Bytes of a block or inode id stored on this volume
+    pub fn id_size(&self) -> usize {
+        self.block_mgr.id_size()
+    }
+
+    /// Number of data blocks of the volume, including those taken by inodes on volumes without an
+    /// inode table
+    pub fn block_cnt(&self) -> usize {
+        self.block_mgr.block_cnt()
+    }
+
+    pub fn free_block_cnt(&self) -> usize {
+        self.block_mgr.free_block_cnt()
+    }
+
+    pub fn set_privileged(&mut self, privileged: bool) {
+        self.block_mgr.set_privileged(privileged)
+    }
+
+    /// Number of free blocks which unprivileged callers can allocate
+    pub fn avail_block_cnt(&self) -> usize {
+        self.block_mgr.avail_block_cnt()
+    }
+
+    pub fn free_inode_cnt(&self) -> usize {
+        self.block_mgr.free_inode_cnt()
     }
 
     pub fn read_inode(&mut self, id: Id) -> Result<std::rc::Rc<Inode>, std::io::Error> {
-        if let Some(inode) = self.inode_table[id as usize - 1].upgrade() {
+        if let Some(inode) = self.inode_table.get(&id).and_then(|inode| inode.upgrade()) {
             return Ok(inode)
         }
-        let inode = std::rc::Rc::new(Inode::new(&mut*self.block_mgr, id)?);
-        self.inode_table[id as usize - 1] = std::rc::Rc::downgrade(&inode);
+        let inode = std::rc::Rc::new(Inode::new(&mut self.block_mgr, id)?);
+        self.inode_table.insert(id, std::rc::Rc::downgrade(&inode));
+        if self.inode_table.len() >= self.prune_size {
+            self.inode_table.retain(|_, inode| inode.strong_count() > 0);
+            self.prune_size = std::cmp::max(INODE_TABLE_PRUNE_SIZE, self.inode_table.len() * 2);
+        }
         Ok(inode)
     }
 
@@ -61,12 +270,24 @@ impl FileMgr {
         self.read_inode(1)
     }
 
+    /// Frees the data blocks by truncating first, step by step in a transaction, then the index and
+    /// xattr blocks
     pub fn del_inode(&mut self, inode: &Inode) -> Result<(), std::io::Error> {
-        let indirect_id = inode.indirect_id();
-        if indirect_id != 0 {
-            self.block_mgr.del_block(indirect_id)?;
+        self.truncate_file(inode, 0)?;
+        let free_block_cnt = self.block_mgr.free_block_cnt();
+        let mut blocks = inode.prune_index(&mut self.block_mgr, 0)?;
+        if inode.xattr_block() != 0 {
+            blocks.push(inode.xattr_block());
         }
-        self.block_mgr.del_block(inode.id())
+        for &id in blocks.iter() {
+            self.block_mgr.del_block(id)?;
+        }
+        self.charge_blocks(inode, free_block_cnt);
+        if let Some(quota) = &mut self.quota {
+            quota.charge(&owners(inode.uid(), inode.gid()), 0, -1, block_mgr::super_block::now());
+        }
+        self.block_mgr.adjust_inode_cnt(-1);
+        self.block_mgr.del_inode(inode.id())
     }
 
     pub fn read_file(&mut self, inode: &Inode, offset: usize, count: usize)
@@ -78,11 +299,13 @@ impl FileMgr {
 
         let start = offset;
         let end = std::cmp::min(length, offset + count);
-        let mut ret = vec![];
-        ret.reserve(end - start);
+        if inode.is_inline() {
+            return Ok(inode.read_inline(start, end - start))
+        }
+        let mut ret = Vec::with_capacity(end - start);
 
         if start / BLOCK_SIZE == end / BLOCK_SIZE {
-            let id = inode.data_block(start / BLOCK_SIZE);
+            let id = inode.data_block(&mut self.block_mgr, start / BLOCK_SIZE)?;
             if id > 0 {
                 let block = self.block_mgr.read_block(id)?;
                 return Ok(Vec::from(&block[start % BLOCK_SIZE .. end % BLOCK_SIZE]));
@@ -91,10 +314,10 @@ impl FileMgr {
             }
         }
 
-        let start_block = (start + BLOCK_SIZE - 1) / BLOCK_SIZE; // First full block
+        let start_block = start.div_ceil(BLOCK_SIZE); // First full block
         let end_block = end / BLOCK_SIZE; // Last full block
         if start % BLOCK_SIZE != 0 {
-            let id = inode.data_block(start_block - 1);
+            let id = inode.data_block(&mut self.block_mgr, start_block - 1)?;
             if id > 0 {
                 let block = self.block_mgr.read_block(id)?;
                 ret.extend_from_slice(&block[start % BLOCK_SIZE ..]);
@@ -102,17 +325,19 @@ impl FileMgr {
                 ret.extend(vec![0; BLOCK_SIZE - start % BLOCK_SIZE])
             }
         }
-        for i in start_block .. end_block {
-            let id = inode.data_block(i);
+        let mut i = start_block;
+        while i < end_block {
+            let id = inode.data_block(&mut self.block_mgr, i)?;
+            let cnt = inode.contiguous_run(&mut self.block_mgr, i, end_block)?;
+            let pos = ret.len();
+            ret.resize(pos + cnt * BLOCK_SIZE, 0);
             if id > 0 {
-                let block = self.block_mgr.read_block(id)?;
-                ret.extend(block.iter());
-            } else {
-                ret.extend([0; BLOCK_SIZE].iter())
+                self.block_mgr.read_blocks(id, &mut ret[pos ..])?;
             }
+            i += cnt;
         }
         if end % BLOCK_SIZE != 0 {
-            let id = inode.data_block(end_block);
+            let id = inode.data_block(&mut self.block_mgr, end_block)?;
             if id > 0 {
                 let block = self.block_mgr.read_block(id)?;
                 ret.extend_from_slice(&block[.. end % BLOCK_SIZE]);
@@ -124,111 +349,659 @@ impl FileMgr {
         Ok(ret)
     }
 
+    /// Where to look for a new block for data block `index` of `inode`: right after the previous data
+    /// block, or where the inode prefers if there is no previous one
+    fn alloc_goal(&mut self, inode: &Inode, index: usize) -> Result<Id, std::io::Error> {
+        Ok(match index {
+            0 => inode.block_goal(),
+            _ => match inode.data_block(&mut self.block_mgr, index - 1)? {
+                0 => inode.block_goal(),
+                id => id + 1
+            }
+        })
+    }
+
+    /// Returns how much is written. If the blocks or the quota of the owner of `inode` run out, or
+    /// writing fails half way, that is only what has been written before, like a short write of other
+    /// file systems. Fails with ENOSPC, EDQUOT or the I/O error only if nothing is written, or with
+    /// EFBIG if the file would be longer than the inode can map
     pub fn write_file(&mut self, inode: &Inode, offset: usize, data: &[u8]) -> Result<usize, std::io::Error> {
+        if (offset + data.len()) as u64 > inode.max_length() {
+            return Err(std::io::Error::from_raw_os_error(libc::EFBIG))
+        }
+        let free_block_cnt = self.block_mgr.free_block_cnt();
+        let budget = self.quota.as_ref().and_then(|quota| {
+            quota.block_headroom(&owners(inode.uid(), inode.gid()), block_mgr::super_block::now())
+        });
+        self.block_mgr.set_alloc_budget(budget.map(|budget| budget as usize));
+        let mut write_cnt = 0;
+        let ret = self.write_blocks(inode, offset, data, &mut write_cnt);
+        self.block_mgr.set_alloc_budget(None);
+        // Flush even if failed half way, so the inode matches what has reached the blocks. The new
+        // blocks must be marked in the bitmap before the inode points to them
+        let flushed = self.block_mgr.flush_bitmap().and_then(|_| inode.flush(&mut self.block_mgr));
+        self.charge_blocks(inode, free_block_cnt); // Flushing may free blocks of the extent tree
+        if write_cnt == 0 {
+            ret?;
+        }
+        flushed?;
+        Ok(write_cnt)
+    }
+
+    /// Counts what is written in `*write_cnt` as it goes, so it is known even if failed half way
+    fn write_blocks(&mut self, inode: &Inode, offset: usize, data: &[u8], write_cnt: &mut usize)
+                    -> Result<(), std::io::Error> {
         let start = offset;
         let end = start + data.len();
 
+        if inode.is_inline() {
+            if end <= inode.inline_capacity() {
+                inode.write_inline(offset, data);
+                inode.set_length(std::cmp::max(inode.length(), end as u64));
+                *write_cnt = data.len();
+                return Ok(())
+            }
+            self.unpack_inline(inode)?;
+        }
+
         if start / BLOCK_SIZE == end / BLOCK_SIZE {
             let blkno = start / BLOCK_SIZE;
-            let mut id = inode.data_block(blkno);
+            let mut id = inode.data_block(&mut self.block_mgr, blkno)?;
             let mut block = if id == 0 {
-                id = self.block_mgr.new_block()?;
+                let goal = self.alloc_goal(inode, blkno)?;
+                id = self.block_mgr.new_block_near(goal)?;
                 inode.set_data_block(&mut self.block_mgr, blkno, id)?;
                 [0; BLOCK_SIZE]
             } else {
                 self.block_mgr.read_block(id)?
             };
             block[start % BLOCK_SIZE .. end % BLOCK_SIZE].copy_from_slice(data);
-            self.block_mgr.write_block(id, &block)?;
-            inode.set_length(std::cmp::max(inode.length(), (offset + data.len()) as u32));
-
-            inode.flush(&mut*self.block_mgr)?;
-            return Ok(data.len())
+            self.write_data(inode, id, &block)?;
+            inode.set_length(std::cmp::max(inode.length(), (offset + data.len()) as u64));
+            *write_cnt = data.len();
+            return Ok(())
         }
 
-        let start_block = (start + BLOCK_SIZE - 1) / BLOCK_SIZE; // First full block
+        let start_block = start.div_ceil(BLOCK_SIZE); // First full block
         let end_block = end / BLOCK_SIZE; // Last full block
-        let mut write_cnt = 0;
         if start % BLOCK_SIZE != 0 {
-            let mut id = inode.data_block(start_block - 1);
+            let mut id = inode.data_block(&mut self.block_mgr, start_block - 1)?;
             let mut block = if id == 0 {
-                id = self.block_mgr.new_block()?;
+                let goal = self.alloc_goal(inode, start_block - 1)?;
+                id = self.block_mgr.new_block_near(goal)?;
                 inode.set_data_block(&mut self.block_mgr, start_block - 1, id)?;
                 [0; BLOCK_SIZE]
             } else {
                 self.block_mgr.read_block(id)?
             };
             block[start % BLOCK_SIZE ..].copy_from_slice(&data[.. BLOCK_SIZE - start % BLOCK_SIZE]);
-            self.block_mgr.write_block(id, &block)?;
-            write_cnt += BLOCK_SIZE - start % BLOCK_SIZE;
-            inode.set_length(std::cmp::max(inode.length(), (offset + write_cnt) as u32))
+            self.write_data(inode, id, &block)?;
+            *write_cnt += BLOCK_SIZE - start % BLOCK_SIZE;
+            inode.set_length(std::cmp::max(inode.length(), (offset + *write_cnt) as u64))
         }
-        for i in start_block .. end_block {
-            let mut id = inode.data_block(i);
-            if id == 0 {
-                id = self.block_mgr.new_block()?;
-                inode.set_data_block(&mut self.block_mgr, i, id)?;
+        // If the blocks run out (or the quota does), write the blocks allocated so far before failing
+        let mut i = start_block;
+        let mut alloc_ret = Ok(());
+        while i < end_block {
+            if inode.data_block(&mut self.block_mgr, i)? != 0 {
+                i += 1;
+                continue
+            }
+            // Allocate the whole hole at once, so it is filled by consecutive blocks if possible
+            let hole = inode.contiguous_run(&mut self.block_mgr, i, end_block)?;
+            let (id, cnt) = match self.alloc_goal(inode, i).and_then(|goal| self.block_mgr.new_blocks(goal, hole)) {
+                Ok(run) => run,
+                Err(err) => {
+                    alloc_ret = Err(err);
+                    break
+                }
+            };
+            // Extents merge with the previous one if the run continues it
+            if let Err(err) = inode.set_data_blocks(&mut self.block_mgr, i, id, cnt) {
+                let mut j = 0;
+                while j < cnt && inode.data_block(&mut self.block_mgr, i + j)? == id + j as Id {
+                    j += 1;
+                }
+                for k in j .. cnt {
+                    self.block_mgr.del_block(id + k as Id)?; // Not pointed to by the inode
+                }
+                alloc_ret = Err(err);
+                i += j;
+                break
             }
-            self.block_mgr.write_block(id, &data[write_cnt .. write_cnt + BLOCK_SIZE])?;
-            write_cnt += BLOCK_SIZE;
-            inode.set_length(std::cmp::max(inode.length(), (offset + write_cnt) as u32))
+            i += cnt;
         }
+        let alloc_end = i;
+        let mut i = start_block;
+        while i < alloc_end {
+            let cnt = inode.contiguous_run(&mut self.block_mgr, i, alloc_end)?;
+            let id = inode.data_block(&mut self.block_mgr, i)?;
+            self.write_data(inode, id, &data[*write_cnt .. *write_cnt + cnt * BLOCK_SIZE])?;
+            *write_cnt += cnt * BLOCK_SIZE;
+            inode.set_length(std::cmp::max(inode.length(), (offset + *write_cnt) as u64));
+            i += cnt;
+        }
+        alloc_ret?;
         if end % BLOCK_SIZE != 0 {
-            let mut id = inode.data_block(end_block);
+            let mut id = inode.data_block(&mut self.block_mgr, end_block)?;
             let mut block = if id == 0 {
-                id = self.block_mgr.new_block()?;
+                let goal = self.alloc_goal(inode, end_block)?;
+                id = self.block_mgr.new_block_near(goal)?;
                 inode.set_data_block(&mut self.block_mgr, end_block, id)?;
                 [0; BLOCK_SIZE]
             } else {
                 self.block_mgr.read_block(id)?
             };
-            block[.. end % BLOCK_SIZE].copy_from_slice(&data[write_cnt ..]);
-            self.block_mgr.write_block(id, &block)?;
-            write_cnt += end % BLOCK_SIZE;
-            inode.set_length(std::cmp::max(inode.length(), (offset + write_cnt) as u32))
+            block[.. end % BLOCK_SIZE].copy_from_slice(&data[*write_cnt ..]);
+            self.write_data(inode, id, &block)?;
+            *write_cnt += end % BLOCK_SIZE;
+            inode.set_length(std::cmp::max(inode.length(), (offset + *write_cnt) as u64))
+        }
+        assert_eq!(*write_cnt, data.len());
+        Ok(())
+    }
+
+    /// Write the data blocks of `inode` from block `id` on. Those of regular files skip the journal,
+    /// see `BlockMgr::write_data_blocks`, while directories and symlinks are metadata
+    fn write_data(&mut self, inode: &Inode, id: Id, data: &[u8]) -> Result<(), std::io::Error> {
+        match inode.kind() {
+            Ok(fuse::FileType::RegularFile) => self.block_mgr.write_data_blocks(id, data),
+            _ => self.block_mgr.write_blocks(id, data)
         }
-        assert_eq!(write_cnt, data.len());
+    }
 
-        inode.flush(&mut*self.block_mgr)?;
-        Ok(write_cnt)
+    /// Move the inline data of `inode` to a new data block, as it no longer fits in the inode
+    fn unpack_inline(&mut self, inode: &Inode) -> Result<(), std::io::Error> {
+        let length = inode.length() as usize;
+        let mut block = [0; BLOCK_SIZE];
+        block[.. length].copy_from_slice(&inode.read_inline(0, length));
+        let id = match length {
+            0 => 0,
+            _ => self.block_mgr.new_block_near(inode.block_goal())?
+        };
+        if id != 0 {
+            self.write_data(inode, id, &block)?;
+        }
+        inode.set_inline(false);
+        if id != 0 {
+            inode.set_data_block(&mut self.block_mgr, 0, id)?;
+        }
+        Ok(())
+    }
+
+    /// Move the data of `inode` into the inode and free its blocks, as it fits there again
+    fn pack_inline(&mut self, inode: &Inode) -> Result<(), std::io::Error> {
+        let length = inode.length() as usize;
+        let data = self.read_file(inode, 0, length)?;
+        self.truncate_blocks(inode, 0)?; // Frees the index blocks too
+        inode.set_inline(true);
+        inode.write_inline(0, &data);
+        inode.set_length(length as u64);
+        Ok(())
     }
 
     pub fn truncate_file(&mut self, inode: &Inode, length: usize) -> Result<(), std::io::Error> {
+        if length as u64 > inode.max_length() {
+            return Err(std::io::Error::from_raw_os_error(libc::EFBIG))
+        }
+        let free_block_cnt = self.block_mgr.free_block_cnt();
+        let ret = self.truncate_steps(inode, length).and_then(|steps| steps.into_iter().try_for_each(|step| {
+            self.truncate_data(inode, step)?;
+            if step > length {
+                inode.flush(&mut self.block_mgr)?;
+                self.block_mgr.checkpoint()?;
+            }
+            Ok(())
+        }));
+        self.charge_blocks(inode, free_block_cnt);
+        // Same as write_file. The freed blocks reach the bitmap later, after the inode stops pointing
+        // to them
+        let flushed = inode.flush(&mut self.block_mgr);
+        ret?;
+        flushed
+    }
+
+    /// Lengths to cut `inode` down to one by one, ending with `length`. Freeing the blocks of a large
+    /// file at once would outgrow the journal, so in a transaction each step frees TRUNCATE_STEP
+    /// blocks at most, leaving a shorter file which is consistent on its own
+    fn truncate_steps(&mut self, inode: &Inode, length: usize) -> Result<Vec<usize>, std::io::Error> {
+        let mut steps = vec![];
+        if self.block_mgr.in_transaction() && !inode.is_inline() && length < inode.length() as usize {
+            let first_empty_block = length.div_ceil(BLOCK_SIZE);
+            let old_block_cnt = (inode.length() as usize).div_ceil(BLOCK_SIZE);
+            let mut cnt = 0;
+            for (i, _, run) in inode.mapped_runs(&mut self.block_mgr, first_empty_block, old_block_cnt)?.into_iter().rev() {
+                let mut end = i + run;
+                while end > i {
+                    let take = std::cmp::min(end - i, TRUNCATE_STEP - cnt);
+                    end -= take;
+                    cnt += take;
+                    if cnt == TRUNCATE_STEP && end * BLOCK_SIZE > length {
+                        steps.push(end * BLOCK_SIZE);
+                        cnt = 0;
+                    }
+                }
+            }
+        }
+        steps.push(length);
+        Ok(steps)
+    }
+
+    /// Inline data moves out of the inode once it grows past it, and back in once it fits again
+    fn truncate_data(&mut self, inode: &Inode, length: usize) -> Result<(), std::io::Error> {
+        let inline_capacity = inode.inline_capacity();
+        if inode.is_inline() {
+            if length <= inline_capacity {
+                let old_length = inode.length() as usize;
+                if length < old_length {
+                    inode.write_inline(length, &vec![0; old_length - length]); // Read back when extended
+                }
+                inode.set_length(length as u64);
+                return Ok(())
+            }
+            self.unpack_inline(inode)?;
+            self.block_mgr.flush_bitmap()?; // Before the inode points to the new block
+        }
+        self.truncate_blocks(inode, length)?;
+        if inline_capacity > 0 && length <= inline_capacity {
+            self.pack_inline(inode)?;
+        }
+        Ok(())
+    }
+
+    fn truncate_blocks(&mut self, inode: &Inode, length: usize) -> Result<(), std::io::Error> {
         if length < inode.length() as usize {
-            let first_empty_block = (length + BLOCK_SIZE - 1) / BLOCK_SIZE;
-            let old_block_cnt = (inode.length() as usize + BLOCK_SIZE - 1) / BLOCK_SIZE;
-            for i in first_empty_block .. old_block_cnt {
-                let id = inode.data_block(i);
-                if id > 0 {
-                    inode.set_data_block(&mut self.block_mgr, i, 0)?;
-                    self.block_mgr.del_block(id)?;
+            let first_empty_block = length.div_ceil(BLOCK_SIZE);
+            let old_block_cnt = (inode.length() as usize).div_ceil(BLOCK_SIZE);
+            for (i, id, cnt) in inode.mapped_runs(&mut self.block_mgr, first_empty_block, old_block_cnt)? {
+                inode.set_data_blocks(&mut self.block_mgr, i, 0, cnt)?;
+                for k in 0 .. cnt {
+                    self.block_mgr.del_block(id + k as Id)?;
                 }
             }
+            for id in inode.prune_index(&mut self.block_mgr, first_empty_block)? {
+                self.block_mgr.del_block(id)?;
+            }
 
             if length % BLOCK_SIZE != 0 {
-                let id = inode.data_block(length / BLOCK_SIZE);
+                let id = inode.data_block(&mut self.block_mgr, length / BLOCK_SIZE)?;
                 if id > 0 {
                     let mut block = self.block_mgr.read_block(id)?;
-                    for i in length % BLOCK_SIZE .. BLOCK_SIZE {
-                        block[i] = 0;
+                    for byte in block[length % BLOCK_SIZE ..].iter_mut() {
+                        *byte = 0;
                     }
-                    self.block_mgr.write_block(id, &block[..])?;
+                    self.write_data(inode, id, &block)?;
                 }
             }
         }
-        inode.set_length(length as u32);
-        inode.flush(&mut*self.block_mgr)
+        inode.set_length(length as u64);
+        Ok(())
     }
 
     pub fn flush(&mut self, inode: &Inode) -> Result<(), std::io::Error> {
-        inode.flush(&mut*self.block_mgr)
+        inode.flush(&mut self.block_mgr)
+    }
+
+    /// Value of extended attribute `name` of `inode`. Fails with ENODATA if there is none
+    pub fn get_xattr(&mut self, inode: &Inode, name: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        inode.xattrs(&mut self.block_mgr)?.into_iter().find(|xattr| xattr.0 == name).map(|xattr| xattr.1)
+            .ok_or(std::io::Error::from_raw_os_error(libc::ENODATA))
+    }
+
+    /// Names of the extended attributes of `inode`
+    pub fn list_xattrs(&mut self, inode: &Inode) -> Result<Vec<Vec<u8>>, std::io::Error> {
+        Ok(inode.xattrs(&mut self.block_mgr)?.into_iter().map(|xattr| xattr.0).collect())
+    }
+
+    /// Set extended attribute `name` of `inode`. With XATTR_CREATE, fails with EEXIST if it exists, and
+    /// with XATTR_REPLACE, fails with ENODATA if it does not. Fails with ENOTSUP if the volume has no
+    /// room for extended attributes, with E2BIG if the attribute alone would not fit in the xattr
+    /// block, and with ENOSPC if it would not fit along with the others
+    pub fn set_xattr(&mut self, inode: &Inode, name: &[u8], value: &[u8], flags: i32) -> Result<(), std::io::Error> {
+        if !inode.has_xattrs() {
+            return Err(std::io::Error::from_raw_os_error(libc::ENOTSUP))
+        }
+        if name.is_empty() || name.len() > u8::MAX as usize {
+            return Err(std::io::Error::from_raw_os_error(libc::ERANGE))
+        }
+        if encode_xattrs(&[(Vec::from(name), Vec::from(value))]).len() > BLOCK_SIZE {
+            return Err(std::io::Error::from_raw_os_error(libc::E2BIG))
+        }
+        let mut xattrs = inode.xattrs(&mut self.block_mgr)?;
+        match xattrs.iter_mut().find(|xattr| xattr.0 == name) {
+            Some(_) if flags & libc::XATTR_CREATE != 0 => return Err(std::io::Error::from_raw_os_error(libc::EEXIST)),
+            Some(xattr) => xattr.1 = Vec::from(value),
+            None if flags & libc::XATTR_REPLACE != 0 => return Err(std::io::Error::from_raw_os_error(libc::ENODATA)),
+            None => xattrs.push((Vec::from(name), Vec::from(value)))
+        }
+        self.store_xattrs(inode, &xattrs)
+    }
+
+    /// Fails with ENODATA if `inode` has no extended attribute `name`
+    pub fn remove_xattr(&mut self, inode: &Inode, name: &[u8]) -> Result<(), std::io::Error> {
+        let mut xattrs = inode.xattrs(&mut self.block_mgr)?;
+        let len = xattrs.len();
+        xattrs.retain(|xattr| xattr.0 != name);
+        if xattrs.len() == len {
+            return Err(std::io::Error::from_raw_os_error(libc::ENODATA))
+        }
+        self.store_xattrs(inode, &xattrs)
+    }
+
+    /// Keep the extended attributes in the inode if they fit, or in the xattr block otherwise, which
+    /// is charged to the owner of `inode`. Like write_file, a new xattr block is marked in the bitmap
+    /// before the inode points to it, and a freed one after the inode stops
+    fn store_xattrs(&mut self, inode: &Inode, xattrs: &[Xattr]) -> Result<(), std::io::Error> {
+        let data = encode_xattrs(xattrs);
+        if data.len() > BLOCK_SIZE {
+            return Err(std::io::Error::from_raw_os_error(libc::ENOSPC))
+        }
+        let free_block_cnt = self.block_mgr.free_block_cnt();
+        let old_id = inode.xattr_block();
+        if data.len() <= inode.inline_xattr_capacity() {
+            inode.set_inline_xattrs(&data);
+            inode.set_xattr_block(0);
+            inode.flush(&mut self.block_mgr)?;
+            if old_id != 0 {
+                self.block_mgr.del_block(old_id)?;
+            }
+        } else {
+            let id = match old_id {
+                0 => {
+                    if let Some(quota) = &self.quota {
+                        quota.check(&owners(inode.uid(), inode.gid()), 1, 0, block_mgr::super_block::now())?;
+                    }
+                    self.block_mgr.new_block_near(inode.block_goal())?
+                },
+                id => id
+            };
+            let mut block = [0; BLOCK_SIZE];
+            block[.. data.len()].copy_from_slice(&data);
+            let written = self.block_mgr.write_block(id, &block).and_then(|_| self.block_mgr.flush_bitmap());
+            if let Err(err) = written {
+                if old_id == 0 {
+                    self.block_mgr.del_block(id)?;
+                }
+                return Err(err)
+            }
+            inode.set_inline_xattrs(&[]);
+            inode.set_xattr_block(id);
+            inode.flush(&mut self.block_mgr)?;
+        }
+        self.charge_blocks(inode, free_block_cnt);
+        Ok(())
+    }
+
+    /// Where each inode reachable from the root is referred from, as (directory, item index) pairs
+    pub fn inode_refs(&mut self) -> Result<std::collections::HashMap<Id, Vec<(Id, usize)>>, std::io::Error> {
+        let id_size = self.id_size();
+        let mut refs = std::collections::HashMap::new();
+        let mut stack = vec![1];
+        refs.insert(1, vec![]);
+        if self.block_mgr.quota_inode() != 0 {
+            refs.insert(self.block_mgr.quota_inode(), vec![]); // Only referred by the super block
+        }
+        while let Some(id) = stack.pop() {
+            let inode = self.read_inode(id)?;
+            if inode.kind()? != fuse::FileType::Directory {
+                continue
+            }
+            for offset in 0 .. inode.length() as usize / DIR_ITEM_SIZE {
+                let item = self.read_file(&inode, offset * DIR_ITEM_SIZE, id_size)?;
+                let ino = decode_id(&item);
+                refs.entry(ino).or_insert_with(|| {
+                    stack.push(ino);
+                    vec![]
+                }).push((id, offset));
+            }
+        }
+        Ok(refs)
+    }
+
+    /// Problems found on the volume: every inode reachable from the root must be allocated and only
+    /// point to allocated blocks, no block may be used twice, every allocated block and inode must be
+    /// in use, and the free block count must match the bitmap
+    #[allow(dead_code)] // Only used by rfs-replay
+    pub fn check(&mut self) -> Result<Vec<String>, std::io::Error> {
+        let id_size = self.id_size();
+        let mut problems = vec![];
+        let mut reachable = std::collections::BTreeSet::new();
+        let mut users = std::collections::BTreeMap::new(); // Inode using each block
+        let mut stack = vec![1];
+        if self.block_mgr.quota_inode() != 0 {
+            stack.push(self.block_mgr.quota_inode()); // Only referred by the super block
+        }
+        while let Some(id) = stack.pop() {
+            if !reachable.insert(id) {
+                continue // Hard link
+            }
+            if !self.block_mgr.is_inode_allocated(id) {
+                problems.push(format!("inode {} is referred but free", id));
+                continue
+            }
+            let inode = self.read_inode(id)?;
+            let kind = match inode.kind() {
+                Ok(kind) => kind,
+                Err(_) => {
+                    problems.push(format!("inode {} has an invalid type", id));
+                    continue
+                }
+            };
+            // Check the index trees before reading the data blocks through them
+            if let Some(root) = inode.index_roots().into_iter().find(|&root| !self.block_mgr.is_allocated(root)) {
+                problems.push(format!("inode {} points to free index block {}", id, root));
+                continue
+            }
+            let mut blocks = match self.inode_blocks(&inode) {
+                Ok(blocks) => blocks,
+                Err(err) => {
+                    problems.push(format!("inode {} cannot be read: {}", id, err));
+                    continue
+                }
+            };
+            if !self.block_mgr.has_inode_table() {
+                blocks.push(id);
+            }
+            for block in blocks {
+                if !self.block_mgr.is_allocated(block) {
+                    problems.push(format!("inode {} points to free block {}", id, block));
+                }
+                if let Some(other) = users.insert(block, id) {
+                    problems.push(format!("block {} is used by both inode {} and inode {}", block, other, id));
+                }
+            }
+            if kind == fuse::FileType::Directory {
+                for offset in 0 .. inode.length() as usize / DIR_ITEM_SIZE {
+                    let item = self.read_file(&inode, offset * DIR_ITEM_SIZE, id_size)?;
+                    stack.push(decode_id(&item));
+                }
+            }
+        }
+
+        // The other way round
+        for block in 1 ..= self.block_cnt() as Id {
+            if self.block_mgr.is_allocated(block) && !users.contains_key(&block) {
+                problems.push(format!("block {} is allocated but not used", block));
+            }
+        }
+        if self.block_mgr.has_inode_table() {
+            for id in 1 ..= self.block_mgr.super_block().inode_table_size as Id {
+                if self.block_mgr.is_inode_allocated(id) && !reachable.contains(&id) {
+                    problems.push(format!("inode {} is allocated but not reachable", id));
+                }
+            }
+        }
+        if self.free_block_cnt() + users.len() != self.block_cnt() {
+            problems.push(format!("{} free blocks counted, but {} of {} blocks are used",
+                                  self.free_block_cnt(), users.len(), self.block_cnt()));
+        }
+        if let Some(inode_cnt) = self.inode_cnt() {
+            if inode_cnt as usize != reachable.len() {
+                problems.push(format!("{} inodes counted, but {} are reachable", inode_cnt, reachable.len()));
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Index, xattr and data blocks of `inode`
+    fn inode_blocks(&mut self, inode: &Inode) -> Result<Vec<Id>, std::io::Error> {
+        let mut blocks = inode.index_blocks(&mut self.block_mgr)?;
+        if inode.xattr_block() != 0 {
+            blocks.push(inode.xattr_block());
+        }
+        if !inode.is_inline() {
+            let end = (inode.length() as usize).div_ceil(BLOCK_SIZE);
+            for (_, first, cnt) in inode.mapped_runs(&mut self.block_mgr, 0, end)? {
+                blocks.extend(first .. first + cnt as Id);
+            }
+        }
+        Ok(blocks)
+    }
+
+    /// Change the number of data blocks of the volume. Shrinking moves the data blocks out of the
+    /// blocks being removed first, and the inodes too if `move_inodes`. Inode ids are handed out to
+    /// the kernel while mounted, so inodes must not be moved then: fails with EBUSY instead if any is
+    /// in the way. Fails with ENOSPC if the used blocks do not fit. In a transaction, what is moved is
+    /// committed step by step, each leaving the files consistent
+    pub fn resize(&mut self, block_cnt: usize, move_inodes: bool) -> Result<(), std::io::Error> {
+        if block_cnt >= self.block_cnt() {
+            return self.block_mgr.resize(block_cnt)
+        }
+        self.block_mgr.limit_alloc(Some(block_cnt))?;
+        let ret = self.evacuate(block_cnt, move_inodes).and_then(|_| self.block_mgr.resize(block_cnt));
+        if ret.is_err() {
+            self.block_mgr.limit_alloc(None)?;
+        }
+        ret
+    }
+
+    /// Move everything reachable from the root out of the blocks after `block_cnt`
+    fn evacuate(&mut self, block_cnt: usize, move_inodes: bool) -> Result<(), std::io::Error> {
+        let refs = self.inode_refs()?;
+        for &id in refs.keys() {
+            let inode = self.read_inode(id)?;
+            self.relocate_blocks(&inode, block_cnt)?;
+        }
+        if self.block_mgr.has_inode_table() {
+            return Ok(()) // Inodes are not in the data blocks
+        }
+        let mut tail: Vec<Id> = refs.keys().cloned().filter(|&id| id as usize > block_cnt).collect();
+        if tail.is_empty() {
+            return Ok(())
+        }
+        if !move_inodes {
+            return Err(std::io::Error::from_raw_os_error(libc::EBUSY))
+        }
+        tail.sort_unstable();
+        let mut moved = std::collections::HashMap::new();
+        for id in tail {
+            self.move_inode(id, &refs[&id], &mut moved)?;
+            self.block_mgr.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    /// Copy the data blocks and the index blocks of `inode` after `block_cnt` to new blocks before it,
+    /// TRUNCATE_STEP data blocks at a time so a large file does not outgrow the journal
+    fn relocate_blocks(&mut self, inode: &Inode, block_cnt: usize) -> Result<(), std::io::Error> {
+        let old_ids = inode.relocate_index(&mut self.block_mgr, block_cnt)?;
+        self.replace_blocks(inode, old_ids)?;
+        let end = (inode.length() as usize).div_ceil(BLOCK_SIZE);
+        let tail: Vec<(usize, Id)> = inode.mapped_runs(&mut self.block_mgr, 0, end)?.into_iter()
+            .flat_map(|(i, id, cnt)| (0 .. cnt).map(move |k| (i + k, id + k as Id)))
+            .filter(|&(_, id)| id as usize > block_cnt).collect();
+        for step in tail.chunks(TRUNCATE_STEP) {
+            let mut old_ids = vec![];
+            for &(i, id) in step {
+                let goal = self.alloc_goal(inode, i)?;
+                let new_id = self.block_mgr.new_block_near(goal)?;
+                let block = self.block_mgr.read_block(id)?;
+                self.write_data(inode, new_id, &block)?;
+                inode.set_data_block(&mut self.block_mgr, i, new_id)?;
+                old_ids.push(id);
+            }
+            self.replace_blocks(inode, old_ids)?;
+        }
+        Ok(())
+    }
+
+    /// Free `old_ids`, which `inode` no longer points to in memory, in the same order as write_file
+    /// and truncate_file. Then commit what is moved so far if the transaction is getting full
+    fn replace_blocks(&mut self, inode: &Inode, old_ids: Vec<Id>) -> Result<(), std::io::Error> {
+        self.block_mgr.flush_bitmap()?;
+        inode.flush(&mut self.block_mgr)?;
+        for id in old_ids {
+            self.block_mgr.del_block(id)?;
+        }
+        self.block_mgr.checkpoint()
+    }
+
+    /// Copy inode `id` to a new block and point the directory items in `refs` to it. `moved` maps the
+    /// inodes moved before to their new ids, as the directories in `refs` may be among them
+    fn move_inode(&mut self, id: Id, refs: &[(Id, usize)], moved: &mut std::collections::HashMap<Id, Id>)
+                  -> Result<(), std::io::Error> {
+        let new_id = self.block_mgr.new_block()?;
+        let block = self.block_mgr.read_block(id)?;
+        self.block_mgr.write_block(new_id, &block)?;
+        self.block_mgr.del_block(id)?;
+        self.inode_table.remove(&id);
+        moved.insert(id, new_id);
+        if id == self.block_mgr.quota_inode() {
+            self.block_mgr.set_quota_inode(new_id); // The old block stays intact until the super block is written
+        }
+        let mut item = vec![0; self.id_size()];
+        encode_id(&mut item, new_id);
+        for &(dir, offset) in refs {
+            let dir = self.read_inode(*moved.get(&dir).unwrap_or(&dir))?;
+            self.write_file(&dir, offset * DIR_ITEM_SIZE, &item)?;
+        }
+        Ok(())
+    }
+
+    /// Start a transaction, so everything written until `commit` reaches the storage atomically
+    pub fn begin(&mut self) {
+        self.block_mgr.begin()
+    }
+
+    pub fn commit(&mut self) -> Result<(), std::io::Error> {
+        self.block_mgr.commit()
+    }
+
+    /// End a transaction for an operation which failed half way, undoing what it has done instead of
+    /// committing it, see `BlockMgr::abort`. The open inodes and the quota usage are read again then
+    pub fn abort(&mut self) -> Result<(), std::io::Error> {
+        if !self.block_mgr.abort()? {
+            return Ok(())
+        }
+        for inode in self.inode_table.values().filter_map(|inode| inode.upgrade()) {
+            inode.reload(&mut self.block_mgr)?;
+        }
+        if let Some(mut quota) = self.quota.take() {
+            let counted = self.count_usage(&mut quota);
+            self.quota = Some(quota);
+            counted?;
+        }
+        Ok(())
+    }
+
+    /// Discard all free blocks, so the storage can release their space. Returns how many are discarded
+    pub fn trim(&mut self) -> Result<usize, std::io::Error> {
+        self.block_mgr.trim()
+    }
+
+    /// Flush everything down to the persistent storage
+    pub fn sync(&mut self) -> Result<(), std::io::Error> {
+        self.save_quota()?;
+        self.block_mgr.sync()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use block_io::FakeMemBlockIO;
+    use block_io::{BlockIO, FakeMemBlockIO, FileBlockIO, ImageBlockIO, ChecksumBlockIO};
+    use block_io::fault_block_io::{FaultBlockIO, FaultPlan};
+    use block_mgr::{decode_id, encode_id};
 
     fn init() -> Result<Box<FileMgr>, std::io::Error> {
         let block_io = Box::new(FakeMemBlockIO::new());
@@ -239,6 +1012,27 @@ mod tests {
         Ok(inode_mgr)
     }
 
+    fn init_with(block_io: Box<dyn BlockIO>) -> Result<Box<FileMgr>, std::io::Error> {
+        let mut inode_mgr = Box::new(FileMgr::new(Box::new(BlockMgr::new(block_io))));
+        let need_format = !inode_mgr.is_formatted()?;
+        inode_mgr.init(need_format)?;
+        Ok(inode_mgr)
+    }
+
+    fn init_faulty(block_io: Box<dyn BlockIO>)
+                   -> Result<(Box<FileMgr>, std::rc::Rc<std::cell::RefCell<FaultPlan>>), std::io::Error> {
+        let fault_block_io = FaultBlockIO::new(block_io);
+        let plan = fault_block_io.plan();
+        Ok((init_with(Box::new(fault_block_io))?, plan))
+    }
+
+    fn temp_image(name: &str) -> std::path::PathBuf {
+        let mut path = std::env::temp_dir();
+        path.push(format!("rfs-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
     #[test]
     fn test_write_inside_1_block() -> Result<(), std::io::Error> {
         let mut inode_mgr = init()?;
@@ -329,6 +1123,26 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_free_counts() -> Result<(), std::io::Error> {
+        let mut inode_mgr = init()?;
+        let free_block_cnt = inode_mgr.free_block_cnt();
+        let free_inode_cnt = inode_mgr.free_inode_cnt();
+        assert_eq!(inode_mgr.inode_cnt(), Some(1));
+        let inode = inode_mgr.new_inode()?;
+        inode_mgr.write_file(&inode, 0, &[1; 3 * BLOCK_SIZE])?;
+        assert_eq!(inode_mgr.inode_cnt(), Some(2));
+        assert_eq!(inode_mgr.free_block_cnt(), free_block_cnt - 3); // The inode is in the inode table
+        assert_eq!(inode_mgr.free_inode_cnt(), free_inode_cnt - 1);
+        inode_mgr.truncate_file(&inode, 0)?;
+        inode_mgr.flush(&inode)?;
+        inode_mgr.del_inode(&inode)?;
+        assert_eq!(inode_mgr.inode_cnt(), Some(1));
+        assert_eq!(inode_mgr.free_block_cnt(), free_block_cnt);
+        assert_eq!(inode_mgr.free_inode_cnt(), free_inode_cnt);
+        Ok(())
+    }
+
     #[test]
     fn test_share_inode() -> Result<(), std::io::Error> {
         let mut inode_mgr = init()?;
@@ -356,5 +1170,803 @@ mod tests {
         assert_eq!(file_read, file);
         Ok(())
     }
-}
 
+    #[test]
+    fn test_failed_write() -> Result<(), std::io::Error> {
+        let (mut inode_mgr, plan) = init_faulty(Box::new(FakeMemBlockIO::new()))?;
+        let file = vec![7; 10000];
+        let inode = inode_mgr.read_root_inode()?;
+        inode_mgr.write_file(&inode, 0, &file[..])?;
+
+        plan.borrow_mut().fail_write(1, libc::EIO);
+        let err = inode_mgr.write_file(&inode, 0, &[8; 5000]).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        assert_eq!(inode.length(), 10000);
+        assert_eq!(inode_mgr.read_file(&inode, 0, 10000)?, file);
+
+        // Growing the file fails half way. Only what has been written is visible
+        plan.borrow_mut().fail_write(3, libc::ENOSPC);
+        let write_cnt = inode_mgr.write_file(&inode, 10000, &[9; 3 * BLOCK_SIZE])?;
+        assert!(write_cnt > 0 && write_cnt < 3 * BLOCK_SIZE);
+        let length = inode.length() as usize;
+        assert_eq!(length, 10000 + write_cnt);
+        let file_read = inode_mgr.read_file(&inode, 0, length)?;
+        assert_eq!(file_read[.. 10000], file[..]);
+        assert!(file_read[10000 ..].iter().all(|&byte| byte == 9));
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_bitmap_write() -> Result<(), std::io::Error> {
+        let (mut inode_mgr, plan) = init_faulty(Box::new(FakeMemBlockIO::new()))?;
+        let inode = inode_mgr.new_inode()?;
+        inode_mgr.write_file(&inode, 0, &[7; BLOCK_SIZE])?;
+
+        // The data block is written, then the bitmap fails. The inode must not point to a block
+        // that is free on disk
+        plan.borrow_mut().fail_write(2, libc::EIO);
+        let write_cnt = plan.borrow().write_cnt();
+        let err = inode_mgr.write_file(&inode, BLOCK_SIZE, &[8; BLOCK_SIZE]).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        assert_eq!(plan.borrow().write_cnt(), write_cnt + 2);
+
+        // Retrying flushes the bitmap, then the inode
+        inode_mgr.write_file(&inode, BLOCK_SIZE, &[8; BLOCK_SIZE])?;
+        assert_eq!(plan.borrow().write_cnt(), write_cnt + 5);
+        assert_eq!(inode_mgr.read_file(&inode, BLOCK_SIZE, BLOCK_SIZE)?, [8; BLOCK_SIZE]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_read() -> Result<(), std::io::Error> {
+        let (mut inode_mgr, plan) = init_faulty(Box::new(FakeMemBlockIO::new()))?;
+        let inode = inode_mgr.read_root_inode()?;
+        inode_mgr.write_file(&inode, 0, &[1; 10000])?;
+        plan.borrow_mut().fail_read(2, libc::EIO);
+        let err = inode_mgr.read_file(&inode, 0, 10000).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        assert_eq!(inode_mgr.read_file(&inode, 0, 10000)?, vec![1; 10000]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_inline_data() -> Result<(), std::io::Error> {
+        let path = temp_image("inline");
+        let mut file = vec![];
+        for i in 0 .. 2 * BLOCK_SIZE {
+            file.push((i % 251) as u8)
+        }
+        {
+            let mut inode_mgr = init_with(Box::new(ImageBlockIO::new(path.clone(), 0)?))?;
+            let free_block_cnt = inode_mgr.free_block_cnt();
+            let inode = inode_mgr.new_inode()?;
+            let capacity = inode.inline_capacity(); // Whatever the inline extended attributes leave
+            assert!(capacity > 100);
+            inode_mgr.write_file(&inode, 0, &file[.. 20])?;
+            inode_mgr.write_file(&inode, 100, &file[100 .. capacity])?;
+            assert!(inode.is_inline());
+            assert_eq!(inode_mgr.free_block_cnt(), free_block_cnt);
+            assert_eq!(inode_mgr.read_file(&inode, 10, 999)?[..], [&file[10 .. 20], &[0; 80], &file[100 .. capacity]].concat()[..]);
+
+            // Outgrows the inode
+            inode_mgr.write_file(&inode, 20, &file[20 .. 100])?;
+            inode_mgr.write_file(&inode, capacity, &file[capacity ..])?;
+            assert!(!inode.is_inline());
+            assert_eq!(inode_mgr.free_block_cnt(), free_block_cnt - 2);
+            assert_eq!(inode_mgr.read_file(&inode, 0, 999999)?, file);
+
+            // Fits again
+            inode_mgr.truncate_file(&inode, 30)?;
+            assert!(inode.is_inline());
+            assert_eq!(inode_mgr.free_block_cnt(), free_block_cnt);
+            inode_mgr.truncate_file(&inode, 60)?;
+            assert_eq!(inode_mgr.read_file(&inode, 0, 999)?[..], [&file[.. 30], &[0; 30][..]].concat()[..]);
+            inode_mgr.truncate_file(&inode, BLOCK_SIZE + 1)?;
+            assert!(!inode.is_inline());
+            assert_eq!(inode_mgr.read_file(&inode, 25, 10)?[..], [&file[25 .. 30], &[0; 5][..]].concat()[..]);
+            inode_mgr.truncate_file(&inode, 60)?;
+            inode_mgr.write_file(&inode, 30, &file[30 .. 60])?;
+            let root = inode_mgr.read_root_inode()?;
+            inode_mgr.write_file(&root, 0, &inode.id().to_le_bytes())?;
+            inode_mgr.sync()?;
+        }
+        let mut inode_mgr = init_with(Box::new(ImageBlockIO::new(path.clone(), 0)?))?;
+        let root = inode_mgr.read_root_inode()?;
+        let id = decode_id(&inode_mgr.read_file(&root, 0, 4)?);
+        let inode = inode_mgr.read_inode(id)?;
+        assert!(inode.is_inline());
+        assert_eq!(inode_mgr.read_file(&inode, 0, 999)?, file[.. 60]);
+        std::fs::remove_file(&path)
+    }
+
+    #[test]
+    fn test_large_file() -> Result<(), std::io::Error> {
+        let mut inode_mgr = init()?;
+        let free_block_cnt = inode_mgr.free_block_cnt();
+        let inode = inode_mgr.new_inode()?;
+        let offset = 5 << 30; // Mapped by the triple indirect block
+        inode_mgr.write_file(&inode, offset, &[1; 100])?;
+        inode_mgr.write_file(&inode, 3 << 30, &[2; 100])?; // By the double indirect block
+        assert_eq!(inode.length(), (5 << 30) + 100);
+        assert_eq!(inode.index_blocks(&mut inode_mgr.block_mgr)?.len(), 3 + 2);
+        assert_eq!(inode_mgr.free_block_cnt(), free_block_cnt - 2 - 5);
+        assert_eq!(inode_mgr.read_file(&inode, offset - 100, 1000)?[..], [[0; 100], [1; 100]].concat()[..]);
+        let max_length = inode.max_length() as usize;
+        assert_eq!(inode_mgr.write_file(&inode, max_length, &[1]).unwrap_err().raw_os_error(), Some(libc::EFBIG));
+
+        // The triple indirect tree goes, the double indirect one stays
+        inode_mgr.truncate_file(&inode, 4 << 30)?;
+        assert_eq!(inode_mgr.free_block_cnt(), free_block_cnt - 1 - 2);
+        assert_eq!(inode_mgr.read_file(&inode, 3 << 30, 100)?, vec![2; 100]);
+        inode_mgr.truncate_file(&inode, 0)?;
+        inode_mgr.del_inode(&inode)?;
+        inode_mgr.sync()?;
+        assert_eq!(inode_mgr.free_block_cnt(), free_block_cnt);
+        Ok(())
+    }
+
+    #[test]
+    fn test_extents() -> Result<(), std::io::Error> {
+        let path = temp_image("extents");
+        let scattered: Vec<usize> = (0 .. 400).map(|i| 10 + 2 * i).collect(); // Single blocks after holes
+        let free_block_cnt;
+        {
+            let mut inode_mgr = init_with(Box::new(ImageBlockIO::new(path.clone(), 0)?))?;
+            free_block_cnt = inode_mgr.free_block_cnt();
+            let inode = inode_mgr.new_inode()?;
+            inode.set_mode(libc::S_IFREG as u16 | 0o644);
+            // Appended blocks are allocated one after another, so they merge into one extent
+            for i in 0 .. 3 {
+                inode_mgr.write_file(&inode, i * BLOCK_SIZE, &[i as u8 + 1; BLOCK_SIZE])?;
+            }
+            assert!(inode.has_extents());
+            let runs = inode.mapped_runs(&mut inode_mgr.block_mgr, 0, 3)?;
+            assert_eq!((runs.len(), runs[0].2), (1, 3));
+            assert!(inode.index_blocks(&mut inode_mgr.block_mgr)?.is_empty());
+            assert_eq!(inode.max_length(), 1 << 44);
+
+            for &index in scattered.iter() {
+                inode_mgr.write_file(&inode, index * BLOCK_SIZE, &(index as u32).to_le_bytes())?;
+            }
+            assert_eq!(inode.mapped_runs(&mut inode_mgr.block_mgr, 0, usize::MAX)?.len(), 1 + 400);
+            assert_eq!(inode.index_blocks(&mut inode_mgr.block_mgr)?.len(), 2); // Two leaves under the root
+            assert_eq!(inode_mgr.free_block_cnt(), free_block_cnt - 3 - 400 - 2);
+            let root = inode_mgr.read_root_inode()?;
+            inode_mgr.write_file(&root, 0, &inode.id().to_le_bytes())?;
+            inode_mgr.sync()?;
+        }
+        let mut inode_mgr = init_with(Box::new(ImageBlockIO::new(path.clone(), 0)?))?;
+        let root = inode_mgr.read_root_inode()?;
+        let id = decode_id(&inode_mgr.read_file(&root, 0, 4)?);
+        let inode = inode_mgr.read_inode(id)?;
+        assert_eq!(inode_mgr.read_file(&inode, 0, 3 * BLOCK_SIZE)?, [[1; BLOCK_SIZE], [2; BLOCK_SIZE], [3; BLOCK_SIZE]].concat());
+        for &index in scattered.iter() {
+            assert_eq!(inode_mgr.read_file(&inode, index * BLOCK_SIZE, 4)?, (index as u32).to_le_bytes());
+        }
+        assert_eq!(inode.contiguous_run(&mut inode_mgr.block_mgr, 1, 10)?, 2);
+        assert_eq!(inode.contiguous_run(&mut inode_mgr.block_mgr, 3, 10)?, 7); // The hole
+
+        // Few enough extents are left to fit in the inode
+        inode_mgr.truncate_file(&inode, 20 * BLOCK_SIZE)?;
+        assert!(inode.index_blocks(&mut inode_mgr.block_mgr)?.is_empty());
+        assert_eq!(inode_mgr.free_block_cnt(), free_block_cnt - 3 - 5);
+        assert_eq!(inode_mgr.read_file(&inode, 18 * BLOCK_SIZE, 4)?, 18u32.to_le_bytes());
+        inode_mgr.truncate_file(&inode, 0)?;
+        inode_mgr.del_inode(&inode)?;
+        inode_mgr.sync()?;
+        assert_eq!(inode_mgr.free_block_cnt(), free_block_cnt);
+        std::fs::remove_file(&path)
+    }
+
+    #[test]
+    fn test_extent_tree_updates() -> Result<(), std::io::Error> {
+        let path = temp_image("extent-tree");
+        let leaves;
+        {
+            let (mut inode_mgr, plan) = init_faulty(Box::new(ImageBlockIO::new(path.clone(), 0)?))?;
+            let inode = inode_mgr.new_inode()?;
+            inode.set_mode(libc::S_IFREG as u16 | 0o644);
+            for i in 0 .. 1000 {
+                inode_mgr.write_file(&inode, (10 + 2 * i) * BLOCK_SIZE, &[1])?; // Single blocks after holes
+            }
+            assert_eq!(inode.index_blocks(&mut inode_mgr.block_mgr)?.len(), 3); // Appended leaves are filled
+            // An extent near the front only splits the first leaf: the data block, the bitmap, both
+            // halves of the leaf and the inode are written
+            let write_cnt = plan.borrow().write_cnt();
+            inode_mgr.write_file(&inode, 11 * BLOCK_SIZE, &[2])?;
+            assert_eq!(plan.borrow().write_cnt(), write_cnt + 5);
+            leaves = inode.index_blocks(&mut inode_mgr.block_mgr)?;
+            assert_eq!(leaves.len(), 4);
+            let root = inode_mgr.read_root_inode()?;
+            inode_mgr.write_file(&root, 0, &inode.id().to_le_bytes())?;
+            inode_mgr.sync()?;
+        }
+        let read_first = |corrupt: &dyn Fn(&mut [u8; BLOCK_SIZE])| -> Result<Vec<u8>, std::io::Error> {
+            let mut inode_mgr = init_with(Box::new(ImageBlockIO::new(path.clone(), 0)?))?;
+            let mut leaf = inode_mgr.block_mgr.read_block(leaves[0])?;
+            corrupt(&mut leaf);
+            inode_mgr.block_mgr.write_block(leaves[0], &leaf)?;
+            let root = inode_mgr.read_root_inode()?;
+            let id = decode_id(&inode_mgr.read_file(&root, 0, 4)?);
+            let inode = inode_mgr.read_inode(id)?;
+            inode_mgr.read_file(&inode, 10 * BLOCK_SIZE, 2 * BLOCK_SIZE + 1)
+        };
+        let mut expected = vec![0; 2 * BLOCK_SIZE + 1];
+        (expected[0], expected[BLOCK_SIZE], expected[2 * BLOCK_SIZE]) = (1, 2, 1);
+        assert_eq!(read_first(&|_| ())?, expected);
+        // Overlapping extents, then a leaf claiming to be an index node
+        let err = read_first(&|leaf| leaf[8 + 12 .. 8 + 16].copy_from_slice(&10u32.to_le_bytes())).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        let err = read_first(&|leaf| leaf[4 .. 8].copy_from_slice(&1u32.to_le_bytes())).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        std::fs::remove_file(&path)
+    }
+
+    #[test]
+    fn test_xattrs() -> Result<(), std::io::Error> {
+        let path = temp_image("xattrs");
+        let big = vec![7; 1000];
+        let free_block_cnt;
+        {
+            let mut inode_mgr = init_with(Box::new(ImageBlockIO::new(path.clone(), 0)?))?;
+            free_block_cnt = inode_mgr.free_block_cnt();
+            let inode = inode_mgr.new_inode()?;
+            inode_mgr.set_xattr(&inode, b"user.a", b"1", 0)?;
+            assert_eq!(inode.xattr_block(), 0); // Small enough to stay in the inode
+            assert_eq!(inode_mgr.get_xattr(&inode, b"user.a")?, b"1");
+            let err = inode_mgr.set_xattr(&inode, b"user.a", b"2", libc::XATTR_CREATE).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EEXIST));
+            let err = inode_mgr.set_xattr(&inode, b"user.b", b"2", libc::XATTR_REPLACE).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::ENODATA));
+            inode_mgr.set_xattr(&inode, b"user.a", b"2", libc::XATTR_REPLACE)?;
+
+            inode_mgr.set_xattr(&inode, b"user.big", &big, libc::XATTR_CREATE)?;
+            assert_ne!(inode.xattr_block(), 0);
+            assert_eq!(inode_mgr.free_block_cnt(), free_block_cnt - 1);
+            let err = inode_mgr.set_xattr(&inode, b"user.huge", &[0; BLOCK_SIZE], 0).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::E2BIG));
+            let err = inode_mgr.set_xattr(&inode, b"user.more", &vec![0; BLOCK_SIZE - 1000], 0).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::ENOSPC));
+            let root = inode_mgr.read_root_inode()?;
+            inode_mgr.write_file(&root, 0, &inode.id().to_le_bytes())?;
+            inode_mgr.sync()?;
+        }
+        let mut inode_mgr = init_with(Box::new(ImageBlockIO::new(path.clone(), 0)?))?;
+        let root = inode_mgr.read_root_inode()?;
+        let id = decode_id(&inode_mgr.read_file(&root, 0, 4)?);
+        let inode = inode_mgr.read_inode(id)?;
+        assert_eq!(inode_mgr.list_xattrs(&inode)?, vec![b"user.a".to_vec(), b"user.big".to_vec()]);
+        assert_eq!(inode_mgr.get_xattr(&inode, b"user.a")?, b"2");
+        assert_eq!(inode_mgr.get_xattr(&inode, b"user.big")?, big);
+        assert_eq!(inode_mgr.get_xattr(&inode, b"user.b").unwrap_err().raw_os_error(), Some(libc::ENODATA));
+
+        // Back in the inode once small enough
+        inode_mgr.remove_xattr(&inode, b"user.big")?;
+        assert_eq!(inode.xattr_block(), 0);
+        inode_mgr.sync()?;
+        assert_eq!(inode_mgr.free_block_cnt(), free_block_cnt);
+        assert_eq!(inode_mgr.remove_xattr(&inode, b"user.big").unwrap_err().raw_os_error(), Some(libc::ENODATA));
+        inode_mgr.set_xattr(&inode, b"user.big", &big, 0)?;
+        inode_mgr.del_inode(&inode)?;
+        inode_mgr.sync()?;
+        assert_eq!(inode_mgr.free_block_cnt(), free_block_cnt);
+        std::fs::remove_file(&path)
+    }
+
+    #[test]
+    fn test_shrink() -> Result<(), std::io::Error> {
+        let path = temp_image("shrink");
+        let id_size = std::mem::size_of::<Id>();
+        // Single blocks after holes, each an extent of its own, so the extents need a tree block
+        let scattered: Vec<usize> = (0 .. 16).map(|i| (11 + 2 * i) * BLOCK_SIZE).collect();
+        let mut file = vec![];
+        for i in 0 .. 10 * BLOCK_SIZE {
+            file.push((i % 251) as u8)
+        }
+        {
+            let block_mgr = BlockMgr::with_block_cnt(Box::new(ImageBlockIO::new(path.clone(), 0)?), 64);
+            let mut inode_mgr = Box::new(FileMgr::new(Box::new(block_mgr)));
+            inode_mgr.init(true)?;
+            inode_mgr.resize(256, true)?;
+            let root = inode_mgr.read_root_inode()?;
+            root.set_mode(libc::S_IFDIR as u16 | 0o777);
+
+            // Push the file to the blocks being removed
+            let filler = inode_mgr.new_inode()?;
+            inode_mgr.write_file(&filler, 0, &[0; 100 * BLOCK_SIZE])?;
+            let inode = inode_mgr.new_inode()?;
+            inode.set_mode(libc::S_IFREG as u16 | 0o644);
+            inode_mgr.write_file(&inode, 0, &file[..])?;
+            for &offset in scattered.iter() {
+                inode_mgr.write_file(&inode, offset, &file[.. BLOCK_SIZE])?;
+            }
+            assert!(inode.has_extents());
+            assert!(inode.data_block(&mut inode_mgr.block_mgr, 0)? > 64 && inode.index_roots()[0] > 64);
+            let mut items = [0; 3 * DIR_ITEM_SIZE]; // ".", ".." and the file
+            encode_id(&mut items[.. id_size], 1);
+            encode_id(&mut items[DIR_ITEM_SIZE .. DIR_ITEM_SIZE + id_size], 1);
+            encode_id(&mut items[2 * DIR_ITEM_SIZE .. 2 * DIR_ITEM_SIZE + id_size], inode.id());
+            inode_mgr.write_file(&root, 0, &items)?;
+            inode_mgr.truncate_file(&filler, 0)?;
+            inode_mgr.del_inode(&filler)?;
+            inode_mgr.sync()?;
+        }
+        {
+            let mut inode_mgr = init_with(Box::new(ImageBlockIO::new(path.clone(), 0)?))?;
+            // Directory block + 26 data blocks + extent tree block, as inodes are in the inode table
+            assert_eq!(inode_mgr.resize(27, true).unwrap_err().raw_os_error(), Some(libc::ENOSPC));
+            inode_mgr.resize(64, false)?; // No inode is in the way
+            assert_eq!(inode_mgr.free_block_cnt(), 64 - 28);
+        }
+        let mut inode_mgr = init_with(Box::new(ImageBlockIO::new(path.clone(), 0)?))?;
+        assert_eq!(inode_mgr.block_cnt(), 64);
+        let root = inode_mgr.read_root_inode()?;
+        let id = decode_id(&inode_mgr.read_file(&root, 2 * DIR_ITEM_SIZE, id_size)?);
+        assert!(id <= 64);
+        let inode = inode_mgr.read_inode(id)?;
+        assert!(inode.index_roots()[0] <= 64);
+        assert_eq!(inode_mgr.read_file(&inode, 0, file.len())?, file);
+        for &offset in scattered.iter() {
+            assert_eq!(inode_mgr.read_file(&inode, offset, BLOCK_SIZE)?[..], file[.. BLOCK_SIZE]);
+        }
+        std::fs::remove_file(&path)
+    }
+
+    #[test]
+    fn test_shrink_in_transaction() -> Result<(), std::io::Error> {
+        let block_mgr = BlockMgr::with_block_cnt(Box::new(FakeMemBlockIO::new()), 2000);
+        let mut inode_mgr = Box::new(FileMgr::new(Box::new(block_mgr)));
+        inode_mgr.init(true)?;
+        let root = inode_mgr.read_root_inode()?;
+        root.set_mode(libc::S_IFDIR as u16 | 0o777);
+        let filler = inode_mgr.new_inode()?;
+        inode_mgr.write_file(&filler, 0, &vec![0; 1000 * BLOCK_SIZE])?;
+        // Directory blocks are journaled, and more of them than the journal holds are moved
+        let dir = inode_mgr.new_inode()?;
+        dir.set_mode(libc::S_IFDIR as u16 | 0o755);
+        let mut data: Vec<u8> = (0 .. 600 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+        for item in data.chunks_exact_mut(DIR_ITEM_SIZE) {
+            encode_id(&mut item[.. std::mem::size_of::<Id>()], 1); // Items of the root, named anything
+        }
+        inode_mgr.write_file(&dir, 0, &data)?;
+        let mut item = [0; DIR_ITEM_SIZE];
+        encode_id(&mut item[.. std::mem::size_of::<Id>()], dir.id());
+        inode_mgr.write_file(&root, 0, &item)?;
+        inode_mgr.truncate_file(&filler, 0)?;
+        inode_mgr.del_inode(&filler)?;
+
+        inode_mgr.begin();
+        inode_mgr.resize(700, false)?;
+        inode_mgr.commit()?;
+        assert_eq!(inode_mgr.block_cnt(), 700);
+        assert!(dir.data_block(&mut inode_mgr.block_mgr, 599)? <= 700);
+        assert_eq!(inode_mgr.read_file(&dir, 0, data.len())?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resize_with_checksums() -> Result<(), std::io::Error> {
+        type Opened = (Box<FileMgr>, std::rc::Rc<std::cell::RefCell<FaultPlan>>, std::sync::Arc<std::sync::atomic::AtomicU64>);
+        let open = |path: &std::path::PathBuf| -> Result<Opened, std::io::Error> {
+            let mut image = ImageBlockIO::new(path.clone(), 0)?;
+            let device_block_cnt = block_mgr::probe_device_block_cnt(&mut image)?.unwrap_or(block_mgr::device_block_cnt(64));
+            let fault_block_io = FaultBlockIO::new(Box::new(image));
+            let plan = fault_block_io.plan();
+            let checksum_block_io = ChecksumBlockIO::new(Box::new(fault_block_io), device_block_cnt);
+            let corruption_cnt = checksum_block_io.corruption_cnt();
+            let mut inode_mgr = Box::new(FileMgr::new(Box::new(BlockMgr::with_block_cnt(Box::new(checksum_block_io), 64))));
+            let need_format = !inode_mgr.is_formatted()?;
+            inode_mgr.init(need_format)?;
+            Ok((inode_mgr, plan, corruption_cnt))
+        };
+        let file: Vec<u8> = (0 .. 10 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+        let create = |inode_mgr: &mut FileMgr| -> Result<(), std::io::Error> {
+            let root = inode_mgr.read_root_inode()?;
+            root.set_mode(libc::S_IFDIR as u16 | 0o777);
+            let inode = inode_mgr.new_inode()?;
+            inode.set_mode(libc::S_IFREG as u16 | 0o644);
+            inode_mgr.write_file(&inode, 0, &file)?;
+            let mut item = [0; DIR_ITEM_SIZE];
+            encode_id(&mut item[.. std::mem::size_of::<Id>()], inode.id());
+            inode_mgr.write_file(&root, 0, &item)?;
+            inode_mgr.sync()
+        };
+        let check = |inode_mgr: &mut FileMgr| -> Result<(), std::io::Error> {
+            let root = inode_mgr.read_root_inode()?;
+            let id = decode_id(&inode_mgr.read_file(&root, 0, std::mem::size_of::<Id>())?);
+            let inode = inode_mgr.read_inode(id)?;
+            assert_eq!(inode_mgr.read_file(&inode, 0, file.len())?, file);
+            Ok(())
+        };
+        let path = temp_image("resize-checksum");
+        // Cut the power at every write of growing the volume
+        for nth in 1 .. 30 {
+            {
+                let (mut inode_mgr, plan, _) = open(&path)?;
+                create(&mut inode_mgr)?;
+                plan.borrow_mut().power_cut(nth);
+                inode_mgr.resize(256, true)?;
+            }
+            let (mut inode_mgr, _, corruption_cnt) = open(&path)?;
+            assert!(inode_mgr.block_cnt() == 64 || inode_mgr.block_cnt() == 256);
+            check(&mut inode_mgr)?;
+            assert_eq!(corruption_cnt.load(std::sync::atomic::Ordering::Relaxed), 0);
+            std::fs::remove_file(&path)?;
+        }
+
+        {
+            let (mut inode_mgr, _, _) = open(&path)?;
+            inode_mgr.resize(256, true)?;
+            create(&mut inode_mgr)?;
+        }
+        {
+            let (mut inode_mgr, _, _) = open(&path)?;
+            assert_eq!(inode_mgr.block_cnt(), 256);
+            inode_mgr.resize(64, true)?;
+            inode_mgr.sync()?;
+        }
+        let (mut inode_mgr, _, corruption_cnt) = open(&path)?;
+        assert_eq!(inode_mgr.block_cnt(), 64);
+        check(&mut inode_mgr)?;
+        assert_eq!(corruption_cnt.load(std::sync::atomic::Ordering::Relaxed), 0);
+        std::fs::remove_file(&path)
+    }
+
+    #[test]
+    fn test_quota() -> Result<(), std::io::Error> {
+        let path = temp_image("quota");
+        let user = owners(1000, 100);
+        let now = block_mgr::super_block::now();
+        {
+            let mut inode_mgr = init_with(Box::new(ImageBlockIO::new(path.clone(), 0)?))?;
+            let root = inode_mgr.read_root_inode()?;
+            root.set_mode(libc::S_IFDIR as u16 | 0o777);
+            inode_mgr.set_quota(user[0], Limits { soft: 0, hard: 5 }, Limits { soft: 0, hard: 2 })?;
+            let inode = inode_mgr.new_inode_owned_by(1000, 100)?;
+            inode.set_mode(libc::S_IFREG as u16 | 0o644);
+            let mut item = [0; DIR_ITEM_SIZE];
+            encode_id(&mut item[.. std::mem::size_of::<Id>()], inode.id());
+            inode_mgr.write_file(&root, 0, &item)?;
+
+            // Only 5 of the 7 blocks fit
+            assert_eq!(inode_mgr.write_file(&inode, 0, &[1; 7 * BLOCK_SIZE])?, 5 * BLOCK_SIZE);
+            assert_eq!(inode.length() as usize, 5 * BLOCK_SIZE);
+            let err = inode_mgr.write_file(&inode, 5 * BLOCK_SIZE, &[1; BLOCK_SIZE]).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EDQUOT));
+            let other = inode_mgr.new_inode_owned_by(1000, 100)?;
+            assert_eq!(inode_mgr.new_inode_owned_by(1000, 100).err().unwrap().raw_os_error(), Some(libc::EDQUOT));
+            inode_mgr.set_owner(&other, 1000, 200)?; // Moving to another group keeps the user charged
+            inode_mgr.flush(&other)?;
+            inode_mgr.del_inode(&other)?;
+
+            inode_mgr.set_owner(&inode, 0, 0)?;
+            assert_eq!(inode_mgr.quota().unwrap().block_headroom(&user, now), Some(5));
+            inode_mgr.set_owner(&inode, 1000, 100)?;
+            inode_mgr.truncate_file(&inode, BLOCK_SIZE)?;
+            assert_eq!(inode_mgr.quota().unwrap().block_headroom(&user, now), Some(4));
+            inode_mgr.mount()?;
+            inode_mgr.sync()?;
+            inode_mgr.write_file(&inode, BLOCK_SIZE, &[2; 2 * BLOCK_SIZE])?;
+            // Crashed before the usage is saved
+        }
+        let inode_mgr = init_with(Box::new(ImageBlockIO::new(path.clone(), 0)?))?;
+        let quota = inode_mgr.quota().unwrap();
+        assert_eq!(quota.block_headroom(&user, now), Some(2)); // Counted again
+        assert_eq!(quota.inode_headroom(&user, now), Some(1));
+        std::fs::remove_file(&path)
+    }
+
+    #[test]
+    fn test_del_inode() -> Result<(), std::io::Error> {
+        let path = temp_image("del-inode");
+        let user = owners(1000, 100);
+        let now = block_mgr::super_block::now();
+        let mut inode_mgr = init_with(Box::new(ImageBlockIO::new(path.clone(), 0)?))?;
+        let root = inode_mgr.read_root_inode()?;
+        root.set_mode(libc::S_IFDIR as u16 | 0o777);
+        inode_mgr.flush(&root)?;
+        inode_mgr.set_quota(user[0], Limits { soft: 0, hard: 1000 }, Limits { soft: 0, hard: 10 })?;
+        inode_mgr.sync()?;
+        let free_block_cnt = inode_mgr.free_block_cnt();
+        let inode = inode_mgr.new_inode_owned_by(1000, 100)?;
+        inode.set_mode(libc::S_IFREG as u16 | 0o644);
+        inode_mgr.write_file(&inode, 0, &[1; 100 * BLOCK_SIZE])?;
+        inode_mgr.set_xattr(&inode, b"user.big", &[2; BLOCK_SIZE / 2], 0)?;
+        assert!(inode_mgr.free_block_cnt() < free_block_cnt - 100);
+        inode_mgr.del_inode(&inode)?; // Without truncating first
+        inode_mgr.sync()?;
+        assert_eq!(inode_mgr.free_block_cnt(), free_block_cnt);
+        let quota = inode_mgr.quota().unwrap();
+        assert_eq!(quota.block_headroom(&user, now), Some(1000));
+        assert_eq!(quota.inode_headroom(&user, now), Some(10));
+        std::fs::remove_file(&path)
+    }
+
+    #[test]
+    fn test_del_inode_discards() -> Result<(), std::io::Error> {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("rfs-test-del-inode-discards-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut inode_mgr = init_with(Box::new(FileBlockIO::new(dir.clone())?))?;
+        inode_mgr.block_mgr.set_discard_batch(Some(1));
+        let inode = inode_mgr.new_inode()?;
+        inode.set_mode(libc::S_IFREG as u16 | 0o644);
+        inode_mgr.write_file(&inode, 0, &[1; 10 * BLOCK_SIZE])?;
+        inode_mgr.sync()?;
+        let blk_cnt = || -> Result<usize, std::io::Error> {
+            Ok(std::fs::read_dir(&dir)?.filter(|entry| {
+                entry.as_ref().is_ok_and(|entry| entry.file_name().to_string_lossy().starts_with("blk-"))
+            }).count())
+        };
+        let old_blk_cnt = blk_cnt()?;
+        inode_mgr.del_inode(&inode)?;
+        inode_mgr.sync()?;
+        assert!(blk_cnt()? <= old_blk_cnt - 10); // The inode itself may be in the inode table
+        std::fs::remove_dir_all(&dir)
+    }
+
+    #[test]
+    fn test_power_cut() -> Result<(), std::io::Error> {
+        let path = temp_image("power-cut");
+        let mut file = vec![];
+        for i in 0 .. 10000 {
+            file.push((i % 256) as u8)
+        }
+        {
+            let (mut inode_mgr, plan) = init_faulty(Box::new(ImageBlockIO::new(path.clone(), 16)?))?;
+            let inode = inode_mgr.read_root_inode()?;
+            inode_mgr.write_file(&inode, 0, &file[..])?;
+            inode_mgr.sync()?;
+
+            plan.borrow_mut().power_cut(1);
+            inode_mgr.write_file(&inode, 10000, &[1; 10000])?;
+            inode_mgr.truncate_file(&inode, 100)?;
+            inode_mgr.new_inode()?;
+        }
+        let mut inode_mgr = init_with(Box::new(ImageBlockIO::new(path.clone(), 16)?))?;
+        let inode = inode_mgr.read_root_inode()?;
+        assert_eq!(inode.length(), 10000);
+        assert_eq!(inode_mgr.read_file(&inode, 0, 999999)?, file);
+        assert_eq!(inode_mgr.new_inode()?.id(), 2); // Only the root is still allocated
+        std::fs::remove_file(&path)
+    }
+
+    #[test]
+    fn test_power_cut_in_transaction() -> Result<(), std::io::Error> {
+        let path = temp_image("power-cut-transaction");
+        for nth in 1 .. 20 {
+            let (old_id, new_id) = {
+                let (mut inode_mgr, plan) = init_faulty(Box::new(ImageBlockIO::new(path.clone(), 16)?))?;
+                let root = inode_mgr.read_root_inode()?;
+                let old = inode_mgr.new_inode()?;
+                inode_mgr.write_file(&root, 0, &old.id().to_le_bytes())?;
+                inode_mgr.sync()?;
+
+                // Replace the inode referred by the root, like renaming over a file
+                plan.borrow_mut().power_cut(nth);
+                inode_mgr.begin();
+                let new = inode_mgr.new_inode()?;
+                inode_mgr.write_file(&root, 0, &new.id().to_le_bytes())?;
+                inode_mgr.del_inode(&old)?;
+                inode_mgr.commit()?;
+                (old.id(), new.id())
+            };
+            let mut inode_mgr = init_with(Box::new(ImageBlockIO::new(path.clone(), 16)?))?;
+            let root = inode_mgr.read_root_inode()?;
+            let referred = decode_id(&inode_mgr.read_file(&root, 0, 4)?);
+            assert!(referred == old_id || referred == new_id);
+            assert_eq!(inode_mgr.block_mgr.is_inode_allocated(old_id), referred == old_id);
+            assert_eq!(inode_mgr.block_mgr.is_inode_allocated(new_id), referred == new_id);
+            std::fs::remove_file(&path)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_large_transaction() -> Result<(), std::io::Error> {
+        let mut inode_mgr = init()?;
+        let inode = inode_mgr.new_inode()?;
+        inode.set_mode(libc::S_IFREG as u16 | 0o644);
+        inode_mgr.begin();
+        inode_mgr.write_file(&inode, 0, &vec![1; 1000 * BLOCK_SIZE])?; // More than the journal holds
+        inode_mgr.commit()?;
+        let first = inode.data_block(&mut inode_mgr.block_mgr, 0)?;
+
+        inode_mgr.begin();
+        inode_mgr.truncate_file(&inode, BLOCK_SIZE + 1)?;
+        let other = inode_mgr.new_inode()?;
+        inode_mgr.write_file(&other, 0, &[2; 10 * BLOCK_SIZE])?;
+        for i in 0 .. 10 {
+            let id = other.data_block(&mut inode_mgr.block_mgr, i)?;
+            assert!(id < first + 2 || id >= first + 1000); // Freed ones are not reused before committed
+        }
+        inode_mgr.commit()?;
+        let mut expected = vec![1; BLOCK_SIZE + 1];
+        expected.resize(2 * BLOCK_SIZE, 0);
+        inode_mgr.truncate_file(&inode, 2 * BLOCK_SIZE)?;
+        assert_eq!(inode_mgr.read_file(&inode, 0, 2 * BLOCK_SIZE)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_abort() -> Result<(), std::io::Error> {
+        let path = temp_image("abort");
+        let inode_id = {
+            let mut inode_mgr = init_with(Box::new(ImageBlockIO::new(path.clone(), 0)?))?;
+            let root = inode_mgr.read_root_inode()?;
+            inode_mgr.write_file(&root, 0, &[1; 2 * BLOCK_SIZE])?;
+            let free_block_cnt = inode_mgr.free_block_cnt();
+            let free_inode_cnt = inode_mgr.free_inode_cnt();
+
+            inode_mgr.begin();
+            inode_mgr.truncate_file(&root, 100)?;
+            let inode = inode_mgr.new_inode()?;
+            inode.set_mode(libc::S_IFREG as u16 | 0o644);
+            inode_mgr.write_file(&inode, 0, &[2; 3 * BLOCK_SIZE])?;
+            inode_mgr.abort()?;
+            assert_eq!(root.length() as usize, 2 * BLOCK_SIZE);
+            assert_eq!(inode_mgr.free_block_cnt(), free_block_cnt);
+            assert_eq!(inode_mgr.free_inode_cnt(), free_inode_cnt);
+            assert!(!inode_mgr.block_mgr.is_inode_allocated(inode.id()));
+
+            // Nothing of it is left to commit later
+            inode_mgr.begin();
+            inode_mgr.commit()?;
+            inode_mgr.sync()?;
+            inode.id()
+        };
+        let mut inode_mgr = init_with(Box::new(ImageBlockIO::new(path.clone(), 0)?))?;
+        let root = inode_mgr.read_root_inode()?;
+        assert_eq!(inode_mgr.read_file(&root, 0, 3 * BLOCK_SIZE)?, vec![1; 2 * BLOCK_SIZE]);
+        assert!(!inode_mgr.block_mgr.is_inode_allocated(inode_id));
+        std::fs::remove_file(&path)
+    }
+
+    #[test]
+    fn test_torn_write_detected() -> Result<(), std::io::Error> {
+        let block_cnt = block_mgr::device_block_cnt(block_mgr::DEFAULT_BLOCK_CNT);
+        let fault_block_io = FaultBlockIO::new(Box::new(FakeMemBlockIO::new()));
+        let plan = fault_block_io.plan();
+        let mut inode_mgr = init_with(Box::new(ChecksumBlockIO::new(Box::new(fault_block_io), block_cnt)))?;
+        let inode = inode_mgr.read_root_inode()?;
+        inode_mgr.write_file(&inode, 0, &[1; BLOCK_SIZE])?;
+        plan.borrow_mut().tear_write(2, 100); // The data block, after its checksum
+        inode_mgr.write_file(&inode, 0, &[2; BLOCK_SIZE])?;
+        let err = inode_mgr.read_file(&inode, 0, BLOCK_SIZE).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        Ok(())
+    }
+
+    #[test]
+    fn test_interleaved_files() -> Result<(), std::io::Error> {
+        let mut inode_mgr = init()?;
+        let inode_a = inode_mgr.read_root_inode()?;
+        let inode_b = inode_mgr.new_inode()?;
+        let mut file_a = vec![];
+        let mut file_b = vec![];
+        for i in 0 .. 6 {
+            // Each write allocates 2 contiguous blocks, interleaved with the other file
+            let chunk_a = vec![i as u8; 2 * BLOCK_SIZE];
+            let chunk_b = vec![100 + i as u8; 2 * BLOCK_SIZE];
+            inode_mgr.write_file(&inode_a, file_a.len(), &chunk_a)?;
+            inode_mgr.write_file(&inode_b, file_b.len(), &chunk_b)?;
+            file_a.extend(chunk_a);
+            file_b.extend(chunk_b);
+        }
+        inode_mgr.write_file(&inode_a, file_a.len() + 3 * BLOCK_SIZE, &[7; 10])?; // Leave a hole
+        file_a.extend(vec![0; 3 * BLOCK_SIZE]);
+        file_a.extend(vec![7; 10]);
+        assert_eq!(inode_mgr.read_file(&inode_a, 0, file_a.len())?, file_a);
+        assert_eq!(inode_mgr.read_file(&inode_b, 100, file_b.len())?[..], file_b[100 ..]);
+
+        inode_mgr.write_file(&inode_b, 100, &file_a[.. 10 * BLOCK_SIZE])?; // Overwrite across runs
+        file_b[100 .. 100 + 10 * BLOCK_SIZE].copy_from_slice(&file_a[.. 10 * BLOCK_SIZE]);
+        assert_eq!(inode_mgr.read_file(&inode_b, 0, file_b.len())?, file_b);
+        Ok(())
+    }
+
+    #[test]
+    fn test_contiguous_allocation() -> Result<(), std::io::Error> {
+        let mut inode_mgr = init()?;
+        let inode_a = inode_mgr.new_inode()?;
+        let inode_b = inode_mgr.new_inode()?;
+        inode_mgr.write_file(&inode_a, 0, &[1; BLOCK_SIZE])?;
+        inode_mgr.write_file(&inode_b, 0, &[2; BLOCK_SIZE])?;
+        inode_mgr.del_inode(&inode_a)?; // Leave a small gap
+        inode_mgr.write_file(&inode_b, BLOCK_SIZE, &[2; 100 * BLOCK_SIZE])?;
+        assert_eq!(inode_b.contiguous_run(&mut inode_mgr.block_mgr, 1, 101)?, 100);
+        let first = inode_b.data_block(&mut inode_mgr.block_mgr, 0)?;
+        assert_eq!(inode_b.data_block(&mut inode_mgr.block_mgr, 1)?, first + 1);
+        inode_mgr.flush(&inode_a)?;
+        inode_mgr.flush(&inode_b)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_inode_table_pruned() -> Result<(), std::io::Error> {
+        let mut inode_mgr = init()?;
+        let root = inode_mgr.read_root_inode()?;
+        for _ in 0 .. 3 * INODE_TABLE_PRUNE_SIZE {
+            let inode = inode_mgr.new_inode()?;
+            inode_mgr.flush(&inode)?;
+        }
+        assert!(inode_mgr.inode_table.len() <= INODE_TABLE_PRUNE_SIZE);
+        assert!(std::rc::Rc::ptr_eq(&root, &inode_mgr.read_root_inode()?));
+        Ok(())
+    }
+
+    #[test]
+    fn test_check() -> Result<(), std::io::Error> {
+        let mut inode_mgr = init()?;
+        let root = inode_mgr.read_root_inode()?;
+        root.set_mode(libc::S_IFDIR as u16 | 0o755);
+        let file = inode_mgr.new_inode()?;
+        file.set_mode(libc::S_IFREG as u16 | 0o644);
+        inode_mgr.write_file(&file, 0, &[1; 3 * BLOCK_SIZE])?;
+        let mut item = [0; DIR_ITEM_SIZE];
+        encode_id(&mut item[.. inode_mgr.id_size()], file.id());
+        inode_mgr.write_file(&root, 0, &item)?;
+        assert!(inode_mgr.check()?.is_empty());
+
+        // A block nobody uses, a data block freed behind the file's back, and an unreachable inode
+        let leaked = inode_mgr.block_mgr.new_block()?;
+        let data_block = file.data_block(&mut inode_mgr.block_mgr, 1)?;
+        inode_mgr.block_mgr.del_block(data_block)?;
+        let orphan = inode_mgr.new_inode()?;
+        orphan.set_mode(libc::S_IFREG as u16 | 0o644);
+        inode_mgr.flush(&orphan)?;
+        let problems = inode_mgr.check()?;
+        assert_eq!(problems, vec![
+            format!("inode {} points to free block {}", file.id(), data_block),
+            format!("block {} is allocated but not used", leaked),
+            format!("inode {} is allocated but not reachable", orphan.id()),
+            String::from("3 inodes counted, but 2 are reachable"),
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_legacy_volume() -> Result<(), std::io::Error> {
+        // A volume formatted with 16-bit ids: root inode with one data block and an indirect block
+        let mut device = FakeMemBlockIO::new();
+        let mut super_block = [0; BLOCK_SIZE];
+        super_block[0 .. 4].copy_from_slice(&[114, 102, 115, 46]);
+        device.write(0, &super_block)?;
+        let mut bitmap = [0; BLOCK_SIZE];
+        bitmap[0] = 0b111;
+        device.write(1, &bitmap)?;
+        let mut root = [0; BLOCK_SIZE];
+        root[8 .. 12].copy_from_slice(&(BLOCK_SIZE as u32 * 2100).to_le_bytes()); // length
+        root[60 .. 62].copy_from_slice(&2u16.to_le_bytes()); // First direct block
+        root[BLOCK_SIZE - 2 ..].copy_from_slice(&3u16.to_le_bytes()); // Indirect block
+        device.write(2, &root)?;
+        device.write(3, &[1; BLOCK_SIZE])?;
+        let mut indirect = [0; BLOCK_SIZE];
+        indirect[(2099 - 2017) * 2 .. (2099 - 2017) * 2 + 2].copy_from_slice(&2u16.to_le_bytes());
+        device.write(4, &indirect)?;
+
+        let mut inode_mgr = init_with(Box::new(device))?;
+        assert_eq!(inode_mgr.id_size(), 2);
+        let inode = inode_mgr.read_root_inode()?;
+        assert_eq!(inode_mgr.read_file(&inode, 0, BLOCK_SIZE)?, vec![1; BLOCK_SIZE]);
+        assert_eq!(inode_mgr.read_file(&inode, 2099 * BLOCK_SIZE, BLOCK_SIZE)?, vec![1; BLOCK_SIZE]);
+        inode_mgr.write_file(&inode, BLOCK_SIZE, &[2; BLOCK_SIZE])?;
+        assert_eq!(inode.data_block(&mut inode_mgr.block_mgr, 1)?, 4);
+        let err = inode_mgr.write_file(&inode, 1 << 32, &[1]).unwrap_err(); // No large files
+        assert_eq!(err.raw_os_error(), Some(libc::EFBIG));
+        assert_eq!(inode_mgr.read_file(&inode, BLOCK_SIZE, BLOCK_SIZE)?, vec![2; BLOCK_SIZE]);
+
+        // Inodes are blocks, so a deleted one is discarded with its generation
+        inode_mgr.block_mgr.set_discard_batch(Some(1));
+        let (id, generation) = {
+            let old = inode_mgr.new_inode()?;
+            inode_mgr.del_inode(&old)?;
+            (old.id(), old.generation())
+        };
+        inode_mgr.block_mgr.flush_bitmap()?;
+        let new = inode_mgr.new_inode()?;
+        assert_eq!(new.id(), id);
+        assert!(new.generation() > generation);
+        Ok(())
+    }
+}
@@ -9,16 +9,53 @@ pub mod block_mgr;
 pub use block_mgr::block_io;
 
 use block_io::{Id, BLOCK_SIZE};
-use block_mgr::BlockMgr;
+use block_mgr::{BlockMgr, decode_id, encode_id};
+
+struct IndexBlock {
+    data: Box<[u8; BLOCK_SIZE]>,
+    dirty: bool,
+}
+
+/// Data blocks from `index` stored in `cnt` consecutive blocks from `id`
+#[derive(Clone, Copy, Debug)]
+struct Extent {
+    index: usize,
+    id: Id,
+    cnt: usize,
+}
+
+impl Extent {
+    fn end(&self) -> usize {
+        self.index + self.cnt
+    }
+}
+
+/// A block of the extent tree holding `cnt` entries: extents in a leaf, or nodes of the level below
+#[derive(Clone, Copy, Debug)]
+struct ExtentNode {
+    id: Id,
+    cnt: usize,
+    dirty: bool, // The block must be encoded again
+}
 
 struct InodeBody {
     dirty: bool,
     data: [u8; BLOCK_SIZE],
-    indirect: Option<[u8; BLOCK_SIZE]>,
+    index: std::collections::HashMap<Id, IndexBlock>, // Blocks of the index trees read so far
+    extents: Option<Vec<Extent>>, // Sorted, read when first needed if the inode maps blocks by extents
+    tree: Vec<Vec<ExtentNode>>, // Nodes of the extent tree by level, from the leaves up
+    freed: Vec<Id>, // Blocks the extent tree no longer uses, to be freed once the inode stops pointing to them
+    extents_dirty: bool, // The extent tree must be written again
 }
 
 pub struct Inode {
     id: Id,
+    id_size: usize, // Bytes of each block pointer, which depends on the volume
+    size: usize, // Bytes of the inode, a whole block if the volume has no inode table
+    flags_enabled: bool, // Whether the inode has a flags word
+    large: bool, // Whether the inode has a 64-bit length and double and triple indirect blocks
+    extents_enabled: bool, // Whether regular files map their blocks by extents on the volume
+    xattrs: bool, // Whether the inode has an xattr block and inline extended attributes
     body: std::cell::RefCell<InodeBody>,
 }
 
@@ -49,53 +86,968 @@ const UID_SIZE: usize = std::mem::size_of::<u32>();
 const GID_OFF: usize = UID_OFF + UID_SIZE;
 const GID_SIZE: usize = std::mem::size_of::<u32>();
 
-const INDEX_OFF: usize = GID_OFF + GID_SIZE;
-const INDEX_SIZE: usize = std::mem::size_of::<Id>();
+const FLAGS_OFF: usize = GID_OFF + GID_SIZE; // Only in inodes of newer inode tables
+const FLAGS_SIZE: usize = std::mem::size_of::<u32>();
+
+const LENGTH_HI_OFF: usize = FLAGS_OFF + FLAGS_SIZE; // Only on volumes with large files
+const LENGTH_HI_SIZE: usize = std::mem::size_of::<u32>();
+
+const XATTR_ID_OFF: usize = LENGTH_HI_OFF + LENGTH_HI_SIZE; // Only on volumes with extended attributes
+const XATTR_ID_SIZE: usize = std::mem::size_of::<Id>();
+
+const INLINE_XATTR_OFF: usize = XATTR_ID_OFF + XATTR_ID_SIZE; // Ditto
+const INLINE_XATTR_SIZE: usize = 48;
+
+const INDEX_OFF: usize = GID_OFF + GID_SIZE; // Of inodes without flags
+const TABLE_INDEX_OFF: usize = FLAGS_OFF + FLAGS_SIZE;
+const LARGE_INDEX_OFF: usize = LENGTH_HI_OFF + LENGTH_HI_SIZE;
+const XATTR_INDEX_OFF: usize = INLINE_XATTR_OFF + INLINE_XATTR_SIZE;
+
+/// The data is kept in the inode, where the block pointers would be
+const FLAG_INLINE_DATA: u32 = 1;
+/// The blocks are mapped by an extent tree, whose root is where the block pointers would be
+const FLAG_EXTENTS: u32 = 2;
+
+const EXTENT_HEADER_SIZE: usize = 8; // entry count (4B) | depth (4B)
+const EXTENT_SIZE: usize = 12; // first data block (4B) | first block (4B) | block count (4B)
+const EXTENT_INDEX_SIZE: usize = 8; // first data block (4B) | child node (4B)
+const EXTENTS_PER_BLOCK: usize = (BLOCK_SIZE - EXTENT_HEADER_SIZE) / EXTENT_SIZE;
+const EXTENT_INDEXES_PER_BLOCK: usize = (BLOCK_SIZE - EXTENT_HEADER_SIZE) / EXTENT_INDEX_SIZE;
+const MAX_EXTENT_DEPTH: usize = 4; // Enough for 2^32 extents under a single entry of the root
 
-const DIRECT_BLK_CNT: usize = (BLOCK_SIZE - INDEX_OFF) / INDEX_SIZE - 1;
+fn u32_at(data: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(data[off .. off + 4].try_into().unwrap())
+}
+
+/// Write a node of the extent tree of `depth` holding `entries` to `node`
+fn encode_extent_node(node: &mut [u8], entries: &[Vec<u8>], depth: usize) {
+    node.fill(0);
+    node[0 .. 4].copy_from_slice(&(entries.len() as u32).to_le_bytes());
+    node[4 .. 8].copy_from_slice(&(depth as u32).to_le_bytes());
+    let mut off = EXTENT_HEADER_SIZE;
+    for entry in entries {
+        node[off .. off + entry.len()].copy_from_slice(entry);
+        off += entry.len();
+    }
+}
+
+/// Encode an entry of a node of the extent tree from its fields
+fn encode_entry(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|value| value.to_le_bytes()).collect()
+}
+
+/// Bytes of each entry of a node of the extent tree of `depth`
+fn extent_entry_size(depth: usize) -> usize {
+    if depth == 0 { EXTENT_SIZE } else { EXTENT_INDEX_SIZE }
+}
+
+/// Entries a block of the extent tree of `depth` holds at most
+fn extent_node_capacity(depth: usize) -> usize {
+    if depth == 0 { EXTENTS_PER_BLOCK } else { EXTENT_INDEXES_PER_BLOCK }
+}
+
+/// Parent of each node of `level` of the extent tree by position, 0 for the root
+fn extent_parents(tree: &[Vec<ExtentNode>], level: usize) -> Vec<usize> {
+    match tree.get(level + 1) {
+        Some(upper) => upper.iter().enumerate().flat_map(|(i, node)| std::iter::repeat_n(i, node.cnt)).collect(),
+        None => vec![0; tree[level].len()]
+    }
+}
+
+/// Entry counts of the nodes `cnt` entries are split into, `cap` at most each. Filling them in order
+/// keeps appended entries packed, otherwise they are balanced to leave room in each
+fn split_cnt(cnt: usize, cap: usize, fill: bool) -> Vec<usize> {
+    let parts = cnt.div_ceil(cap);
+    (0 .. parts).map(|i| if fill { std::cmp::min(cap, cnt - i * cap) } else { cnt / parts + usize::from(i < cnt % parts) })
+        .collect()
+}
+
+/// An extended attribute as (name, value)
+pub type Xattr = (Vec<u8>, Vec<u8>);
+
+/// Encode extended attributes, each like:
+/// [ name length (1B) | value length (2B) | name | value ]
+/// A name length of 0 ends them, unless they fill the space
+pub fn encode_xattrs(xattrs: &[Xattr]) -> Vec<u8> {
+    let mut data = vec![];
+    for (name, value) in xattrs {
+        data.push(name.len() as u8);
+        data.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        data.extend_from_slice(name);
+        data.extend_from_slice(value);
+    }
+    data
+}
+
+/// Fails with EIO if an attribute runs past the end of `data`
+fn parse_xattrs(data: &[u8]) -> Result<Vec<Xattr>, std::io::Error> {
+    let mut xattrs = vec![];
+    let mut off = 0;
+    while off < data.len() && data[off] != 0 {
+        let name_len = data[off] as usize;
+        if off + 3 > data.len() {
+            return Err(std::io::Error::from_raw_os_error(libc::EIO))
+        }
+        let value_len = u16::from_le_bytes(data[off + 1 .. off + 3].try_into().unwrap()) as usize;
+        let (name_off, value_off) = (off + 3, off + 3 + name_len);
+        if value_off + value_len > data.len() {
+            return Err(std::io::Error::from_raw_os_error(libc::EIO))
+        }
+        xattrs.push((Vec::from(&data[name_off .. value_off]), Vec::from(&data[value_off .. value_off + value_len])));
+        off = value_off + value_len;
+    }
+    Ok(xattrs)
+}
 
 /// For layout of each inode is like:
 /// [ generation (8B) | length (4B) | last access time (12B) | last modification time (12B) |
 ///   last change time (12B) | type + perm (2B) | link count (2B) | uid (4B) | gid (4B) |
-///   direct block (Id) ... | indirect block (Id) ]
+///   flags (4B, only in newer inode tables) | length high half (4B, only with large files) |
+///   xattr block (4B, only with extended attributes) | inline extended attributes (48B, ditto) |
+///   direct block (id size) ... | indirect block (id size) |
+///   double indirect block (id size, only with large files) | triple indirect block (ditto) ]
+/// Inodes fill a slot of the inode table, or a whole block on volumes without an inode table, so
+/// the number of direct blocks depends on the volume. Extended attributes are kept inline if they
+/// fit, or in the xattr block otherwise. With FLAG_INLINE_DATA, the data of the file
+/// takes the place of the block pointers instead.
+/// An indirect block is full of pointers to data blocks, and a double (triple) indirect block is
+/// full of pointers to indirect (double indirect) blocks. They are the index trees of the inode.
+/// With FLAG_EXTENTS, the place of the block pointers holds the root node of an extent tree
+/// instead. Each node, the root or a block, is a header followed by entries. Entries of leaves
+/// (depth 0) are extents, and entries of other nodes point to the nodes of one depth less, each
+/// mapping data blocks from the first one of the entry on
 impl Inode {
 
+    fn has_flags(&self) -> bool {
+        self.flags_enabled
+    }
+
+    fn index_off(&self) -> usize {
+        match (self.xattrs, self.large, self.has_flags()) {
+            (true, _, _) => XATTR_INDEX_OFF,
+            (false, true, _) => LARGE_INDEX_OFF,
+            (false, false, true) => TABLE_INDEX_OFF,
+            (false, false, false) => INDEX_OFF
+        }
+    }
+
+    /// Number of index trees, whose roots are at the end of the inode. The one of depth `n` is the
+    /// n-th of them
+    fn tree_cnt(&self) -> usize {
+        if self.large { 3 } else { 1 }
+    }
+
+    fn direct_blk_cnt(&self) -> usize {
+        (self.size - self.index_off()) / self.id_size - self.tree_cnt()
+    }
+
+    /// Block pointers in each index block
+    fn ids_per_block(&self) -> usize {
+        BLOCK_SIZE / self.id_size
+    }
+
+    fn flags(&self) -> u32 {
+        if !self.has_flags() {
+            return 0
+        }
+        let body = self.body.borrow();
+        u32::from_le_bytes(body.data[FLAGS_OFF .. FLAGS_OFF + FLAGS_SIZE].try_into().unwrap())
+    }
+
+    /// Index blocks are read when first needed
     pub fn new(block_mgr: &mut BlockMgr, id: Id) -> Result<Inode, std::io::Error> {
-        let obj = Inode { id: id, body: std::cell::RefCell::new(InodeBody {
+        Ok(Inode { id, id_size: block_mgr.id_size(), size: block_mgr.inode_size(), flags_enabled: block_mgr.has_inode_flags(),
+                   large: block_mgr.has_large_files(),
+                   extents_enabled: block_mgr.has_extents(), xattrs: block_mgr.has_xattrs(),
+                   body: std::cell::RefCell::new(InodeBody {
             dirty: false,
-            data: block_mgr.read_block(id)?,
-            indirect: None
-        }) };
-        let indirect_id = obj.indirect_id();
-        if indirect_id != 0 {
-            let mut body = obj.body.borrow_mut();
-            body.indirect = Some(block_mgr.read_block(indirect_id)?);
+            data: block_mgr.read_inode(id)?,
+            index: std::collections::HashMap::new(),
+            extents: None,
+            tree: vec![],
+            freed: vec![],
+            extents_dirty: false
+        }) })
+    }
+
+    /// Read the inode again, dropping what is changed in memory, after the transaction writing it is
+    /// aborted. Index blocks are read again when needed. An inode allocated in that transaction is
+    /// only left to be dropped
+    pub fn reload(&self, block_mgr: &mut BlockMgr) -> Result<(), std::io::Error> {
+        let mut body = self.body.borrow_mut();
+        body.dirty = false;
+        body.index.clear();
+        body.extents = None;
+        body.tree.clear();
+        body.freed.clear();
+        body.extents_dirty = false;
+        if block_mgr.is_inode_allocated(self.id) {
+            body.data = block_mgr.read_inode(self.id)?;
         }
-        Ok(obj)
+        Ok(())
     }
 
     pub fn id(&self) -> Id {
         self.id
     }
 
-    pub fn indirect_id(&self) -> Id {
+    /// Where to look for the first blocks of the inode: right after the inode itself if it takes a
+    /// block of its own
+    pub fn block_goal(&self) -> Id {
+        if self.size == BLOCK_SIZE { self.id + 1 } else { 1 }
+    }
+
+    fn root_off(&self, depth: usize) -> usize {
+        self.size - (self.tree_cnt() - depth + 1) * self.id_size
+    }
+
+    /// Root of the index tree of `depth`, 0 if there is none
+    fn root(&self, depth: usize) -> Id {
+        if self.is_inline() {
+            return 0
+        }
+        let body = self.body.borrow();
+        decode_id(&body.data[self.root_off(depth) .. self.root_off(depth) + self.id_size])
+    }
+
+    fn set_root(&self, depth: usize, id: Id) {
+        let off = self.root_off(depth);
+        let mut body = self.body.borrow_mut();
+        encode_id(&mut body.data[off .. off + self.id_size], id);
+        body.dirty = true;
+    }
+
+    /// Roots of the index trees, without reading them
+    #[allow(dead_code)] // Only used by rfs-replay
+    pub fn index_roots(&self) -> Vec<Id> {
+        if self.has_extents() {
+            let body = self.body.borrow();
+            let root = &body.data[self.index_off() .. self.size];
+            let (cnt, depth) = (u32_at(root, 0) as usize, u32_at(root, 4));
+            return match depth {
+                0 => vec![],
+                _ => (0 .. cnt).map(|i| u32_at(root, EXTENT_HEADER_SIZE + i * EXTENT_INDEX_SIZE + 4)).collect()
+            }
+        }
+        (1 ..= self.tree_cnt()).map(|depth| self.root(depth)).filter(|&id| id != 0).collect()
+    }
+
+    fn load_index(&self, block_mgr: &mut BlockMgr, id: Id) -> Result<(), std::io::Error> {
+        if !self.body.borrow().index.contains_key(&id) {
+            let data = Box::new(block_mgr.read_block(id)?);
+            self.body.borrow_mut().index.insert(id, IndexBlock { data, dirty: false });
+        }
+        Ok(())
+    }
+
+    /// Pointer `slot` of index block `id`, which must have been loaded
+    fn entry(&self, id: Id, slot: usize) -> Id {
+        let body = self.body.borrow();
+        decode_id(&body.index[&id].data[slot * self.id_size .. (slot + 1) * self.id_size])
+    }
+
+    fn set_entry(&self, id: Id, slot: usize, value: Id) {
+        let mut body = self.body.borrow_mut();
+        let block = body.index.get_mut(&id).unwrap();
+        encode_id(&mut block.data[slot * self.id_size .. (slot + 1) * self.id_size], value);
+        block.dirty = true;
+    }
+
+    fn new_index_block(&self, block_mgr: &mut BlockMgr) -> Result<Id, std::io::Error> {
+        let id = block_mgr.new_block_near(self.block_goal())?;
+        self.body.borrow_mut().index.insert(id, IndexBlock { data: Box::new([0; BLOCK_SIZE]), dirty: true });
+        Ok(id)
+    }
+
+    /// The index tree mapping data block `index`, as its depth and the index within it, or None if
+    /// the index is too large for any tree
+    fn locate(&self, index: usize) -> Option<(usize, usize)> {
+        let mut index = index - self.direct_blk_cnt();
+        let mut span = 1;
+        for depth in 1 ..= self.tree_cnt() {
+            span *= self.ids_per_block();
+            if index < span {
+                return Some((depth, index))
+            }
+            index -= span;
+        }
+        None
+    }
+
+    /// Largest length of a file the inode can map
+    pub fn max_length(&self) -> u64 {
+        let mut block_cnt = self.direct_blk_cnt() as u64;
+        let mut span = 1;
+        for _ in 0 .. self.tree_cnt() {
+            span *= self.ids_per_block() as u64;
+            block_cnt += span;
+        }
+        if self.has_extents() || (self.is_inline() && self.wants_extents()) {
+            block_cnt = 1 << 32; // Extents have 32-bit data block indexes
+        }
+        let max_length = block_cnt * BLOCK_SIZE as u64;
+        if self.large { max_length } else { std::cmp::min(max_length, u32::MAX as u64) }
+    }
+
+    /// All blocks of the index trees
+    pub fn index_blocks(&self, block_mgr: &mut BlockMgr) -> Result<Vec<Id>, std::io::Error> {
+        if self.has_extents() {
+            self.load_extents(block_mgr)?;
+            return Ok(self.body.borrow().tree.iter().flatten().map(|node| node.id).collect())
+        }
+        let mut ids = vec![];
+        for depth in 1 ..= self.tree_cnt() {
+            let root = self.root(depth);
+            if root != 0 {
+                self.collect_tree(block_mgr, root, depth, &mut ids)?;
+            }
+        }
+        Ok(ids)
+    }
+
+    fn collect_tree(&self, block_mgr: &mut BlockMgr, id: Id, depth: usize, ids: &mut Vec<Id>)
+                    -> Result<(), std::io::Error> {
+        ids.push(id);
+        if depth > 1 {
+            self.load_index(block_mgr, id)?;
+            for slot in 0 .. self.ids_per_block() {
+                let child = self.entry(id, slot);
+                if child != 0 {
+                    self.collect_tree(block_mgr, child, depth - 1, ids)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Data blocks from index `begin` (but before `end`) as (index, first block, count) runs of
+    /// consecutive blocks, skipping holes
+    pub fn mapped_runs(&self, block_mgr: &mut BlockMgr, begin: usize, end: usize)
+                       -> Result<Vec<(usize, Id, usize)>, std::io::Error> {
+        if self.has_extents() {
+            self.load_extents(block_mgr)?;
+            let body = self.body.borrow();
+            let extents = body.extents.as_ref().unwrap();
+            let first = extents.partition_point(|extent| extent.end() <= begin);
+            return Ok(extents[first ..].iter().take_while(|extent| extent.index < end).map(|extent| {
+                let (run_begin, run_end) = (std::cmp::max(extent.index, begin), std::cmp::min(extent.end(), end));
+                (run_begin, extent.id + (run_begin - extent.index) as Id, run_end - run_begin)
+            }).collect())
+        }
+        let mut runs: Vec<(usize, Id, usize)> = vec![];
+        for (index, id) in self.mapped_blocks(block_mgr, begin, end)? {
+            match runs.last_mut() {
+                Some(run) if run.0 + run.2 == index && run.1 + run.2 as Id == id => run.2 += 1,
+                _ => runs.push((index, id, 1))
+            }
+        }
+        Ok(runs)
+    }
+
+    /// Data blocks mapped by block pointers from index `begin` (but before `end`) as (index, block)
+    /// pairs, skipping holes
+    fn mapped_blocks(&self, block_mgr: &mut BlockMgr, begin: usize, end: usize)
+                     -> Result<Vec<(usize, Id)>, std::io::Error> {
+        let mut blocks = vec![];
+        if self.is_inline() {
+            return Ok(blocks)
+        }
+        let direct_blk_cnt = self.direct_blk_cnt();
+        for index in begin .. std::cmp::min(end, direct_blk_cnt) {
+            let id = self.data_block(block_mgr, index)?;
+            if id != 0 {
+                blocks.push((index, id));
+            }
+        }
+        let mut first = direct_blk_cnt; // First data block mapped by the tree
+        let mut span = 1;
+        for depth in 1 ..= self.tree_cnt() {
+            span *= self.ids_per_block();
+            let root = self.root(depth);
+            if root != 0 && begin < first + span && first < end {
+                self.collect_mapped(block_mgr, root, depth, first, (begin, end), &mut blocks)?;
+            }
+            first += span;
+        }
+        Ok(blocks)
+    }
+
+    /// Add the data blocks mapped by index block `id` within `range`. The first one it maps is `first`
+    fn collect_mapped(&self, block_mgr: &mut BlockMgr, id: Id, depth: usize, first: usize, range: (usize, usize),
+                      blocks: &mut Vec<(usize, Id)>) -> Result<(), std::io::Error> {
+        let child_span = self.ids_per_block().pow(depth as u32 - 1);
+        self.load_index(block_mgr, id)?;
+        for slot in 0 .. self.ids_per_block() {
+            let child_first = first + slot * child_span;
+            if child_first >= range.1 {
+                break
+            }
+            let child = self.entry(id, slot);
+            if child == 0 || child_first + child_span <= range.0 {
+                continue
+            }
+            if depth == 1 {
+                blocks.push((child_first, child));
+            } else {
+                self.collect_mapped(block_mgr, child, depth - 1, child_first, range, blocks)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Unlink the index blocks which only map data blocks from `block_cnt` on, which must have been
+    /// cleared already. Returns them to be freed
+    pub fn prune_index(&self, block_mgr: &mut BlockMgr, block_cnt: usize) -> Result<Vec<Id>, std::io::Error> {
+        if self.has_extents() {
+            self.load_extents(block_mgr)?;
+            return Ok(std::mem::take(&mut self.body.borrow_mut().freed)) // Already unlinked by set_data_blocks
+        }
+        let mut pruned = vec![];
+        let mut first = self.direct_blk_cnt(); // First data block mapped by the tree
+        let mut span = 1;
+        for depth in 1 ..= self.tree_cnt() {
+            span *= self.ids_per_block();
+            let root = self.root(depth);
+            if root != 0 && block_cnt <= first {
+                self.collect_tree(block_mgr, root, depth, &mut pruned)?;
+                self.set_root(depth, 0);
+            } else if root != 0 && block_cnt < first + span {
+                self.prune_tree(block_mgr, root, depth, block_cnt - first, &mut pruned)?;
+            }
+            first += span;
+        }
+        let mut body = self.body.borrow_mut();
+        for id in pruned.iter() {
+            body.index.remove(id);
+        }
+        Ok(pruned)
+    }
+
+    /// Prune the subtrees of index block `id` which only map data blocks from `keep` on, counting
+    /// from the first one it maps
+    fn prune_tree(&self, block_mgr: &mut BlockMgr, id: Id, depth: usize, keep: usize, pruned: &mut Vec<Id>)
+                  -> Result<(), std::io::Error> {
+        if depth == 1 {
+            return Ok(()) // Points to data blocks only
+        }
+        let child_span = self.ids_per_block().pow(depth as u32 - 1);
+        self.load_index(block_mgr, id)?;
+        for slot in keep / child_span .. self.ids_per_block() {
+            let child = self.entry(id, slot);
+            let first = slot * child_span;
+            if child != 0 && keep <= first {
+                self.collect_tree(block_mgr, child, depth - 1, pruned)?;
+                self.set_entry(id, slot, 0);
+            } else if child != 0 {
+                self.prune_tree(block_mgr, child, depth - 1, keep - first, pruned)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy the index blocks after `block_cnt` to new blocks before it. Returns the old blocks to be
+    /// freed once the inode is flushed
+    pub fn relocate_index(&self, block_mgr: &mut BlockMgr, block_cnt: usize) -> Result<Vec<Id>, std::io::Error> {
+        let mut old_ids = vec![];
+        if self.has_extents() {
+            self.load_extents(block_mgr)?;
+            let depth = self.body.borrow().tree.len();
+            for level in 0 .. depth {
+                let (parents, ids) = {
+                    let body = self.body.borrow();
+                    (extent_parents(&body.tree, level), body.tree[level].iter().map(|node| node.id).collect::<Vec<_>>())
+                };
+                for (i, id) in ids.into_iter().enumerate().filter(|&(_, id)| id as usize > block_cnt) {
+                    let new_id = block_mgr.new_block_near(self.block_goal())?;
+                    let mut body = self.body.borrow_mut();
+                    body.tree[level][i] = ExtentNode { id: new_id, dirty: true, ..body.tree[level][i] };
+                    if let Some(upper) = body.tree.get_mut(level + 1) {
+                        upper[parents[i]].dirty = true; // Points to the new block
+                    }
+                    body.index.remove(&id); // So the node is written to the new block
+                    body.extents_dirty = true;
+                    old_ids.push(id);
+                }
+            }
+            return Ok(old_ids)
+        }
+        for depth in 1 ..= self.tree_cnt() {
+            let mut root = self.root(depth);
+            if root == 0 {
+                continue
+            }
+            if root as usize > block_cnt {
+                old_ids.push(root);
+                root = self.move_index_block(block_mgr, root)?;
+                self.set_root(depth, root);
+            }
+            self.relocate_tree(block_mgr, root, depth, block_cnt, &mut old_ids)?;
+        }
+        Ok(old_ids)
+    }
+
+    fn relocate_tree(&self, block_mgr: &mut BlockMgr, id: Id, depth: usize, block_cnt: usize, old_ids: &mut Vec<Id>)
+                     -> Result<(), std::io::Error> {
+        if depth == 1 {
+            return Ok(())
+        }
+        self.load_index(block_mgr, id)?;
+        for slot in 0 .. self.ids_per_block() {
+            let mut child = self.entry(id, slot);
+            if child == 0 {
+                continue
+            }
+            if child as usize > block_cnt {
+                old_ids.push(child);
+                child = self.move_index_block(block_mgr, child)?;
+                self.set_entry(id, slot, child);
+            }
+            self.relocate_tree(block_mgr, child, depth - 1, block_cnt, old_ids)?;
+        }
+        Ok(())
+    }
+
+    /// Index block `id` is written to a new block when flushed
+    fn move_index_block(&self, block_mgr: &mut BlockMgr, id: Id) -> Result<Id, std::io::Error> {
+        self.load_index(block_mgr, id)?;
+        let new_id = block_mgr.new_block_near(self.block_goal())?;
+        let mut body = self.body.borrow_mut();
+        let mut block = body.index.remove(&id).unwrap();
+        block.dirty = true;
+        body.index.insert(new_id, block);
+        Ok(new_id)
+    }
+
+    /// Whether the data is kept in the inode rather than in data blocks
+    pub fn is_inline(&self) -> bool {
+        self.flags() & FLAG_INLINE_DATA != 0
+    }
+
+    /// Bytes of data the inode can keep by itself, 0 if it cannot on this volume
+    pub fn inline_capacity(&self) -> usize {
+        if self.has_flags() { self.size - self.index_off() } else { 0 }
+    }
+
+    /// Switch between inline data and mapping blocks, by extents for regular files if the volume
+    /// allows. Either starts out as zeros, so the caller must move the inline data away or free the
+    /// blocks first
+    pub fn set_inline(&self, inline: bool) {
+        assert!(self.has_flags());
+        let mut flags = self.flags() & !(FLAG_INLINE_DATA | FLAG_EXTENTS);
+        if inline {
+            flags |= FLAG_INLINE_DATA;
+        } else if self.wants_extents() {
+            flags |= FLAG_EXTENTS;
+        }
+        let mut body = self.body.borrow_mut();
+        body.data[FLAGS_OFF .. FLAGS_OFF + FLAGS_SIZE].copy_from_slice(&flags.to_le_bytes());
+        body.data[self.index_off() .. self.size].fill(0); // Also an empty root node of an extent tree
+        body.index.clear();
+        body.extents = if flags & FLAG_EXTENTS != 0 { Some(vec![]) } else { None };
+        body.tree.clear();
+        body.extents_dirty = false;
+        body.dirty = true;
+    }
+
+    /// Whether the blocks are mapped by extents rather than block pointers
+    pub fn has_extents(&self) -> bool {
+        self.flags() & FLAG_EXTENTS != 0
+    }
+
+    /// Whether the blocks would be mapped by extents once the inode maps any
+    fn wants_extents(&self) -> bool {
+        self.extents_enabled && self.kind().ok() == Some(fuse::FileType::RegularFile)
+    }
+
+    /// Read the extent tree if not yet
+    fn load_extents(&self, block_mgr: &mut BlockMgr) -> Result<(), std::io::Error> {
+        if self.body.borrow().extents.is_some() {
+            return Ok(())
+        }
+        let root = Vec::from(&self.body.borrow().data[self.index_off() .. self.size]);
+        let depth = u32_at(&root, 4) as usize;
+        if depth > MAX_EXTENT_DEPTH {
+            return Err(std::io::Error::from_raw_os_error(libc::EIO))
+        }
+        let mut extents = vec![];
+        let mut tree = vec![vec![]; depth];
+        self.read_extent_node(block_mgr, &root, depth, &mut extents, &mut tree, &mut std::collections::HashSet::new())?;
+        let mut body = self.body.borrow_mut();
+        body.extents = Some(extents);
+        body.tree = tree;
+        Ok(())
+    }
+
+    /// Add the extents under `node` to `extents`, and the nodes below it to `tree` by level. Fails with
+    /// EIO if the node is corrupted: it is not of `depth`, it holds more entries than fit, a node below
+    /// is empty or reached twice, or the extents are not sorted and disjoint
+    fn read_extent_node(&self, block_mgr: &mut BlockMgr, node: &[u8], depth: usize, extents: &mut Vec<Extent>,
+                        tree: &mut [Vec<ExtentNode>], seen: &mut std::collections::HashSet<Id>)
+                        -> Result<(), std::io::Error> {
+        let cnt = u32_at(node, 0) as usize;
+        if u32_at(node, 4) as usize != depth || cnt > (node.len() - EXTENT_HEADER_SIZE) / extent_entry_size(depth) {
+            return Err(std::io::Error::from_raw_os_error(libc::EIO))
+        }
+        for i in 0 .. cnt {
+            let off = EXTENT_HEADER_SIZE + i * extent_entry_size(depth);
+            if depth == 0 {
+                let extent = Extent { index: u32_at(node, off) as usize, id: u32_at(node, off + 4),
+                                      cnt: u32_at(node, off + 8) as usize };
+                if extent.cnt == 0 || extents.last().is_some_and(|prev| prev.end() > extent.index) {
+                    return Err(std::io::Error::from_raw_os_error(libc::EIO))
+                }
+                extents.push(extent);
+                continue
+            }
+            let child = u32_at(node, off + 4);
+            if child == 0 || !seen.insert(child) {
+                return Err(std::io::Error::from_raw_os_error(libc::EIO))
+            }
+            self.load_index(block_mgr, child)?;
+            let data = self.body.borrow().index[&child].data.clone();
+            let child_cnt = u32_at(&data[..], 0) as usize;
+            if child_cnt == 0 {
+                return Err(std::io::Error::from_raw_os_error(libc::EIO))
+            }
+            tree[depth - 1].push(ExtentNode { id: child, cnt: child_cnt, dirty: false });
+            self.read_extent_node(block_mgr, &data[..], depth - 1, extents, tree, seen)?;
+        }
+        Ok(())
+    }
+
+    /// Entries the root node of the extent tree of `depth` holds at most
+    fn extent_root_capacity(&self, depth: usize) -> usize {
+        (self.size - self.index_off() - EXTENT_HEADER_SIZE) / extent_entry_size(depth)
+    }
+
+    /// Update the nodes of `tree` for entries `range` of the leaves, which hold `extent_cnt` extents,
+    /// being replaced by `cnt` entries. A node which overflows is split and one left with few entries
+    /// is merged with a neighbour, and levels are added or removed as the root needs. The nodes which
+    /// changed are marked dirty with their ancestors. New blocks are added to `allocated`, and blocks
+    /// no longer used to `freed`
+    #[allow(clippy::too_many_arguments)]
+    fn update_extent_tree(&self, block_mgr: &mut BlockMgr, tree: &mut Vec<Vec<ExtentNode>>, range: (usize, usize),
+                          cnt: usize, extent_cnt: usize, allocated: &mut Vec<Id>, freed: &mut Vec<Id>)
+                          -> Result<(), std::io::Error> {
+        let mut new_block = |block_mgr: &mut BlockMgr| -> Result<Id, std::io::Error> {
+            let id = block_mgr.new_block_near(self.block_goal())?;
+            allocated.push(id);
+            Ok(id)
+        };
+        let mut touched = vec![]; // Nodes of the current level which changed, by position
+        if let Some(leaves) = tree.first_mut() {
+            let (mut begin, leaf_cnt) = (0, leaves.len());
+            for (i, leaf) in leaves.iter_mut().enumerate() {
+                let end = begin + leaf.cnt;
+                // The new entries go to the leaf holding the first replaced one, or the last leaf
+                let target = (begin <= range.0 && range.0 < end) || (i + 1 == leaf_cnt && range.0 >= end);
+                let removed = std::cmp::min(end, range.1).saturating_sub(std::cmp::max(begin, range.0));
+                leaf.cnt = leaf.cnt - removed + if target { cnt } else { 0 };
+                if target || removed > 0 {
+                    touched.push(i);
+                }
+                begin = end;
+            }
+        }
+        for level in 0 .. tree.len() {
+            let (cap, parents) = (extent_node_capacity(level), extent_parents(tree, level));
+            let few = |node: &ExtentNode| node.cnt < cap / 4;
+            let old = std::mem::take(&mut tree[level]);
+            let mut nodes: Vec<ExtentNode> = Vec::with_capacity(old.len() + 1);
+            let mut node_parents: Vec<usize> = Vec::with_capacity(old.len() + 1);
+            let mut changed: Vec<bool> = Vec::with_capacity(old.len() + 1);
+            let mut touched_parents = vec![];
+            for (i, &node) in old.iter().enumerate() {
+                let is_touched = touched.binary_search(&i).is_ok();
+                if is_touched {
+                    touched_parents.push(parents[i]);
+                }
+                if node.cnt == 0 {
+                    freed.push(node.id);
+                    continue
+                }
+                if let (Some(prev), Some(prev_changed)) = (nodes.last_mut(), changed.last_mut()) {
+                    if ((is_touched && few(&node)) || (*prev_changed && few(prev))) && prev.cnt + node.cnt <= cap {
+                        prev.cnt += node.cnt;
+                        prev.dirty = true;
+                        *prev_changed = true;
+                        freed.push(node.id);
+                        touched_parents.extend([parents[i], *node_parents.last().unwrap()]);
+                        continue
+                    }
+                }
+                if node.cnt > cap {
+                    for (k, part) in split_cnt(node.cnt, cap, i + 1 == old.len()).into_iter().enumerate() {
+                        let id = if k == 0 { node.id } else { new_block(block_mgr)? };
+                        nodes.push(ExtentNode { id, cnt: part, dirty: true });
+                        node_parents.push(parents[i]);
+                        changed.push(true);
+                    }
+                    continue
+                }
+                nodes.push(ExtentNode { dirty: node.dirty || is_touched, ..node });
+                node_parents.push(parents[i]);
+                changed.push(is_touched);
+            }
+            tree[level] = nodes;
+            touched_parents.sort_unstable();
+            touched_parents.dedup();
+            if let Some(upper) = tree.get_mut(level + 1) {
+                for node in upper.iter_mut() {
+                    node.cnt = 0;
+                }
+                for &parent in node_parents.iter() {
+                    upper[parent].cnt += 1;
+                }
+                for &parent in touched_parents.iter() {
+                    upper[parent].dirty = true;
+                }
+            }
+            touched = touched_parents;
+        }
+        loop {
+            let depth = tree.len();
+            let root_cnt = tree.last().map_or(extent_cnt - (range.1 - range.0) + cnt, |top| top.len());
+            if root_cnt > self.extent_root_capacity(depth) {
+                let mut level = vec![];
+                for part in split_cnt(root_cnt, extent_node_capacity(depth), true) {
+                    level.push(ExtentNode { id: new_block(block_mgr)?, cnt: part, dirty: true });
+                }
+                tree.push(level);
+            } else if tree.last().is_some_and(|top| top.len() <= 1
+                                              && top.iter().all(|node| node.cnt <= self.extent_root_capacity(depth - 1))) {
+                freed.extend(tree.pop().unwrap().iter().map(|node| node.id)); // Its entries move to the root
+            } else {
+                return Ok(())
+            }
+        }
+    }
+
+    /// Encode the nodes of the extent tree which changed, and the root node in the inode, marking what
+    /// changed dirty. Returns the blocks no longer needed
+    fn store_extents(&self) -> Vec<Id> {
+        let mut body = self.body.borrow_mut();
+        let body = &mut *body;
+        let (extents, tree) = (body.extents.as_ref().unwrap(), &body.tree);
+        // For each node by level, its first entry in the level below and the first extent under it
+        let (mut firsts, mut starts): (Vec<Vec<usize>>, Vec<Vec<usize>>) = (vec![], vec![]);
+        for (level, nodes) in tree.iter().enumerate() {
+            let mut first = 0;
+            let level_firsts: Vec<usize> = nodes.iter().map(|node| { first += node.cnt; first - node.cnt }).collect();
+            starts.push(match level {
+                0 => level_firsts.clone(),
+                _ => level_firsts.iter().map(|&first| starts[level - 1][first]).collect()
+            });
+            firsts.push(level_firsts);
+        }
+        let entries = |level: usize, first: usize, cnt: usize| -> Vec<Vec<u8>> {
+            (first .. first + cnt).map(|i| match level {
+                0 => encode_entry(&[extents[i].index as u32, extents[i].id, extents[i].cnt as u32]),
+                _ => encode_entry(&[extents[starts[level - 1][i]].index as u32, tree[level - 1][i].id])
+            }).collect()
+        };
+        let mut encoded = vec![];
+        for (level, nodes) in tree.iter().enumerate() {
+            for (i, node) in nodes.iter().enumerate().filter(|(_, node)| node.dirty) {
+                let mut data = Box::new([0; BLOCK_SIZE]);
+                encode_extent_node(&mut data[..], &entries(level, firsts[level][i], node.cnt), level);
+                encoded.push((node.id, data));
+            }
+        }
+        let mut root = vec![0; self.size - self.index_off()];
+        encode_extent_node(&mut root, &entries(tree.len(), 0, tree.last().map_or(extents.len(), |top| top.len())), tree.len());
+        for (id, data) in encoded {
+            if body.index.get(&id).is_none_or(|block| block.data != data) {
+                body.index.insert(id, IndexBlock { data, dirty: true });
+            }
+        }
+        for node in body.tree.iter_mut().flatten() {
+            node.dirty = false;
+        }
+        if body.data[self.index_off() .. self.size] != root[..] {
+            body.data[self.index_off() .. self.size].copy_from_slice(&root);
+            body.dirty = true;
+        }
+        body.extents_dirty = false;
+        std::mem::take(&mut body.freed)
+    }
+
+    /// Map `cnt` data blocks from `index` to consecutive blocks from `id`, or unmap them if `id` is 0,
+    /// merging extents which turn out contiguous. The extent tree is updated on a copy first, so
+    /// nothing changes if it needs more blocks and that fails
+    fn map_extent(&self, block_mgr: &mut BlockMgr, index: usize, id: Id, cnt: usize) -> Result<(), std::io::Error> {
+        let end = index + cnt;
+        if end > 1 << 32 {
+            return Err(std::io::Error::from_raw_os_error(libc::EFBIG))
+        }
+        self.load_extents(block_mgr)?;
+        // Replace the extents overlapping the range, or next to it, by what is left of them
+        let (first, last, merged) = {
+            let body = self.body.borrow();
+            let extents = body.extents.as_ref().unwrap();
+            let first = extents.partition_point(|extent| extent.end() < index);
+            let last = extents.partition_point(|extent| extent.index <= end);
+            let mut pieces = vec![];
+            for extent in extents[first .. last].iter().filter(|extent| extent.index < index) {
+                pieces.push(Extent { cnt: std::cmp::min(extent.end(), index) - extent.index, ..*extent });
+            }
+            if id != 0 {
+                pieces.push(Extent { index, id, cnt });
+            }
+            for extent in extents[first .. last].iter().filter(|extent| extent.end() > end) {
+                let begin = std::cmp::max(extent.index, end);
+                pieces.push(Extent { index: begin, id: extent.id + (begin - extent.index) as Id,
+                                     cnt: extent.end() - begin });
+            }
+            let mut merged: Vec<Extent> = vec![];
+            for piece in pieces {
+                match merged.last_mut() {
+                    Some(prev) if prev.end() == piece.index && prev.id as usize + prev.cnt == piece.id as usize
+                        && prev.cnt + piece.cnt <= u32::MAX as usize => prev.cnt += piece.cnt,
+                    _ => merged.push(piece)
+                }
+            }
+            (first, last, merged)
+        };
+        let extent_cnt = self.body.borrow().extents.as_ref().unwrap().len();
+        let mut tree = self.body.borrow().tree.clone();
+        let (mut allocated, mut freed) = (vec![], vec![]);
+        if let Err(err) = self.update_extent_tree(block_mgr, &mut tree, (first, last), merged.len(), extent_cnt,
+                                                  &mut allocated, &mut freed) {
+            for id in allocated {
+                block_mgr.del_block(id)?;
+            }
+            return Err(err)
+        }
+        let mut body = self.body.borrow_mut();
+        for id in freed.iter() {
+            body.index.remove(id);
+        }
+        body.freed.extend(freed);
+        body.tree = tree;
+        body.extents.as_mut().unwrap().splice(first .. last, merged);
+        body.extents_dirty = true;
+        Ok(())
+    }
+
+    /// Number of data blocks from index `begin` (but before `end`) stored in consecutive blocks, or
+    /// all being holes
+    pub fn contiguous_run(&self, block_mgr: &mut BlockMgr, begin: usize, end: usize) -> Result<usize, std::io::Error> {
+        if self.has_extents() {
+            self.load_extents(block_mgr)?;
+            let body = self.body.borrow();
+            let extents = body.extents.as_ref().unwrap();
+            let run_end = match extents.get(extents.partition_point(|extent| extent.end() <= begin)) {
+                Some(extent) if extent.index <= begin => extent.end(),
+                Some(extent) => extent.index, // A hole before the extent
+                None => end
+            };
+            return Ok(std::cmp::min(run_end, end) - begin)
+        }
+        let first = self.data_block(block_mgr, begin)? as usize;
+        let mut cnt = 1;
+        while begin + cnt < end {
+            let id = self.data_block(block_mgr, begin + cnt)? as usize;
+            if (first == 0 && id != 0) || (first != 0 && id != first + cnt) {
+                break
+            }
+            cnt += 1;
+        }
+        Ok(cnt)
+    }
+
+    /// Inline data from `offset`, up to `count` bytes
+    pub fn read_inline(&self, offset: usize, count: usize) -> Vec<u8> {
+        assert!(self.is_inline());
         let body = self.body.borrow();
-        Id::from_le_bytes(body.data[BLOCK_SIZE - INDEX_SIZE ..].try_into().unwrap())
+        let begin = std::cmp::min(self.index_off() + offset, self.size);
+        let end = std::cmp::min(begin + count, self.size);
+        Vec::from(&body.data[begin .. end])
     }
 
+    /// Write inline data at `offset`. The length is not changed
+    pub fn write_inline(&self, offset: usize, data: &[u8]) {
+        assert!(self.is_inline() && offset + data.len() <= self.inline_capacity());
+        let off = self.index_off() + offset;
+        let mut body = self.body.borrow_mut();
+        body.data[off .. off + data.len()].copy_from_slice(data);
+        body.dirty = true;
+    }
+
+    /// Index blocks are written before the inode, so a new index block is written before anything
+    /// points to it. Blocks the extent tree no longer needs are freed after the inode stops pointing
+    /// to them
     pub fn flush(&self, block_mgr: &mut BlockMgr) -> Result<(), std::io::Error> {
-        let indirect_id = self.indirect_id();
+        let unused = if self.body.borrow().extents_dirty { self.store_extents() } else { vec![] };
+        self.write_dirty(block_mgr)?;
+        for id in unused {
+            block_mgr.del_block(id)?;
+        }
+        Ok(())
+    }
+
+    fn write_dirty(&self, block_mgr: &mut BlockMgr) -> Result<(), std::io::Error> {
         let mut body = self.body.borrow_mut();
+        for (&id, block) in body.index.iter_mut().filter(|(_, block)| block.dirty) {
+            block_mgr.write_block(id, &block.data[..])?;
+            block.dirty = false;
+        }
         if body.dirty {
-            block_mgr.write_block(self.id, &body.data)?;
-            if indirect_id != 0 {
-                block_mgr.write_block(indirect_id, &body.indirect.unwrap())?;
-            }
+            block_mgr.write_inode(self.id, &body.data[.. self.size])?;
             body.dirty = false;
         }
         Ok(())
     }
 
+    /// Whether the inode can have extended attributes, which depends on the volume
+    pub fn has_xattrs(&self) -> bool {
+        self.xattrs
+    }
+
+    /// Bytes of extended attributes the inode can keep by itself
+    pub fn inline_xattr_capacity(&self) -> usize {
+        if self.xattrs { INLINE_XATTR_SIZE } else { 0 }
+    }
+
+    /// The block keeping the extended attributes which do not fit in the inode, 0 if there is none
+    pub fn xattr_block(&self) -> Id {
+        if !self.xattrs {
+            return 0
+        }
+        let body = self.body.borrow();
+        decode_id(&body.data[XATTR_ID_OFF .. XATTR_ID_OFF + XATTR_ID_SIZE])
+    }
+
+    pub fn set_xattr_block(&self, id: Id) {
+        assert!(self.xattrs);
+        let mut body = self.body.borrow_mut();
+        encode_id(&mut body.data[XATTR_ID_OFF .. XATTR_ID_OFF + XATTR_ID_SIZE], id);
+        body.dirty = true;
+    }
+
+    /// Keep encoded extended attributes in the inode, at most `inline_xattr_capacity` bytes
+    pub fn set_inline_xattrs(&self, data: &[u8]) {
+        assert!(data.len() <= self.inline_xattr_capacity());
+        let mut body = self.body.borrow_mut();
+        body.data[INLINE_XATTR_OFF .. INLINE_XATTR_OFF + INLINE_XATTR_SIZE].fill(0);
+        body.data[INLINE_XATTR_OFF .. INLINE_XATTR_OFF + data.len()].copy_from_slice(data);
+        body.dirty = true;
+    }
+
+    /// Extended attributes as (name, value) pairs, from the inode and then the xattr block. Fails with
+    /// EIO if they are corrupted
+    pub fn xattrs(&self, block_mgr: &mut BlockMgr) -> Result<Vec<Xattr>, std::io::Error> {
+        if !self.xattrs {
+            return Ok(vec![])
+        }
+        let mut xattrs = parse_xattrs(&self.body.borrow().data[INLINE_XATTR_OFF .. INLINE_XATTR_OFF + INLINE_XATTR_SIZE])?;
+        let id = self.xattr_block();
+        if id != 0 {
+            xattrs.extend(parse_xattrs(&block_mgr.read_block(id)?)?);
+        }
+        Ok(xattrs)
+    }
+
     pub fn generation(&self) -> u64 {
         let body = self.body.borrow();
         u64::from_le_bytes(body.data[GENERATION_OFF .. GENERATION_OFF + GENERATION_SIZE].try_into().unwrap())
@@ -103,14 +1055,24 @@ impl Inode {
 
     // No need to set geneartion
 
-    pub fn length(&self) -> u32 {
+    pub fn length(&self) -> u64 {
         let body = self.body.borrow();
-        u32::from_le_bytes(body.data[LENGTH_OFF .. LENGTH_OFF + LENGTH_SIZE].try_into().unwrap())
+        let low = u32::from_le_bytes(body.data[LENGTH_OFF .. LENGTH_OFF + LENGTH_SIZE].try_into().unwrap());
+        let high = match self.large {
+            true => u32::from_le_bytes(body.data[LENGTH_HI_OFF .. LENGTH_HI_OFF + LENGTH_HI_SIZE].try_into().unwrap()),
+            false => 0
+        };
+        (high as u64) << 32 | low as u64
     }
 
-    pub fn set_length(&self, length: u32) {
+    /// At most `max_length`
+    pub fn set_length(&self, length: u64) {
+        assert!(length <= self.max_length());
         let mut body = self.body.borrow_mut();
-        body.data[LENGTH_OFF .. LENGTH_OFF + LENGTH_SIZE].copy_from_slice(&length.to_le_bytes());
+        body.data[LENGTH_OFF .. LENGTH_OFF + LENGTH_SIZE].copy_from_slice(&(length as u32).to_le_bytes());
+        if self.large {
+            body.data[LENGTH_HI_OFF .. LENGTH_HI_OFF + LENGTH_HI_SIZE].copy_from_slice(&((length >> 32) as u32).to_le_bytes());
+        }
         body.dirty = true;
     }
 
@@ -118,7 +1080,7 @@ impl Inode {
         let body = self.body.borrow();
         let sec = i64::from_le_bytes(body.data[ATIME_OFF .. ATIME_OFF + 8].try_into().unwrap());
         let nsec = i32::from_le_bytes(body.data[ATIME_OFF + 8 .. ATIME_OFF + 12].try_into().unwrap());
-        time::Timespec { sec: sec, nsec: nsec }
+        time::Timespec { sec, nsec }
     }
 
     pub fn set_atime(&self, atime: time::Timespec) {
@@ -132,7 +1094,7 @@ impl Inode {
         let body = self.body.borrow();
         let sec = i64::from_le_bytes(body.data[MTIME_OFF .. MTIME_OFF + 8].try_into().unwrap());
         let nsec = i32::from_le_bytes(body.data[MTIME_OFF + 8 .. MTIME_OFF + 12].try_into().unwrap());
-        time::Timespec { sec: sec, nsec: nsec }
+        time::Timespec { sec, nsec }
     }
 
     pub fn set_mtime(&self, mtime: time::Timespec) {
@@ -146,7 +1108,7 @@ impl Inode {
         let body = self.body.borrow();
         let sec = i64::from_le_bytes(body.data[CTIME_OFF .. CTIME_OFF + 8].try_into().unwrap());
         let nsec = i32::from_le_bytes(body.data[CTIME_OFF + 8 .. CTIME_OFF + 12].try_into().unwrap());
-        time::Timespec { sec: sec, nsec: nsec }
+        time::Timespec { sec, nsec }
     }
 
     pub fn set_ctime(&self, ctime: time::Timespec) {
@@ -213,55 +1175,104 @@ impl Inode {
         body.dirty = true;
     }
 
-    pub fn data_block(&self, index: usize) -> Id {
-        let body = self.body.borrow();
-        match index {
-            i if i < DIRECT_BLK_CNT => Id::from_le_bytes(
-                body.data[INDEX_OFF + index * INDEX_SIZE .. INDEX_OFF + (index + 1) * INDEX_SIZE]
-                .try_into().unwrap()
-            ),
-            i if i < DIRECT_BLK_CNT + (BLOCK_SIZE / INDEX_SIZE) => {
-                if let Some(indirect) = body.indirect {
-                    let _index = index - DIRECT_BLK_CNT;
-                    Id::from_le_bytes(indirect[_index * INDEX_SIZE .. (_index + 1) * INDEX_SIZE].try_into().unwrap())
-                } else {
-                    0
-                }
-            },
-            _ => 0
+    /// 0 for a hole, or if the data is inline
+    pub fn data_block(&self, block_mgr: &mut BlockMgr, index: usize) -> Result<Id, std::io::Error> {
+        if self.is_inline() {
+            return Ok(0)
         }
+        if self.has_extents() {
+            self.load_extents(block_mgr)?;
+            let body = self.body.borrow();
+            let extents = body.extents.as_ref().unwrap();
+            return Ok(match extents.get(extents.partition_point(|extent| extent.end() <= index)) {
+                Some(extent) if extent.index <= index => extent.id + (index - extent.index) as Id,
+                _ => 0
+            })
+        }
+        let (direct_blk_cnt, id_size, index_off) = (self.direct_blk_cnt(), self.id_size, self.index_off());
+        if index < direct_blk_cnt {
+            let body = self.body.borrow();
+            return Ok(decode_id(&body.data[index_off + index * id_size .. index_off + (index + 1) * id_size]))
+        }
+        let (depth, mut rest) = match self.locate(index) {
+            Some(location) => location,
+            None => return Ok(0)
+        };
+        let mut id = self.root(depth);
+        for level in (0 .. depth).rev() {
+            if id == 0 {
+                break
+            }
+            let span = self.ids_per_block().pow(level as u32);
+            self.load_index(block_mgr, id)?;
+            id = self.entry(id, rest / span);
+            rest %= span;
+        }
+        Ok(id)
+    }
+
+    /// Map `cnt` data blocks from `index` to consecutive blocks from `id`, or clear them if `id` is 0.
+    /// With block pointers, the first ones may have been set if it fails
+    pub fn set_data_blocks(&self, block_mgr: &mut BlockMgr, index: usize, id: Id, cnt: usize)
+                           -> Result<(), std::io::Error> {
+        if self.has_extents() {
+            return self.map_extent(block_mgr, index, id, cnt)
+        }
+        for i in 0 .. cnt {
+            self.set_data_block(block_mgr, index + i, if id == 0 { 0 } else { id + i as Id })?;
+        }
+        Ok(())
     }
 
-    /// Set data block pointer. Need to flush manually later
+    /// Set data block pointer, adding index blocks if needed. Need to flush manually later
     pub fn set_data_block(&self, block_mgr: &mut BlockMgr, index: usize, data_block: Id) -> Result<(), std::io::Error> {
-        let mut body = self.body.borrow_mut();
-        body.dirty = true;
-        match index {
-            i if i < DIRECT_BLK_CNT => {
-                body.data[INDEX_OFF + index * INDEX_SIZE .. INDEX_OFF + (index + 1) * INDEX_SIZE]
-                    .copy_from_slice(&data_block.to_le_bytes());
-                Ok(())
-            },
-            i if i < DIRECT_BLK_CNT + (BLOCK_SIZE / INDEX_SIZE) => {
-                if body.indirect.is_none() {
-                    let indirect_id = block_mgr.new_block()?;
-                    body.data[BLOCK_SIZE - INDEX_SIZE ..].copy_from_slice(&indirect_id.to_le_bytes());
-                    body.indirect = Some([0; BLOCK_SIZE]);
+        assert!(!self.is_inline());
+        if self.has_extents() {
+            return self.map_extent(block_mgr, index, data_block, 1)
+        }
+        let (direct_blk_cnt, id_size, index_off) = (self.direct_blk_cnt(), self.id_size, self.index_off());
+        if index < direct_blk_cnt {
+            let mut body = self.body.borrow_mut();
+            encode_id(&mut body.data[index_off + index * id_size .. index_off + (index + 1) * id_size], data_block);
+            body.dirty = true;
+            return Ok(())
+        }
+        let (depth, mut rest) = match self.locate(index) {
+            Some(location) => location,
+            None => return Err(std::io::Error::from_raw_os_error(libc::EFBIG))
+        };
+        let mut id = self.root(depth);
+        if id == 0 {
+            if data_block == 0 {
+                return Ok(()) // Already a hole
+            }
+            id = self.new_index_block(block_mgr)?;
+            self.set_root(depth, id);
+        }
+        for level in (1 .. depth).rev() {
+            let span = self.ids_per_block().pow(level as u32);
+            self.load_index(block_mgr, id)?;
+            let mut child = self.entry(id, rest / span);
+            if child == 0 {
+                if data_block == 0 {
+                    return Ok(())
                 }
-                let _index = index - DIRECT_BLK_CNT;
-                body.indirect.as_mut().unwrap()[_index * INDEX_SIZE .. (_index + 1) * INDEX_SIZE]
-                    .copy_from_slice(&data_block.to_le_bytes());
-                Ok(())
-            },
-            _ => Err(std::io::Error::from_raw_os_error(libc::EFBIG))
+                child = self.new_index_block(block_mgr)?;
+                self.set_entry(id, rest / span, child);
+            }
+            id = child;
+            rest %= span;
         }
+        self.load_index(block_mgr, id)?;
+        self.set_entry(id, rest, data_block);
+        Ok(())
     }
 }
 
 impl Drop for Inode {
     fn drop(&mut self) {
         let body = self.body.borrow();
-        assert!(!body.dirty); // Everything should be flushed manually
+        assert!(!body.dirty && !body.extents_dirty && body.index.values().all(|block| !block.dirty)); // Everything should be flushed manually
     }
 }
 
@@ -0,0 +1,296 @@
+use std::convert::TryInto;
+
+use super::block_io::{BlockIO, Id, BLOCK_SIZE};
+use super::block_io::checksum_block_io::crc32c;
+
+const MAGIC: [u8; 4] = [114, 102, 115, 106]; // "rfsj"
+
+const STATE_EMPTY: u32 = 0;
+const STATE_COMMITTED: u32 = 1;
+
+const HEADER_SIZE: usize = 24;
+const TAG_SIZE: usize = std::mem::size_of::<Id>();
+
+/// Most blocks one header can describe
+const MAX_TAGS: usize = (BLOCK_SIZE - HEADER_SIZE) / TAG_SIZE;
+
+/// Write-ahead redo journal in a region of the device. Layout of the region is like:
+/// [ header | copy of block 1 | copy of block 2 | ... ]
+/// and layout of the header is like:
+/// [ magic "rfsj" (4B) | state (4B) | sequence (8B) | block count (4B) | checksum of the copies (4B) |
+///   device block id of copy 1 (4B) | device block id of copy 2 (4B) | ... ]
+/// A transaction buffers every block written in it. Committing it writes the copies, then the header
+/// marked as committed, then the blocks in place, and at last marks the header empty again. If a
+/// crash happens after the header is committed, the blocks are written in place again by `replay`.
+/// Only one transaction is in the journal at a time
+pub struct Journal {
+    start: Id,
+    block_cnt: usize,
+    seq: u64,
+    depth: usize, // Nesting of begin and commit
+    txn: std::collections::BTreeMap<Id, [u8; BLOCK_SIZE]>, // Device block id -> data
+    aborted: bool, // A transaction outgrew the journal, so nothing is written until mounted again
+    replayed: std::collections::BTreeMap<Id, [u8; BLOCK_SIZE]>, // Committed blocks the read-only storage kept from being written in place
+}
+
+impl Journal {
+    /// A journal of `block_cnt` blocks starting from device block `start`
+    pub fn new(start: Id, block_cnt: usize) -> Journal {
+        assert!(block_cnt >= 2);
+        Journal {
+            start, block_cnt, seq: 0, depth: 0, txn: std::collections::BTreeMap::new(), aborted: false,
+            replayed: std::collections::BTreeMap::new()
+        }
+    }
+
+    /// Most blocks a transaction can hold. A transaction writing more blocks is aborted, see `write`
+    pub fn capacity(&self) -> usize {
+        std::cmp::min(self.block_cnt - 1, MAX_TAGS)
+    }
+
+    /// An empty journal to write when formatting
+    pub fn empty_header() -> [u8; BLOCK_SIZE] {
+        let mut header = [0; BLOCK_SIZE];
+        header[0 .. 4].copy_from_slice(&MAGIC);
+        header
+    }
+
+    fn checksum(copies: &[[u8; BLOCK_SIZE]]) -> u32 {
+        let crcs: Vec<u8> = copies.iter().flat_map(|copy| crc32c(copy).to_le_bytes()).collect();
+        crc32c(&crcs)
+    }
+
+    /// Write the blocks of a committed transaction in place, in case the last commit was interrupted.
+    /// A transaction whose header is not committed, or whose copies do not match the checksum, never
+    /// happened. If the storage is read-only, the blocks are kept in memory instead, and `read` returns
+    /// them in place of the stale ones on the storage. Returns the number of blocks replayed
+    pub fn replay(&mut self, block_io: &mut dyn BlockIO) -> Result<usize, std::io::Error> {
+        let header = block_io.read(self.start)?;
+        if header[0 .. 4] != MAGIC {
+            return Ok(0) // Never used
+        }
+        let u32_at = |off: usize| u32::from_le_bytes(header[off .. off + 4].try_into().unwrap());
+        self.seq = u64::from_le_bytes(header[8 .. 16].try_into().unwrap()) + 1;
+        if u32_at(4) != STATE_COMMITTED {
+            return Ok(0)
+        }
+        let cnt = u32_at(16) as usize;
+        if cnt > self.capacity() {
+            return Err(std::io::Error::from_raw_os_error(libc::EUCLEAN))
+        }
+        let mut copies = vec![[0; BLOCK_SIZE]; cnt];
+        for (i, copy) in copies.iter_mut().enumerate() {
+            *copy = block_io.read(self.start + 1 + i as Id)?;
+        }
+        let valid = Journal::checksum(&copies) == u32_at(20);
+        let ids: Vec<Id> = (0 .. cnt).map(|i| u32_at(HEADER_SIZE + i * TAG_SIZE)).collect();
+        let written = if valid {
+            ids.iter().zip(copies.iter()).try_for_each(|(&id, copy)| block_io.write(id, copy)).and_then(|_| block_io.flush())
+        } else {
+            Ok(())
+        };
+        match written.and_then(|_| self.write_header(block_io, STATE_EMPTY, &[], 0)) {
+            Err(err) if err.raw_os_error() == Some(libc::EROFS) => {
+                if valid {
+                    self.replayed = ids.into_iter().zip(copies).collect();
+                }
+            },
+            ret => ret?
+        }
+        Ok(if valid { cnt } else { 0 })
+    }
+
+    fn write_header(&self, block_io: &mut dyn BlockIO, state: u32, ids: &[Id], checksum: u32) -> Result<(), std::io::Error> {
+        let mut header = Journal::empty_header();
+        header[4 .. 8].copy_from_slice(&state.to_le_bytes());
+        header[8 .. 16].copy_from_slice(&self.seq.to_le_bytes());
+        header[16 .. 20].copy_from_slice(&(ids.len() as u32).to_le_bytes());
+        header[20 .. 24].copy_from_slice(&checksum.to_le_bytes());
+        for (i, id) in ids.iter().enumerate() {
+            header[HEADER_SIZE + i * TAG_SIZE .. HEADER_SIZE + (i + 1) * TAG_SIZE].copy_from_slice(&id.to_le_bytes());
+        }
+        block_io.write(self.start, &header)?;
+        block_io.flush()
+    }
+
+    /// Start a transaction, or join the running one
+    pub fn begin(&mut self) {
+        self.depth += 1;
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.depth > 0
+    }
+
+    /// Number of transactions running, the outermost one included
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Number of blocks buffered in the running transaction
+    pub fn buffered_cnt(&self) -> usize {
+        self.txn.len()
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.aborted
+    }
+
+    /// Latest data of a device block written in the running transaction, or kept by `replay`
+    pub fn read(&self, device_id: Id) -> Option<&[u8; BLOCK_SIZE]> {
+        self.txn.get(&device_id).or_else(|| self.replayed.get(&device_id))
+    }
+
+    /// Whether no block is kept in memory, so every block can be read from the storage
+    pub fn is_empty(&self) -> bool {
+        self.txn.is_empty() && self.replayed.is_empty()
+    }
+
+    /// Buffer a block in the running transaction. If it is full, committing part of it would break
+    /// its atomicity, so the transaction is dropped instead and fails with ENOSPC. The blocks changed
+    /// in memory no longer match the storage then, so every later write and commit fails with EROFS
+    /// until the volume is mounted again
+    pub fn write(&mut self, device_id: Id, data: &[u8]) -> Result<(), std::io::Error> {
+        assert!(self.in_transaction());
+        if self.aborted {
+            return Err(std::io::Error::from_raw_os_error(libc::EROFS))
+        }
+        if self.txn.len() >= self.capacity() && !self.txn.contains_key(&device_id) {
+            self.txn.clear();
+            self.aborted = true;
+            eprintln!("A transaction outgrew the journal. The volume is read-only until mounted again");
+            return Err(std::io::Error::from_raw_os_error(libc::ENOSPC))
+        }
+        let mut block = [0; BLOCK_SIZE];
+        block.copy_from_slice(data);
+        self.txn.insert(device_id, block);
+        Ok(())
+    }
+
+    /// End a transaction. The outermost one writes everything buffered atomically
+    pub fn commit(&mut self, block_io: &mut dyn BlockIO) -> Result<(), std::io::Error> {
+        assert!(self.in_transaction());
+        self.depth -= 1;
+        if self.aborted {
+            return Err(std::io::Error::from_raw_os_error(libc::EROFS))
+        }
+        if self.depth == 0 {
+            self.flush_txn(block_io)?;
+        }
+        Ok(())
+    }
+
+    /// End a transaction without writing anything. The outermost one drops everything buffered, as if
+    /// the transaction never ran. Returns whether it did
+    pub fn abort(&mut self) -> bool {
+        assert!(self.in_transaction());
+        self.depth -= 1;
+        if self.depth > 0 {
+            return false
+        }
+        self.txn.clear();
+        true
+    }
+
+    fn flush_txn(&mut self, block_io: &mut dyn BlockIO) -> Result<(), std::io::Error> {
+        if self.txn.is_empty() {
+            return Ok(())
+        }
+        let txn = std::mem::take(&mut self.txn);
+        let ids: Vec<Id> = txn.keys().cloned().collect();
+        let copies: Vec<[u8; BLOCK_SIZE]> = txn.into_values().collect();
+        for (i, copy) in copies.iter().enumerate() {
+            block_io.write(self.start + 1 + i as Id, copy)?;
+        }
+        block_io.flush()?;
+        self.write_header(block_io, STATE_COMMITTED, &ids, Journal::checksum(&copies))?;
+        for (id, copy) in ids.iter().zip(copies.iter()) {
+            block_io.write(*id, copy)?;
+        }
+        block_io.flush()?;
+        self.write_header(block_io, STATE_EMPTY, &[], 0)?; // Never replay it after the blocks are reused
+        self.seq += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::block_io::{FakeMemBlockIO, ReadOnlyBlockIO};
+
+    #[test]
+    fn test_replay_committed_only() -> Result<(), std::io::Error> {
+        let mut device = FakeMemBlockIO::new();
+        let mut journal = Journal::new(100, 4);
+        device.write(100, &Journal::empty_header())?;
+        journal.begin();
+        journal.write(1, &[1; BLOCK_SIZE])?;
+        journal.write(2, &[2; BLOCK_SIZE])?;
+        assert_eq!(journal.read(1).unwrap()[..], [1; BLOCK_SIZE][..]);
+        assert_eq!(device.read(1)?[..], [0; BLOCK_SIZE][..]);
+        journal.commit(&mut device)?;
+        assert_eq!(device.read(2)?[..], [2; BLOCK_SIZE][..]);
+        assert_eq!(journal.replay(&mut device)?, 0);
+
+        // Crashed after the header is committed but before the blocks are written in place
+        device.write(101, &[3; BLOCK_SIZE])?;
+        device.write(102, &[4; BLOCK_SIZE])?;
+        journal.write_header(&mut device, STATE_COMMITTED, &[1, 2], Journal::checksum(&[[3; BLOCK_SIZE], [4; BLOCK_SIZE]]))?;
+        assert_eq!(journal.replay(&mut device)?, 2);
+        assert_eq!(device.read(1)?[..], [3; BLOCK_SIZE][..]);
+        assert_eq!(device.read(2)?[..], [4; BLOCK_SIZE][..]);
+
+        // Copies torn before the header is committed: checksum mismatch
+        journal.write_header(&mut device, STATE_COMMITTED, &[1], Journal::checksum(&[[5; BLOCK_SIZE]]))?;
+        assert_eq!(journal.replay(&mut device)?, 0);
+        assert_eq!(device.read(1)?[..], [3; BLOCK_SIZE][..]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_read_only() -> Result<(), std::io::Error> {
+        let mut device = FakeMemBlockIO::new();
+        let journal = Journal::new(100, 4);
+        device.write(101, &[3; BLOCK_SIZE])?;
+        device.write(102, &[4; BLOCK_SIZE])?;
+        journal.write_header(&mut device, STATE_COMMITTED, &[1, 2], Journal::checksum(&[[3; BLOCK_SIZE], [4; BLOCK_SIZE]]))?;
+        let mut device = ReadOnlyBlockIO::new(Box::new(device));
+        let mut journal = Journal::new(100, 4);
+        assert_eq!(journal.replay(&mut device)?, 2);
+        assert!(!journal.is_empty());
+        assert_eq!(journal.read(1).unwrap()[..], [3; BLOCK_SIZE][..]);
+        assert_eq!(journal.read(2).unwrap()[..], [4; BLOCK_SIZE][..]);
+        assert_eq!(device.read(1)?[..], [0; BLOCK_SIZE][..]); // Still stale on the storage
+        Ok(())
+    }
+
+    #[test]
+    fn test_overflow_aborts() -> Result<(), std::io::Error> {
+        let mut device = FakeMemBlockIO::new();
+        let mut journal = Journal::new(100, 3);
+        device.write(100, &Journal::empty_header())?;
+        journal.begin();
+        journal.write(1, &[1; BLOCK_SIZE])?;
+        journal.write(2, &[2; BLOCK_SIZE])?;
+        journal.write(1, &[3; BLOCK_SIZE])?; // Already buffered
+        assert_eq!(journal.buffered_cnt(), 2);
+        assert_eq!(journal.write(3, &[3; BLOCK_SIZE]).unwrap_err().raw_os_error(), Some(libc::ENOSPC));
+        assert!(journal.is_aborted());
+        assert_eq!(journal.commit(&mut device).unwrap_err().raw_os_error(), Some(libc::EROFS));
+        // Nothing of the transaction is written, not even the part which fit
+        assert_eq!(device.read(1)?[..], [0; BLOCK_SIZE][..]);
+        assert_eq!(device.read(2)?[..], [0; BLOCK_SIZE][..]);
+        journal.begin();
+        assert_eq!(journal.write(1, &[1; BLOCK_SIZE]).unwrap_err().raw_os_error(), Some(libc::EROFS));
+
+        // Until mounted again
+        let mut journal = Journal::new(100, 3);
+        assert_eq!(journal.replay(&mut device)?, 0);
+        journal.begin();
+        journal.write(1, &[1; BLOCK_SIZE])?;
+        journal.commit(&mut device)?;
+        assert_eq!(device.read(1)?[..], [1; BLOCK_SIZE][..]);
+        Ok(())
+    }
+}
@@ -3,61 +3,107 @@ extern crate time;
 extern crate fuse;
 extern crate libc;
 
-use std::convert::TryInto;
+use std::os::unix::ffi::OsStrExt;
 use std::str::FromStr;
 
 mod file_mgr;
 use file_mgr::*;
 use block_io::*;
-use block_mgr::BlockMgr;
+use block_mgr::{BlockMgr, DEFAULT_BLOCK_CNT, decode_id, encode_id, parse_size};
 use inode::Inode;
 
-const DIR_ITEM_SIZE: usize = 64;
-const DIR_ITEM_INODE_SIZE: usize = std::mem::size_of::<Id>();
 const DIR_ITME_NAME_LEN_SIZE: usize = 1;
-const DIR_ITEM_NAME_SIZE: usize = DIR_ITEM_SIZE - DIR_ITEM_INODE_SIZE - DIR_ITME_NAME_LEN_SIZE;
-const MAX_NAME_LEN: usize = DIR_ITEM_NAME_SIZE - 1;
+
+/// Longest name, which depends on the id size of the volume
+fn max_name_len(id_size: usize) -> usize {
+    DIR_ITEM_SIZE - id_size - DIR_ITME_NAME_LEN_SIZE - 1
+}
+
+/// Extended attribute of the root directory to get or set the volume size in bytes, for resizing
+/// while mounted
+const SIZE_XATTR: &str = "user.rfs.size";
+
+/// Extended attribute of the root directory to set for discarding all free blocks, like fstrim
+const TRIM_XATTR: &str = "user.rfs.trim";
+
+/// Extended attribute of the root directory to get the number of corrupted block reads detected
+/// since mounting, with CHECKSUM
+const CORRUPTIONS_XATTR: &str = "user.rfs.corruptions";
 
 struct Rfs {
     file_mgr: Box<FileMgr>,
+    read_only: bool,
+    reserved_uid: Option<u32>, // Besides root, may allocate the reserved blocks
+    reserved_gid: Option<u32>,
+    corruption_cnts: Vec<std::sync::Arc<std::sync::atomic::AtomicU64>>, // One per checksummed storage
 }
 
 impl Rfs {
-    fn new(file_mgr: Box<FileMgr>) -> Rfs {
-        Rfs { file_mgr: file_mgr }
+    fn new(file_mgr: Box<FileMgr>, read_only: bool) -> Rfs {
+        Rfs { file_mgr, read_only, reserved_uid: None, reserved_gid: None, corruption_cnts: vec![] }
+    }
+
+    fn with_reserved_owner(mut self, uid: Option<u32>, gid: Option<u32>) -> Rfs {
+        self.reserved_uid = uid;
+        self.reserved_gid = gid;
+        self
+    }
+
+    fn with_corruption_cnts(mut self, corruption_cnts: Vec<std::sync::Arc<std::sync::atomic::AtomicU64>>) -> Rfs {
+        self.corruption_cnts = corruption_cnts;
+        self
+    }
+
+    fn corruption_cnt(&self) -> u64 {
+        self.corruption_cnts.iter().map(|cnt| cnt.load(std::sync::atomic::Ordering::Relaxed)).sum()
     }
 
     // Helper functions
 
+    fn check_writable(&self) -> Result<(), std::io::Error> {
+        if self.read_only {
+            Err(std::io::Error::from_raw_os_error(libc::EROFS))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Let the allocator know whether the caller may use the reserved blocks. Only until the end of
+    /// the operation, see `transaction`
+    fn act_as(&mut self, _req: &fuse::Request) {
+        let privileged = _req.uid() == 0 || Some(_req.uid()) == self.reserved_uid || Some(_req.gid()) == self.reserved_gid;
+        self.file_mgr.set_privileged(privileged);
+    }
+
     fn as_id(x: u64) -> Result<Id, std::io::Error> {
-        if x > Id::max_value() as u64 {
+        if x > Id::MAX as u64 {
             Err(std::io::Error::from_raw_os_error(libc::EBADF))
         } else {
             Ok(x as Id)
         }
     }
 
-    fn parse_dir_item(item: &[u8]) -> (Id, std::ffi::OsString) {
-        let ino = Id::from_le_bytes(item[.. DIR_ITEM_INODE_SIZE].try_into().unwrap());
-        let name_len = item[DIR_ITEM_INODE_SIZE] as usize;
+    fn parse_dir_item(item: &[u8], id_size: usize) -> (Id, std::ffi::OsString) {
+        let ino = decode_id(&item[.. id_size]);
+        let name_len = item[id_size] as usize;
         let name = std::str::from_utf8(&item[
-            DIR_ITEM_INODE_SIZE + DIR_ITME_NAME_LEN_SIZE .. DIR_ITEM_INODE_SIZE + DIR_ITME_NAME_LEN_SIZE + name_len
+            id_size + DIR_ITME_NAME_LEN_SIZE .. id_size + DIR_ITME_NAME_LEN_SIZE + name_len
         ]).unwrap();
         (ino, std::ffi::OsString::from(name))
     }
 
-    fn assembly_dir_itme(ino: Id, name: &std::ffi::OsStr) -> Result<[u8; DIR_ITEM_SIZE], std::io::Error> {
+    fn assembly_dir_itme(ino: Id, name: &std::ffi::OsStr, id_size: usize) -> Result<[u8; DIR_ITEM_SIZE], std::io::Error> {
         let name_str = name.to_string_lossy();
         let name_bytes = name_str.as_bytes();
-        if name_bytes.len() > MAX_NAME_LEN {
+        if name_bytes.len() > max_name_len(id_size) {
             return Err(std::io::Error::from_raw_os_error(libc::ENAMETOOLONG))
         }
         let mut ret = [0; DIR_ITEM_SIZE];
-        ret[.. DIR_ITEM_INODE_SIZE].copy_from_slice(&ino.to_le_bytes());
-        ret[DIR_ITEM_INODE_SIZE] = name_bytes.len() as u8;
+        encode_id(&mut ret[.. id_size], ino);
+        ret[id_size] = name_bytes.len() as u8;
         ret[
-            DIR_ITEM_INODE_SIZE + DIR_ITME_NAME_LEN_SIZE .. DIR_ITEM_INODE_SIZE + DIR_ITME_NAME_LEN_SIZE + name_bytes.len()
-        ].copy_from_slice(&name_bytes);
+            id_size + DIR_ITME_NAME_LEN_SIZE .. id_size + DIR_ITME_NAME_LEN_SIZE + name_bytes.len()
+        ].copy_from_slice(name_bytes);
         Ok(ret)
     }
 
@@ -82,7 +128,7 @@ impl Rfs {
             if item.is_empty() {
                 break Err(std::io::Error::from_raw_os_error(libc::ENOENT))
             }
-            let (ino, name) = Rfs::parse_dir_item(&item);
+            let (ino, name) = Rfs::parse_dir_item(&item, self.file_mgr.id_size());
             if name == _name {
                 break Ok((offset, ino))
             }
@@ -92,7 +138,7 @@ impl Rfs {
 
     fn write_dir_item(&mut self, id: Id, newparent: &Inode, _newname: &std::ffi::OsStr)
                                  -> Result<(), std::io::Error> {
-        let item = Rfs::assembly_dir_itme(id, _newname)?;
+        let item = Rfs::assembly_dir_itme(id, _newname, self.file_mgr.id_size())?;
         let end_of_file = newparent.length() as usize;
         self.file_mgr.write_file(newparent, end_of_file, &item)?;
         Ok(())
@@ -138,24 +184,67 @@ impl Rfs {
     fn check_perm(_req: &fuse::Request, inode: &Inode, _flags: u32) -> Result<(), std::io::Error> {
         let is_reading = _flags as i32 & libc::O_ACCMODE == libc::O_RDONLY || _flags as i32 & libc::O_ACCMODE == libc::O_RDWR;
         let is_writing = _flags as i32 & libc::O_ACCMODE == libc::O_WRONLY || _flags as i32 & libc::O_ACCMODE == libc::O_RDWR;
-        if !Rfs::has_read_perm(_req, &inode) && is_reading {
+        if !Rfs::has_read_perm(_req, inode) && is_reading {
             return Err(std::io::Error::from_raw_os_error(libc::EPERM))
         }
-        if !Rfs::has_write_perm(_req, &inode) && is_writing {
+        if !Rfs::has_write_perm(_req, inode) && is_writing {
             return Err(std::io::Error::from_raw_os_error(libc::EPERM))
         }
         Ok(())
     }
 
+    /// Reply the size of `value` if `size` is 0, or `value` if it fits in `size`
+    fn reply_xattr(value: &[u8], size: u32, reply: fuse::ReplyXattr) {
+        if size == 0 {
+            reply.size(value.len() as u32);
+        } else if (size as usize) < value.len() {
+            reply.error(libc::ERANGE);
+        } else {
+            reply.data(value);
+        }
+    }
+
+    /// Run an operation in one transaction, so a crash never leaves its metadata updates half done.
+    /// If it fails, what it has done so far is undone instead
+    fn transaction<T>(&mut self, op: impl FnOnce(&mut Rfs) -> Result<T, std::io::Error>) -> Result<T, std::io::Error> {
+        self.file_mgr.begin();
+        let ret = op(self);
+        self.file_mgr.set_privileged(true); // Writes of the file system itself, like the quota file, are not limited
+        match ret {
+            Ok(ret) => self.file_mgr.commit().map(|_| ret),
+            Err(err) => {
+                if let Err(abort_err) = self.file_mgr.abort() {
+                    eprintln!("Failed to undo a failed operation: {}", abort_err);
+                }
+                Err(err)
+            }
+        }
+    }
+
     // API implementations
 
     fn init_impl(&mut self, _req: &fuse::Request) -> Result<(), std::io::Error> {
         let need_format = !self.file_mgr.is_formatted()?;
+        if need_format {
+            self.check_writable()?;
+        }
         self.file_mgr.init(need_format)?;
-        let root = self.file_mgr.read_root_inode()?;
-        self.set_newly_created(_req, &root, 0o040777)?; // uid = 0, so we must give others permission
-        self.write_dir_item(root.id(), &root, &std::ffi::OsString::from("."))?;
-        self.write_dir_item(root.id(), &root, &std::ffi::OsString::from(".."))?;
+        if need_format {
+            self.transaction(|rfs| {
+                let root = rfs.file_mgr.read_root_inode()?;
+                rfs.set_newly_created(_req, &root, 0o040777)?; // uid = 0, so we must give others permission
+                rfs.write_dir_item(root.id(), &root, &std::ffi::OsString::from("."))?;
+                rfs.write_dir_item(root.id(), &root, &std::ffi::OsString::from(".."))
+            })?;
+        }
+        if self.file_mgr.inode_cnt().is_none() {
+            // Count inodes reachable from the root. Only written to the volume if mounted writable
+            let inode_cnt = self.file_mgr.inode_refs()?.len() as u64;
+            self.file_mgr.set_inode_cnt(inode_cnt);
+        }
+        if !self.read_only {
+            self.file_mgr.mount()?;
+        }
         Ok(())
     }
 
@@ -171,8 +260,8 @@ impl Rfs {
     fn getattr_impl(&mut self, _req: &fuse::Request, inode: &Inode) -> Result<fuse::FileAttr, std::io::Error> {
         Ok(fuse::FileAttr {
             ino: inode.id() as u64,
-            size: inode.length() as u64,
-            blocks: ((inode.length() as usize + BLOCK_SIZE - 1) / BLOCK_SIZE) as u64,
+            size: inode.length(),
+            blocks: if inode.is_inline() { 0 } else { (inode.length() as usize).div_ceil(BLOCK_SIZE) as u64 },
             atime: inode.atime(),
             mtime: inode.mtime(),
             ctime: inode.ctime(),
@@ -187,33 +276,41 @@ impl Rfs {
         })
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn setattr_impl(
         &mut self, _req: &fuse::Request, inode: &Inode, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>,
         _size: Option<u64>, _atime: Option<time::Timespec>, _mtime: Option<time::Timespec>, _crtime: Option<time::Timespec>,
         _chgtime: Option<time::Timespec>, _bkuptime: Option<time::Timespec>, _flags: Option<u32>
     ) -> Result<fuse::FileAttr, std::io::Error> {
+        self.check_writable()?;
+        self.act_as(_req);
         if let Some(mode) = _mode { inode.set_mode(mode as u16); }
-        if let Some(uid) = _uid { inode.set_uid(uid); }
-        if let Some(gid) = _gid { inode.set_gid(gid); }
+        if _uid.is_some() || _gid.is_some() {
+            self.file_mgr.set_owner(inode, _uid.unwrap_or(inode.uid()), _gid.unwrap_or(inode.gid()))?;
+        }
         if let Some(size) = _size { self.file_mgr.truncate_file(inode, size as usize)?; }
         if let Some(atime) = _atime { inode.set_atime(atime); }
         if let Some(mtime) = _mtime { inode.set_mtime(mtime); }
         if let Some(ctime) = _chgtime { inode.set_ctime(ctime); }
         self.file_mgr.flush(inode)?;
-        self.getattr_impl(_req, &inode)
+        self.getattr_impl(_req, inode)
     }
 
     fn link_impl(&mut self, _req: &fuse::Request, inode: &Inode, newparent: &Inode, _newname: &std::ffi::OsStr)
                  -> Result<(fuse::FileAttr, u64 /* generation */), std::io::Error> {
+        self.check_writable()?;
+        self.act_as(_req);
         inode.set_nlink(inode.nlink() + 1);
         self.file_mgr.flush(inode)?;
-        let attr = self.getattr_impl(_req, &inode)?;
+        let attr = self.getattr_impl(_req, inode)?;
         let generation = inode.generation();
         self.write_dir_item(inode.id(), newparent, _newname)?;
         Ok((attr, generation))
     }
 
     fn unlink_impl(&mut self, _req: &fuse::Request, parent: &Inode, _name: &std::ffi::OsStr) -> Result<(), std::io::Error> {
+        self.check_writable()?;
+        self.act_as(_req);
         let (offset, ino) = self.lookup_item(_req, parent, _name)?;
         let inode = self.file_mgr.read_inode(ino)?;
         if inode.kind()? == fuse::FileType::Directory && inode.length() as usize > 2 * DIR_ITEM_SIZE { // 2 = "." + ".."
@@ -233,6 +330,8 @@ impl Rfs {
 
     fn rename_impl(&mut self, _req: &fuse::Request, parent: &Inode, _name: &std::ffi::OsStr, newparent: &Inode, _newname: &std::ffi::OsStr)
                    -> Result<(), std::io::Error> {
+        self.check_writable()?;
+        self.act_as(_req);
         let (offset, ino) = self.lookup_item(_req, parent, _name)?;
         self.erase_dir_item(parent, offset)?; // This goes first, in case parent == newparent
         if let Ok((overwritten_offset, _)) = self.lookup_item(_req, newparent, _newname) {
@@ -243,7 +342,9 @@ impl Rfs {
 
     fn symlink_impl(&mut self, _req: &fuse::Request, parent: &Inode, _name: &std::ffi::OsStr, _link: &std::path::Path)
                ->Result<(fuse::FileAttr, u64 /* generation */), std::io::Error> {
-        let inode = self.file_mgr.new_inode()?;
+        self.check_writable()?;
+        self.act_as(_req);
+        let inode = self.file_mgr.new_inode_owned_by(_req.uid(), _req.gid())?;
         self.set_newly_created(_req, &inode, libc::S_IFLNK as u16 | 0o0777)?;
         let attr = self.getattr_impl(_req, &inode)?;
         let generation = inode.generation();
@@ -255,7 +356,9 @@ impl Rfs {
         // OsStr cannot be converted to bytes. Since fuse is only supported on Linux and OSX, it's
         // fine though.
         self.file_mgr.truncate_file(&inode, bytes.len())?;
-        self.file_mgr.write_file(&inode, 0, bytes)?;
+        if self.file_mgr.write_file(&inode, 0, bytes)? < bytes.len() {
+            return Err(std::io::Error::from_raw_os_error(libc::ENOSPC)) // Half a target is no target
+        }
         Ok((attr, generation))
     }
 
@@ -269,6 +372,8 @@ impl Rfs {
 
     fn write_impl(&mut self, _req: &fuse::Request, inode: &Inode, _offset: i64, _data: &[u8], _flags: u32)
                   ->Result<usize, std::io::Error> {
+        self.check_writable()?;
+        self.act_as(_req);
         if _offset < 0 {
             return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
         }
@@ -277,7 +382,9 @@ impl Rfs {
 
     fn mkdir_impl(&mut self, _req: &fuse::Request, parent: &Inode, _name: &std::ffi::OsStr, _mode: u16)
                   -> Result<(fuse::FileAttr, u64 /* generation */), std::io::Error> {
-        let inode = self.file_mgr.new_inode()?;
+        self.check_writable()?;
+        self.act_as(_req);
+        let inode = self.file_mgr.new_inode_owned_by(_req.uid(), _req.gid())?;
         self.set_newly_created(_req, &inode, libc::S_IFDIR as u16 | (0o7777 &_mode))?;
         self.write_dir_item(inode.id(), &inode, &std::ffi::OsString::from("."))?;
         self.write_dir_item(parent.id(), &inode, &std::ffi::OsString::from(".."))?;
@@ -291,9 +398,67 @@ impl Rfs {
                     -> Result<std::rc::Rc<Inode>, std::io::Error> {
         let inode = self.file_mgr.read_inode(Rfs::as_id(_ino)?)?;
         Rfs::check_perm(_req, &inode, _flags)?;
+        if _flags as i32 & libc::O_ACCMODE != libc::O_RDONLY {
+            self.check_writable()?;
+        }
         Ok(inode)
     }
 
+    /// Set extended attribute `_name` to `_value`, or remove it if None, as xattr(7) allows: user
+    /// attributes of regular files and directories by who may write them, ACLs by the owner, and
+    /// trusted and security attributes by the superuser. Fails with EOPNOTSUPP for other namespaces
+    fn setxattr_impl(&mut self, _req: &fuse::Request, _ino: u64, _name: &std::ffi::OsStr, _value: Option<&[u8]>,
+                     _flags: u32) -> Result<(), std::io::Error> {
+        let name = _name.as_bytes();
+        let inode = self.file_mgr.read_inode(Rfs::as_id(_ino)?)?;
+        if name.starts_with(b"user.") {
+            if !matches!(inode.kind()?, fuse::FileType::RegularFile | fuse::FileType::Directory) {
+                return Err(std::io::Error::from_raw_os_error(libc::EPERM))
+            }
+            Rfs::check_perm(_req, &inode, libc::O_WRONLY as u32)?;
+        } else if name == b"system.posix_acl_access" || name == b"system.posix_acl_default" {
+            if _req.uid() != 0 && _req.uid() != inode.uid() {
+                return Err(std::io::Error::from_raw_os_error(libc::EPERM))
+            }
+        } else if name.starts_with(b"trusted.") || name.starts_with(b"security.") {
+            if _req.uid() != 0 {
+                return Err(std::io::Error::from_raw_os_error(libc::EPERM))
+            }
+        } else {
+            return Err(std::io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+        }
+        self.check_writable()?;
+        self.act_as(_req);
+        match _value {
+            Some(value) => self.file_mgr.set_xattr(&inode, _name.as_bytes(), value, _flags as i32)?,
+            None => self.file_mgr.remove_xattr(&inode, _name.as_bytes())?
+        }
+        inode.set_ctime(time::now_utc().to_timespec());
+        self.file_mgr.flush(&inode)
+    }
+
+    /// Resize the volume by setting SIZE_XATTR of the root directory to the new size, like 512M. Only
+    /// the superuser can do it. Shrinking fails with EBUSY if an inode is in the way, as inodes are only
+    /// moved by rfs-resize while unmounted. Setting TRIM_XATTR to anything discards all free blocks
+    fn set_volume_xattr(&mut self, _req: &fuse::Request, _name: &std::ffi::OsStr, _value: &[u8])
+                        -> Result<(), std::io::Error> {
+        if _req.uid() != 0 {
+            return Err(std::io::Error::from_raw_os_error(libc::EPERM))
+        }
+        self.check_writable()?;
+        if _name == TRIM_XATTR {
+            return self.file_mgr.trim().map(|_| ())
+        }
+        let size = match std::str::from_utf8(_value).ok().and_then(|value| parse_size(value.trim()).ok()) {
+            Some(size) => size,
+            None => return Err(std::io::Error::from_raw_os_error(libc::EINVAL))
+        };
+        self.transaction(|rfs| {
+            rfs.act_as(_req);
+            rfs.file_mgr.resize(size / BLOCK_SIZE, false)
+        })
+    }
+
     fn readdir_impl(&mut self, _req: &fuse::Request, inode: &Inode, _offset: i64, reply: &mut fuse::ReplyDirectory)
                     -> Result<(), std::io::Error> {
         if _offset < 0 {
@@ -301,11 +466,11 @@ impl Rfs {
         }
         let mut offset = _offset as usize;
         loop {
-            let item = self.file_mgr.read_file(&inode, offset * DIR_ITEM_SIZE, DIR_ITEM_SIZE)?;
+            let item = self.file_mgr.read_file(inode, offset * DIR_ITEM_SIZE, DIR_ITEM_SIZE)?;
             if item.is_empty() {
                 break
             }
-            let (ino, name) = Rfs::parse_dir_item(&item);
+            let (ino, name) = Rfs::parse_dir_item(&item, self.file_mgr.id_size());
             let kind = self.file_mgr.read_inode(ino)?.kind()?;
             if reply.add(ino as u64, offset as i64 + 1, kind, &name) {
                 break
@@ -317,7 +482,9 @@ impl Rfs {
 
     fn create_impl(&mut self, _req: &fuse::Request, parent: &Inode, _name: &std::ffi::OsStr, _mode: u16, _flags: u32)
                    -> Result<(std::rc::Rc<Inode>, fuse::FileAttr, u64 /* generation */), std::io::Error> {
-        let inode = self.file_mgr.new_inode()?;
+        self.check_writable()?;
+        self.act_as(_req);
+        let inode = self.file_mgr.new_inode_owned_by(_req.uid(), _req.gid())?;
         self.set_newly_created(_req, &inode, libc::S_IFREG as u16 | (0o7777 &_mode))?;
         let attr = self.getattr_impl(_req, &inode)?;
         let generation = inode.generation();
@@ -335,10 +502,17 @@ impl fuse::Filesystem for Rfs {
         Ok(())
     }
 
+    fn destroy(&mut self, _req: &fuse::Request) {
+        let result = if self.read_only { self.file_mgr.sync() } else { self.file_mgr.unmount() };
+        if let Err(err) = result {
+            eprintln!("Failed to sync on unmount: {}", err);
+        }
+    }
+
     fn lookup(&mut self, _req: &fuse::Request, _parent: u64, _name: &std::ffi::OsStr, reply: fuse::ReplyEntry) {
         match (|| {
             let inode = self.open_impl(_req, _parent, libc::O_RDONLY as u32)?;
-            self.lookup_impl(_req, &*inode, _name)
+            self.lookup_impl(_req, &inode, _name)
         })() {
             Ok((attr, generation)) => reply.entry(&time::Timespec::new(0, 0), &attr, generation),
             Err(err) => reply.error(err.raw_os_error().unwrap())
@@ -348,7 +522,7 @@ impl fuse::Filesystem for Rfs {
     fn getattr(&mut self, _req: &fuse::Request, _ino: u64, reply: fuse::ReplyAttr) {
         match (|| {
             let inode = self.file_mgr.read_inode(Rfs::as_id(_ino)?)?; // No permision check?
-            self.getattr_impl(_req, &*inode)
+            self.getattr_impl(_req, &inode)
         })() {
             Ok(attr) => reply.attr(&time::Timespec::new(0, 0), &attr),
             Err(err) => reply.error(err.raw_os_error().unwrap())
@@ -361,40 +535,40 @@ impl fuse::Filesystem for Rfs {
         _crtime: Option<time::Timespec>, _chgtime: Option<time::Timespec>, _bkuptime: Option<time::Timespec>,
         _flags: Option<u32>, reply: fuse::ReplyAttr
     ) {
-        match (|| {
+        match self.transaction(|rfs| {
             let _inode;
             let inode = if let Some(fh) = _fh {
                 unsafe { &*(fh as *const Inode) }
             } else {
                 _inode = match _flags {
-                    Some(flags) => self.open_impl(_req, _ino, flags)?,
-                    None => self.file_mgr.read_inode(Rfs::as_id(_ino)?)? // No permision check?
+                    Some(flags) => rfs.open_impl(_req, _ino, flags)?,
+                    None => rfs.file_mgr.read_inode(Rfs::as_id(_ino)?)? // No permision check?
                 };
                 &_inode
             };
-            self.setattr_impl(_req, &inode, _mode, _uid, _gid, _size, _atime, _mtime, _crtime, _chgtime, _bkuptime, _flags)
-        })() {
+            rfs.setattr_impl(_req, inode, _mode, _uid, _gid, _size, _atime, _mtime, _crtime, _chgtime, _bkuptime, _flags)
+        }) {
             Ok(attr) => reply.attr(&time::Timespec::new(0, 0), &attr),
             Err(err) => reply.error(err.raw_os_error().unwrap())
         }
     }
 
     fn link(&mut self, _req: &fuse::Request, _ino: u64, _newparent: u64, _newname: &std::ffi::OsStr, reply: fuse::ReplyEntry) {
-        match (|| {
-            let newparent = self.open_impl(_req, _newparent, libc::O_WRONLY as u32)?;
-            let inode = self.file_mgr.read_inode(Rfs::as_id(_ino)?)?; // No permision check?
-            self.link_impl(_req, &inode, &newparent, _newname)
-        })() {
+        match self.transaction(|rfs| {
+            let newparent = rfs.open_impl(_req, _newparent, libc::O_WRONLY as u32)?;
+            let inode = rfs.file_mgr.read_inode(Rfs::as_id(_ino)?)?; // No permision check?
+            rfs.link_impl(_req, &inode, &newparent, _newname)
+        }) {
             Ok((attr, generation)) => reply.entry(&time::Timespec::new(0, 0), &attr, generation),
             Err(err) => reply.error(err.raw_os_error().unwrap())
         }
     }
 
     fn unlink(&mut self, _req: &fuse::Request, _parent: u64, _name: &std::ffi::OsStr, reply: fuse::ReplyEmpty) {
-        match (|| {
-            let parent = self.open_impl(_req, _parent, libc::O_WRONLY as u32)?;
-            self.unlink_impl(_req, &parent, _name)
-        })() {
+        match self.transaction(|rfs| {
+            let parent = rfs.open_impl(_req, _parent, libc::O_WRONLY as u32)?;
+            rfs.unlink_impl(_req, &parent, _name)
+        }) {
             Ok(_) => reply.ok(),
             Err(err) => reply.error(err.raw_os_error().unwrap())
         }
@@ -403,21 +577,21 @@ impl fuse::Filesystem for Rfs {
     fn rename(
         &mut self, _req: &fuse::Request, _parent: u64, _name: &std::ffi::OsStr, _newparent: u64,
         _newname: &std::ffi::OsStr, reply: fuse::ReplyEmpty) {
-        match (|| {
-            let parent = self.open_impl(_req, _parent, libc::O_WRONLY as u32)?;
-            let newparent = self.open_impl(_req, _newparent, libc::O_WRONLY as u32)?;
-            self.rename_impl(_req, &parent, _name, &newparent, _newname)
-        })() {
+        match self.transaction(|rfs| {
+            let parent = rfs.open_impl(_req, _parent, libc::O_WRONLY as u32)?;
+            let newparent = rfs.open_impl(_req, _newparent, libc::O_WRONLY as u32)?;
+            rfs.rename_impl(_req, &parent, _name, &newparent, _newname)
+        }) {
             Ok(_) => reply.ok(),
             Err(err) => reply.error(err.raw_os_error().unwrap())
         }
     }
 
     fn symlink(&mut self, _req: &fuse::Request, _parent: u64, _name: &std::ffi::OsStr, _link: &std::path::Path, reply: fuse::ReplyEntry) {
-        match (|| {
-            let parent = self.open_impl(_req, _parent, libc::O_WRONLY as u32)?;
-            self.symlink_impl(_req, &parent, _name, _link)
-        })() {
+        match self.transaction(|rfs| {
+            let parent = rfs.open_impl(_req, _parent, libc::O_WRONLY as u32)?;
+            rfs.symlink_impl(_req, &parent, _name, _link)
+        }) {
             Ok((attr, generation)) => reply.entry(&time::Timespec::new(0, 0), &attr, generation),
             Err(err) => reply.error(err.raw_os_error().unwrap())
         }
@@ -426,7 +600,7 @@ impl fuse::Filesystem for Rfs {
     fn readlink(&mut self, _req: &fuse::Request, _ino: u64, reply: fuse::ReplyData) {
         match (|| {
             let inode = self.open_impl(_req, _ino, libc::O_RDONLY as u32)?;
-            let len = inode.length();
+            let len = inode.length() as u32; // Symlink targets are short
             self.read_impl(_req, &inode, 0, len)
         })() {
             Ok(data) => reply.data(&data[..]),
@@ -443,7 +617,7 @@ impl fuse::Filesystem for Rfs {
 
     fn read(&mut self, _req: &fuse::Request, _ino: u64, _fh: u64, _offset: i64, _size: u32, reply: fuse::ReplyData) {
         let inode = unsafe { &*(_fh as *const Inode) };
-        match self.read_impl(_req, &inode, _offset, _size) {
+        match self.read_impl(_req, inode, _offset, _size) {
             Ok(data) => reply.data(&data[..]),
             Err(err) => reply.error(err.raw_os_error().unwrap())
         }
@@ -454,7 +628,7 @@ impl fuse::Filesystem for Rfs {
         reply: fuse::ReplyWrite
     ) {
         let inode = unsafe { &*(_fh as *const Inode) };
-        match self.write_impl(_req, inode, _offset, _data, _flags) {
+        match self.transaction(|rfs| rfs.write_impl(_req, inode, _offset, _data, _flags)) {
             Ok(size) => reply.written(size as u32),
             Err(err) => reply.error(err.raw_os_error().unwrap())
         }
@@ -473,24 +647,27 @@ impl fuse::Filesystem for Rfs {
     }
 
     fn fsync(&mut self, _req: &fuse::Request, _ino: u64, _fh: u64, _datasync: bool, reply: fuse::ReplyEmpty) {
-        reply.ok();
+        match self.file_mgr.sync() {
+            Ok(_) => reply.ok(),
+            Err(err) => reply.error(err.raw_os_error().unwrap())
+        }
     }
 
     fn mkdir(&mut self, _req: &fuse::Request, _parent: u64, _name: &std::ffi::OsStr, _mode: u32, reply: fuse::ReplyEntry) {
-        match (|| {
-            let parent = self.open_impl(_req, _parent, libc::O_WRONLY as u32)?;
-            self.mkdir_impl(_req, &parent, _name, _mode as u16)
-        })() {
+        match self.transaction(|rfs| {
+            let parent = rfs.open_impl(_req, _parent, libc::O_WRONLY as u32)?;
+            rfs.mkdir_impl(_req, &parent, _name, _mode as u16)
+        }) {
             Ok((attr, generation)) => reply.entry(&time::Timespec::new(0, 0), &attr, generation),
             Err(err) => reply.error(err.raw_os_error().unwrap())
         }
     }
 
     fn rmdir(&mut self, _req: &fuse::Request, _parent: u64, _name: &std::ffi::OsStr, reply: fuse::ReplyEmpty) {
-        match (|| {
-            let parent = self.open_impl(_req, _parent, libc::O_WRONLY as u32)?;
-            self.unlink_impl(_req, &parent, _name)
-        })() {
+        match self.transaction(|rfs| {
+            let parent = rfs.open_impl(_req, _parent, libc::O_WRONLY as u32)?;
+            rfs.unlink_impl(_req, &parent, _name)
+        }) {
             Ok(_) => reply.ok(),
             Err(err) => reply.error(err.raw_os_error().unwrap())
         }
@@ -519,16 +696,19 @@ impl fuse::Filesystem for Rfs {
     }
 
     fn fsyncdir(&mut self, _req: &fuse::Request, _ino: u64, _fh: u64, _datasync: bool, reply: fuse::ReplyEmpty) {
-        reply.ok();
+        match self.file_mgr.sync() {
+            Ok(_) => reply.ok(),
+            Err(err) => reply.error(err.raw_os_error().unwrap())
+        }
     }
 
     fn create(
         &mut self, _req: &fuse::Request, _parent: u64, _name: &std::ffi::OsStr, _mode: u32, _flags: u32, reply: fuse::ReplyCreate
     ) {
-        match (|| {
-            let parent = self.open_impl(_req, _parent, libc::O_WRONLY as u32)?;
-            self.create_impl(_req, &parent, _name, _mode as u16, _flags)
-        })() {
+        match self.transaction(|rfs| {
+            let parent = rfs.open_impl(_req, _parent, libc::O_WRONLY as u32)?;
+            rfs.create_impl(_req, &parent, _name, _mode as u16, _flags)
+        }) {
             Ok((inode, attr, generation)) => reply.created(
                 &time::Timespec::new(0, 0), &attr, generation, std::rc::Rc::into_raw(inode) as u64, _flags
             ),
@@ -536,13 +716,85 @@ impl fuse::Filesystem for Rfs {
         }
     }
 
-    fn listxattr(&mut self, _req: &fuse::Request, _ino: u64, _size: u32, reply: fuse::ReplyXattr) {
-        if _size == 0 {
-            reply.size(0);
+    fn statfs(&mut self, _req: &fuse::Request, _ino: u64, reply: fuse::ReplyStatfs) {
+        let free_block_cnt = self.file_mgr.free_block_cnt() as u64;
+        let avail_block_cnt = self.file_mgr.avail_block_cnt() as u64;
+        let free_inode_cnt = self.file_mgr.free_inode_cnt() as u64;
+        let inode_cnt = self.file_mgr.inode_cnt().unwrap_or(0);
+        reply.statfs(
+            self.file_mgr.block_cnt() as u64, free_block_cnt, avail_block_cnt, inode_cnt + free_inode_cnt, free_inode_cnt,
+            BLOCK_SIZE as u32, max_name_len(self.file_mgr.id_size()) as u32, BLOCK_SIZE as u32
+        );
+    }
+
+    /// SIZE_XATTR of the root directory is the volume size in bytes, and CORRUPTIONS_XATTR the number of
+    /// corrupted block reads so far
+    fn getxattr(&mut self, _req: &fuse::Request, _ino: u64, _name: &std::ffi::OsStr, _size: u32, reply: fuse::ReplyXattr) {
+        if _ino == fuse::FUSE_ROOT_ID && _name == SIZE_XATTR {
+            let value = (self.file_mgr.block_cnt() * BLOCK_SIZE).to_string();
+            return Rfs::reply_xattr(value.as_bytes(), _size, reply)
+        }
+        if _ino == fuse::FUSE_ROOT_ID && _name == CORRUPTIONS_XATTR && !self.corruption_cnts.is_empty() {
+            let value = self.corruption_cnt().to_string();
+            return Rfs::reply_xattr(value.as_bytes(), _size, reply)
+        }
+        match self.open_impl(_req, _ino, libc::O_RDONLY as u32)
+            .and_then(|inode| self.file_mgr.get_xattr(&inode, _name.as_bytes())) {
+            Ok(value) => Rfs::reply_xattr(&value, _size, reply),
+            Err(err) => reply.error(err.raw_os_error().unwrap_or(libc::EIO))
+        }
+    }
+
+    /// SIZE_XATTR and TRIM_XATTR of the root directory resize the volume and discard its free blocks
+    /// rather than being kept
+    fn setxattr(
+        &mut self, _req: &fuse::Request, _ino: u64, _name: &std::ffi::OsStr, _value: &[u8], _flags: u32, _position: u32,
+        reply: fuse::ReplyEmpty
+    ) {
+        let ret = if _ino == fuse::FUSE_ROOT_ID && (_name == SIZE_XATTR || _name == TRIM_XATTR) {
+            self.set_volume_xattr(_req, _name, _value)
         } else {
-            reply.data(&[]);
+            self.transaction(|rfs| rfs.setxattr_impl(_req, _ino, _name, Some(_value), _flags))
+        };
+        match ret {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err.raw_os_error().unwrap_or(libc::EIO))
+        }
+    }
+
+    /// Names are each followed by a NUL
+    fn listxattr(&mut self, _req: &fuse::Request, _ino: u64, _size: u32, reply: fuse::ReplyXattr) {
+        match self.open_impl(_req, _ino, libc::O_RDONLY as u32).and_then(|inode| self.file_mgr.list_xattrs(&inode)) {
+            Ok(names) => {
+                let value: Vec<u8> = names.into_iter().flat_map(|mut name| { name.push(0); name }).collect();
+                Rfs::reply_xattr(&value, _size, reply)
+            },
+            Err(err) => reply.error(err.raw_os_error().unwrap_or(libc::EIO))
         }
     }
+
+    fn removexattr(&mut self, _req: &fuse::Request, _ino: u64, _name: &std::ffi::OsStr, reply: fuse::ReplyEmpty) {
+        match self.transaction(|rfs| rfs.setxattr_impl(_req, _ino, _name, None, 0)) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err.raw_os_error().unwrap_or(libc::EIO))
+        }
+    }
+}
+
+/// Whether `-o name` or `-o ...,name,...` is among the FUSE options
+fn has_mount_option(options: &[&std::ffi::OsStr], name: &str) -> bool {
+    let mut values = vec![];
+    for (i, option) in options.iter().enumerate() {
+        let option = option.to_string_lossy();
+        if option == "-o" {
+            if let Some(value) = options.get(i + 1) {
+                values.push(value.to_string_lossy().into_owned());
+            }
+        } else if let Some(value) = option.strip_prefix("-o") {
+            values.push(String::from(value));
+        }
+    }
+    values.iter().any(|value| value.split(',').any(|item| item == name))
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -556,28 +808,172 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         println!("Environment variables:");
         println!(" RUST_LOG : Verbose log");
         println!(" STORAGE_DIR=<any directory> : Location to store the filesystem content. Default to /tmp/rfs");
+        println!(" IMAGE_FILE=<any file> : Store the filesystem content in a single image file instead of STORAGE_DIR");
+        println!(" NBD_SERVER=<host:port | unix:path> : Store the filesystem content on an NBD export instead of STORAGE_DIR");
+        println!(" NBD_EXPORT=<name> : Name of the NBD export. Default to the server's default export");
+        println!(" MIRRORS=<path>,<path>,... : Mirror the filesystem content to several directories or image files (RAID1) instead of STORAGE_DIR");
+        println!(" OVERLAY=<any directory | memory> : Never modify the storage, and redirect writes to a delta directory or to memory. Use rfs-overlay to commit or discard a delta directory");
+        println!(" VOLUME_SIZE=<n>[K|M|G|T] : Size of the filesystem content when formatting. Default to 128M");
+        println!(" VOLUME_LABEL=<label> : Label of the filesystem when formatting, up to 64 bytes. Use rfs-info to show it");
+        println!(" RESERVED_PERCENT=<n> : Keep n% of the blocks for root, so it can clean up when others have filled the volume. Default to 0");
+        println!(" RESERVED_UID=<uid> : Let this user use the reserved blocks as well as root");
+        println!(" RESERVED_GID=<gid> : Let this group use the reserved blocks as well as root");
+        println!(" DISCARD=<n> : Tell the storage about freed blocks in batches of n, so it can release their space. Default to never");
+        println!(" CACHE_BLOCKS=<n> : Cache up to n blocks in memory and write them back lazily. Default to no cache");
+        println!(" CHECKSUM : Keep a CRC32C for every block and fail reads of corrupted blocks with EIO");
+        println!(" TRACE_FILE=<any file> : Record every block read and write to a binary trace");
+        println!(" TRACE_DATA : Also record the written data in the trace, so it can be replayed by rfs-replay");
+        println!(" READ_ONLY : Mount read-only, the same as -o ro");
         println!(" FAKE_STORAGE : Do not store content to STORAGE_DIR and use memory only. This is for debug purpose");
+        println!("Resize while mounted with `setfattr -n {} -v <n>[K|M|G|T] mount_point` as root, or with rfs-resize while unmounted", SIZE_XATTR);
+        println!("Discard all free blocks while mounted with `setfattr -n {} -v 1 mount_point` as root, or with rfs-trim while unmounted", TRIM_XATTR);
+        println!("Get the number of corrupted block reads so far with `getfattr -n {} mount_point`, with CHECKSUM", CORRUPTIONS_XATTR);
         std::process::exit(-1);
     }
 
     let mut fake_storage = false;
     let mut storage_path = std::path::PathBuf::from_str("/tmp/rfs")?;
+    let mut image_path = None;
+    let mut nbd_server = None;
+    let mut nbd_export = String::new();
+    let mut mirror_paths = vec![];
+    let mut overlay = None;
+    let mut volume_size = DEFAULT_BLOCK_CNT * BLOCK_SIZE;
+    let mut volume_label = String::new();
+    let mut reserved_percent = 0;
+    let mut reserved_uid = None;
+    let mut reserved_gid = None;
+    let mut discard_batch = None;
+    let mut cache_blocks = 0;
+    let mut checksum = false;
+    let mut trace_path = None;
+    let mut trace_data = false;
+    let mut read_only = false;
     for (key, value) in std::env::vars() {
         match key.as_ref() {
             "STORAGE_DIR" => storage_path = std::path::PathBuf::from_str(&value)?,
+            "IMAGE_FILE" => image_path = Some(std::path::PathBuf::from_str(&value)?),
+            "NBD_SERVER" => nbd_server = Some(value),
+            "NBD_EXPORT" => nbd_export = value,
+            "MIRRORS" => mirror_paths = value.split(',').map(std::path::PathBuf::from).collect(),
+            "OVERLAY" => overlay = Some(value),
+            "VOLUME_SIZE" => volume_size = parse_size(&value)?,
+            "VOLUME_LABEL" => volume_label = value,
+            "RESERVED_PERCENT" => reserved_percent = usize::from_str(&value)?,
+            "RESERVED_UID" => reserved_uid = Some(u32::from_str(&value)?),
+            "RESERVED_GID" => reserved_gid = Some(u32::from_str(&value)?),
+            "DISCARD" => discard_batch = Some(usize::from_str(&value)?),
+            "CACHE_BLOCKS" => cache_blocks = usize::from_str(&value)?,
+            "CHECKSUM" => checksum = true,
+            "TRACE_FILE" => trace_path = Some(std::path::PathBuf::from_str(&value)?),
+            "TRACE_DATA" => trace_data = true,
+            "READ_ONLY" => read_only = true,
             "FAKE_STORAGE" => fake_storage = true,
             _ => ()
         }
     }
 
-    let block_io: Box<dyn BlockIO> = if fake_storage {
-        Box::new(FakeMemBlockIO::new())
+    if reserved_percent > 100 {
+        eprintln!("RESERVED_PERCENT must be at most 100");
+        std::process::exit(-1);
+    }
+    if discard_batch == Some(0) {
+        eprintln!("DISCARD must be at least 1");
+        std::process::exit(-1);
+    }
+
+    let mut options = argv_ref[2 ..].to_vec();
+    if has_mount_option(&options, "ro") {
+        read_only = true;
+    } else if read_only {
+        options.extend_from_slice(&[std::ffi::OsStr::new("-o"), std::ffi::OsStr::new("ro")]); // Let the kernel know
+    }
+
+    // Only used when formatting. Existing volumes keep their own size
+    let format_block_cnt = volume_size / BLOCK_SIZE;
+    let with_checksum_cnt = |device_block_cnt: usize| if checksum {
+        device_block_cnt + ChecksumBlockIO::checksum_block_cnt(device_block_cnt)
     } else {
-        Box::new(FileBlockIO::new(storage_path)?)
+        device_block_cnt
+    };
+    let format_device_block_cnt = with_checksum_cnt(block_mgr::device_block_cnt(format_block_cnt));
+    // With mirrors, every mirror gets its own checksums, so a corrupted copy can be repaired from
+    // another one
+    let mut corruption_cnts = vec![];
+    let mut with_checksum = |block_io: Box<dyn BlockIO>, device_block_cnt: usize| -> Box<dyn BlockIO> {
+        if checksum {
+            let checksum_block_io = ChecksumBlockIO::new(block_io, device_block_cnt);
+            corruption_cnts.push(checksum_block_io.corruption_cnt());
+            Box::new(checksum_block_io)
+        } else {
+            block_io
+        }
     };
-    let block_mgr = Box::new(BlockMgr::new(block_io));
+    let mut repaired_cnt = None;
+    let mut block_io: Box<dyn BlockIO> = if !mirror_paths.is_empty() {
+        if overlay.is_some() {
+            eprintln!("OVERLAY cannot be used together with MIRRORS");
+            std::process::exit(-1);
+        }
+        let mut raw_children = vec![];
+        for path in mirror_paths {
+            raw_children.push(open_path(path, format_device_block_cnt)?);
+        }
+        let device_block_cnt = block_mgr::probe_device_block_cnt(&mut *raw_children[0])?
+            .unwrap_or(block_mgr::device_block_cnt(format_block_cnt));
+        let children = raw_children.into_iter().map(|child| with_checksum(child, device_block_cnt)).collect();
+        let mirror_block_io = MirrorBlockIO::new(children);
+        repaired_cnt = Some(mirror_block_io.repaired_cnt());
+        Box::new(mirror_block_io)
+    } else {
+        let mut base: Box<dyn BlockIO> = if fake_storage {
+            Box::new(FakeMemBlockIO::new())
+        } else if let Some(nbd_server) = nbd_server {
+            let nbd_block_io = NbdBlockIO::connect(&nbd_server, &nbd_export)?;
+            if nbd_block_io.size() < (format_device_block_cnt * BLOCK_SIZE) as u64 {
+                eprintln!("Warning: the NBD export is smaller than {} bytes. Writes beyond it will fail",
+                          format_device_block_cnt * BLOCK_SIZE);
+            }
+            Box::new(nbd_block_io)
+        } else if let Some(image_path) = image_path {
+            Box::new(ImageBlockIO::new(image_path, format_device_block_cnt)?)
+        } else {
+            Box::new(FileBlockIO::new(storage_path)?)
+        };
+        // Below the checksums, so the checksum blocks are redirected to the delta as well
+        if let Some(overlay) = overlay {
+            let delta_dir = if overlay == "memory" { None } else { Some(std::path::PathBuf::from(overlay)) };
+            base = Box::new(OverlayBlockIO::new(base, delta_dir)?);
+        }
+        let device_block_cnt = block_mgr::probe_device_block_cnt(&mut *base)?
+            .unwrap_or(block_mgr::device_block_cnt(format_block_cnt));
+        with_checksum(base, device_block_cnt)
+    };
+    if cache_blocks > 0 {
+        block_io = Box::new(CachedBlockIO::new(block_io, cache_blocks));
+    }
+    if let Some(trace_path) = trace_path {
+        let writer = Box::new(std::io::BufWriter::new(std::fs::File::create(trace_path)?));
+        block_io = Box::new(TraceBlockIO::new(block_io, writer, true, trace_data)?);
+    }
+    if read_only {
+        block_io = Box::new(ReadOnlyBlockIO::new(block_io));
+    }
+    let mut block_mgr = Box::new(BlockMgr::with_block_cnt(block_io, format_block_cnt));
+    block_mgr.set_format_label(&volume_label);
+    block_mgr.set_reserved_percent(reserved_percent);
+    block_mgr.set_discard_batch(discard_batch);
     let file_mgr = Box::new(FileMgr::new(block_mgr));
-    fuse::mount(Rfs::new(file_mgr), &argv_ref[1], &argv_ref[2 ..])?;
+    let rfs = Rfs::new(file_mgr, read_only).with_reserved_owner(reserved_uid, reserved_gid)
+        .with_corruption_cnts(corruption_cnts.clone());
+    fuse::mount(rfs, &argv_ref[1], &options)?;
+    if checksum {
+        let cnt: u64 = corruption_cnts.iter().map(|cnt| cnt.load(std::sync::atomic::Ordering::Relaxed)).sum();
+        println!("{} corrupted block reads detected", cnt);
+    }
+    if let Some(cnt) = repaired_cnt {
+        println!("{} blocks repaired from other mirrors", cnt.load(std::sync::atomic::Ordering::Relaxed));
+    }
     Ok(())
 }
 
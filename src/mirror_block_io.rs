@@ -0,0 +1,342 @@
+use std::convert::TryInto;
+
+use super::{BlockIO, Id, BLOCK_SIZE};
+
+/// Where each child keeps its generation: the last bytes of block 0, which the super block leaves
+/// unused
+const GENERATION_OFF: usize = BLOCK_SIZE - std::mem::size_of::<u64>();
+
+/// Mirror every write to all children (RAID1). Reads are served by the first healthy child, and
+/// children failing a read are repaired from the good copy. Wrap the children in ChecksumBlockIO
+/// to also repair silent corruptions. A child failing a write is out of sync, and is no longer
+/// used until `resync`.
+///
+/// Each child has a generation, which is raised on the healthy children whenever one fails. When
+/// opened again, children behind the latest generation are out of sync as well
+pub struct MirrorBlockIO {
+    children: Vec<Box<dyn BlockIO>>,
+    failed: Vec<bool>,
+    generation: u64,
+    repaired_cnt: std::sync::Arc<std::sync::atomic::AtomicU64>,
+}
+
+impl MirrorBlockIO {
+    pub fn new(mut children: Vec<Box<dyn BlockIO>>) -> MirrorBlockIO {
+        assert!(!children.is_empty());
+        let generations: Vec<Option<u64>> = children.iter_mut().map(|child| child.read(0).ok().map(|block| {
+            u64::from_le_bytes(block[GENERATION_OFF ..].try_into().unwrap())
+        })).collect();
+        let generation = generations.iter().flatten().copied().max().unwrap_or(0);
+        let failed: Vec<bool> = generations.iter().map(|&child| child != Some(generation)).collect();
+        for (i, _) in failed.iter().enumerate().filter(|(_, &failed)| failed) {
+            eprintln!("Mirror {} is out of sync. Resync it to use it again", i);
+        }
+        MirrorBlockIO {
+            children,
+            failed,
+            generation: std::cmp::max(generation, 1), // So a blank replacement is behind
+            repaired_cnt: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        }
+    }
+
+    /// Counter of blocks rewritten by read repair, which can be polled from other threads
+    pub fn repaired_cnt(&self) -> std::sync::Arc<std::sync::atomic::AtomicU64> {
+        self.repaired_cnt.clone()
+    }
+
+    fn fail(&mut self, index: usize, err: &std::io::Error) {
+        eprintln!("Mirror {} failed ({}). Resync it to use it again", index, err);
+        self.failed[index] = true;
+        // Before the failed write is reported done, so the failed child is never trusted again
+        while self.failed.iter().any(|&failed| !failed) && self.raise_generation().is_err() {}
+    }
+
+    /// Write a new generation to the healthy children. Children failing it are out of sync too
+    fn raise_generation(&mut self) -> Result<(), ()> {
+        let mut block = match self.read_and_repair(0, None) {
+            Ok(block) => block,
+            Err(_) => return Ok(()) // Nothing readable left to mark
+        };
+        self.generation += 1;
+        block[GENERATION_OFF ..].copy_from_slice(&self.generation.to_le_bytes());
+        let mut ret = Ok(());
+        for i in 0 .. self.children.len() {
+            if self.failed[i] {
+                continue
+            }
+            if let Err(err) = self.children[i].write(0, &block).and_then(|_| self.children[i].flush()) {
+                eprintln!("Mirror {} failed ({}). Resync it to use it again", i, err);
+                self.failed[i] = true;
+                ret = Err(());
+            }
+        }
+        ret
+    }
+
+    /// Block 0 as it should be written to each child, with the generation
+    fn with_generation(&self, block_id: Id, data: &[u8]) -> Option<[u8; BLOCK_SIZE]> {
+        if block_id != 0 {
+            return None
+        }
+        let mut block = [0; BLOCK_SIZE];
+        block.copy_from_slice(data);
+        block[GENERATION_OFF ..].copy_from_slice(&self.generation.to_le_bytes());
+        Some(block)
+    }
+
+    /// Fails with EIO once no child is healthy, so writes are not silently dropped
+    fn check_healthy(&self) -> Result<(), std::io::Error> {
+        if self.failed.iter().all(|&failed| failed) {
+            return Err(std::io::Error::from_raw_os_error(libc::EIO))
+        }
+        Ok(())
+    }
+
+    /// Read from the first healthy child other than `except`, and repair the children which failed
+    fn read_and_repair(&mut self, block_id: Id, except: Option<usize>) -> Result<[u8; BLOCK_SIZE], std::io::Error> {
+        let mut bad: Vec<usize> = vec![];
+        let mut last_err = None;
+        for i in 0 .. self.children.len() {
+            if self.failed[i] || except == Some(i) {
+                continue
+            }
+            match self.children[i].read(block_id) {
+                Ok(data) => {
+                    for j in bad {
+                        if let Err(err) = self.children[j].write(block_id, &data) {
+                            self.fail(j, &err);
+                        } else {
+                            self.repaired_cnt.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        }
+                    }
+                    return Ok(data)
+                },
+                Err(err) => {
+                    bad.push(i);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| std::io::Error::from_raw_os_error(libc::EIO)))
+    }
+
+    /// Rebuild child `target`, e.g. a replaced disk, from the healthy ones. It only gets the current
+    /// generation once everything else is copied, so an interrupted resync can be resumed
+    #[allow(dead_code)] // Only used by rfs-resync
+    pub fn resync(&mut self, target: usize, block_cnt: usize) -> Result<(), std::io::Error> {
+        self.failed[target] = true;
+        for block_id in 1 .. block_cnt {
+            let data = self.read_and_repair(block_id as Id, Some(target))?;
+            let stale = self.children[target].read(block_id as Id);
+            if stale.map(|stale| stale[..] != data[..]).unwrap_or(true) {
+                self.children[target].write(block_id as Id, &data)?;
+            }
+        }
+        self.children[target].flush()?;
+        let data = self.read_and_repair(0, Some(target))?;
+        let block = self.with_generation(0, &data).unwrap();
+        self.children[target].write(0, &block)?;
+        self.children[target].flush()?;
+        self.failed[target] = false;
+        Ok(())
+    }
+}
+
+impl BlockIO for MirrorBlockIO {
+    fn read(&mut self, block_id: Id) -> Result<[u8; BLOCK_SIZE], std::io::Error> {
+        self.read_and_repair(block_id, None)
+    }
+
+    fn write(&mut self, block_id: Id, data: &[u8]) -> Result<(), std::io::Error> {
+        self.check_healthy()?;
+        let mut last_err = None;
+        for i in 0 .. self.children.len() {
+            if self.failed[i] {
+                continue
+            }
+            // The generation may be raised by a failure in this loop, so stamp it for each child
+            let block = self.with_generation(block_id, data);
+            if let Err(err) = self.children[i].write(block_id, block.as_ref().map_or(data, |block| &block[..])) {
+                self.fail(i, &err);
+                last_err = Some(err);
+            }
+        }
+        match last_err {
+            Some(err) if self.failed.iter().all(|&failed| failed) => Err(err),
+            _ => Ok(())
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.check_healthy()?;
+        let mut last_err = None;
+        for i in 0 .. self.children.len() {
+            if self.failed[i] {
+                continue
+            }
+            if let Err(err) = self.children[i].flush() {
+                self.fail(i, &err);
+                last_err = Some(err);
+            }
+        }
+        match last_err {
+            Some(err) if self.failed.iter().all(|&failed| failed) => Err(err),
+            _ => Ok(())
+        }
+    }
+
+    fn discard(&mut self, block_id: Id, cnt: usize) -> Result<(), std::io::Error> {
+        self.check_healthy()?;
+        let mut last_err = None;
+        for i in 0 .. self.children.len() {
+            if self.failed[i] {
+                continue
+            }
+            if let Err(err) = self.children[i].discard(block_id, cnt) {
+                self.fail(i, &err);
+                last_err = Some(err);
+            }
+        }
+        match last_err {
+            Some(err) if self.failed.iter().all(|&failed| failed) => Err(err),
+            _ => Ok(())
+        }
+    }
+
+    /// The children are stacked alike, each with its own copy of the tail
+    fn tail_block_cnt(&self, block_cnt: usize) -> usize {
+        self.children[0].tail_block_cnt(block_cnt)
+    }
+
+    fn resize(&mut self, block_cnt: usize) -> Result<(), std::io::Error> {
+        self.check_healthy()?;
+        let mut last_err = None;
+        for i in 0 .. self.children.len() {
+            if self.failed[i] {
+                continue
+            }
+            if let Err(err) = self.children[i].resize(block_cnt) {
+                self.fail(i, &err);
+                last_err = Some(err);
+            }
+        }
+        match last_err {
+            Some(err) if self.failed.iter().all(|&failed| failed) => Err(err),
+            _ => Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{ChecksumBlockIO, FakeMemBlockIO, ImageBlockIO};
+    use super::super::fault_block_io::FaultBlockIO;
+
+    fn temp_image(name: &str) -> std::path::PathBuf {
+        let mut path = std::env::temp_dir();
+        path.push(format!("rfs-test-mirror-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_read_repair() -> Result<(), std::io::Error> {
+        let path = temp_image("repair");
+        let block_cnt = 4;
+        let device_cnt = block_cnt + ChecksumBlockIO::checksum_block_cnt(block_cnt);
+        let child0 = ChecksumBlockIO::new(Box::new(ImageBlockIO::new(path.clone(), device_cnt)?), block_cnt);
+        let mut block_io = MirrorBlockIO::new(vec![Box::new(child0), Box::new(FakeMemBlockIO::new())]);
+        let repaired_cnt = block_io.repaired_cnt();
+        block_io.write(1, &[1; BLOCK_SIZE])?;
+
+        // Silently corrupt the first mirror
+        let mut raw = ImageBlockIO::new(path.clone(), device_cnt)?;
+        raw.write(1, &[7; BLOCK_SIZE])?;
+
+        assert_eq!(block_io.read(1)?[..], [1; BLOCK_SIZE][..]);
+        assert_eq!(repaired_cnt.load(std::sync::atomic::Ordering::Relaxed), 1);
+        assert_eq!(raw.read(1)?[..], [1; BLOCK_SIZE][..]);
+        assert_eq!(block_io.read(1)?[..], [1; BLOCK_SIZE][..]);
+        assert_eq!(repaired_cnt.load(std::sync::atomic::Ordering::Relaxed), 1);
+        std::fs::remove_file(&path)
+    }
+
+    #[test]
+    fn test_degrade_and_resync() -> Result<(), std::io::Error> {
+        let path = temp_image("resync");
+        let child0 = FaultBlockIO::new(Box::new(FakeMemBlockIO::new()));
+        let plan = child0.plan();
+        let child1 = ImageBlockIO::new(path.clone(), 4)?;
+        let mut block_io = MirrorBlockIO::new(vec![Box::new(child0), Box::new(child1)]);
+        block_io.write(1, &[1; BLOCK_SIZE])?;
+
+        plan.borrow_mut().fail_write(1, libc::EIO);
+        block_io.write(1, &[2; BLOCK_SIZE])?; // The first mirror is out of sync from now on
+        block_io.write(3, &[3; BLOCK_SIZE])?;
+        assert_eq!(block_io.read(1)?[..], [2; BLOCK_SIZE][..]);
+
+        // Pretend the second mirror is replaced by an empty disk, and rebuild it
+        std::fs::remove_file(&path)?;
+        let mut healthy = FakeMemBlockIO::new();
+        healthy.write(2, &[2; BLOCK_SIZE])?;
+        let mut block_io = MirrorBlockIO::new(vec![Box::new(healthy), Box::new(ImageBlockIO::new(path.clone(), 4)?)]);
+        block_io.resync(1, 4)?;
+        let mut raw = ImageBlockIO::new(path.clone(), 4)?;
+        assert_eq!(raw.read(2)?[..], [2; BLOCK_SIZE][..]);
+        assert_eq!(raw.read(3)?[..], [0; BLOCK_SIZE][..]);
+        std::fs::remove_file(&path)
+    }
+
+    #[test]
+    fn test_all_failed() -> Result<(), std::io::Error> {
+        let children: Vec<FaultBlockIO> = (0 .. 2).map(|_| FaultBlockIO::new(Box::new(FakeMemBlockIO::new()))).collect();
+        let plans: Vec<_> = children.iter().map(|child| child.plan()).collect();
+        let mut block_io = MirrorBlockIO::new(children.into_iter().map(|child| Box::new(child) as Box<dyn BlockIO>).collect());
+        for plan in plans.iter() {
+            plan.borrow_mut().fail_write(1, libc::EIO);
+        }
+        assert!(block_io.write(1, &[1; BLOCK_SIZE]).is_err());
+        // Nothing is left to write to, which must not look like a success
+        let err = block_io.write(2, &[2; BLOCK_SIZE]).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        assert_eq!(block_io.flush().unwrap_err().raw_os_error(), Some(libc::EIO));
+        assert_eq!(block_io.discard(2, 1).unwrap_err().raw_os_error(), Some(libc::EIO));
+        Ok(())
+    }
+
+    #[test]
+    fn test_degraded_after_reopen() -> Result<(), std::io::Error> {
+        let paths = [temp_image("reopen0"), temp_image("reopen1")];
+        let child0 = FaultBlockIO::new(Box::new(ImageBlockIO::new(paths[0].clone(), 4)?));
+        let plan = child0.plan();
+        let child1 = ImageBlockIO::new(paths[1].clone(), 4)?;
+        let mut block_io = MirrorBlockIO::new(vec![Box::new(child0), Box::new(child1)]);
+        block_io.write(0, &[9; BLOCK_SIZE])?;
+        block_io.write(1, &[1; BLOCK_SIZE])?;
+        plan.borrow_mut().fail_write(1, libc::EIO);
+        block_io.write(1, &[2; BLOCK_SIZE])?;
+        drop(block_io);
+
+        // The first mirror is still behind, and must not serve the stale block
+        let open = || -> Result<MirrorBlockIO, std::io::Error> {
+            Ok(MirrorBlockIO::new(vec![
+                Box::new(ImageBlockIO::new(paths[0].clone(), 4)?), Box::new(ImageBlockIO::new(paths[1].clone(), 4)?)
+            ]))
+        };
+        let mut block_io = open()?;
+        assert_eq!(block_io.read(1)?[..], [2; BLOCK_SIZE][..]);
+        assert_eq!(block_io.read(0)?[.. GENERATION_OFF], [9; GENERATION_OFF][..]);
+        block_io.write(3, &[3; BLOCK_SIZE])?;
+        assert_eq!(ImageBlockIO::new(paths[0].clone(), 4)?.read(3)?[..], [0; BLOCK_SIZE][..]);
+
+        block_io.resync(0, 4)?;
+        drop(block_io);
+        let mut block_io = open()?;
+        assert!(block_io.failed.iter().all(|&failed| !failed));
+        assert_eq!(ImageBlockIO::new(paths[0].clone(), 4)?.read(1)?[..], [2; BLOCK_SIZE][..]);
+        assert_eq!(block_io.read(3)?[..], [3; BLOCK_SIZE][..]);
+        std::fs::remove_file(&paths[0])?;
+        std::fs::remove_file(&paths[1])
+    }
+}
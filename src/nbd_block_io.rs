@@ -0,0 +1,359 @@
+use std::convert::TryInto;
+use std::io::{Read, Write};
+
+use super::{BlockIO, Id, BLOCK_SIZE};
+
+const NBDMAGIC: u64 = 0x4e42444d41474943;
+const IHAVEOPT: u64 = 0x49484156454f5054;
+const REPLY_MAGIC: u64 = 0x0003e889045565a9;
+const REQUEST_MAGIC: u32 = 0x25609513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x67446698;
+
+const FLAG_FIXED_NEWSTYLE: u16 = 1;
+const FLAG_NO_ZEROES: u16 = 2;
+
+const OPT_EXPORT_NAME: u32 = 1;
+const OPT_GO: u32 = 7;
+
+const REP_ACK: u32 = 1;
+const REP_INFO: u32 = 3;
+const REP_FLAG_ERROR: u32 = 1 << 31;
+const REP_ERR_UNSUP: u32 = REP_FLAG_ERROR | 1;
+
+const INFO_EXPORT: u16 = 0;
+
+const TRANS_FLAG_READ_ONLY: u16 = 2;
+const TRANS_FLAG_SEND_FLUSH: u16 = 4;
+const TRANS_FLAG_SEND_TRIM: u16 = 32;
+
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+const CMD_FLUSH: u16 = 3;
+const CMD_TRIM: u16 = 4;
+
+/// Most blocks one request can cover, as its length is 32 bits
+const MAX_REQUEST_BLOCK_CNT: usize = u32::MAX as usize / BLOCK_SIZE;
+
+pub trait Stream: Read + Write {}
+impl<T: Read + Write> Stream for T {}
+
+fn protocol_error(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+fn read_u16(stream: &mut dyn Stream) -> Result<u16, std::io::Error> {
+    let mut buf = [0; 2];
+    stream.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32(stream: &mut dyn Stream) -> Result<u32, std::io::Error> {
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64(stream: &mut dyn Stream) -> Result<u64, std::io::Error> {
+    let mut buf = [0; 8];
+    stream.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+/// Client of the NBD protocol (fixed newstyle handshake, simple replies). Block `i` is at offset
+/// `i * BLOCK_SIZE` of the export. Errors during the handshake carry a message, while errors
+/// afterwards are raw errnos as reported by the server, or EPROTO if the server misbehaves
+pub struct NbdBlockIO {
+    stream: Box<dyn Stream>,
+    size: u64,
+    flags: u16,
+    next_handle: u64,
+}
+
+impl NbdBlockIO {
+    /// Connect to `addr`, which is either `host:port` or `unix:<path>`
+    pub fn connect(addr: &str, export: &str) -> Result<NbdBlockIO, std::io::Error> {
+        let stream: Box<dyn Stream> = if let Some(path) = addr.strip_prefix("unix:") {
+            Box::new(std::os::unix::net::UnixStream::connect(path)?)
+        } else {
+            let stream = std::net::TcpStream::connect(addr)?;
+            stream.set_nodelay(true)?;
+            Box::new(stream)
+        };
+        NbdBlockIO::handshake(stream, export)
+    }
+
+    pub fn handshake(mut stream: Box<dyn Stream>, export: &str) -> Result<NbdBlockIO, std::io::Error> {
+        if read_u64(&mut stream)? != NBDMAGIC || read_u64(&mut stream)? != IHAVEOPT {
+            return Err(protocol_error(String::from("Not a newstyle NBD server")))
+        }
+        let server_flags = read_u16(&mut stream)?;
+        if server_flags & FLAG_FIXED_NEWSTYLE == 0 {
+            return Err(protocol_error(String::from("NBD server does not support fixed newstyle")))
+        }
+        let client_flags = (FLAG_FIXED_NEWSTYLE | (server_flags & FLAG_NO_ZEROES)) as u32;
+        stream.write_all(&client_flags.to_be_bytes())?;
+
+        let (size, flags) = match NbdBlockIO::opt_go(&mut stream, export)? {
+            Some(info) => info,
+            None => NbdBlockIO::opt_export_name(&mut stream, export, server_flags & FLAG_NO_ZEROES != 0)?
+        };
+        Ok(NbdBlockIO { stream, size, flags, next_handle: 0 })
+    }
+
+    fn send_option(stream: &mut dyn Stream, option: u32, data: &[u8]) -> Result<(), std::io::Error> {
+        let mut buf = vec![];
+        buf.extend_from_slice(&IHAVEOPT.to_be_bytes());
+        buf.extend_from_slice(&option.to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        buf.extend_from_slice(data);
+        stream.write_all(&buf)
+    }
+
+    /// Returns None if the server does not know NBD_OPT_GO
+    fn opt_go(stream: &mut dyn Stream, export: &str) -> Result<Option<(u64, u16)>, std::io::Error> {
+        let mut data = vec![];
+        data.extend_from_slice(&(export.len() as u32).to_be_bytes());
+        data.extend_from_slice(export.as_bytes());
+        data.extend_from_slice(&0u16.to_be_bytes()); // No extra info requests
+        NbdBlockIO::send_option(stream, OPT_GO, &data)?;
+
+        let mut info = None;
+        loop {
+            if read_u64(stream)? != REPLY_MAGIC || read_u32(stream)? != OPT_GO {
+                return Err(protocol_error(String::from("Malformed NBD option reply")))
+            }
+            let reply_type = read_u32(stream)?;
+            let mut reply = vec![0; read_u32(stream)? as usize];
+            stream.read_exact(&mut reply)?;
+            match reply_type {
+                REP_ACK => break,
+                REP_INFO if reply.len() >= 12 && u16::from_be_bytes(reply[0 .. 2].try_into().unwrap()) == INFO_EXPORT => {
+                    info = Some((
+                        u64::from_be_bytes(reply[2 .. 10].try_into().unwrap()),
+                        u16::from_be_bytes(reply[10 .. 12].try_into().unwrap())
+                    ));
+                },
+                REP_ERR_UNSUP => return Ok(None),
+                t if t & REP_FLAG_ERROR != 0 => return Err(protocol_error(format!(
+                    "NBD server refused export {:?} (error {:#x}): {}", export, t, String::from_utf8_lossy(&reply)
+                ))),
+                _ => () // Ignore unknown replies
+            }
+        }
+        match info {
+            Some(info) => Ok(Some(info)),
+            None => Err(protocol_error(String::from("NBD server did not report the export size")))
+        }
+    }
+
+    fn opt_export_name(stream: &mut dyn Stream, export: &str, no_zeroes: bool) -> Result<(u64, u16), std::io::Error> {
+        NbdBlockIO::send_option(stream, OPT_EXPORT_NAME, export.as_bytes())?;
+        let size = read_u64(stream)?;
+        let flags = read_u16(stream)?;
+        if !no_zeroes {
+            stream.read_exact(&mut [0; 124])?;
+        }
+        Ok((size, flags))
+    }
+
+    /// Size of the export in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn request(&mut self, cmd: u16, offset: u64, length: u32, data: &[u8]) -> Result<u64 /* handle */, std::io::Error> {
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        let mut buf = Vec::with_capacity(28 + data.len());
+        buf.extend_from_slice(&REQUEST_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&0u16.to_be_bytes()); // Command flags
+        buf.extend_from_slice(&cmd.to_be_bytes());
+        buf.extend_from_slice(&handle.to_be_bytes());
+        buf.extend_from_slice(&offset.to_be_bytes());
+        buf.extend_from_slice(&length.to_be_bytes());
+        buf.extend_from_slice(data);
+        self.stream.write_all(&buf)?;
+        Ok(handle)
+    }
+
+    fn reply(&mut self, handle: u64) -> Result<(), std::io::Error> {
+        if read_u32(&mut self.stream)? != SIMPLE_REPLY_MAGIC {
+            return Err(std::io::Error::from_raw_os_error(libc::EPROTO))
+        }
+        let error = read_u32(&mut self.stream)?;
+        if read_u64(&mut self.stream)? != handle {
+            return Err(std::io::Error::from_raw_os_error(libc::EPROTO))
+        }
+        if error != 0 {
+            return Err(std::io::Error::from_raw_os_error(error as i32)) // NBD errors share values with Linux errnos
+        }
+        Ok(())
+    }
+
+    fn check_range(&self, block_id: Id, len: usize) -> Result<u64 /* offset */, std::io::Error> {
+        let offset = block_id as u64 * BLOCK_SIZE as u64;
+        if offset + len as u64 > self.size {
+            return Err(std::io::Error::from_raw_os_error(libc::ENOSPC))
+        }
+        Ok(offset)
+    }
+
+    /// Map errors from the stream itself to raw errnos, which is what the upper layers expect
+    fn raw_error(err: std::io::Error) -> std::io::Error {
+        if err.raw_os_error().is_some() {
+            err
+        } else {
+            std::io::Error::from_raw_os_error(libc::EIO)
+        }
+    }
+}
+
+impl BlockIO for NbdBlockIO {
+    fn read(&mut self, block_id: Id) -> Result<[u8; BLOCK_SIZE], std::io::Error> {
+        let mut data = [0; BLOCK_SIZE];
+        self.read_blocks(block_id, &mut data)?;
+        Ok(data)
+    }
+
+    fn write(&mut self, block_id: Id, data: &[u8]) -> Result<(), std::io::Error> {
+        assert_eq!(data.len(), BLOCK_SIZE);
+        self.write_blocks(block_id, data)
+    }
+
+    fn read_blocks(&mut self, block_id: Id, buf: &mut [u8]) -> Result<(), std::io::Error> {
+        assert_eq!(buf.len() % BLOCK_SIZE, 0);
+        let offset = self.check_range(block_id, buf.len())?;
+        (|| {
+            let handle = self.request(CMD_READ, offset, buf.len() as u32, &[])?;
+            self.reply(handle)?;
+            self.stream.read_exact(buf)
+        })().map_err(NbdBlockIO::raw_error)
+    }
+
+    fn write_blocks(&mut self, block_id: Id, data: &[u8]) -> Result<(), std::io::Error> {
+        assert_eq!(data.len() % BLOCK_SIZE, 0);
+        if self.flags & TRANS_FLAG_READ_ONLY != 0 {
+            return Err(std::io::Error::from_raw_os_error(libc::EROFS))
+        }
+        let offset = self.check_range(block_id, data.len())?;
+        (|| {
+            let handle = self.request(CMD_WRITE, offset, data.len() as u32, data)?;
+            self.reply(handle)
+        })().map_err(NbdBlockIO::raw_error)
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        if self.flags & TRANS_FLAG_SEND_FLUSH == 0 {
+            return Ok(()) // Writes are persistent once acknowledged
+        }
+        (|| {
+            let handle = self.request(CMD_FLUSH, 0, 0, &[])?;
+            self.reply(handle)
+        })().map_err(NbdBlockIO::raw_error)
+    }
+
+    fn discard(&mut self, block_id: Id, cnt: usize) -> Result<(), std::io::Error> {
+        if self.flags & TRANS_FLAG_READ_ONLY != 0 {
+            return Err(std::io::Error::from_raw_os_error(libc::EROFS))
+        }
+        if self.flags & TRANS_FLAG_SEND_TRIM == 0 {
+            return Ok(()) // Not supported by the server
+        }
+        let offset = self.check_range(block_id, cnt * BLOCK_SIZE)?;
+        (|| {
+            let mut done = 0;
+            while done < cnt {
+                let len = std::cmp::min(cnt - done, MAX_REQUEST_BLOCK_CNT) * BLOCK_SIZE;
+                let handle = self.request(CMD_TRIM, offset + (done * BLOCK_SIZE) as u64, len as u32, &[])?;
+                self.reply(handle)?;
+                done += len / BLOCK_SIZE;
+            }
+            Ok(())
+        })().map_err(NbdBlockIO::raw_error)
+    }
+}
+
+impl Drop for NbdBlockIO {
+    fn drop(&mut self) {
+        // The server does not reply to a disconnect. Just let the connection close
+        let _ = self.request(CMD_DISC, 0, 0, &[]);
+        let _ = self.stream.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Minimal NBD server exporting `size` bytes of memory. Returns the written content after
+    /// the client disconnects
+    fn serve(mut stream: std::os::unix::net::UnixStream, size: usize) -> Result<Vec<u8>, std::io::Error> {
+        let mut export = vec![0; size];
+        stream.write_all(&NBDMAGIC.to_be_bytes())?;
+        stream.write_all(&IHAVEOPT.to_be_bytes())?;
+        stream.write_all(&(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES).to_be_bytes())?;
+        assert_eq!(read_u32(&mut stream)?, (FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES) as u32);
+        assert_eq!(read_u64(&mut stream)?, IHAVEOPT);
+        assert_eq!(read_u32(&mut stream)?, OPT_GO);
+        let option_len = read_u32(&mut stream)? as usize;
+        stream.read_exact(&mut vec![0; option_len])?;
+        let mut info = vec![];
+        info.extend_from_slice(&INFO_EXPORT.to_be_bytes());
+        info.extend_from_slice(&(size as u64).to_be_bytes());
+        info.extend_from_slice(&TRANS_FLAG_SEND_FLUSH.to_be_bytes());
+        for (reply_type, data) in [(REP_INFO, &info[..]), (REP_ACK, &[][..])].iter() {
+            stream.write_all(&REPLY_MAGIC.to_be_bytes())?;
+            stream.write_all(&OPT_GO.to_be_bytes())?;
+            stream.write_all(&reply_type.to_be_bytes())?;
+            stream.write_all(&(data.len() as u32).to_be_bytes())?;
+            stream.write_all(data)?;
+        }
+
+        loop {
+            assert_eq!(read_u32(&mut stream)?, REQUEST_MAGIC);
+            read_u16(&mut stream)?;
+            let cmd = read_u16(&mut stream)?;
+            let handle = read_u64(&mut stream)?;
+            let offset = read_u64(&mut stream)? as usize;
+            let length = read_u32(&mut stream)? as usize;
+            let mut reply = vec![];
+            reply.extend_from_slice(&SIMPLE_REPLY_MAGIC.to_be_bytes());
+            reply.extend_from_slice(&0u32.to_be_bytes());
+            reply.extend_from_slice(&handle.to_be_bytes());
+            match cmd {
+                CMD_READ => reply.extend_from_slice(&export[offset .. offset + length]),
+                CMD_WRITE => stream.read_exact(&mut export[offset .. offset + length])?,
+                CMD_FLUSH => (),
+                CMD_DISC => return Ok(export),
+                _ => panic!("Unexpected command {}", cmd)
+            }
+            stream.write_all(&reply)?;
+        }
+    }
+
+    #[test]
+    fn test_nbd() -> Result<(), std::io::Error> {
+        let mut path = std::env::temp_dir();
+        path.push(format!("rfs-test-nbd-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = std::os::unix::net::UnixListener::bind(&path)?;
+        let server = std::thread::spawn(move || serve(listener.accept().unwrap().0, 4 * BLOCK_SIZE));
+
+        {
+            let mut block_io = NbdBlockIO::connect(&format!("unix:{}", path.to_str().unwrap()), "")?;
+            assert_eq!(block_io.size(), 4 * BLOCK_SIZE as u64);
+            block_io.write(1, &[1; BLOCK_SIZE])?;
+            block_io.write_blocks(2, &[2; 2 * BLOCK_SIZE])?;
+            block_io.flush()?;
+            assert_eq!(block_io.read(0)?[..], [0; BLOCK_SIZE][..]);
+            assert_eq!(block_io.read(1)?[..], [1; BLOCK_SIZE][..]);
+            assert_eq!(block_io.write(4, &[4; BLOCK_SIZE]).unwrap_err().raw_os_error(), Some(libc::ENOSPC));
+        }
+
+        let export = server.join().unwrap()?;
+        assert_eq!(export[BLOCK_SIZE .. 2 * BLOCK_SIZE], [1; BLOCK_SIZE][..]);
+        assert_eq!(export[2 * BLOCK_SIZE ..], [2; 2 * BLOCK_SIZE][..]);
+        std::fs::remove_file(&path)
+    }
+}
@@ -0,0 +1,197 @@
+use super::{BlockIO, FakeMemBlockIO, FileBlockIO, Id, BLOCK_SIZE};
+
+/// Copy-on-write overlay. The base BlockIO is only read, and every write is redirected to a delta
+/// store, which is either memory or a directory laid out like FileBlockIO. Discarded blocks are kept
+/// as tombstones, which read as zeros, and are an empty `zero-N` file each in a delta directory. A
+/// delta directory survives remounts until it is committed into the base or dropped
+pub struct OverlayBlockIO {
+    base: Box<dyn BlockIO>,
+    delta: Box<dyn BlockIO>,
+    delta_dir: Option<std::path::PathBuf>,
+    delta_ids: std::collections::BTreeSet<Id>,
+    tombstones: std::collections::BTreeSet<Id>,
+}
+
+impl OverlayBlockIO {
+    /// Keep the delta in `delta_dir`, or in memory if None
+    pub fn new(base: Box<dyn BlockIO>, delta_dir: Option<std::path::PathBuf>) -> Result<OverlayBlockIO, std::io::Error> {
+        let mut delta_ids = std::collections::BTreeSet::new();
+        let mut tombstones = std::collections::BTreeSet::new();
+        let delta: Box<dyn BlockIO> = match &delta_dir {
+            Some(dir) => {
+                let delta = FileBlockIO::new(dir.clone())?;
+                let parse = |id: &str| id.parse().map_err(|_| std::io::Error::from_raw_os_error(libc::EINVAL));
+                for entry in std::fs::read_dir(dir)? {
+                    let name = entry?.file_name();
+                    if let Some(id) = name.to_str().and_then(|name| name.strip_prefix("blk-")) {
+                        delta_ids.insert(parse(id)?);
+                    } else if let Some(id) = name.to_str().and_then(|name| name.strip_prefix("zero-")) {
+                        tombstones.insert(parse(id)?);
+                    }
+                }
+                // Left behind by a crash in the middle of a write, which removes the tombstone last
+                let written: Vec<Id> = tombstones.intersection(&delta_ids).cloned().collect();
+                for id in written {
+                    tombstones.remove(&id);
+                }
+                Box::new(delta)
+            },
+            None => Box::new(FakeMemBlockIO::new())
+        };
+        Ok(OverlayBlockIO { base, delta, delta_dir, delta_ids, tombstones })
+    }
+
+    fn tombstone_path(&self, block_id: Id) -> Option<std::path::PathBuf> {
+        self.delta_dir.as_ref().map(|dir| dir.join(format!("zero-{}", block_id)))
+    }
+
+    /// Number of blocks which differ from the base
+    #[allow(dead_code)] // Only used by rfs-overlay
+    pub fn delta_cnt(&self) -> usize {
+        self.delta_ids.len() + self.tombstones.len()
+    }
+
+    /// Write the delta into the base, and start over with an empty delta
+    #[allow(dead_code)] // Only used by rfs-overlay
+    pub fn commit(&mut self) -> Result<(), std::io::Error> {
+        for &block_id in &self.delta_ids {
+            self.base.write(block_id, &self.delta.read(block_id)?)?;
+        }
+        for &block_id in &self.tombstones {
+            self.base.write(block_id, &[0; BLOCK_SIZE])?;
+        }
+        self.base.flush()?;
+        self.drop_delta()
+    }
+
+    /// Throw the delta away, so the base is seen as is again
+    #[allow(dead_code)] // Only used by rfs-overlay
+    pub fn drop_delta(&mut self) -> Result<(), std::io::Error> {
+        match &self.delta_dir {
+            Some(dir) => {
+                for &block_id in &self.delta_ids {
+                    std::fs::remove_file(dir.join(format!("blk-{}", block_id)))?;
+                }
+                for &block_id in &self.tombstones {
+                    std::fs::remove_file(dir.join(format!("zero-{}", block_id)))?;
+                }
+            },
+            None => self.delta = Box::new(FakeMemBlockIO::new())
+        }
+        self.delta_ids.clear();
+        self.tombstones.clear();
+        Ok(())
+    }
+}
+
+impl BlockIO for OverlayBlockIO {
+    fn read(&mut self, block_id: Id) -> Result<[u8; BLOCK_SIZE], std::io::Error> {
+        if self.delta_ids.contains(&block_id) {
+            self.delta.read(block_id)
+        } else if self.tombstones.contains(&block_id) {
+            Ok([0; BLOCK_SIZE])
+        } else {
+            self.base.read(block_id)
+        }
+    }
+
+    fn write(&mut self, block_id: Id, data: &[u8]) -> Result<(), std::io::Error> {
+        self.delta.write(block_id, data)?;
+        self.delta_ids.insert(block_id);
+        if self.tombstones.remove(&block_id) {
+            if let Some(path) = self.tombstone_path(block_id) {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.delta.flush()
+    }
+
+    /// The base is left as is, like for every write, so the blocks become tombstones instead, which
+    /// take no space in the delta. Otherwise they would read back as the stale base
+    fn discard(&mut self, block_id: Id, cnt: usize) -> Result<(), std::io::Error> {
+        self.delta.discard(block_id, cnt)?;
+        for id in block_id .. block_id + cnt as Id {
+            self.delta_ids.remove(&id);
+            if self.tombstones.insert(id) {
+                if let Some(path) = self.tombstone_path(id) {
+                    std::fs::File::create(path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commit_and_discard() -> Result<(), std::io::Error> {
+        let mut golden = FakeMemBlockIO::new();
+        golden.write(1, &[1; BLOCK_SIZE])?;
+        let mut block_io = OverlayBlockIO::new(Box::new(golden), None)?;
+        block_io.write(2, &[2; BLOCK_SIZE])?;
+        assert_eq!(block_io.read(1)?[..], [1; BLOCK_SIZE][..]);
+        assert_eq!(block_io.read(2)?[..], [2; BLOCK_SIZE][..]);
+        assert_eq!(block_io.base.read(2)?[..], [0; BLOCK_SIZE][..]);
+
+        block_io.drop_delta()?;
+        assert_eq!(block_io.read(2)?[..], [0; BLOCK_SIZE][..]);
+
+        block_io.write(1, &[3; BLOCK_SIZE])?;
+        block_io.commit()?;
+        assert_eq!(block_io.delta_cnt(), 0);
+        assert_eq!(block_io.base.read(1)?[..], [3; BLOCK_SIZE][..]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_discard_blocks() -> Result<(), std::io::Error> {
+        let mut golden = FakeMemBlockIO::new();
+        golden.write(1, &[1; BLOCK_SIZE])?;
+        golden.write(2, &[2; BLOCK_SIZE])?;
+        let mut block_io = OverlayBlockIO::new(Box::new(golden), None)?;
+        block_io.write(2, &[3; BLOCK_SIZE])?;
+        block_io.discard(1, 2)?;
+        assert_eq!(block_io.read(1)?[..], [0; BLOCK_SIZE][..]); // Not the base
+        assert_eq!(block_io.read(2)?[..], [0; BLOCK_SIZE][..]);
+        assert_eq!(block_io.base.read(1)?[..], [1; BLOCK_SIZE][..]);
+        assert!(block_io.delta_ids.is_empty()); // No zero blocks stored
+        block_io.write(2, &[4; BLOCK_SIZE])?;
+        assert_eq!(block_io.read(2)?[..], [4; BLOCK_SIZE][..]);
+        block_io.commit()?;
+        assert_eq!(block_io.base.read(1)?[..], [0; BLOCK_SIZE][..]);
+        assert_eq!(block_io.base.read(2)?[..], [4; BLOCK_SIZE][..]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delta_dir() -> Result<(), std::io::Error> {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("rfs-test-overlay-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        {
+            let mut golden = FakeMemBlockIO::new();
+            golden.write(6, &[6; BLOCK_SIZE])?;
+            let mut block_io = OverlayBlockIO::new(Box::new(golden), Some(dir.clone()))?;
+            block_io.write(5, &[5; BLOCK_SIZE])?;
+            block_io.write(6, &[7; BLOCK_SIZE])?;
+            block_io.discard(6, 1)?;
+        }
+        let mut golden = FakeMemBlockIO::new();
+        golden.write(6, &[6; BLOCK_SIZE])?;
+        let mut block_io = OverlayBlockIO::new(Box::new(golden), Some(dir.clone()))?;
+        assert_eq!(block_io.delta_cnt(), 2);
+        assert_eq!(block_io.read(5)?[..], [5; BLOCK_SIZE][..]);
+        assert_eq!(block_io.read(6)?[..], [0; BLOCK_SIZE][..]);
+        assert!(!dir.join("blk-6").exists());
+        block_io.drop_delta()?;
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 0);
+        std::fs::remove_dir(&dir)
+    }
+}
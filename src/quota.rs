@@ -0,0 +1,234 @@
+use std::convert::TryInto;
+
+/// Seconds a soft limit may be exceeded before it is enforced like a hard limit
+pub const GRACE_PERIOD: i64 = 7 * 24 * 3600;
+
+const RECORD_SIZE: usize = 72;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum QuotaKind {
+    User,
+    Group,
+}
+
+/// A user or a group, whose usage is charged
+pub type Owner = (QuotaKind, u32);
+
+/// The user and the group an inode is charged to
+pub fn owners(uid: u32, gid: u32) -> [Owner; 2] {
+    [(QuotaKind::User, uid), (QuotaKind::Group, gid)]
+}
+
+/// 0 means no limit
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct Limits {
+    pub soft: u64,
+    pub hard: u64,
+}
+
+impl Limits {
+    /// How much more can be used now, or None if unlimited. The soft limit is enforced once it has
+    /// been exceeded for longer than GRACE_PERIOD
+    fn headroom(&self, usage: u64, over_since: i64, now: i64) -> Option<u64> {
+        let mut limit = if self.hard > 0 { Some(self.hard) } else { None };
+        if self.soft > 0 && over_since != 0 && now - over_since >= GRACE_PERIOD {
+            limit = Some(limit.map_or(self.soft, |hard| std::cmp::min(hard, self.soft)));
+        }
+        limit.map(|limit| limit.saturating_sub(usage))
+    }
+
+    /// When the soft limit started being exceeded, given when it did before
+    fn over_since(&self, usage: u64, over_since: i64, now: i64) -> i64 {
+        match (self.soft > 0 && usage > self.soft, over_since) {
+            (false, _) => 0,
+            (true, 0) => now,
+            (true, since) => since,
+        }
+    }
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct QuotaRecord {
+    pub blocks: Limits,
+    pub inodes: Limits,
+    pub block_usage: u64,
+    pub inode_usage: u64,
+    pub block_over_since: i64, // When the soft limit started being exceeded, 0 if it is not
+    pub inode_over_since: i64,
+}
+
+impl QuotaRecord {
+    fn update_grace(&mut self, now: i64) {
+        self.block_over_since = self.blocks.over_since(self.block_usage, self.block_over_since, now);
+        self.inode_over_since = self.inodes.over_since(self.inode_usage, self.inode_over_since, now);
+    }
+}
+
+/// Block and inode usage and limits of every user and group, kept in the quota inode. Layout of
+/// each record is like:
+/// [ kind (4B, 0 = user, 1 = group) | uid or gid (4B) | block soft limit (8B) | block hard limit (8B) |
+///   inode soft limit (8B) | inode hard limit (8B) | block usage (8B) | inode usage (8B) |
+///   block soft limit exceeded since (8B) | inode soft limit exceeded since (8B) ]
+/// Blocks count data blocks, index blocks and xattr blocks, but not the inode blocks, which count as
+/// inodes
+pub struct QuotaTable {
+    records: std::collections::BTreeMap<Owner, QuotaRecord>,
+    dirty: bool, // Changed since loaded or saved
+}
+
+impl QuotaTable {
+    #[allow(dead_code)] // Only used by rfs-quota
+    pub fn new() -> QuotaTable {
+        QuotaTable { records: std::collections::BTreeMap::new(), dirty: true }
+    }
+
+    pub fn parse(data: &[u8]) -> QuotaTable {
+        let mut table = QuotaTable { records: std::collections::BTreeMap::new(), dirty: false };
+        for record in data.chunks_exact(RECORD_SIZE) {
+            let u32_at = |off: usize| u32::from_le_bytes(record[off .. off + 4].try_into().unwrap());
+            let u64_at = |off: usize| u64::from_le_bytes(record[off .. off + 8].try_into().unwrap());
+            let kind = if u32_at(0) == 0 { QuotaKind::User } else { QuotaKind::Group };
+            table.records.insert((kind, u32_at(4)), QuotaRecord {
+                blocks: Limits { soft: u64_at(8), hard: u64_at(16) },
+                inodes: Limits { soft: u64_at(24), hard: u64_at(32) },
+                block_usage: u64_at(40),
+                inode_usage: u64_at(48),
+                block_over_since: u64_at(56) as i64,
+                inode_over_since: u64_at(64) as i64,
+            });
+        }
+        table
+    }
+
+    /// Records without usage or limits are left out
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = vec![];
+        for (&(kind, id), record) in self.records.iter() {
+            if record.block_usage == 0 && record.inode_usage == 0 && record.blocks == Limits::default()
+                && record.inodes == Limits::default() {
+                continue
+            }
+            data.extend_from_slice(&(kind as u32).to_le_bytes());
+            data.extend_from_slice(&id.to_le_bytes());
+            for value in [record.blocks.soft, record.blocks.hard, record.inodes.soft, record.inodes.hard,
+                          record.block_usage, record.inode_usage] {
+                data.extend_from_slice(&value.to_le_bytes());
+            }
+            data.extend_from_slice(&record.block_over_since.to_le_bytes());
+            data.extend_from_slice(&record.inode_over_since.to_le_bytes());
+        }
+        data
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn set_clean(&mut self) {
+        self.dirty = false;
+    }
+
+    #[allow(dead_code)] // Only used by rfs-quota
+    pub fn records(&self) -> impl Iterator<Item = (&Owner, &QuotaRecord)> {
+        self.records.iter()
+    }
+
+    #[allow(dead_code)] // Only used by rfs-quota
+    pub fn set_limits(&mut self, owner: Owner, blocks: Limits, inodes: Limits, now: i64) {
+        let record = self.records.entry(owner).or_default();
+        record.blocks = blocks;
+        record.inodes = inodes;
+        record.update_grace(now);
+        self.dirty = true;
+    }
+
+    /// Blocks which can still be charged to all of `owners`, or None if unlimited
+    pub fn block_headroom(&self, owners: &[Owner], now: i64) -> Option<u64> {
+        owners.iter().filter_map(|owner| self.records.get(owner))
+            .filter_map(|record| record.blocks.headroom(record.block_usage, record.block_over_since, now))
+            .min()
+    }
+
+    /// Inodes which can still be charged to all of `owners`, or None if unlimited
+    pub fn inode_headroom(&self, owners: &[Owner], now: i64) -> Option<u64> {
+        owners.iter().filter_map(|owner| self.records.get(owner))
+            .filter_map(|record| record.inodes.headroom(record.inode_usage, record.inode_over_since, now))
+            .min()
+    }
+
+    /// Fails with EDQUOT if `blocks` and `inodes` more cannot be charged to all of `owners`
+    pub fn check(&self, owners: &[Owner], blocks: u64, inodes: u64, now: i64) -> Result<(), std::io::Error> {
+        if self.block_headroom(owners, now).is_some_and(|headroom| headroom < blocks)
+            || self.inode_headroom(owners, now).is_some_and(|headroom| headroom < inodes) {
+            return Err(std::io::Error::from_raw_os_error(libc::EDQUOT))
+        }
+        Ok(())
+    }
+
+    /// Charge blocks and inodes to every one of `owners`, or refund them if negative
+    pub fn charge(&mut self, owners: &[Owner], blocks: i64, inodes: i64, now: i64) {
+        if blocks == 0 && inodes == 0 {
+            return
+        }
+        for owner in owners {
+            let record = self.records.entry(*owner).or_default();
+            record.block_usage = record.block_usage.saturating_add_signed(blocks);
+            record.inode_usage = record.inode_usage.saturating_add_signed(inodes);
+            record.update_grace(now);
+        }
+        self.dirty = true;
+    }
+
+    /// Forget the usage, to count it again from the inodes
+    pub fn clear_usage(&mut self) {
+        for record in self.records.values_mut() {
+            record.block_usage = 0;
+            record.inode_usage = 0;
+        }
+        self.dirty = true;
+    }
+
+    /// Add to the usage without touching the grace periods. Call `update_grace` when done
+    pub fn add_usage(&mut self, owners: &[Owner], blocks: u64, inodes: u64) {
+        for owner in owners {
+            let record = self.records.entry(*owner).or_default();
+            record.block_usage += blocks;
+            record.inode_usage += inodes;
+        }
+    }
+
+    pub fn update_grace(&mut self, now: i64) {
+        for record in self.records.values_mut() {
+            record.update_grace(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits_and_grace() {
+        let mut table = QuotaTable::new();
+        let user = owners(1000, 100);
+        table.set_limits(user[0], Limits { soft: 10, hard: 20 }, Limits { soft: 0, hard: 2 }, 1);
+        assert_eq!(table.block_headroom(&user, 1), Some(20));
+        assert_eq!(table.inode_headroom(&user, 1), Some(2));
+        assert_eq!(table.block_headroom(&owners(1001, 100), 1), None);
+
+        table.charge(&user, 15, 1, 100); // Over the soft limit, in the grace period
+        assert_eq!(table.block_headroom(&user, 100 + GRACE_PERIOD - 1), Some(5));
+        assert_eq!(table.block_headroom(&user, 100 + GRACE_PERIOD), Some(0));
+        assert_eq!(table.check(&user, 1, 0, 100 + GRACE_PERIOD).unwrap_err().raw_os_error(), Some(libc::EDQUOT));
+        assert!(table.check(&user, 0, 1, 100).is_ok());
+        assert!(table.check(&user, 0, 2, 100).is_err());
+
+        table.charge(&user, -10, 0, 200); // Back under the soft limit
+        let parsed = QuotaTable::parse(&table.to_bytes());
+        assert_eq!(parsed.block_headroom(&user, 200 + GRACE_PERIOD), Some(15));
+        let records: Vec<_> = parsed.records().collect();
+        assert_eq!(records.len(), 2); // The user and the group
+        assert_eq!((records[1].0, records[1].1.block_usage), (&(QuotaKind::Group, 100), 5));
+    }
+}
@@ -0,0 +1,369 @@
+use std::convert::TryInto;
+use std::io::Read;
+
+use super::block_io::{Id, BLOCK_SIZE};
+
+pub const MAGIC: [u8; 4] = [114, 102, 115, 46]; // "rfs."
+
+/// Volumes formatted before ids were widened have all-zero bytes after the magic
+const VERSION_LEGACY: u32 = 0;
+/// Volumes with only the geometry after the magic
+const VERSION_GEOMETRY: u32 = 1;
+/// Volumes without a journal
+const VERSION_NO_JOURNAL: u32 = 2;
+/// Volumes whose regions are always in the initial places
+const VERSION_FIXED_LAYOUT: u32 = 3;
+/// Volumes without quotas
+const VERSION_NO_QUOTA: u32 = 4;
+/// Volumes whose inodes take a data block each
+const VERSION_NO_INODE_TABLE: u32 = 5;
+/// Volumes whose inodes have a 32-bit length and a single indirect block
+const VERSION_NO_LARGE_FILES: u32 = 6;
+/// Volumes whose files map their blocks by block pointers only
+const VERSION_NO_EXTENTS: u32 = 7;
+/// Volumes whose inodes have no room for extended attributes
+const VERSION_NO_XATTRS: u32 = 8;
+/// Volumes which do not record whether inodes have flags. They do if in an inode table, except on
+/// version 6
+const VERSION_IMPLIED_INODE_FLAGS: u32 = 9;
+pub const VERSION: u32 = 10;
+
+/// Bytes of each inode in the inode table
+pub const INODE_SIZE: usize = 256;
+const INODES_PER_BLOCK: usize = BLOCK_SIZE / INODE_SIZE;
+
+const BITS_PER_BLOCK: usize = BLOCK_SIZE * 8;
+
+const LEGACY_ID_SIZE: usize = 2;
+const LEGACY_BLOCK_CNT: usize = BLOCK_SIZE * 8;
+
+pub const LABEL_SIZE: usize = 64;
+
+const STATE_DIRTY: u32 = 0;
+const STATE_CLEAN: u32 = 1;
+
+/// Seconds since epoch
+pub fn now() -> i64 {
+    time::get_time().sec
+}
+
+/// Random version 4 UUID
+pub fn random_uuid() -> Result<[u8; 16], std::io::Error> {
+    let mut uuid = [0; 16];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut uuid)?;
+    uuid[6] = (uuid[6] & 0x0f) | 0x40; // Version 4
+    uuid[8] = (uuid[8] & 0x3f) | 0x80; // Variant 1
+    Ok(uuid)
+}
+
+/// Metadata of the whole volume, kept in device block 0. Layout is like:
+/// [ magic "rfs." (4B) | format version (4B) | data block count (8B) | block size (4B) | id size (4B) |
+///   free block count (8B) | inode count (8B) | UUID (16B) | label (64B, zero padded) |
+///   creation time (8B) | last mount time (8B) | last write time (8B) | mount count (4B) |
+///   state (4B, 1 = cleanly unmounted) | journal block count (4B) | first bitmap block (4B) |
+///   first data block (4B) | first journal block (4B) | quota inode (4B) | inode table size (4B) |
+///   first inode bitmap block (4B) | first inode table block (4B) | large files (4B, 1 = yes) |
+///   extents (4B, 1 = yes) | extended attributes (4B, 1 = yes) | inode flags (4B, 1 = yes) ]
+/// Version 0 only has the magic, and implies 16-bit ids and one bitmap block. Version 1 only has
+/// the magic, the version and the data block count, and implies 32-bit ids. Version 2 has no
+/// journal. Until version 3, the bitmap always follows the super block, and the data blocks and
+/// the journal follow the bitmap. Version 4 has no quotas. Until version 5, every inode takes a
+/// data block of its own. Until version 6, files are limited to 4 GiB, and inodes have no flags. Older versions are upgraded
+/// when mounted writable, still without a journal, an inode table or large files if they had none.
+/// The last 8 bytes of the block belong to MirrorBlockIO
+#[derive(Clone)]
+pub struct SuperBlock {
+    pub version: u32,
+    pub block_cnt: usize,
+    pub block_size: usize,
+    pub id_size: usize,
+    pub free_block_cnt: usize,
+    pub inode_cnt: Option<u64>, // Unknown for volumes upgraded from older versions
+    pub uuid: [u8; 16],
+    pub label: String,
+    pub created: i64,
+    pub last_mounted: i64,
+    pub last_written: i64,
+    pub mount_cnt: u32,
+    pub clean: bool,
+    pub journal_block_cnt: usize, // 0 if no journal
+    // Regions on the device. Resizing may move the bitmap and the journal after the data blocks
+    pub bitmap_start: usize,
+    pub data_start: usize,
+    pub journal_start: usize,
+    pub quota_inode: Id, // 0 if quotas have never been enabled
+    pub inode_table_size: usize, // Number of inodes the inode table holds, 0 if inodes take data blocks
+    pub inode_bitmap_start: usize,
+    pub inode_table_start: usize,
+    pub large_files: bool, // Whether inodes have a 64-bit length and double and triple indirect blocks
+    pub extents: bool, // Whether regular files map their blocks by extents
+    pub xattrs: bool, // Whether inodes have an xattr block and inline extended attributes
+    pub inode_flags: bool, // Whether inodes of the inode table have a flags word
+}
+
+impl SuperBlock {
+    /// A new volume with `block_cnt` data blocks and room for `inode_table_size` inodes, all free.
+    /// `label` should be at most LABEL_SIZE bytes
+    pub fn new(block_cnt: usize, inode_table_size: usize, journal_block_cnt: usize, label: &str, uuid: [u8; 16])
+               -> SuperBlock {
+        assert!(label.len() <= LABEL_SIZE);
+        SuperBlock {
+            version: VERSION,
+            block_cnt,
+            block_size: BLOCK_SIZE,
+            id_size: std::mem::size_of::<Id>(),
+            free_block_cnt: block_cnt,
+            inode_cnt: Some(0),
+            uuid,
+            label: String::from(label),
+            created: now(),
+            last_mounted: 0,
+            last_written: 0,
+            mount_cnt: 0,
+            clean: true,
+            journal_block_cnt,
+            bitmap_start: 0,
+            data_start: 0,
+            journal_start: 0,
+            quota_inode: 0,
+            inode_table_size,
+            inode_bitmap_start: 0,
+            inode_table_start: 0,
+            large_files: true,
+            extents: true,
+            xattrs: true,
+            inode_flags: true,
+        }.with_initial_layout()
+    }
+
+    /// Bitmap right after the super block, followed by the inode bitmap, the inode table, the data
+    /// blocks and the journal
+    fn with_initial_layout(mut self) -> SuperBlock {
+        self.bitmap_start = 1;
+        self.inode_bitmap_start = self.bitmap_start + self.bitmap_block_cnt();
+        self.inode_table_start = self.inode_bitmap_start + self.inode_bitmap_block_cnt();
+        self.data_start = self.inode_table_start + self.inode_table_block_cnt();
+        self.journal_start = self.data_start + self.block_cnt;
+        self
+    }
+
+    pub fn bitmap_block_cnt(&self) -> usize {
+        self.block_cnt.div_ceil(BITS_PER_BLOCK)
+    }
+
+    pub fn inode_bitmap_block_cnt(&self) -> usize {
+        self.inode_table_size.div_ceil(BITS_PER_BLOCK)
+    }
+
+    pub fn inode_table_block_cnt(&self) -> usize {
+        self.inode_table_size.div_ceil(INODES_PER_BLOCK)
+    }
+
+    /// Device block holding inode `id` of the inode table, and its offset in the block
+    pub fn locate_inode(&self, id: Id) -> (Id, usize) {
+        let index = id as usize - 1;
+        assert!(index < self.inode_table_size);
+        ((self.inode_table_start + index / INODES_PER_BLOCK) as Id, index % INODES_PER_BLOCK * INODE_SIZE)
+    }
+
+    /// Number of blocks the volume takes on the device. The journal is always the last region
+    pub fn device_block_cnt(&self) -> usize {
+        self.journal_start + self.journal_block_cnt
+    }
+
+    pub fn is_formatted(block: &[u8; BLOCK_SIZE]) -> bool {
+        block[0 .. 4] == MAGIC
+    }
+
+    /// Fails with EINVAL for versions or block sizes this build does not understand
+    pub fn parse(block: &[u8; BLOCK_SIZE]) -> Result<SuperBlock, std::io::Error> {
+        let u32_at = |off: usize| u32::from_le_bytes(block[off .. off + 4].try_into().unwrap());
+        let u64_at = |off: usize| u64::from_le_bytes(block[off .. off + 8].try_into().unwrap());
+        let version = u32_at(4);
+        let mut super_block = SuperBlock {
+            version,
+            block_cnt: LEGACY_BLOCK_CNT,
+            block_size: BLOCK_SIZE,
+            id_size: LEGACY_ID_SIZE,
+            free_block_cnt: 0, // Counted from the bitmap
+            inode_cnt: None,
+            uuid: [0; 16],
+            label: String::new(),
+            created: 0,
+            last_mounted: 0,
+            last_written: 0,
+            mount_cnt: 0,
+            clean: true,
+            journal_block_cnt: 0,
+            bitmap_start: 0,
+            data_start: 0,
+            journal_start: 0,
+            quota_inode: 0,
+            inode_table_size: 0,
+            inode_bitmap_start: 0,
+            inode_table_start: 0,
+            large_files: false,
+            extents: false,
+            xattrs: false,
+            inode_flags: false,
+        };
+        match version {
+            VERSION_LEGACY => (),
+            VERSION_GEOMETRY => {
+                super_block.block_cnt = u64_at(8) as usize;
+                super_block.id_size = std::mem::size_of::<Id>();
+            },
+            VERSION_NO_JOURNAL | VERSION_FIXED_LAYOUT | VERSION_NO_QUOTA | VERSION_NO_INODE_TABLE
+                | VERSION_NO_LARGE_FILES | VERSION_NO_EXTENTS | VERSION_NO_XATTRS
+                | VERSION_IMPLIED_INODE_FLAGS | VERSION => {
+                super_block.block_cnt = u64_at(8) as usize;
+                super_block.block_size = u32_at(16) as usize;
+                super_block.id_size = u32_at(20) as usize;
+                super_block.free_block_cnt = u64_at(24) as usize;
+                super_block.inode_cnt = Some(u64_at(32));
+                super_block.uuid.copy_from_slice(&block[40 .. 56]);
+                let label = &block[56 .. 56 + LABEL_SIZE];
+                let len = label.iter().position(|&byte| byte == 0).unwrap_or(LABEL_SIZE);
+                super_block.label = String::from_utf8_lossy(&label[.. len]).into_owned();
+                super_block.created = u64_at(120) as i64;
+                super_block.last_mounted = u64_at(128) as i64;
+                super_block.last_written = u64_at(136) as i64;
+                super_block.mount_cnt = u32_at(144);
+                super_block.clean = u32_at(148) == STATE_CLEAN;
+                if version >= VERSION_FIXED_LAYOUT {
+                    super_block.journal_block_cnt = u32_at(152) as usize;
+                }
+            },
+            _ => {
+                eprintln!("Unsupported format version {}. Please upgrade rfs", version);
+                return Err(std::io::Error::from_raw_os_error(libc::EINVAL))
+            }
+        }
+        super_block = super_block.with_initial_layout();
+        if version >= VERSION_NO_QUOTA {
+            super_block.bitmap_start = u32_at(156) as usize;
+            super_block.data_start = u32_at(160) as usize;
+            super_block.journal_start = u32_at(164) as usize;
+        }
+        if version >= VERSION_NO_INODE_TABLE {
+            super_block.quota_inode = u32_at(168);
+        }
+        if version >= VERSION_NO_LARGE_FILES {
+            super_block.inode_table_size = u32_at(172) as usize;
+            super_block.inode_bitmap_start = u32_at(176) as usize;
+            super_block.inode_table_start = u32_at(180) as usize;
+        }
+        if version >= VERSION_NO_EXTENTS {
+            super_block.large_files = u32_at(184) == 1;
+        }
+        if version >= VERSION_NO_XATTRS {
+            super_block.extents = u32_at(188) == 1;
+        }
+        if version >= VERSION_IMPLIED_INODE_FLAGS {
+            super_block.xattrs = u32_at(192) == 1;
+        }
+        // Flags were added to the inodes of the inode table without a version of their own
+        if version > VERSION_NO_LARGE_FILES {
+            super_block.inode_flags = super_block.inode_table_size > 0;
+        }
+        if version == VERSION {
+            super_block.inode_flags = u32_at(196) == 1;
+        }
+        if super_block.block_size != BLOCK_SIZE || (super_block.id_size != 2 && super_block.id_size != 4) {
+            eprintln!("Unsupported block size {} or id size {}", super_block.block_size, super_block.id_size);
+            return Err(std::io::Error::from_raw_os_error(libc::EINVAL))
+        }
+        Ok(super_block)
+    }
+
+    /// Always in the latest version
+    pub fn to_block(&self) -> [u8; BLOCK_SIZE] {
+        let mut block = [0; BLOCK_SIZE];
+        block[0 .. 4].copy_from_slice(&MAGIC);
+        block[4 .. 8].copy_from_slice(&VERSION.to_le_bytes());
+        block[8 .. 16].copy_from_slice(&(self.block_cnt as u64).to_le_bytes());
+        block[16 .. 20].copy_from_slice(&(self.block_size as u32).to_le_bytes());
+        block[20 .. 24].copy_from_slice(&(self.id_size as u32).to_le_bytes());
+        block[24 .. 32].copy_from_slice(&(self.free_block_cnt as u64).to_le_bytes());
+        block[32 .. 40].copy_from_slice(&self.inode_cnt.unwrap_or(0).to_le_bytes());
+        block[40 .. 56].copy_from_slice(&self.uuid);
+        block[56 .. 56 + self.label.len()].copy_from_slice(self.label.as_bytes());
+        block[120 .. 128].copy_from_slice(&self.created.to_le_bytes());
+        block[128 .. 136].copy_from_slice(&self.last_mounted.to_le_bytes());
+        block[136 .. 144].copy_from_slice(&self.last_written.to_le_bytes());
+        block[144 .. 148].copy_from_slice(&self.mount_cnt.to_le_bytes());
+        block[148 .. 152].copy_from_slice(&if self.clean { STATE_CLEAN } else { STATE_DIRTY }.to_le_bytes());
+        block[152 .. 156].copy_from_slice(&(self.journal_block_cnt as u32).to_le_bytes());
+        block[156 .. 160].copy_from_slice(&(self.bitmap_start as u32).to_le_bytes());
+        block[160 .. 164].copy_from_slice(&(self.data_start as u32).to_le_bytes());
+        block[164 .. 168].copy_from_slice(&(self.journal_start as u32).to_le_bytes());
+        block[168 .. 172].copy_from_slice(&self.quota_inode.to_le_bytes());
+        block[172 .. 176].copy_from_slice(&(self.inode_table_size as u32).to_le_bytes());
+        block[176 .. 180].copy_from_slice(&(self.inode_bitmap_start as u32).to_le_bytes());
+        block[180 .. 184].copy_from_slice(&(self.inode_table_start as u32).to_le_bytes());
+        block[184 .. 188].copy_from_slice(&(self.large_files as u32).to_le_bytes());
+        block[188 .. 192].copy_from_slice(&(self.extents as u32).to_le_bytes());
+        block[192 .. 196].copy_from_slice(&(self.xattrs as u32).to_le_bytes());
+        block[196 .. 200].copy_from_slice(&(self.inode_flags as u32).to_le_bytes());
+        block
+    }
+
+    #[allow(dead_code)] // Only used by rfs-info
+    pub fn uuid_string(&self) -> String {
+        let hex: Vec<String> = self.uuid.iter().map(|byte| format!("{:02x}", byte)).collect();
+        format!("{}-{}-{}-{}-{}", hex[0 .. 4].concat(), hex[4 .. 6].concat(), hex[6 .. 8].concat(),
+                hex[8 .. 10].concat(), hex[10 ..].concat())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() -> Result<(), std::io::Error> {
+        let mut super_block = SuperBlock::new(100, 40, 8, "data", random_uuid()?);
+        super_block.free_block_cnt = 90;
+        super_block.mount_cnt = 3;
+        super_block.clean = false;
+        super_block.quota_inode = 7;
+        let parsed = SuperBlock::parse(&super_block.to_block())?;
+        assert_eq!(parsed.block_cnt, 100);
+        assert_eq!(parsed.free_block_cnt, 90);
+        assert_eq!(parsed.inode_cnt, Some(0));
+        assert_eq!(parsed.uuid, super_block.uuid);
+        assert_eq!(parsed.uuid_string().len(), 36);
+        assert_eq!(parsed.label, "data");
+        assert_eq!(parsed.created, super_block.created);
+        assert_eq!(parsed.mount_cnt, 3);
+        assert!(!parsed.clean);
+        assert_eq!(parsed.journal_block_cnt, 8);
+        assert_eq!((parsed.bitmap_start, parsed.data_start, parsed.journal_start), (1, 6, 106));
+        assert_eq!(parsed.device_block_cnt(), 114);
+        assert_eq!(parsed.quota_inode, 7);
+        assert_eq!((parsed.inode_table_size, parsed.inode_bitmap_start, parsed.inode_table_start), (40, 2, 3));
+        assert_eq!(parsed.locate_inode(17), (4, 0));
+        assert_eq!(parsed.locate_inode(40), (5, 7 * INODE_SIZE));
+        assert!(parsed.large_files && parsed.extents && parsed.xattrs && parsed.inode_flags);
+
+        let mut block = super_block.to_block();
+        block[4 .. 8].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(SuperBlock::parse(&block).err().unwrap().raw_os_error(), Some(libc::EINVAL));
+        Ok(())
+    }
+
+    #[test]
+    fn test_implied_inode_flags() -> Result<(), std::io::Error> {
+        let mut block = SuperBlock::new(100, 40, 8, "", [0; 16]).to_block();
+        block[196 .. 200].fill(0); // Not recorded before
+        for (version, inode_flags) in [(VERSION_NO_LARGE_FILES, false), (VERSION_NO_EXTENTS, true),
+                                       (VERSION_IMPLIED_INODE_FLAGS, true), (VERSION, false)] {
+            block[4 .. 8].copy_from_slice(&version.to_le_bytes());
+            assert_eq!(SuperBlock::parse(&block)?.inode_flags, inode_flags);
+        }
+        block[172 .. 176].fill(0); // No inode table
+        block[4 .. 8].copy_from_slice(&VERSION_NO_EXTENTS.to_le_bytes());
+        assert!(!SuperBlock::parse(&block)?.inode_flags);
+        Ok(())
+    }
+}
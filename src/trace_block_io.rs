@@ -0,0 +1,219 @@
+use std::convert::TryInto;
+use std::io::{Read, Write};
+
+use super::{BlockIO, Id, BLOCK_SIZE};
+use super::checksum_block_io::crc32c;
+
+const TRACE_MAGIC: [u8; 4] = [114, 102, 115, 116]; // "rfst"
+const TRACE_VERSION: u8 = 1;
+
+const FLAG_HASH: u8 = 1;
+const FLAG_DATA: u8 = 2;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TraceOp {
+    Read = 0,
+    Write = 1,
+    Flush = 2,
+    Discard = 3,
+}
+
+/// One traced operation. Layout of a record is like:
+/// [ op (1B) | flags (1B) | block id (Id) | timestamp in µs since epoch (8B) |
+///   CRC32C of the data (4B, if FLAG_HASH) | data (BLOCK_SIZE, if FLAG_DATA) ]
+/// Blocks of Flush records are always 0
+pub struct TraceRecord {
+    pub op: TraceOp,
+    pub block_id: Id,
+    pub timestamp: u64,
+    pub hash: Option<u32>,
+    pub data: Option<Box<[u8; BLOCK_SIZE]>>,
+}
+
+impl TraceRecord {
+    fn write_to(&self, writer: &mut dyn Write) -> Result<(), std::io::Error> {
+        let flags = if self.hash.is_some() { FLAG_HASH } else { 0 } | if self.data.is_some() { FLAG_DATA } else { 0 };
+        writer.write_all(&[self.op as u8, flags])?;
+        writer.write_all(&self.block_id.to_le_bytes())?;
+        writer.write_all(&self.timestamp.to_le_bytes())?;
+        if let Some(hash) = self.hash {
+            writer.write_all(&hash.to_le_bytes())?;
+        }
+        if let Some(data) = &self.data {
+            writer.write_all(&data[..])?;
+        }
+        Ok(())
+    }
+
+    /// Read the next record. Returns None at the end of the trace
+    #[allow(dead_code)] // Only used by rfs-replay
+    pub fn read_from(reader: &mut dyn Read) -> Result<Option<TraceRecord>, std::io::Error> {
+        let mut head = [0; 2 + std::mem::size_of::<Id>() + 8];
+        match reader.read_exact(&mut head) {
+            Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            ret => ret?
+        }
+        let op = match head[0] {
+            0 => TraceOp::Read,
+            1 => TraceOp::Write,
+            2 => TraceOp::Flush,
+            3 => TraceOp::Discard,
+            _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Unknown trace operation"))
+        };
+        let flags = head[1];
+        let block_id = Id::from_le_bytes(head[2 .. 2 + std::mem::size_of::<Id>()].try_into().unwrap());
+        let timestamp = u64::from_le_bytes(head[2 + std::mem::size_of::<Id>() ..].try_into().unwrap());
+        let hash = if flags & FLAG_HASH != 0 {
+            let mut buf = [0; 4];
+            reader.read_exact(&mut buf)?;
+            Some(u32::from_le_bytes(buf))
+        } else {
+            None
+        };
+        let data = if flags & FLAG_DATA != 0 {
+            let mut buf = Box::new([0; BLOCK_SIZE]);
+            reader.read_exact(&mut buf[..])?;
+            Some(buf)
+        } else {
+            None
+        };
+        Ok(Some(TraceRecord { op, block_id, timestamp, hash, data }))
+    }
+}
+
+/// Header: [ magic "rfst" (4B) | version (1B) | size of Id (1B) | BLOCK_SIZE (4B) ]
+fn write_header(writer: &mut dyn Write) -> Result<(), std::io::Error> {
+    writer.write_all(&TRACE_MAGIC)?;
+    writer.write_all(&[TRACE_VERSION, std::mem::size_of::<Id>() as u8])?;
+    writer.write_all(&(BLOCK_SIZE as u32).to_le_bytes())
+}
+
+#[allow(dead_code)] // Only used by rfs-replay
+pub fn read_header(reader: &mut dyn Read) -> Result<(), std::io::Error> {
+    let mut header = [0; 10];
+    reader.read_exact(&mut header)?;
+    if header[0 .. 4] != TRACE_MAGIC || header[4] != TRACE_VERSION || header[5] as usize != std::mem::size_of::<Id>()
+        || u32::from_le_bytes(header[6 ..].try_into().unwrap()) as usize != BLOCK_SIZE {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Unsupported trace format"))
+    }
+    Ok(())
+}
+
+/// Log every operation on another BlockIO to a binary trace, which can be replayed by rfs-replay.
+/// Write data is only recorded if `with_data` is set, which is needed for replaying
+pub struct TraceBlockIO {
+    block_io: Box<dyn BlockIO>,
+    writer: Box<dyn Write>,
+    with_hash: bool,
+    with_data: bool,
+}
+
+impl TraceBlockIO {
+    pub fn new(block_io: Box<dyn BlockIO>, mut writer: Box<dyn Write>, with_hash: bool, with_data: bool)
+               -> Result<TraceBlockIO, std::io::Error> {
+        write_header(&mut writer)?;
+        Ok(TraceBlockIO { block_io, writer, with_hash, with_data })
+    }
+
+    fn log(&mut self, op: TraceOp, block_id: Id, data: Option<&[u8]>) -> Result<(), std::io::Error> {
+        let now = time::get_time();
+        let hash = match data {
+            Some(data) if self.with_hash => Some(crc32c(data)),
+            _ => None
+        };
+        let data = match data {
+            Some(data) if self.with_data && op == TraceOp::Write => {
+                let mut buf = Box::new([0; BLOCK_SIZE]);
+                buf.copy_from_slice(data);
+                Some(buf)
+            },
+            _ => None
+        };
+        let record = TraceRecord {
+            op, block_id, timestamp: now.sec as u64 * 1000000 + now.nsec as u64 / 1000, hash, data
+        };
+        record.write_to(&mut self.writer)
+    }
+}
+
+impl BlockIO for TraceBlockIO {
+    fn read(&mut self, block_id: Id) -> Result<[u8; BLOCK_SIZE], std::io::Error> {
+        let data = self.block_io.read(block_id)?;
+        self.log(TraceOp::Read, block_id, Some(&data))?;
+        Ok(data)
+    }
+
+    fn write(&mut self, block_id: Id, data: &[u8]) -> Result<(), std::io::Error> {
+        self.log(TraceOp::Write, block_id, Some(data))?; // Log first, so a crash in between is visible
+        self.block_io.write(block_id, data)
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.block_io.flush()?;
+        self.log(TraceOp::Flush, 0, None)?;
+        self.writer.flush()
+    }
+
+    /// Logged block by block
+    fn discard(&mut self, block_id: Id, cnt: usize) -> Result<(), std::io::Error> {
+        for id in block_id .. block_id + cnt as Id {
+            self.log(TraceOp::Discard, id, None)?;
+        }
+        self.block_io.discard(block_id, cnt)
+    }
+
+    fn tail_block_cnt(&self, block_cnt: usize) -> usize {
+        self.block_io.tail_block_cnt(block_cnt)
+    }
+
+    fn resize(&mut self, block_cnt: usize) -> Result<(), std::io::Error> {
+        self.block_io.resize(block_cnt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::FakeMemBlockIO;
+
+    /// A Write whose content stays accessible after being moved into TraceBlockIO
+    struct SharedBuf(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), std::io::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_record_and_parse() -> Result<(), std::io::Error> {
+        let buf = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        let writer = Box::new(SharedBuf(buf.clone()));
+        let mut block_io = TraceBlockIO::new(Box::new(FakeMemBlockIO::new()), writer, true, true)?;
+        block_io.write(3, &[3; BLOCK_SIZE])?;
+        block_io.read(3)?;
+        block_io.flush()?;
+
+        let trace = buf.borrow();
+        let mut reader = &trace[..];
+        read_header(&mut reader)?;
+        let write = TraceRecord::read_from(&mut reader)?.unwrap();
+        assert_eq!(write.op, TraceOp::Write);
+        assert_eq!(write.block_id, 3);
+        assert_eq!(write.hash, Some(crc32c(&[3; BLOCK_SIZE])));
+        assert_eq!(write.data.unwrap()[..], [3; BLOCK_SIZE][..]);
+        let read = TraceRecord::read_from(&mut reader)?.unwrap();
+        assert_eq!(read.op, TraceOp::Read);
+        assert_eq!(read.hash, write.hash);
+        assert!(read.data.is_none());
+        assert!(read.timestamp >= write.timestamp);
+        assert_eq!(TraceRecord::read_from(&mut reader)?.unwrap().op, TraceOp::Flush);
+        assert!(TraceRecord::read_from(&mut reader)?.is_none());
+        Ok(())
+    }
+}